@@ -0,0 +1,52 @@
+/*
+The 2-byte checksum is calculated over the packet’s contents - the message class
+and ID bytes, the payload length bytes, and the payload itself. The formula is:
+    // assuming Packet is a byte array containing the entire
+    // packet with header and 2 spare bytes at the end for
+    // the checksum:
+    byte CK_A = 0, CK_B = 0
+    for (int i = 2; i < len(Packet)-2; i++) {
+        CK_A = CK_A + Packet[i]
+        CK_B = CK_B + CK_A
+    }
+    Packet[len(Packet)-2] = CK_A
+    Packet[len(Packet)-1] = CK_B
+*/
+
+// Computes (CK_A, CK_B) over a whole packet, including the sync bytes and checksum slots
+pub fn checksum(raw: &[u8]) -> (u8, u8) {
+    let mut ck_a: u8 = 0;
+    let mut ck_b: u8 = 0;
+
+    for byte in raw.iter().take(raw.len().saturating_sub(2)).skip(2) {
+        ck_a = ck_a.wrapping_add(*byte);
+        ck_b = ck_b.wrapping_add(ck_a);
+    }
+    (ck_a, ck_b)
+}
+
+// True if the checksum at the end of the packet matches its contents
+pub fn rb_checksum(raw: &[u8]) -> bool {
+    if raw.len() < 4 {
+        return false;
+    }
+    let (ck_a, ck_b) = checksum(raw);
+    ck_a == raw[raw.len() - 2] && ck_b == raw[raw.len() - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, rb_checksum};
+    use crate::packet::tests::RAW;
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(&RAW), (0x06, 0xDB));
+        assert!(rb_checksum(&RAW));
+
+        let mut bad = RAW;
+        bad[40] ^= 0x01;
+        assert!(!rb_checksum(&bad));
+        assert!(!rb_checksum(&[0xB5, 0x62]));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::packet::Packet;
+use crate::{HEADER_LEN, MESSAGE_CLASS, MESSAGE_ID, PACKET_LEN, PAYLOAD_LEN, SYNC};
+
+/*
+Reassembles packets from a byte stream. BLE notifications don't have to line
+up with packet boundaries, so bytes are fed in one at a time and a complete
+packet with a valid checksum is returned once it has arrived. On any framing or
+checksum error the assembler goes back to hunting for the sync bytes, starting
+from the byte after the failed sync so a packet that follows garbage isn't lost.
+*/
+#[derive(Clone, Debug)]
+pub struct FrameAssembler {
+    buffer: [u8; PACKET_LEN],
+    len: usize,
+    checksum_failures: u32,
+}
+
+impl Default for FrameAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameAssembler {
+    pub const fn new() -> Self {
+        FrameAssembler {
+            buffer: [0; PACKET_LEN],
+            len: 0,
+            checksum_failures: 0,
+        }
+    }
+
+    // Number of complete frames dropped because of a bad checksum
+    pub fn checksum_failures(&self) -> u32 {
+        self.checksum_failures
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<[u8; PACKET_LEN]> {
+        self.buffer[self.len] = byte;
+        self.len += 1;
+
+        if !self.header_ok() {
+            self.resync();
+            return None;
+        }
+        if self.len < PACKET_LEN {
+            return None;
+        }
+
+        let packet = self.buffer;
+        if Packet::new(&packet).is_valid() {
+            self.len = 0;
+            Some(packet)
+        } else {
+            self.checksum_failures += 1;
+            self.resync();
+            None
+        }
+    }
+
+    // Feeds a slice, calling back for each complete packet
+    pub fn push_slice<F: FnMut(&[u8; PACKET_LEN])>(&mut self, bytes: &[u8], mut on_packet: F) {
+        for byte in bytes {
+            if let Some(packet) = self.push(*byte) {
+                on_packet(&packet);
+            }
+        }
+    }
+
+    // True if the bytes received so far could be the start of a packet
+    fn header_ok(&self) -> bool {
+        let expected = [
+            SYNC[0],
+            SYNC[1],
+            MESSAGE_CLASS,
+            MESSAGE_ID,
+            PAYLOAD_LEN as u8,
+            (PAYLOAD_LEN >> 8) as u8,
+        ];
+        let n = self.len.min(HEADER_LEN);
+        self.buffer[..n] == expected[..n]
+    }
+
+    // Drops the first byte and shifts along to the next possible start of a packet
+    fn resync(&mut self) {
+        let mut start = 1;
+        while start < self.len {
+            let n = self.len - start;
+            self.buffer.copy_within(start..self.len, 0);
+            self.len = n;
+            if self.header_ok() {
+                return;
+            }
+            self.buffer.copy_within(0..n, start);
+            self.len = n + start;
+            start += 1;
+        }
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameAssembler;
+    use crate::packet::tests::RAW;
+
+    #[test]
+    fn test_whole_packet() {
+        let mut assembler = FrameAssembler::new();
+        let mut count = 0;
+        assembler.push_slice(&RAW, |packet| {
+            assert_eq!(*packet, RAW);
+            count += 1;
+        });
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_split_and_merged_packets() {
+        // Garbage, a packet split across notifications, then two back to back
+        let mut stream = [0u8; 3 + 88 * 3];
+        stream[..3].copy_from_slice(&[0x00, 0xB5, 0x13]);
+        stream[3..91].copy_from_slice(&RAW);
+        stream[91..179].copy_from_slice(&RAW);
+        stream[179..].copy_from_slice(&RAW);
+
+        let mut assembler = FrameAssembler::new();
+        let mut count = 0;
+        for chunk in stream.chunks(20) {
+            assembler.push_slice(chunk, |packet| {
+                assert_eq!(*packet, RAW);
+                count += 1;
+            });
+        }
+        assert_eq!(count, 3);
+        assert_eq!(assembler.checksum_failures(), 0);
+    }
+
+    #[test]
+    fn test_bad_checksum_resyncs() {
+        let mut corrupt = RAW;
+        corrupt[40] ^= 0xFF;
+
+        let mut assembler = FrameAssembler::new();
+        let mut count = 0;
+        assembler.push_slice(&corrupt, |_| count += 1);
+        assembler.push_slice(&RAW, |_| count += 1);
+        assert_eq!(count, 1);
+        assert_eq!(assembler.checksum_failures(), 1);
+    }
+
+    #[test]
+    fn test_sync_inside_payload() {
+        // A truncated packet followed by a good one, the good one's sync lands mid frame
+        let mut stream = [0u8; 40 + 88];
+        stream[..40].copy_from_slice(&RAW[..40]);
+        stream[40..].copy_from_slice(&RAW);
+
+        let mut assembler = FrameAssembler::new();
+        let mut count = 0;
+        assembler.push_slice(&stream, |packet| {
+            assert_eq!(*packet, RAW);
+            count += 1;
+        });
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,27 @@
+#![no_std]
+
+/*
+Allocation free RaceBox Mini decoding core
+
+Everything here works on fixed size byte arrays with no heap, no floating point
+maths library and no dependencies, so it can run on a microcontroller sharing
+the BLE link. The rbmini crate builds its std API on top of this.
+*/
+
+pub mod checksum;
+pub mod frame;
+pub mod packet;
+pub mod units;
+
+pub use checksum::rb_checksum;
+pub use frame::FrameAssembler;
+pub use packet::Packet;
+
+// Packet framing, a u-blox style header, 80 byte payload and 2 byte checksum
+pub const SYNC: [u8; 2] = [0xB5, 0x62];
+pub const MESSAGE_CLASS: u8 = 0xFF;
+pub const MESSAGE_ID: u8 = 0x01;
+pub const HEADER_LEN: usize = 6;
+pub const PAYLOAD_LEN: usize = 80;
+pub const CHECKSUM_LEN: usize = 2;
+pub const PACKET_LEN: usize = HEADER_LEN + PAYLOAD_LEN + CHECKSUM_LEN;
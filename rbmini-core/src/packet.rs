@@ -0,0 +1,317 @@
+use crate::{MESSAGE_CLASS, MESSAGE_ID, PACKET_LEN, PAYLOAD_LEN, SYNC};
+
+/*
+A borrowed view of a RaceBox Mini data packet. Fields are read straight out of
+the buffer at their fixed little endian offsets, nothing is copied or decoded
+until it is asked for.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packet<'a> {
+    raw: &'a [u8; PACKET_LEN],
+}
+
+// Offsets into the packet, the payload starts after the 6 byte header
+const ITOW: usize = 6;
+const YEAR: usize = 10;
+const MONTH: usize = 12;
+const DAY: usize = 13;
+const HOUR: usize = 14;
+const MINUTE: usize = 15;
+const SECOND: usize = 16;
+const VALIDITY: usize = 17;
+const TIME_ACCURACY: usize = 18;
+const NANOSECONDS: usize = 22;
+const FIX_STATUS: usize = 26;
+const FIX_STATUS_FLAGS: usize = 27;
+const DATE_TIME_FLAGS: usize = 28;
+const NUMBER_OF_SVS: usize = 29;
+const LONGITUDE: usize = 30;
+const LATITUDE: usize = 34;
+const WGS_ALTITUDE: usize = 38;
+const MSL_ALTITUDE: usize = 42;
+const HORIZONTAL_ACCURACY: usize = 46;
+const VERTICAL_ACCURACY: usize = 50;
+const SPEED: usize = 54;
+const HEADING: usize = 58;
+const SPEED_ACCURACY: usize = 62;
+const HEADING_ACCURACY: usize = 66;
+const PDOP: usize = 70;
+const LAT_LON_FLAGS: usize = 72;
+const BATTERY_STATUS: usize = 73;
+const G_FORCE_X: usize = 74;
+const G_FORCE_Y: usize = 76;
+const G_FORCE_Z: usize = 78;
+const ROT_RATE_X: usize = 80;
+const ROT_RATE_Y: usize = 82;
+const ROT_RATE_Z: usize = 84;
+const CHECKSUM: usize = 86;
+
+impl<'a> Packet<'a> {
+    // Wraps a buffer without checking it, see is_valid()
+    pub fn new(raw: &'a [u8; PACKET_LEN]) -> Self {
+        Packet { raw }
+    }
+
+    // Wraps a buffer holding a well formed data packet with a correct checksum
+    pub fn parse(raw: &'a [u8; PACKET_LEN]) -> Option<Self> {
+        let packet = Packet { raw };
+        if packet.is_valid() {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+
+    pub fn raw(&self) -> &'a [u8; PACKET_LEN] {
+        self.raw
+    }
+
+    // Sync bytes, message class and id, payload length and checksum all match
+    pub fn is_valid(&self) -> bool {
+        self.raw[0..2] == SYNC
+            && self.class() == MESSAGE_CLASS
+            && self.id() == MESSAGE_ID
+            && usize::from(self.payload_length()) == PAYLOAD_LEN
+            && crate::rb_checksum(self.raw)
+    }
+
+    fn u8(&self, at: usize) -> u8 {
+        self.raw[at]
+    }
+
+    fn u16(&self, at: usize) -> u16 {
+        u16::from_le_bytes([self.raw[at], self.raw[at + 1]])
+    }
+
+    fn i16(&self, at: usize) -> i16 {
+        i16::from_le_bytes([self.raw[at], self.raw[at + 1]])
+    }
+
+    fn u32(&self, at: usize) -> u32 {
+        u32::from_le_bytes([
+            self.raw[at],
+            self.raw[at + 1],
+            self.raw[at + 2],
+            self.raw[at + 3],
+        ])
+    }
+
+    fn i32(&self, at: usize) -> i32 {
+        self.u32(at) as i32
+    }
+
+    pub fn class(&self) -> u8 {
+        self.u8(2)
+    }
+
+    pub fn id(&self) -> u8 {
+        self.u8(3)
+    }
+
+    pub fn payload_length(&self) -> u16 {
+        self.u16(4)
+    }
+
+    pub fn itow(&self) -> u32 {
+        self.u32(ITOW)
+    }
+
+    pub fn year(&self) -> u16 {
+        self.u16(YEAR)
+    }
+
+    pub fn month(&self) -> u8 {
+        self.u8(MONTH)
+    }
+
+    pub fn day(&self) -> u8 {
+        self.u8(DAY)
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.u8(HOUR)
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.u8(MINUTE)
+    }
+
+    pub fn second(&self) -> u8 {
+        self.u8(SECOND)
+    }
+
+    pub fn validity(&self) -> u8 {
+        self.u8(VALIDITY)
+    }
+
+    pub fn time_accuracy(&self) -> u32 {
+        self.u32(TIME_ACCURACY)
+    }
+
+    pub fn nanoseconds(&self) -> i32 {
+        self.i32(NANOSECONDS)
+    }
+
+    pub fn fix_status(&self) -> u8 {
+        self.u8(FIX_STATUS)
+    }
+
+    pub fn fix_status_flags(&self) -> u8 {
+        self.u8(FIX_STATUS_FLAGS)
+    }
+
+    pub fn date_time_flags(&self) -> u8 {
+        self.u8(DATE_TIME_FLAGS)
+    }
+
+    pub fn number_of_svs(&self) -> u8 {
+        self.u8(NUMBER_OF_SVS)
+    }
+
+    // Degrees with a factor of 10^7
+    pub fn longitude(&self) -> i32 {
+        self.i32(LONGITUDE)
+    }
+
+    pub fn latitude(&self) -> i32 {
+        self.i32(LATITUDE)
+    }
+
+    // Millimetres
+    pub fn wgs_altitude(&self) -> i32 {
+        self.i32(WGS_ALTITUDE)
+    }
+
+    pub fn msl_altitude(&self) -> i32 {
+        self.i32(MSL_ALTITUDE)
+    }
+
+    pub fn horizontal_accuracy(&self) -> u32 {
+        self.u32(HORIZONTAL_ACCURACY)
+    }
+
+    pub fn vertical_accuracy(&self) -> u32 {
+        self.u32(VERTICAL_ACCURACY)
+    }
+
+    // Millimetres per second
+    pub fn speed(&self) -> i32 {
+        self.i32(SPEED)
+    }
+
+    // Degrees with a factor of 10^5
+    pub fn heading(&self) -> i32 {
+        self.i32(HEADING)
+    }
+
+    pub fn speed_accuracy(&self) -> u32 {
+        self.u32(SPEED_ACCURACY)
+    }
+
+    pub fn heading_accuracy(&self) -> u32 {
+        self.u32(HEADING_ACCURACY)
+    }
+
+    // Factor of 100
+    pub fn pdop(&self) -> u16 {
+        self.u16(PDOP)
+    }
+
+    pub fn lat_lon_flags(&self) -> u8 {
+        self.u8(LAT_LON_FLAGS)
+    }
+
+    pub fn battery_status(&self) -> u8 {
+        self.u8(BATTERY_STATUS)
+    }
+
+    // Milli-g on the X, Y and Z axes
+    pub fn g_forces(&self) -> (i16, i16, i16) {
+        (
+            self.i16(G_FORCE_X),
+            self.i16(G_FORCE_Y),
+            self.i16(G_FORCE_Z),
+        )
+    }
+
+    // Centi-degrees per second on the X, Y and Z axes
+    pub fn rot_rates(&self) -> (i16, i16, i16) {
+        (
+            self.i16(ROT_RATE_X),
+            self.i16(ROT_RATE_Y),
+            self.i16(ROT_RATE_Z),
+        )
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.u16(CHECKSUM)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::Packet;
+    use crate::PACKET_LEN;
+
+    pub(crate) const RAW: [u8; PACKET_LEN] = [
+        0xB5, 0x62, 0xFF, 0x01, 0x50, 0x00, 0xA0, 0xE7, 0x0C, 0x07, 0xE6, 0x07, 0x01, 0x0A, 0x08,
+        0x33, 0x08, 0x37, 0x19, 0x00, 0x00, 0x00, 0x2A, 0xAD, 0x4D, 0x0E, 0x03, 0x01, 0xEA, 0x0B,
+        0xC6, 0x93, 0xE1, 0x0D, 0x3B, 0x37, 0x6F, 0x19, 0x61, 0x8C, 0x09, 0x00, 0x0F, 0x01, 0x09,
+        0x00, 0x9C, 0x03, 0x00, 0x00, 0x2C, 0x07, 0x00, 0x00, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xD0, 0x00, 0x00, 0x00, 0x88, 0xA9, 0xDD, 0x00, 0x2C, 0x01, 0x00, 0x59, 0xFD,
+        0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00, 0xFC, 0xFF, 0x06, 0xDB,
+    ];
+
+    #[test]
+    fn test_packet_fields() {
+        let packet = Packet::parse(&RAW).unwrap();
+        assert_eq!(packet.class(), 0xFF);
+        assert_eq!(packet.id(), 0x01);
+        assert_eq!(packet.payload_length(), 80);
+        assert_eq!(packet.itow(), 118286240);
+        assert_eq!(packet.year(), 2022);
+        assert_eq!(packet.month(), 1);
+        assert_eq!(packet.day(), 10);
+        assert_eq!(packet.hour(), 8);
+        assert_eq!(packet.minute(), 51);
+        assert_eq!(packet.second(), 8);
+        assert_eq!(packet.validity(), 0x37);
+        assert_eq!(packet.time_accuracy(), 25);
+        assert_eq!(packet.nanoseconds(), 239971626);
+        assert_eq!(packet.fix_status(), 3);
+        assert_eq!(packet.fix_status_flags(), 0x01);
+        assert_eq!(packet.date_time_flags(), 0xEA);
+        assert_eq!(packet.number_of_svs(), 11);
+        assert_eq!(packet.longitude(), 232887238);
+        assert_eq!(packet.latitude(), 426719035);
+        assert_eq!(packet.wgs_altitude(), 625761);
+        assert_eq!(packet.msl_altitude(), 590095);
+        assert_eq!(packet.horizontal_accuracy(), 924);
+        assert_eq!(packet.vertical_accuracy(), 1836);
+        assert_eq!(packet.speed(), 35);
+        assert_eq!(packet.heading(), 0);
+        assert_eq!(packet.speed_accuracy(), 208);
+        assert_eq!(packet.heading_accuracy(), 14526856);
+        assert_eq!(packet.pdop(), 300);
+        assert_eq!(packet.lat_lon_flags(), 0);
+        assert_eq!(packet.battery_status(), 89);
+        assert_eq!(packet.g_forces(), (-3, 113, 974));
+        assert_eq!(packet.rot_rates(), (-209, 86, -4));
+        assert_eq!(packet.checksum(), 0xDB06);
+    }
+
+    #[test]
+    fn test_packet_validation() {
+        assert!(Packet::new(&RAW).is_valid());
+
+        let mut bad = RAW;
+        bad[0] = 0x00;
+        assert!(Packet::parse(&bad).is_none());
+
+        let mut bad = RAW;
+        bad[50] = 0x00;
+        assert!(Packet::parse(&bad).is_none());
+        // Unchecked access still works on a corrupt packet
+        assert_eq!(Packet::new(&bad).itow(), 118286240);
+    }
+}
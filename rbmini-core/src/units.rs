@@ -0,0 +1,68 @@
+/*
+Unit conversions for the scaled integers in a packet
+
+Plain f64 arithmetic only, so they work on targets without a maths library. f64
+keeps the precision of the raw values, an f32 coordinate is only good to about a
+metre. They take i64 so every packet field, signed or unsigned, converts without
+a cast. The std crate's unit newtypes are built from these.
+*/
+
+// Degrees with a factor of 10^7 to degrees
+pub fn coordinate_degrees(raw: i64) -> f64 {
+    raw as f64 / 10_000_000.0
+}
+
+// Millimetres to metres
+pub fn meters(mm: i64) -> f64 {
+    mm as f64 / 1000.0
+}
+
+// Millimetres per second to metres per second
+pub fn meters_per_second(mm_per_second: i64) -> f64 {
+    mm_per_second as f64 / 1000.0
+}
+
+// Millimetres per second to kilometres per hour
+pub fn kph(mm_per_second: i64) -> f64 {
+    mm_per_second as f64 * 0.0036
+}
+
+// Degrees with a factor of 10^5 to degrees, for heading and heading accuracy
+pub fn heading_degrees(raw: i64) -> f64 {
+    raw as f64 / 100_000.0
+}
+
+// PDOP is sent with a factor of 100
+pub fn dop(raw: i64) -> f64 {
+    raw as f64 / 100.0
+}
+
+// Milli-g to g
+pub fn g(milli_g: i64) -> f64 {
+    milli_g as f64 / 1000.0
+}
+
+// Centi-degrees per second to degrees per second
+pub fn degrees_per_second(centi_degrees: i64) -> f64 {
+    centi_degrees as f64 / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coordinate_degrees, degrees_per_second, dop, g, heading_degrees, kph, meters};
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_units() {
+        assert_close(coordinate_degrees(426719035), 42.6719035);
+        assert_close(meters(625761), 625.761);
+        assert_close(kph(27778), 100.0008);
+        assert_close(heading_degrees(14526856), 145.26856);
+        assert_close(dop(300), 3.0);
+        assert_close(g(974), 0.974);
+        assert_close(degrees_per_second(-209), -2.09);
+    }
+}
@@ -0,0 +1,648 @@
+use crate::gpstime::ElapsedClock;
+use crate::message::RbMessage;
+use crate::quality::{FixQuality, QualityThresholds};
+use crate::session::Session;
+use crate::units::{Acceleration, Length, Speed, STANDARD_GRAVITY};
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/*
+Acceleration performance measurements
+
+A run starts from standstill. The car has to be stopped, then a sample faster
+than the launch speed with at least the launch g on the X axis starts the run.
+The moment it started moving is put back from that sample using the measured
+acceleration, so the start isn't rounded to the 40ms sample interval. Creeping
+forward without the g doesn't start a run, rolling away faster than the launch
+speed without it cancels the standing start.
+
+Speed between samples is taken as changing linearly, so distance is the area
+under the speed and a speed or distance is found to within the sample interval
+by solving for it in the segment it falls in. With a rollout, such as the foot a
+car moves on a drag strip before breaking the start beam, the clock starts when
+the car has covered it and distances are measured from there. Speed ranges from
+a standstill also start after the rollout, ranges starting above zero don't
+depend on it.
+
+A run ends when every target has been reached, when the speed drops the set
+amount below its peak, or on a sample without a usable fix. Each result has the
+slope over it, the altitude change over the distance covered, positive uphill,
+and a time corrected to level ground by taking the gravity along the slope out of
+the average acceleration.
+*/
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SpeedRange {
+    pub from: Speed,
+    pub to: Speed,
+}
+
+impl SpeedRange {
+    pub fn new(from: Speed, to: Speed) -> Self {
+        SpeedRange { from, to }
+    }
+
+    pub fn kph(from: f64, to: f64) -> Self {
+        Self::new(Speed::from_kph(from), Speed::from_kph(to))
+    }
+
+    pub fn mph(from: f64, to: f64) -> Self {
+        Self::new(Speed::from_mph(from), Speed::from_mph(to))
+    }
+
+    fn is_standing_start(&self) -> bool {
+        self.from.meters_per_second() <= 0.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccelerationSettings {
+    pub speed_ranges: Vec<SpeedRange>,
+    pub distances: Vec<Length>,
+    pub rollout: Length,
+    pub stationary_speed: Speed,
+    pub launch_speed: Speed,
+    pub launch_g: Acceleration,
+    pub max_speed_drop: Speed, // below the peak speed, ends a run
+}
+
+impl Default for AccelerationSettings {
+    fn default() -> Self {
+        AccelerationSettings {
+            speed_ranges: vec![
+                SpeedRange::kph(0.0, 100.0),
+                SpeedRange::kph(100.0, 200.0),
+                SpeedRange::mph(0.0, 60.0),
+            ],
+            distances: vec![
+                Length::from_feet(60.0),
+                Length::from_feet(660.0),  // 1/8 mile
+                Length::from_feet(1320.0), // 1/4 mile
+            ],
+            rollout: Length::from_meters(0.0),
+            stationary_speed: Speed::from_kph(1.0),
+            launch_speed: Speed::from_kph(3.0),
+            launch_g: Acceleration::from_g(0.1),
+            max_speed_drop: Speed::from_kph(10.0),
+        }
+    }
+}
+
+impl AccelerationSettings {
+    // Drag strip timing, with the 1 foot rollout
+    pub fn drag() -> Self {
+        AccelerationSettings {
+            rollout: Length::from_feet(1.0),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SpeedResult {
+    pub range: SpeedRange,
+    pub time: Duration,
+    pub distance: Length,
+    pub slope: f64, // rise over distance
+    pub corrected_time: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct DistanceResult {
+    pub distance: Length,
+    pub time: Duration,
+    pub trap_speed: Speed, // speed at the distance
+    pub slope: f64,
+    pub corrected_time: Option<Duration>,
+}
+
+// A measured run, with results for the targets it reached
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccelerationRun {
+    pub start: Duration, // from the meter's first message to the car moving
+    pub rollout: Length,
+    pub peak_speed: Speed,
+    pub speeds: Vec<SpeedResult>,
+    pub distances: Vec<DistanceResult>,
+}
+
+// A sample of a run, time in seconds and the rest in metres
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Point {
+    pub(crate) time: f64,
+    pub(crate) speed: f64,
+    pub(crate) distance: f64,
+    pub(crate) altitude: f64,
+}
+
+// Measures runs from a message stream, live with update() or over a session with session_runs()
+#[derive(Debug)]
+pub struct AccelerationMeter {
+    settings: AccelerationSettings,
+    thresholds: QualityThresholds,
+    clock: ElapsedClock,
+    staged: Option<Point>, // the last stationary sample, while waiting for a launch
+    trace: Vec<Point>,     // the run in progress
+    peak: f64,
+}
+
+impl AccelerationMeter {
+    pub fn new(settings: AccelerationSettings) -> Self {
+        AccelerationMeter {
+            settings,
+            thresholds: QualityThresholds::default(),
+            clock: ElapsedClock::default(),
+            staged: None,
+            trace: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn settings(&self) -> &AccelerationSettings {
+        &self.settings
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.trace.is_empty()
+    }
+
+    // Returns a run when it ends
+    pub fn update(&mut self, message: &RbMessage) -> Option<AccelerationRun> {
+        let time = self.clock.advance(message)? / 1000.0;
+        if !FixQuality::assess(message, &self.thresholds).is_usable() {
+            self.staged = None;
+            return self.finish();
+        }
+        let speed = message.speed().meters_per_second();
+        let altitude = message.wgs_altitude().meters();
+        if self.is_running() {
+            return self.extend(time, speed, altitude);
+        }
+
+        if speed < self.settings.stationary_speed.meters_per_second() {
+            self.staged = Some(Point {
+                time,
+                speed: 0.0,
+                distance: 0.0,
+                altitude,
+            });
+            return None;
+        }
+        let staged = self.staged?;
+        if speed < self.settings.launch_speed.meters_per_second() {
+            return None;
+        }
+        let g = message.g_forces().0;
+        self.staged = None;
+        if g < self.settings.launch_g {
+            return None;
+        }
+        // Back to when the car was still at the acceleration it launched with. The
+        // last stationary sample can already be moving slower than stationary_speed,
+        // but not from before the first message.
+        let acceleration = g.meters_per_second_squared();
+        let earliest =
+            staged.time - self.settings.stationary_speed.meters_per_second() / acceleration;
+        let launch = (time - speed / acceleration).clamp(earliest.max(0.0), time);
+        self.trace.push(Point {
+            time: launch,
+            ..staged
+        });
+        self.peak = 0.0;
+        self.extend(time, speed, altitude)
+    }
+
+    fn extend(&mut self, time: f64, speed: f64, altitude: f64) -> Option<AccelerationRun> {
+        let last = *self.trace.last()?;
+        let distance = last.distance + (last.speed + speed) / 2.0 * (time - last.time);
+        self.trace.push(Point {
+            time,
+            speed,
+            distance,
+            altitude,
+        });
+        self.peak = self.peak.max(speed);
+
+        let settings = &self.settings;
+        let lifted = speed < self.peak - settings.max_speed_drop.meters_per_second();
+        let top_speed = settings
+            .speed_ranges
+            .iter()
+            .map(|range| range.to.meters_per_second())
+            .fold(0.0, f64::max);
+        let longest = settings
+            .distances
+            .iter()
+            .map(|distance| distance.meters())
+            .fold(0.0, f64::max);
+        let done = self.peak >= top_speed && distance >= settings.rollout.meters() + longest;
+        if lifted || done {
+            return self.finish();
+        }
+        None
+    }
+
+    // Ends the run in progress, at the end of a recording
+    pub fn finish(&mut self) -> Option<AccelerationRun> {
+        let trace = std::mem::take(&mut self.trace);
+        if trace.len() < 2 {
+            return None;
+        }
+        let run = measure(
+            &trace,
+            &self.settings,
+            Speed::from_meters_per_second(self.peak),
+        )?;
+        (!run.speeds.is_empty() || !run.distances.is_empty()).then_some(run)
+    }
+}
+
+fn measure(
+    trace: &[Point],
+    settings: &AccelerationSettings,
+    peak: Speed,
+) -> Option<AccelerationRun> {
+    let rollout = settings.rollout.meters();
+    let clock_start = if rollout > 0.0 {
+        time_at_distance(trace, rollout)?
+    } else {
+        trace[0].time
+    };
+
+    let speeds = settings
+        .speed_ranges
+        .iter()
+        .filter_map(|range| {
+            let start = if range.is_standing_start() {
+                clock_start
+            } else {
+                time_at_speed(trace, range.from.meters_per_second())?
+            };
+            let end = time_at_speed(trace, range.to.meters_per_second())?;
+            // A range from a standstill that ends inside the rollout has no time
+            let time = end - start;
+            if time <= 0.0 {
+                return None;
+            }
+            let (from, to) = (state_at(trace, start), state_at(trace, end));
+            let distance = to.distance - from.distance;
+            let slope = slope(from, to);
+            // Level ground acceleration is the measured one plus the gravity it climbed against
+            let gained = to.speed - from.speed;
+            let level = gained / time + STANDARD_GRAVITY * slope;
+            Some(SpeedResult {
+                range: *range,
+                time: Duration::from_secs_f64(time),
+                distance: Length::from_meters(distance),
+                slope,
+                corrected_time: (level > 0.0).then(|| Duration::from_secs_f64(gained / level)),
+            })
+        })
+        .collect();
+
+    let distances = settings
+        .distances
+        .iter()
+        .filter_map(|target| {
+            let end = time_at_distance(trace, rollout + target.meters())?;
+            let time = end - clock_start;
+            if time <= 0.0 {
+                return None;
+            }
+            let (from, to) = (state_at(trace, clock_start), state_at(trace, end));
+            let slope = slope(from, to);
+            let covered = target.meters();
+            let average = 2.0 * (covered - from.speed * time) / (time * time);
+            let level = average + STANDARD_GRAVITY * slope;
+            Some(DistanceResult {
+                distance: *target,
+                time: Duration::from_secs_f64(time),
+                trap_speed: Speed::from_meters_per_second(to.speed),
+                slope,
+                corrected_time: travel_time(from.speed, level, covered)
+                    .map(Duration::from_secs_f64),
+            })
+        })
+        .collect();
+
+    Some(AccelerationRun {
+        start: Duration::from_secs_f64(trace[0].time),
+        rollout: settings.rollout,
+        peak_speed: peak,
+        speeds,
+        distances,
+    })
+}
+
+pub(crate) fn slope(from: Point, to: Point) -> f64 {
+    let distance = to.distance - from.distance;
+    if distance > 0.0 {
+        (to.altitude - from.altitude) / distance
+    } else {
+        0.0
+    }
+}
+
+// Time to cover a distance from a speed at a constant acceleration
+fn travel_time(speed: f64, acceleration: f64, distance: f64) -> Option<f64> {
+    if acceleration.abs() < 1e-9 {
+        return (speed > 0.0).then(|| distance / speed);
+    }
+    let discriminant = speed * speed + 2.0 * acceleration * distance;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let time = (discriminant.sqrt() - speed) / acceleration;
+    (time >= 0.0).then_some(time)
+}
+
+// When the speed first reaches the given speed
+fn time_at_speed(trace: &[Point], speed: f64) -> Option<f64> {
+    trace.windows(2).find_map(|w| {
+        let (a, b) = (w[0], w[1]);
+        if b.speed < speed {
+            None
+        } else if a.speed >= speed {
+            Some(a.time)
+        } else {
+            Some(a.time + (speed - a.speed) / (b.speed - a.speed) * (b.time - a.time))
+        }
+    })
+}
+
+// When the distance is first covered
+fn time_at_distance(trace: &[Point], distance: f64) -> Option<f64> {
+    trace.windows(2).find_map(|w| {
+        let (a, b) = (w[0], w[1]);
+        if b.distance < distance {
+            return None;
+        }
+        let acceleration = (b.speed - a.speed) / (b.time - a.time);
+        let time = travel_time(a.speed, acceleration, distance - a.distance).unwrap_or(0.0);
+        Some(a.time + time.min(b.time - a.time))
+    })
+}
+
+// The run at a time, speed changing linearly between samples
+pub(crate) fn state_at(trace: &[Point], time: f64) -> Point {
+    let i = trace
+        .windows(2)
+        .position(|w| w[1].time >= time)
+        .unwrap_or(trace.len().saturating_sub(2));
+    let (a, b) = (trace[i], trace[(i + 1).min(trace.len() - 1)]);
+    let interval = b.time - a.time;
+    if interval <= 0.0 {
+        return a;
+    }
+    let t = time - a.time;
+    let acceleration = (b.speed - a.speed) / interval;
+    Point {
+        time,
+        speed: a.speed + acceleration * t,
+        distance: a.distance + a.speed * t + acceleration * t * t / 2.0,
+        altitude: a.altitude + (b.altitude - a.altitude) * t / interval,
+    }
+}
+
+// Every run in a recorded session
+pub fn session_runs(session: &Session, settings: AccelerationSettings) -> Vec<AccelerationRun> {
+    let mut meter = AccelerationMeter::new(settings);
+    let mut runs: Vec<_> = session
+        .messages()
+        .iter()
+        .filter_map(|message| meter.update(message))
+        .collect();
+    runs.extend(meter.finish());
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{session_runs, AccelerationMeter, AccelerationSettings, SpeedRange};
+    use crate::message::RbMessage;
+    use crate::session::Session;
+    use crate::testing::fix;
+    use crate::units::{Acceleration, Length, Speed, STANDARD_GRAVITY};
+    use std::time::Duration;
+
+    const LAUNCH: f64 = 1.013; // seconds, between samples
+
+    fn message(itow: u32, speed: f64, g: f64, altitude: f64) -> RbMessage {
+        fix(itow)
+            .speed_mps(speed)
+            .altitude(Length::from_meters(altitude))
+            .g_forces(
+                Acceleration::from_g(g),
+                Acceleration::from_g(0.0),
+                Acceleration::from_g(1.0),
+            )
+            .build()
+    }
+
+    /*
+    Stopped until LAUNCH then a constant acceleration up a slope, the altitude
+    rising by slope metres per metre covered
+    */
+    fn run(acceleration: f64, slope: f64, seconds: f64) -> Session {
+        let mut session = Session::default();
+        for i in 0..(seconds * 25.0) as u32 {
+            let t = (f64::from(i) * 0.04 - LAUNCH).max(0.0);
+            let g = if t > 0.0 {
+                acceleration / STANDARD_GRAVITY
+            } else {
+                0.0
+            };
+            let distance = acceleration * t * t / 2.0;
+            session.push(message(
+                i * 40,
+                acceleration * t,
+                g,
+                100.0 + slope * distance,
+            ));
+        }
+        session
+    }
+
+    fn assert_secs(duration: Duration, seconds: f64, tolerance: f64) {
+        assert!(
+            (duration.as_secs_f64() - seconds).abs() < tolerance,
+            "{:?} {}",
+            duration,
+            seconds
+        );
+    }
+
+    // Time to a speed and to a distance from standstill
+    fn to_speed(speed: Speed, acceleration: f64) -> f64 {
+        speed.meters_per_second() / acceleration
+    }
+
+    fn to_distance(distance: f64, acceleration: f64) -> f64 {
+        (2.0 * distance / acceleration).sqrt()
+    }
+
+    #[test]
+    fn test_standing_start() {
+        let runs = session_runs(&run(5.0, 0.0, 20.0), AccelerationSettings::default());
+        assert_eq!(runs.len(), 1);
+        let run = &runs[0];
+        assert_secs(run.start, LAUNCH, 0.005);
+
+        let expected = [
+            to_speed(Speed::from_kph(100.0), 5.0),
+            to_speed(Speed::from_kph(100.0), 5.0),
+            to_speed(Speed::from_mph(60.0), 5.0),
+        ];
+        assert_eq!(run.speeds.len(), 3);
+        for (result, expected) in run.speeds.iter().zip(expected) {
+            assert_secs(result.time, expected, 0.01);
+            assert!(result.slope.abs() < 1e-3);
+            assert_secs(result.corrected_time.unwrap(), expected, 0.01);
+        }
+        assert_eq!(run.speeds[1].range, SpeedRange::kph(100.0, 200.0));
+        let covered = run.speeds[0].distance.meters();
+        assert!((covered - 5.0 * expected[0] * expected[0] / 2.0).abs() < 0.1);
+
+        assert_eq!(run.distances.len(), 3);
+        for result in &run.distances {
+            let expected = to_distance(result.distance.meters(), 5.0);
+            assert_secs(result.time, expected, 0.01);
+            assert!((result.trap_speed.meters_per_second() - 5.0 * expected).abs() < 0.05);
+            assert_secs(result.corrected_time.unwrap(), expected, 0.01);
+        }
+        // 1/4 mile
+        assert_secs(run.distances[2].time, 12.686, 0.01);
+    }
+
+    #[test]
+    fn test_rollout() {
+        let runs = session_runs(&run(5.0, 0.0, 20.0), AccelerationSettings::drag());
+        let run = &runs[0];
+        let rollout = to_distance(Length::from_feet(1.0).meters(), 5.0);
+        assert_eq!(run.rollout, Length::from_feet(1.0));
+
+        // From standstill starts after the rollout, 100-200 doesn't
+        let sixty = to_speed(Speed::from_mph(60.0), 5.0);
+        assert_secs(run.speeds[2].time, sixty - rollout, 0.01);
+        assert_secs(
+            run.speeds[1].time,
+            to_speed(Speed::from_kph(100.0), 5.0),
+            0.01,
+        );
+
+        // Distances are from the end of the rollout
+        for result in &run.distances {
+            let from_standstill = result.distance.meters() + Length::from_feet(1.0).meters();
+            assert_secs(
+                result.time,
+                to_distance(from_standstill, 5.0) - rollout,
+                0.01,
+            );
+        }
+    }
+
+    #[test]
+    fn test_slope_correction() {
+        // Up a 5% slope, the car would do 5 m/s^2 less gravity along the slope on the level
+        let level = 4.5 + STANDARD_GRAVITY * 0.05;
+        let runs = session_runs(&run(4.5, 0.05, 25.0), AccelerationSettings::default());
+        let measured = &runs[0];
+        let hundred = &measured.speeds[0];
+        assert_secs(hundred.time, to_speed(Speed::from_kph(100.0), 4.5), 0.01);
+        assert!((hundred.slope - 0.05).abs() < 0.001, "{}", hundred.slope);
+        assert_secs(
+            hundred.corrected_time.unwrap(),
+            to_speed(Speed::from_kph(100.0), level),
+            0.02,
+        );
+
+        let quarter = &measured.distances[2];
+        assert!((quarter.slope - 0.05).abs() < 0.001);
+        assert_secs(
+            quarter.time,
+            to_distance(quarter.distance.meters(), 4.5),
+            0.01,
+        );
+        assert_secs(
+            quarter.corrected_time.unwrap(),
+            to_distance(quarter.distance.meters(), level),
+            0.02,
+        );
+
+        // Downhill is corrected the other way
+        let runs = session_runs(&run(5.0, -0.05, 20.0), Default::default());
+        let hundred = &runs[0].speeds[0];
+        assert!(hundred.corrected_time.unwrap() > hundred.time);
+    }
+
+    #[test]
+    fn test_launch_on_second_sample() {
+        // At rest on the first sample only, already past the launch speed on the next
+        let mut session = Session::default();
+        session.push(message(0, 0.0, 0.0, 100.0));
+        for i in 1..300 {
+            let speed = 1.0 + 5.0 * f64::from(i - 1) * 0.04;
+            session.push(message(i * 40, speed, 5.0 / STANDARD_GRAVITY, 100.0));
+        }
+        let settings = AccelerationSettings {
+            speed_ranges: vec![SpeedRange::kph(0.0, 5.0), SpeedRange::kph(0.0, 100.0)],
+            ..AccelerationSettings::drag()
+        };
+        let runs = session_runs(&session, settings);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].start, Duration::ZERO);
+        // 5 km/h is reached inside the rollout, so only 0-100 has a time
+        assert_eq!(runs[0].speeds.len(), 1);
+        assert_eq!(runs[0].speeds[0].range, SpeedRange::kph(0.0, 100.0));
+        assert!(!runs[0].distances.is_empty());
+    }
+
+    #[test]
+    fn test_lifted() {
+        // Up to 80 km/h then braking, only the 60ft is reached
+        let mut meter = AccelerationMeter::new(AccelerationSettings::default());
+        let mut runs = Vec::new();
+        let mut speed: f64 = 0.0;
+        for i in 0..500 {
+            let t = f64::from(i) * 0.04;
+            let g = match t {
+                t if t < 1.0 => 0.0,
+                _ if speed < 80.0 / 3.6 && i < 200 => 0.5,
+                _ => -0.8,
+            };
+            speed = (speed + g * STANDARD_GRAVITY * 0.04).max(0.0);
+            runs.extend(meter.update(&message(i * 40, speed, g, 100.0)));
+            if i == 100 {
+                assert!(meter.is_running());
+            }
+        }
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].speeds.is_empty());
+        assert_eq!(runs[0].distances.len(), 1);
+        assert_eq!(runs[0].distances[0].distance, Length::from_feet(60.0));
+        assert!((runs[0].peak_speed.kph() - 80.0).abs() < 2.0);
+        assert!(!meter.is_running());
+        assert_eq!(meter.finish(), None);
+    }
+
+    #[test]
+    fn test_no_standing_start() {
+        // Already moving when the recording starts
+        let mut session = Session::default();
+        for i in 0..500 {
+            let speed = 10.0 + f64::from(i) * 0.2;
+            session.push(message(i * 40, speed, 0.5, 100.0));
+        }
+        assert!(session_runs(&session, AccelerationSettings::default()).is_empty());
+
+        // Rolling away without accelerating cancels the standing start
+        let mut session = Session::default();
+        for i in 0..500 {
+            let speed = (f64::from(i) * 0.04 - 1.0).max(0.0) * 2.0;
+            let g = if i > 100 { 0.5 } else { 0.0 };
+            session.push(message(i * 40, speed, g, 100.0));
+        }
+        assert!(session_runs(&session, AccelerationSettings::default()).is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::gpstime::itow_delta;
+use crate::message::RbMessage;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+
+/*
+The battery status byte contains charging status in the most significant bit
+(1 if charging) and estimation of the battery level in percentage in the
+remaining 7 bits.
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatteryStatus {
+    pub percent: u8,
+    pub charging: bool,
+}
+
+impl From<u8> for BatteryStatus {
+    fn from(raw: u8) -> Self {
+        BatteryStatus {
+            percent: raw & 0x7F,
+            charging: raw >> 7 == 1,
+        }
+    }
+}
+
+impl From<BatteryStatus> for u8 {
+    fn from(status: BatteryStatus) -> Self {
+        (u8::from(status.charging) << 7) | (status.percent & 0x7F)
+    }
+}
+
+impl fmt::Display for BatteryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.charging {
+            write!(f, "{}% (charging)", self.percent)
+        } else {
+            write!(f, "{}%", self.percent)
+        }
+    }
+}
+
+/*
+Estimates the drain rate from the battery level over a session with a least
+squares fit of percent against time, and from that the remaining runtime. The
+level is only reported in whole percent so the estimate needs a few minutes of
+data before it means anything. Charging resets the fit.
+*/
+#[derive(Debug, Default)]
+pub struct BatteryTrend {
+    last_itow: Option<u32>,
+    elapsed: f64, // seconds since the fit started
+    n: f64,
+    sum_t: f64,
+    sum_p: f64,
+    sum_tt: f64,
+    sum_tp: f64,
+    last: Option<BatteryStatus>,
+}
+
+// Shortest span of samples the drain rate is estimated from
+const MIN_TREND_SECONDS: f64 = 60.0;
+
+impl BatteryTrend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, message: &RbMessage) {
+        self.update_status(message.itow(), message.battery_status());
+    }
+
+    pub fn update_status(&mut self, itow: u32, status: BatteryStatus) {
+        self.last = Some(status);
+        if status.charging {
+            *self = BatteryTrend {
+                last: self.last,
+                ..Default::default()
+            };
+            return;
+        }
+
+        if let Some(last_itow) = self.last_itow {
+            let delta = itow_delta(last_itow, itow);
+            if delta > 0 {
+                self.elapsed += delta as f64 / 1000.0;
+            }
+        }
+        self.last_itow = Some(itow);
+
+        let (t, p) = (self.elapsed, f64::from(status.percent));
+        self.n += 1.0;
+        self.sum_t += t;
+        self.sum_p += p;
+        self.sum_tt += t * t;
+        self.sum_tp += t * p;
+    }
+
+    // Drain in percent per hour, None until there is enough data
+    pub fn drain_rate(&self) -> Option<f64> {
+        if self.elapsed < MIN_TREND_SECONDS {
+            return None;
+        }
+        let denominator = self.n * self.sum_tt - self.sum_t * self.sum_t;
+        if denominator <= 0.0 {
+            return None;
+        }
+        let slope = (self.n * self.sum_tp - self.sum_t * self.sum_p) / denominator;
+        Some(-slope * 3600.0)
+    }
+
+    // Remaining runtime at the current drain rate, None while charging or not draining
+    pub fn remaining(&self) -> Option<Duration> {
+        let status = self.last?;
+        if status.charging {
+            return None;
+        }
+        let rate = self.drain_rate()?;
+        if rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            f64::from(status.percent) / rate * 3600.0,
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatteryEvent {
+    // The level dropped to the low battery threshold, with the estimated runtime left
+    Low {
+        status: BatteryStatus,
+        remaining: Option<Duration>,
+    },
+    ChargingStarted(BatteryStatus),
+    ChargingStopped(BatteryStatus),
+}
+
+// The level must recover this far above the threshold before warning again
+const LOW_BATTERY_HYSTERESIS: u8 = 5;
+
+// Watches the message stream and raises battery events
+#[derive(Debug)]
+pub struct BatteryMonitor {
+    threshold: u8,
+    warned: bool,
+    trend: BatteryTrend,
+}
+
+impl BatteryMonitor {
+    // Warns once the level is at or below the threshold percentage
+    pub fn new(threshold: u8) -> Self {
+        BatteryMonitor {
+            threshold,
+            warned: false,
+            trend: BatteryTrend::new(),
+        }
+    }
+
+    pub fn update(&mut self, message: &RbMessage) -> Option<BatteryEvent> {
+        let status = message.battery_status();
+        let previous = self.trend.last;
+        self.trend.update(message);
+
+        if let Some(previous) = previous {
+            if status.charging && !previous.charging {
+                self.warned = false;
+                return Some(BatteryEvent::ChargingStarted(status));
+            }
+            if !status.charging && previous.charging {
+                return Some(BatteryEvent::ChargingStopped(status));
+            }
+        }
+
+        if status.charging {
+            return None;
+        }
+        if status.percent > self.threshold.saturating_add(LOW_BATTERY_HYSTERESIS) {
+            self.warned = false;
+        }
+        if status.percent <= self.threshold && !self.warned {
+            self.warned = true;
+            return Some(BatteryEvent::Low {
+                status,
+                remaining: self.trend.remaining(),
+            });
+        }
+        None
+    }
+
+    pub fn trend(&self) -> &BatteryTrend {
+        &self.trend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatteryEvent, BatteryMonitor, BatteryStatus, BatteryTrend};
+    use crate::message::RbMessage;
+    use std::time::Duration;
+
+    fn message(itow: u32, battery_status: u8) -> RbMessage {
+        RbMessage::builder()
+            .itow(itow)
+            .battery(BatteryStatus::from(battery_status))
+            .build()
+    }
+
+    #[test]
+    fn test_battery_status() {
+        assert_eq!(
+            BatteryStatus::from(89),
+            BatteryStatus {
+                percent: 89,
+                charging: false
+            }
+        );
+        assert_eq!(
+            BatteryStatus::from(0x80 | 42),
+            BatteryStatus {
+                percent: 42,
+                charging: true
+            }
+        );
+        assert_eq!(BatteryStatus::from(0x80).percent, 0);
+        assert_eq!(BatteryStatus::from(0x7F).percent, 127);
+        assert_eq!(BatteryStatus::from(89).to_string(), "89%");
+        assert_eq!(BatteryStatus::from(0x80 | 89).to_string(), "89% (charging)");
+        assert_eq!(u8::from(BatteryStatus::from(0x80 | 89)), 0x80 | 89);
+        assert_eq!(u8::from(BatteryStatus::from(89)), 89);
+    }
+
+    #[test]
+    fn test_battery_trend() {
+        let mut trend = BatteryTrend::new();
+        assert_eq!(trend.drain_rate(), None);
+
+        // 1% every 6 minutes for half an hour is 10% an hour
+        for minute in 0..=30u32 {
+            let percent = 80 - (minute / 6) as u8;
+            trend.update_status(minute * 60_000, BatteryStatus::from(percent));
+        }
+        let rate = trend.drain_rate().unwrap();
+        assert!((rate - 10.0).abs() < 1.0, "{}", rate);
+        let remaining = trend.remaining().unwrap();
+        // 75% left at 10% an hour
+        assert!((remaining.as_secs_f64() / 3600.0 - 7.5).abs() < 1.0);
+
+        // Charging resets the estimate
+        trend.update_status(31 * 60_000, BatteryStatus::from(0x80 | 75));
+        assert_eq!(trend.drain_rate(), None);
+        assert_eq!(trend.remaining(), None);
+    }
+
+    #[test]
+    fn test_battery_monitor() {
+        let mut monitor = BatteryMonitor::new(20);
+        assert_eq!(monitor.update(&message(0, 22)), None);
+        assert_eq!(monitor.update(&message(40, 21)), None);
+        assert_eq!(
+            monitor.update(&message(80, 20)),
+            Some(BatteryEvent::Low {
+                status: BatteryStatus::from(20),
+                remaining: None
+            })
+        );
+        // Only warned once, even if the level bounces around the threshold
+        assert_eq!(monitor.update(&message(120, 19)), None);
+        assert_eq!(monitor.update(&message(160, 21)), None);
+        assert_eq!(monitor.update(&message(200, 20)), None);
+
+        assert_eq!(
+            monitor.update(&message(240, 0x80 | 20)),
+            Some(BatteryEvent::ChargingStarted(BatteryStatus::from(
+                0x80 | 20
+            )))
+        );
+        assert_eq!(monitor.update(&message(280, 0x80 | 30)), None);
+        assert_eq!(
+            monitor.update(&message(320, 30)),
+            Some(BatteryEvent::ChargingStopped(BatteryStatus::from(30)))
+        );
+        assert!(matches!(
+            monitor.update(&message(360, 15)),
+            Some(BatteryEvent::Low { .. })
+        ));
+    }
+
+    #[test]
+    fn test_battery_monitor_remaining() {
+        let mut monitor = BatteryMonitor::new(10);
+        let mut events = Vec::new();
+        // 1% a minute from 15%
+        for minute in 0..=5u32 {
+            let event = monitor.update(&message(minute * 60_000, 15 - minute as u8));
+            events.extend(event);
+        }
+        match events.as_slice() {
+            [BatteryEvent::Low { status, remaining }] => {
+                assert_eq!(status.percent, 10);
+                let remaining = remaining.unwrap();
+                assert!(
+                    (remaining.as_secs_f64() - Duration::from_secs(600).as_secs_f64()).abs() < 30.0
+                );
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+}
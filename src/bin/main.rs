@@ -2,9 +2,13 @@ use std::error::Error;
 use std::io::{self, Write};
 use tokio::sync::mpsc;
 
+use rbmini::battery::{BatteryEvent, BatteryMonitor};
 use rbmini::connection::RbManager;
 use rbmini::message::{decode_rb_message, rb_checksum};
 
+// Warn when the RaceBox battery is at or below this level
+const LOW_BATTERY_PERCENT: u8 = 15;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     println!("Creating a new RbConnecting handler");
@@ -32,14 +36,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
     });
 
     let mut checksum_failures = 0;
+    let mut battery = BatteryMonitor::new(LOW_BATTERY_PERCENT);
+    let mut battery_warning = String::new();
     loop {
         while let Some(msg) = rx.recv().await {
             if !rb_checksum(&msg.value) {
                 checksum_failures += 1;
             }
-            let rb_msg = decode_rb_message(&msg.value);
+            let Some(rb_msg) = decode_rb_message(&msg.value) else {
+                continue;
+            };
+            match battery.update(&rb_msg) {
+                Some(BatteryEvent::Low { status, remaining }) => {
+                    battery_warning = match remaining {
+                        Some(r) => {
+                            format!("LOW BATTERY {} ({} min left)", status, r.as_secs() / 60)
+                        }
+                        None => format!("LOW BATTERY {}", status),
+                    };
+                }
+                Some(BatteryEvent::ChargingStarted(_)) => battery_warning.clear(),
+                _ => {}
+            }
             print!("{esc}[2J{esc}[1;1H {d}", esc = 27 as char, d = rb_msg);
-            print!("Checksum failures {}", checksum_failures);
+            println!("Checksum failures {}", checksum_failures);
+            print!("{}", battery_warning);
             io::stdout().flush().expect("Couldn't flush stdout");
         }
     }
@@ -0,0 +1,470 @@
+use crate::acceleration::{slope, state_at, Point, SpeedRange};
+use crate::gpstime::ElapsedClock;
+use crate::message::RbMessage;
+use crate::quality::{FixQuality, QualityThresholds};
+use crate::session::Session;
+use crate::units::{Acceleration, Length, Speed, STANDARD_GRAVITY};
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/*
+Braking performance measurements
+
+A braking event is triggered by a sample decelerating harder than brake_g on
+the X axis, above the minimum start speed. It is taken back through the samples
+just before that to where the deceleration first went over release_g, so the
+build up of brake pressure is part of the event. It ends when the car stops,
+when the deceleration drops below release_g again, or on a sample without a
+usable fix. A stop between two samples is put in by carrying on the last
+deceleration down to zero.
+
+Each event reports the whole stop from its start speed, and each speed range
+(100 to 0 km/h, or any pair from a higher speed to a lower one) it covered.
+Speed between samples is taken as changing linearly, as for acceleration runs.
+Peak deceleration is from the accelerometer, mean deceleration is the speed
+lost over the time taken. Distances are also given corrected to level ground,
+taking the gravity along the slope out of the mean deceleration.
+
+The accuracy is a one sigma estimate of the distance error from the receiver's
+speed accuracy. A speed error moves the point each end speed is crossed, by the
+speed error over the deceleration in time, and adds up over the samples the
+distance is integrated from.
+*/
+
+// Samples kept from before a trigger, to find where braking started
+const PRE_TRIGGER_SAMPLES: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BrakingSettings {
+    pub speed_ranges: Vec<SpeedRange>, // from the higher speed to the lower one
+    pub brake_g: Acceleration,
+    pub release_g: Acceleration,
+    pub min_start_speed: Speed,
+    pub stationary_speed: Speed,
+}
+
+impl Default for BrakingSettings {
+    fn default() -> Self {
+        BrakingSettings {
+            speed_ranges: vec![SpeedRange::kph(100.0, 0.0), SpeedRange::mph(60.0, 0.0)],
+            brake_g: Acceleration::from_g(0.3),
+            release_g: Acceleration::from_g(0.1),
+            min_start_speed: Speed::from_kph(20.0),
+            stationary_speed: Speed::from_kph(1.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BrakingResult {
+    pub range: SpeedRange,
+    pub time: Duration,
+    pub distance: Length,
+    pub distance_accuracy: Length,
+    pub mean_deceleration: Acceleration,
+    pub slope: f64, // rise over distance
+    pub corrected_distance: Option<Length>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BrakingEvent {
+    pub start: Duration, // from the meter's first message
+    pub start_speed: Speed,
+    pub end_speed: Speed,
+    pub stopped: bool,
+    pub time: Duration,
+    pub distance: Length,
+    pub distance_accuracy: Length,
+    pub peak_deceleration: Acceleration,
+    pub mean_deceleration: Acceleration,
+    pub time_to_peak: Duration,
+    pub slope: f64,
+    pub corrected_distance: Option<Length>,
+    pub ranges: Vec<BrakingResult>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    point: Point,
+    deceleration: f64, // m/s^2, positive when slowing
+    speed_accuracy: f64,
+}
+
+// Finds braking events in a message stream, live with update() or over a session with session_events()
+#[derive(Debug)]
+pub struct BrakeMeter {
+    settings: BrakingSettings,
+    thresholds: QualityThresholds,
+    clock: ElapsedClock,
+    recent: VecDeque<Sample>, // before a trigger
+    trace: Vec<Sample>,       // the event in progress
+}
+
+impl BrakeMeter {
+    pub fn new(settings: BrakingSettings) -> Self {
+        BrakeMeter {
+            settings,
+            thresholds: QualityThresholds::default(),
+            clock: ElapsedClock::default(),
+            recent: VecDeque::with_capacity(PRE_TRIGGER_SAMPLES),
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &BrakingSettings {
+        &self.settings
+    }
+
+    pub fn is_braking(&self) -> bool {
+        !self.trace.is_empty()
+    }
+
+    // Returns an event when it ends
+    pub fn update(&mut self, message: &RbMessage) -> Option<BrakingEvent> {
+        let time = self.clock.advance(message)? / 1000.0;
+        if !FixQuality::assess(message, &self.thresholds).is_usable() {
+            self.recent.clear();
+            return self.finish();
+        }
+        let sample = Sample {
+            point: Point {
+                time,
+                speed: message.speed().meters_per_second(),
+                distance: 0.0,
+                altitude: message.wgs_altitude().meters(),
+            },
+            deceleration: -message.g_forces().0.meters_per_second_squared(),
+            speed_accuracy: message.speed_accuracy().meters_per_second(),
+        };
+        if self.is_braking() {
+            return self.extend(sample);
+        }
+
+        if self.recent.len() == PRE_TRIGGER_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(sample);
+        let triggered = sample.point.speed >= self.settings.min_start_speed.meters_per_second()
+            && sample.deceleration >= self.settings.brake_g.meters_per_second_squared();
+        if !triggered {
+            return None;
+        }
+        let release = self.settings.release_g.meters_per_second_squared();
+        let onset = self
+            .recent
+            .iter()
+            .rposition(|s| s.deceleration < release)
+            .map_or(0, |i| i + 1);
+        let samples: Vec<_> = self.recent.drain(..).skip(onset).collect();
+        for sample in samples {
+            self.push(sample);
+        }
+        None
+    }
+
+    fn push(&mut self, mut sample: Sample) {
+        if let Some(last) = self.trace.last() {
+            let interval = sample.point.time - last.point.time;
+            sample.point.distance =
+                last.point.distance + (last.point.speed + sample.point.speed) / 2.0 * interval;
+        }
+        self.trace.push(sample);
+    }
+
+    fn extend(&mut self, sample: Sample) -> Option<BrakingEvent> {
+        self.push(sample);
+        if sample.point.speed < self.settings.stationary_speed.meters_per_second() {
+            self.stop();
+            return self.finish();
+        }
+        if sample.deceleration < self.settings.release_g.meters_per_second_squared() {
+            return self.finish();
+        }
+        None
+    }
+
+    // Carries the last deceleration on to a standstill
+    fn stop(&mut self) {
+        let [.., a, b] = self.trace[..] else {
+            return;
+        };
+        let deceleration = (a.point.speed - b.point.speed) / (b.point.time - a.point.time);
+        if b.point.speed <= 0.0 || deceleration <= 0.0 {
+            return;
+        }
+        let time = b.point.speed / deceleration;
+        self.trace.push(Sample {
+            point: Point {
+                time: b.point.time + time,
+                speed: 0.0,
+                distance: b.point.distance + b.point.speed * time / 2.0,
+                altitude: b.point.altitude,
+            },
+            ..b
+        });
+    }
+
+    // Ends the event in progress, at the end of a recording
+    pub fn finish(&mut self) -> Option<BrakingEvent> {
+        let trace = std::mem::take(&mut self.trace);
+        measure(&trace, &self.settings)
+    }
+}
+
+fn measure(trace: &[Sample], settings: &BrakingSettings) -> Option<BrakingEvent> {
+    let points: Vec<_> = trace.iter().map(|s| s.point).collect();
+    let (first, last) = (*points.first()?, *points.last()?);
+    let time = last.time - first.time;
+    if time <= 0.0 {
+        return None;
+    }
+    let peak = trace.iter().fold(trace[0], |peak, s| {
+        if s.deceleration > peak.deceleration {
+            *s
+        } else {
+            peak
+        }
+    });
+    let mean = (first.speed - last.speed) / time;
+
+    let ranges = settings
+        .speed_ranges
+        .iter()
+        .filter_map(|range| {
+            let start = time_at_speed(&points, range.from.meters_per_second())?;
+            let end = time_at_speed(&points, range.to.meters_per_second())?;
+            let (from, to) = (state_at(&points, start), state_at(&points, end));
+            let time = end - start;
+            let mean = (from.speed - to.speed) / time;
+            let slope = slope(from, to);
+            let within: Vec<_> = trace
+                .iter()
+                .filter(|s| (start..=end).contains(&s.point.time))
+                .copied()
+                .collect();
+            Some(BrakingResult {
+                range: *range,
+                time: Duration::from_secs_f64(time),
+                distance: Length::from_meters(to.distance - from.distance),
+                distance_accuracy: distance_accuracy(&within, from.speed, to.speed, mean),
+                mean_deceleration: Acceleration::from_meters_per_second_squared(mean),
+                slope,
+                corrected_distance: level_distance(from.speed, to.speed, mean, slope),
+            })
+        })
+        .collect();
+
+    let slope = slope(first, last);
+    Some(BrakingEvent {
+        start: Duration::from_secs_f64(first.time.max(0.0)),
+        start_speed: Speed::from_meters_per_second(first.speed),
+        end_speed: Speed::from_meters_per_second(last.speed),
+        stopped: last.speed < settings.stationary_speed.meters_per_second(),
+        time: Duration::from_secs_f64(time),
+        distance: Length::from_meters(last.distance),
+        distance_accuracy: distance_accuracy(trace, first.speed, last.speed, mean),
+        peak_deceleration: Acceleration::from_meters_per_second_squared(peak.deceleration),
+        mean_deceleration: Acceleration::from_meters_per_second_squared(mean),
+        time_to_peak: Duration::from_secs_f64(peak.point.time - first.time),
+        slope,
+        corrected_distance: level_distance(first.speed, last.speed, mean, slope),
+        ranges,
+    })
+}
+
+// When the speed first drops to the given speed
+fn time_at_speed(points: &[Point], speed: f64) -> Option<f64> {
+    points.windows(2).find_map(|w| {
+        let (a, b) = (w[0], w[1]);
+        (a.speed > speed && b.speed <= speed)
+            .then(|| a.time + (a.speed - speed) / (a.speed - b.speed) * (b.time - a.time))
+    })
+}
+
+// Distance to slow between two speeds at the deceleration there would have been on the level
+fn level_distance(from: f64, to: f64, deceleration: f64, slope: f64) -> Option<Length> {
+    let level = deceleration - STANDARD_GRAVITY * slope;
+    (level > 0.0).then(|| Length::from_meters((from * from - to * to) / (2.0 * level)))
+}
+
+fn distance_accuracy(samples: &[Sample], from: f64, to: f64, deceleration: f64) -> Length {
+    let count = samples.len().max(1) as f64;
+    let speed_accuracy = samples.iter().map(|s| s.speed_accuracy).sum::<f64>() / count;
+    let crossings = if deceleration > 0.0 {
+        from.hypot(to) * speed_accuracy / deceleration
+    } else {
+        0.0
+    };
+    let interval = match samples {
+        [first, .., last] => (last.point.time - first.point.time) / (count - 1.0),
+        _ => 0.0,
+    };
+    let integrated = speed_accuracy * interval * count.sqrt();
+    Length::from_meters(crossings.hypot(integrated))
+}
+
+// Every braking event in a recorded session
+pub fn session_events(session: &Session, settings: BrakingSettings) -> Vec<BrakingEvent> {
+    let mut meter = BrakeMeter::new(settings);
+    let mut events: Vec<_> = session
+        .messages()
+        .iter()
+        .filter_map(|message| meter.update(message))
+        .collect();
+    events.extend(meter.finish());
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{session_events, BrakeMeter, BrakingSettings};
+    use crate::acceleration::SpeedRange;
+    use crate::message::RbMessage;
+    use crate::session::Session;
+    use crate::testing::fix;
+    use crate::units::{Acceleration, Length, Speed, STANDARD_GRAVITY};
+
+    const BRAKE: f64 = 1.013; // seconds, between samples
+
+    fn message(itow: u32, speed: f64, g: f64, altitude: f64, accuracy: f64) -> RbMessage {
+        fix(itow)
+            .speed_accuracy(Speed::from_meters_per_second(accuracy))
+            .speed_mps(speed)
+            .altitude(Length::from_meters(altitude))
+            .g_forces(
+                Acceleration::from_g(g),
+                Acceleration::from_g(0.0),
+                Acceleration::from_g(1.0),
+            )
+            .build()
+    }
+
+    struct Stop {
+        session: Session,
+        from_100: f64, // metres from 100 km/h to a stop
+    }
+
+    /*
+    At 110 km/h, then braking from BRAKE with the brakes building up to full
+    deceleration on the level over ramp seconds, until release_at m/s. Simulated
+    in millisecond steps and sampled at 25 Hz.
+    */
+    fn braking(full: f64, ramp: f64, slope: f64, accuracy: f64, release_at: f64) -> Stop {
+        let mut session = Session::default();
+        let (mut speed, mut distance, mut altitude) = (110.0 / 3.6, 0.0, 100.0);
+        let hundred = 100.0 / 3.6;
+        let mut from_100 = None;
+        for ms in 0..10_000 {
+            let t = f64::from(ms) / 1000.0;
+            let braking = t >= BRAKE && speed > release_at;
+            let brakes = if braking {
+                full * ((t - BRAKE) / ramp).min(1.0)
+            } else {
+                0.0
+            };
+            let acceleration = if speed > 0.0 && braking {
+                -brakes - STANDARD_GRAVITY * slope
+            } else {
+                0.0
+            };
+            if ms % 40 == 0 {
+                let g = acceleration / STANDARD_GRAVITY;
+                session.push(message(ms, speed, g, altitude, accuracy));
+            }
+            let step = (speed + acceleration * 0.001).max(0.0);
+            if speed > hundred && step <= hundred {
+                from_100 = Some(distance);
+            }
+            distance += (speed + step) / 2.0 * 0.001;
+            altitude += (speed + step) / 2.0 * 0.001 * slope;
+            speed = step;
+        }
+        Stop {
+            session,
+            from_100: distance - from_100.unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_stop() {
+        let stop = braking(STANDARD_GRAVITY, 0.3, 0.0, 0.1, 0.0);
+        let events = session_events(&stop.session, BrakingSettings::default());
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert!(event.stopped);
+        assert_eq!(event.end_speed, Speed::from_meters_per_second(0.0));
+        assert!((event.start_speed.kph() - 110.0).abs() < 1.0);
+        // The first sample over release_g, 0.1 g is reached 0.03s into the ramp
+        let after = event.start.as_secs_f64() - BRAKE;
+        assert!(after > 0.03 && after < 0.07, "{}", after);
+        assert!((event.peak_deceleration.g() - 1.0).abs() < 0.01);
+        assert!((event.time_to_peak.as_secs_f64() - 0.3).abs() < 0.08);
+        assert!(event.mean_deceleration.g() < event.peak_deceleration.g());
+        assert!(event.slope.abs() < 1e-3);
+
+        let hundred = &event.ranges[0];
+        assert_eq!(hundred.range, SpeedRange::kph(100.0, 0.0));
+        assert!((hundred.distance.meters() - stop.from_100).abs() < 0.1);
+        assert!((hundred.time.as_secs_f64() - 100.0 / 3.6 / STANDARD_GRAVITY).abs() < 0.02);
+        assert!((hundred.mean_deceleration.g() - 1.0).abs() < 0.01);
+        let corrected = hundred.corrected_distance.unwrap().meters();
+        assert!((corrected - hundred.distance.meters()).abs() < 0.1);
+        // 60 mph is less than 100 km/h
+        assert!(event.ranges[1].distance < hundred.distance);
+
+        // Mostly the speed error over the deceleration, at 100 km/h
+        let accuracy = hundred.distance_accuracy.meters();
+        assert!(accuracy > 0.25 && accuracy < 0.35, "{}", accuracy);
+        let noisy = braking(STANDARD_GRAVITY, 0.3, 0.0, 0.5, 0.0);
+        let noisy = &session_events(&noisy.session, BrakingSettings::default())[0];
+        assert!(noisy.ranges[0].distance_accuracy.meters() > 4.0 * accuracy);
+    }
+
+    #[test]
+    fn test_slope_correction() {
+        // Downhill the same brakes take longer to stop, the level distance is shorter
+        let stop = braking(8.0, 0.01, -0.05, 0.1, 0.0);
+        let event = &session_events(&stop.session, BrakingSettings::default())[0];
+        let hundred = &event.ranges[0];
+        assert!((hundred.slope + 0.05).abs() < 0.001, "{}", hundred.slope);
+        assert!((hundred.distance.meters() - stop.from_100).abs() < 0.1);
+        let level = (100.0f64 / 3.6).powi(2) / (2.0 * 8.0);
+        let corrected = hundred.corrected_distance.unwrap().meters();
+        assert!((corrected - level).abs() < 0.2, "{}", corrected);
+        assert!(hundred.distance.meters() > corrected + 2.0);
+    }
+
+    #[test]
+    fn test_released() {
+        // Braking from 110 to 50 km/h, only a range ending above 50 is covered
+        let stop = braking(STANDARD_GRAVITY, 0.1, 0.0, 0.1, 50.0 / 3.6);
+        let settings = BrakingSettings {
+            speed_ranges: vec![SpeedRange::kph(100.0, 0.0), SpeedRange::kph(100.0, 60.0)],
+            ..Default::default()
+        };
+        let mut meter = BrakeMeter::new(settings);
+        let mut events = Vec::new();
+        for message in stop.session.messages() {
+            events.extend(meter.update(message));
+            if message.itow() == 2000 {
+                assert!(meter.is_braking());
+            }
+        }
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert!(!event.stopped);
+        assert!((event.end_speed.kph() - 50.0).abs() < 2.0);
+        assert_eq!(event.ranges.len(), 1);
+        assert_eq!(event.ranges[0].range, SpeedRange::kph(100.0, 60.0));
+        assert_eq!(meter.finish(), None);
+    }
+
+    #[test]
+    fn test_gentle() {
+        // Slowing at 0.2 g isn't braking hard enough to measure
+        let stop = braking(0.2 * STANDARD_GRAVITY, 0.1, 0.0, 0.1, 0.0);
+        assert!(session_events(&stop.session, BrakingSettings::default()).is_empty());
+    }
+}
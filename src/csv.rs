@@ -0,0 +1,576 @@
+use crate::geoid::Geoid;
+use crate::message::{Coordinates, RbMessage};
+use crate::session::{Session, SessionMetadata};
+use crate::status::{FixFlags, FixStatus, LatLonFlags};
+use crate::units::{Acceleration, Angle, AngularRate, Length};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use std::io::{self, BufRead, Write};
+
+/*
+CSV import and export for phone lap timing apps
+
+RaceChrono v3 CSV: a block of "key,value" metadata lines terminated by a blank
+line, then a row of column titles, a row of channel ids, a row of units and a
+row of data sources, followed by one row per sample. Timestamps are seconds
+since the unix epoch. Columns are matched on the channel id row so the export
+can contain any subset of channels.
+
+Harry's LapTimer GPS log: a single header row of column names followed by one
+row per sample. Time is an RFC 3339 UTC timestamp (unix seconds are accepted on
+import). Columns are matched by name, unknown columns are ignored.
+*/
+
+const RACECHRONO_FORMAT: &str = "3";
+
+// A sample in physical units, the common ground between RbMessage and the CSV formats
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Sample {
+    time: Option<DateTime<Utc>>,
+    latitude: Option<f64>,  // degrees
+    longitude: Option<f64>, // degrees
+    altitude: Option<f64>,  // metres above mean sea level
+    speed: Option<f64>,     // metres per second
+    heading: Option<f64>,   // degrees
+    satellites: Option<u8>,
+    accuracy: Option<f64>, // metres
+    fix_type: Option<u8>,
+    g_force: [Option<f64>; 3],  // g, RaceBox X, Y and Z axes
+    rot_rate: [Option<f64>; 3], // degrees per second, roll, pitch and yaw
+}
+
+impl Sample {
+    fn from_message(message: &RbMessage, geoid: Option<&Geoid>) -> Self {
+        let position = message.is_valid_fix() && message.is_valid_position();
+        let coordinates = message.gps_coordinates();
+        let (x, y, z) = message.g_forces();
+        let (roll, pitch, yaw) = message.rot_rates();
+        Sample {
+            time: message_time(message),
+            latitude: position.then(|| coordinates.latitude()),
+            longitude: position.then(|| coordinates.longitude()),
+            altitude: position.then(|| message.corrected_altitude(geoid).meters()),
+            speed: Some(message.speed().meters_per_second()),
+            heading: Some(message.heading().degrees()),
+            satellites: Some(message.satelites()),
+            accuracy: Some(message.horiz_accuracy().meters()),
+            fix_type: Some(message.fix_status().into()),
+            g_force: [Some(x.g()), Some(y.g()), Some(z.g())],
+            rot_rate: [
+                Some(roll.degrees_per_second()),
+                Some(pitch.degrees_per_second()),
+                Some(yaw.degrees_per_second()),
+            ],
+        }
+    }
+
+    fn to_message(&self) -> RbMessage {
+        let mut builder = RbMessage::builder();
+        let mut fix_flags = 0;
+
+        if let Some(time) = self.time {
+            builder = builder.time(time);
+        }
+
+        if let (Some(latitude), Some(longitude)) = (self.latitude, self.longitude) {
+            let fix = self.fix_type.unwrap_or(3);
+            builder = builder
+                .coordinates(Coordinates::from_degrees(latitude, longitude))
+                .fix_status(FixStatus::from(fix));
+            if fix >= 2 {
+                fix_flags |= 1; // valid fix
+            }
+        } else {
+            builder = builder.lat_lon_flags(LatLonFlags(1)); // invalid position
+        }
+
+        if let Some(altitude) = self.altitude {
+            builder = builder.altitude(Length::from_meters(altitude));
+        }
+        if let Some(speed) = self.speed {
+            builder = builder.speed_mps(speed);
+        }
+        if let Some(heading) = self.heading {
+            builder = builder.heading(Angle::from_degrees(heading));
+            fix_flags |= 1 << 5; // valid heading
+        }
+        if let Some(accuracy) = self.accuracy {
+            builder = builder.horiz_accuracy(Length::from_meters(accuracy));
+        }
+
+        let g = |g: Option<f64>| Acceleration::from_g(g.unwrap_or(0.0));
+        let deg = |r: Option<f64>| AngularRate::from_degrees_per_second(r.unwrap_or(0.0));
+        builder
+            .fix_flags(FixFlags(fix_flags))
+            .satellites(self.satellites.unwrap_or(0))
+            .g_forces(g(self.g_force[0]), g(self.g_force[1]), g(self.g_force[2]))
+            .rot_rates(
+                deg(self.rot_rate[0]),
+                deg(self.rot_rate[1]),
+                deg(self.rot_rate[2]),
+            )
+            .build()
+    }
+}
+
+fn message_time(message: &RbMessage) -> Option<DateTime<Utc>> {
+    message.timestamp().map(|t| t.time)
+}
+
+fn unix_time(seconds: f64) -> Option<DateTime<Utc>> {
+    let whole = seconds.floor();
+    let nanos = ((seconds - whole) * 1e9).round() as u32;
+    Utc.timestamp_opt(whole as i64, nanos.min(999_999_999))
+        .single()
+}
+
+fn unix_seconds(time: DateTime<Utc>) -> f64 {
+    time.timestamp() as f64 + time.timestamp_subsec_nanos() as f64 / 1e9
+}
+
+// Splits a CSV line into fields, honouring double quoted fields and "" escapes
+fn split_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+fn number(fields: &[String], column: Option<usize>) -> Option<f64> {
+    fields.get(column?)?.trim().parse().ok()
+}
+
+fn format_option(value: Option<f64>, precision: usize) -> String {
+    match value {
+        Some(v) => format!("{:.*}", precision, v),
+        None => String::new(),
+    }
+}
+
+fn read_lines<R: BufRead>(reader: R) -> Result<Vec<String>, String> {
+    reader
+        .lines()
+        .map(|line| line.map_err(|e| e.to_string()))
+        .collect()
+}
+
+// Column layout of a RaceChrono export, (channel id, title, unit)
+const RACECHRONO_COLUMNS: [(&str, &str, &str); 16] = [
+    ("timestamp", "Time (s)", "unix time"),
+    ("distance_traveled", "Distance (m)", "m"),
+    ("speed", "Speed (m/s)", "m/s"),
+    ("altitude", "Altitude (m)", "m"),
+    ("bearing", "Bearing (deg)", "deg"),
+    ("accuracy", "Accuracy (m)", "m"),
+    ("fix_type", "Fix type", ""),
+    ("latitude", "Latitude (deg)", "deg"),
+    ("longitude", "Longitude (deg)", "deg"),
+    ("satellites", "Satellites (sats)", "sats"),
+    ("x_acc", "X acceleration (G)", "G"),
+    ("y_acc", "Y acceleration (G)", "G"),
+    ("z_acc", "Z acceleration (G)", "G"),
+    ("x_rate_of_rotation", "X rate of rotation (deg/s)", "deg/s"),
+    ("y_rate_of_rotation", "Y rate of rotation (deg/s)", "deg/s"),
+    ("z_rate_of_rotation", "Z rate of rotation (deg/s)", "deg/s"),
+];
+
+// Reads a RaceChrono v3 CSV export into a session
+pub fn read_racechrono<R: BufRead>(reader: R) -> Result<Session, String> {
+    let lines = read_lines(reader)?;
+    let mut metadata = SessionMetadata::default();
+    let mut rows = lines.iter();
+
+    // Metadata block, up to the first blank line
+    for line in rows.by_ref() {
+        if line.trim().is_empty() {
+            break;
+        }
+        let fields = split_line(line);
+        let value = fields.get(1).cloned().unwrap_or_default();
+        match fields[0].as_str() {
+            "Format" if value != RACECHRONO_FORMAT => {
+                return Err(format!("unsupported RaceChrono format {}", value));
+            }
+            "Session title" => metadata.session = value,
+            "Track name" => metadata.venue = value,
+            "Driver name" => metadata.driver = value,
+            "Note" => metadata.comment = value,
+            _ => {}
+        }
+    }
+
+    // Skip the titles and find the channel id row
+    let ids = rows
+        .by_ref()
+        .map(|line| split_line(line))
+        .find(|fields| fields.first().map(|f| f.as_str()) == Some("timestamp"))
+        .ok_or_else(|| String::from("missing RaceChrono channel row"))?;
+    let column = |id: &str| ids.iter().position(|f| f == id);
+    let columns = |ids: [&str; 2]| column(ids[0]).or_else(|| column(ids[1]));
+
+    let time = column("timestamp");
+    let latitude = column("latitude");
+    let longitude = column("longitude");
+    let altitude = column("altitude");
+    let speed = column("speed");
+    let bearing = column("bearing");
+    let accuracy = column("accuracy");
+    let satellites = column("satellites");
+    let fix_type = column("fix_type");
+    let acc = [
+        columns(["x_acc", "longitudinal_acc"]),
+        columns(["y_acc", "lateral_acc"]),
+        column("z_acc"),
+    ];
+    let rate = [
+        column("x_rate_of_rotation"),
+        column("y_rate_of_rotation"),
+        column("z_rate_of_rotation"),
+    ];
+
+    let mut session = Session::new(metadata);
+    for line in rows {
+        let fields = split_line(line);
+        // Units and sources rows, and anything else that isn't a sample
+        let Some(timestamp) = number(&fields, time) else {
+            continue;
+        };
+        let sample = Sample {
+            time: unix_time(timestamp),
+            latitude: number(&fields, latitude),
+            longitude: number(&fields, longitude),
+            altitude: number(&fields, altitude),
+            speed: number(&fields, speed),
+            heading: number(&fields, bearing),
+            satellites: number(&fields, satellites).map(|s| s as u8),
+            accuracy: number(&fields, accuracy),
+            fix_type: number(&fields, fix_type).map(|f| f as u8),
+            g_force: acc.map(|c| number(&fields, c)),
+            rot_rate: rate.map(|c| number(&fields, c)),
+        };
+        session.push(sample.to_message());
+    }
+    Ok(session)
+}
+
+// Writes the session as a RaceChrono v3 CSV export, with altitudes from the geoid if given
+pub fn write_racechrono<W: Write>(
+    writer: &mut W,
+    session: &Session,
+    geoid: Option<&Geoid>,
+) -> io::Result<()> {
+    let metadata = &session.metadata;
+    let created = session
+        .messages()
+        .iter()
+        .find_map(message_time)
+        .map(|t| t.format("%d/%m/%Y,%H:%M").to_string())
+        .unwrap_or_default();
+
+    writeln!(writer, "This file is created using rbmini")?;
+    writeln!(writer, "Format,{}", RACECHRONO_FORMAT)?;
+    writeln!(writer, "Session title,{}", quote(&metadata.session))?;
+    writeln!(writer, "Session type,Lap timing")?;
+    writeln!(writer, "Track name,{}", quote(&metadata.venue))?;
+    writeln!(writer, "Driver name,{}", quote(&metadata.driver))?;
+    writeln!(writer, "Created,{}", created)?;
+    writeln!(writer, "Note,{}", quote(&metadata.comment))?;
+    writeln!(writer)?;
+
+    let titles: Vec<&str> = RACECHRONO_COLUMNS.iter().map(|c| c.1).collect();
+    let ids: Vec<&str> = RACECHRONO_COLUMNS.iter().map(|c| c.0).collect();
+    let units: Vec<&str> = RACECHRONO_COLUMNS.iter().map(|c| c.2).collect();
+    writeln!(writer, "{}", titles.join(","))?;
+    writeln!(writer, "{}", ids.join(","))?;
+    writeln!(writer, "{}", units.join(","))?;
+    writeln!(writer, "{}", vec!["RaceBox Mini"; ids.len()].join(","))?;
+
+    let mut distance = 0.0;
+    let mut last: Option<(f64, f64)> = None;
+    for message in session.messages() {
+        let sample = Sample::from_message(message, geoid);
+        let Some(time) = sample.time else {
+            continue;
+        };
+
+        // Distance from the speed over the sample interval
+        let seconds = unix_seconds(time);
+        if let (Some((last_time, last_speed)), Some(speed)) = (last, sample.speed) {
+            distance += (last_speed + speed) / 2.0 * (seconds - last_time);
+        }
+        last = sample.speed.map(|speed| (seconds, speed));
+
+        let row = [
+            format!("{:.3}", seconds),
+            format!("{:.3}", distance),
+            format_option(sample.speed, 3),
+            format_option(sample.altitude, 3),
+            format_option(sample.heading, 5),
+            format_option(sample.accuracy, 3),
+            sample.fix_type.map(|f| f.to_string()).unwrap_or_default(),
+            format_option(sample.latitude, 7),
+            format_option(sample.longitude, 7),
+            sample.satellites.map(|s| s.to_string()).unwrap_or_default(),
+            format_option(sample.g_force[0], 3),
+            format_option(sample.g_force[1], 3),
+            format_option(sample.g_force[2], 3),
+            format_option(sample.rot_rate[0], 2),
+            format_option(sample.rot_rate[1], 2),
+            format_option(sample.rot_rate[2], 2),
+        ];
+        writeln!(writer, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+const HARRYS_COLUMNS: [&str; 14] = [
+    "Time",
+    "Latitude",
+    "Longitude",
+    "Altitude (m)",
+    "Speed (km/h)",
+    "Heading (deg)",
+    "Satellites",
+    "Accuracy (m)",
+    "AccelX (g)",
+    "AccelY (g)",
+    "AccelZ (g)",
+    "GyroX (deg/s)",
+    "GyroY (deg/s)",
+    "GyroZ (deg/s)",
+];
+
+fn harrys_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f") {
+        return Some(Utc.from_utc_datetime(&time));
+    }
+    unix_time(value.parse().ok()?)
+}
+
+// Reads a Harry's LapTimer GPS log into a session
+pub fn read_harrys<R: BufRead>(reader: R) -> Result<Session, String> {
+    let lines = read_lines(reader)?;
+    let mut rows = lines.iter().filter(|line| !line.trim().is_empty());
+    let header = split_line(rows.next().ok_or("empty Harry's LapTimer log")?);
+
+    // Matched on the column name without its unit, case insensitive
+    let names: Vec<String> = header
+        .iter()
+        .map(|h| h.split('(').next().unwrap().trim().to_lowercase())
+        .collect();
+    let column = |name: &str| names.iter().position(|n| n == name);
+
+    let time = column("time").ok_or("missing Time column")?;
+    let latitude = column("latitude");
+    let longitude = column("longitude");
+    let altitude = column("altitude");
+    let speed = column("speed");
+    let heading = column("heading");
+    let satellites = column("satellites");
+    let accuracy = column("accuracy");
+    let acc = [column("accelx"), column("accely"), column("accelz")];
+    let rate = [column("gyrox"), column("gyroy"), column("gyroz")];
+
+    let mut session = Session::new(SessionMetadata::default());
+    for line in rows {
+        let fields = split_line(line);
+        let Some(timestamp) = fields.get(time).and_then(|t| harrys_time(t)) else {
+            return Err(format!("bad time in Harry's LapTimer row: {}", line));
+        };
+        let sample = Sample {
+            time: Some(timestamp),
+            latitude: number(&fields, latitude),
+            longitude: number(&fields, longitude),
+            altitude: number(&fields, altitude),
+            speed: number(&fields, speed).map(|kph| kph / 3.6),
+            heading: number(&fields, heading),
+            satellites: number(&fields, satellites).map(|s| s as u8),
+            accuracy: number(&fields, accuracy),
+            fix_type: None,
+            g_force: acc.map(|c| number(&fields, c)),
+            rot_rate: rate.map(|c| number(&fields, c)),
+        };
+        session.push(sample.to_message());
+    }
+    Ok(session)
+}
+
+// Writes the session as a Harry's LapTimer GPS log, with altitudes from the geoid if given
+pub fn write_harrys<W: Write>(
+    writer: &mut W,
+    session: &Session,
+    geoid: Option<&Geoid>,
+) -> io::Result<()> {
+    writeln!(writer, "{}", HARRYS_COLUMNS.join(","))?;
+    for message in session.messages() {
+        let sample = Sample::from_message(message, geoid);
+        let Some(time) = sample.time else {
+            continue;
+        };
+        let row = [
+            time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            format_option(sample.latitude, 7),
+            format_option(sample.longitude, 7),
+            format_option(sample.altitude, 3),
+            format_option(sample.speed.map(|mps| mps * 3.6), 3),
+            format_option(sample.heading, 5),
+            sample.satellites.map(|s| s.to_string()).unwrap_or_default(),
+            format_option(sample.accuracy, 3),
+            format_option(sample.g_force[0], 3),
+            format_option(sample.g_force[1], 3),
+            format_option(sample.g_force[2], 3),
+            format_option(sample.rot_rate[0], 2),
+            format_option(sample.rot_rate[1], 2),
+            format_option(sample.rot_rate[2], 2),
+        ];
+        writeln!(writer, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_harrys, read_racechrono, split_line, write_harrys, write_racechrono};
+    use crate::message::decode_rb_message;
+    use crate::session::{Session, SessionMetadata};
+    use crate::testing::RAW;
+    use crate::units::{Length, Speed};
+
+    const RACECHRONO: &str = "This file is created using RaceChrono v7.4.7 ( http://racechrono.com/ ).
+Format,3
+Session title,\"Morning, session\"
+Session type,Lap timing
+Track name,\"Sofia Ring\"
+Driver name,Driver
+Created,10/01/2022,08:51
+Note,
+
+Time (s),Fragment ID,Lap #,Trap name,Distance (m),Speed (m/s),Altitude (m),Bearing (deg),Latitude (deg),Longitude (deg),Satellites (sats),Lateral acceleration (G),Longitudinal acceleration (G)
+timestamp,fragment_id,lap_number,trap_name,distance_traveled,speed,altitude,bearing,latitude,longitude,satellites,lateral_acc,longitudinal_acc
+unit,,,,m,m/s,m,deg,deg,deg,sats,G,G
+,,,,100: gps,100: gps,100: gps,100: gps,100: gps,100: gps,100: gps,calc,calc
+1641804668.240,0,,,0.000,12.500,590.095,271.5,42.6719035,23.2887238,11,0.113,-0.003
+1641804668.280,0,,,0.500,12.600,590.100,271.6,42.6719040,23.2887180,11,0.120,-0.004
+";
+
+    #[test]
+    fn test_split_line() {
+        assert_eq!(split_line("a,b,,c"), vec!["a", "b", "", "c"]);
+        assert_eq!(
+            split_line("\"a,b\",\"say \"\"hi\"\"\""),
+            vec!["a,b", "say \"hi\""]
+        );
+    }
+
+    #[test]
+    fn test_read_racechrono() {
+        let session = read_racechrono(RACECHRONO.as_bytes()).unwrap();
+        assert_eq!(session.metadata.session, "Morning, session");
+        assert_eq!(session.metadata.venue, "Sofia Ring");
+        assert_eq!(session.metadata.driver, "Driver");
+        assert_eq!(session.len(), 2);
+
+        let first = &session.messages()[0];
+        assert_eq!(first.gps_coordinates().latitude(), 42.6719035);
+        assert_eq!(first.gps_coordinates().longitude(), 23.2887238);
+        assert_eq!(first.speed(), Speed::from_mm_per_second(12500));
+        assert_eq!(first.msl_altitude(), Length::from_mm(590095));
+        assert_eq!(first.raw_heading(), 27150000);
+        assert_eq!(first.satelites(), 11);
+        assert_eq!(first.raw_g_forces(), (-3, 113, 0));
+        assert!(first.is_valid_fix());
+        assert_eq!(first.datetime().year, 2022);
+        assert_eq!(first.datetime().hour, 8);
+        assert_eq!(first.datetime().second, 8);
+        assert_eq!(
+            first.timestamp().unwrap().time.timestamp_subsec_millis(),
+            240
+        );
+        // Monday 08:51:08.240 + 18 leap seconds, a day into the GPS week
+        assert_eq!(first.itow(), 118286240);
+    }
+
+    #[test]
+    fn test_read_racechrono_bad_format() {
+        let csv = RACECHRONO.replace("Format,3", "Format,2");
+        assert!(read_racechrono(csv.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_racechrono_round_trip() {
+        let mut session = Session::new(SessionMetadata {
+            session: String::from("Practice"),
+            venue: String::from("Sofia Ring"),
+            ..Default::default()
+        });
+        session.push(decode_rb_message(&RAW).unwrap());
+
+        let mut out = Vec::new();
+        write_racechrono(&mut out, &session, None).unwrap();
+        let read = read_racechrono(out.as_slice()).unwrap();
+
+        assert_eq!(read.metadata.venue, "Sofia Ring");
+        assert_eq!(read.len(), 1);
+        let original = &session.messages()[0];
+        let message = &read.messages()[0];
+        assert_eq!(message.gps_coordinates(), original.gps_coordinates());
+        assert_eq!(message.datetime(), original.datetime());
+        assert_eq!(message.itow(), original.itow());
+        assert_eq!(message.speed(), original.speed());
+        assert_eq!(message.msl_altitude(), original.msl_altitude());
+        assert_eq!(message.g_forces(), original.g_forces());
+        assert_eq!(message.rot_rates(), original.rot_rates());
+        assert_eq!(message.satelites(), original.satelites());
+    }
+
+    #[test]
+    fn test_harrys_round_trip() {
+        let mut session = Session::default();
+        session.push(decode_rb_message(&RAW).unwrap());
+
+        let mut out = Vec::new();
+        write_harrys(&mut out, &session, None).unwrap();
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.starts_with("Time,Latitude,Longitude"));
+        assert!(text.contains("2022-01-10T08:51:08.239Z,42.6719035,23.2887238,590.095,0.126"));
+
+        let read = read_harrys(out.as_slice()).unwrap();
+        let original = &session.messages()[0];
+        let message = &read.messages()[0];
+        assert_eq!(message.gps_coordinates(), original.gps_coordinates());
+        assert_eq!(message.datetime(), original.datetime());
+        assert_eq!(message.speed(), original.speed());
+        assert_eq!(message.g_forces(), original.g_forces());
+    }
+
+    #[test]
+    fn test_read_harrys_unix_time() {
+        let log = "time,latitude,longitude,speed (km/h)\n1641804668.24,42.6719035,23.2887238,36\n";
+        let session = read_harrys(log.as_bytes()).unwrap();
+        let message = &session.messages()[0];
+        assert_eq!(message.speed(), Speed::from_mm_per_second(10000));
+        assert_eq!(message.datetime().minute, 51);
+        assert!(read_harrys("time\nyesterday\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,495 @@
+use crate::gpstime::ElapsedClock;
+use crate::laps::{Lap, LapEvent, LapTimer, TimingLines};
+use crate::message::{Coordinates, RbMessage};
+use crate::quality::{FixQuality, QualityThresholds};
+use crate::units::Length;
+use futures::future;
+use futures::stream::{Stream, StreamExt};
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/*
+Predictive lap delta
+
+The lap in progress is compared with a reference lap by distance travelled
+since the start/finish line. At each sample the reference's time at the same
+distance is looked up, the difference is the delta and the reference lap time
+plus the delta is the predicted lap time. Distance is measured along the path
+between samples, so a different line through the corners shows up as a small
+drift in the delta, as it does on any GPS lap timer.
+*/
+
+// A point on a lap, distance and time from the start/finish line
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct TracePoint {
+    pub distance: f64, // metres
+    pub time: f64,     // seconds
+}
+
+// A lap to compare against, its distance and time trace
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReferenceLap {
+    pub time: Duration,
+    points: Vec<TracePoint>,
+}
+
+impl ReferenceLap {
+    // Points must be in order of distance, starting from the line
+    pub fn new(time: Duration, points: Vec<TracePoint>) -> Self {
+        ReferenceLap { time, points }
+    }
+
+    pub fn points(&self) -> &[TracePoint] {
+        &self.points
+    }
+
+    pub fn distance(&self) -> Length {
+        Length::from_meters(self.points.last().map_or(0.0, |p| p.distance))
+    }
+
+    // Time into the reference lap at a distance, None past its end
+    pub fn time_at(&self, distance: Length) -> Option<Duration> {
+        let distance = distance.meters();
+        let i = self.points.partition_point(|p| p.distance < distance);
+        let after = self.points.get(i)?;
+        let seconds = match i.checked_sub(1).map(|j| self.points[j]) {
+            Some(before) if after.distance > before.distance => {
+                let t = (distance - before.distance) / (after.distance - before.distance);
+                before.time + t * (after.time - before.time)
+            }
+            _ => after.time,
+        };
+        Some(Duration::from_secs_f64(seconds.max(0.0)))
+    }
+
+    pub fn read_json<R: Read>(reader: R) -> Result<Self, String> {
+        serde_json::from_reader(reader).map_err(|e| e.to_string())
+    }
+
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        serde_json::to_writer(writer, self).map_err(io::Error::from)
+    }
+}
+
+// Which lap the delta is measured against
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reference {
+    BestLap,
+    LastLap,
+    // A lap loaded from a file, such as the best lap of an earlier session
+    Fixed(ReferenceLap),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delta {
+    pub lap: u32,
+    pub distance: Length,
+    pub lap_time: Duration,
+    // Negative when ahead of the reference
+    pub delta: chrono::Duration,
+    pub predicted: Duration,
+}
+
+// What a message changed, the lap events from the timer and the current delta
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeltaUpdate {
+    pub events: Vec<LapEvent>,
+    pub delta: Option<Delta>,
+}
+
+// The last usable sample
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    coordinates: Coordinates,
+    elapsed: f64, // milliseconds since the first message
+}
+
+/*
+Times laps and works out the delta to the reference for every sample. Without
+a reference lap yet, on the out lap and first flying lap when comparing to the
+best or last lap, there's no delta.
+*/
+#[derive(Debug)]
+pub struct DeltaPredictor {
+    timer: LapTimer,
+    reference: Reference,
+    thresholds: QualityThresholds,
+    clock: ElapsedClock,
+    previous: Option<Sample>,
+    distance: f64, // metres into the lap in progress
+    trace: Vec<TracePoint>,
+    bests: Vec<(u32, ReferenceLap)>, // each new best lap and its number, the best last
+    last: Option<ReferenceLap>,
+}
+
+impl DeltaPredictor {
+    pub fn new<L: Into<TimingLines>>(lines: L, reference: Reference) -> Self {
+        Self::with_timer(LapTimer::new(lines), reference)
+    }
+
+    pub fn with_timer(timer: LapTimer, reference: Reference) -> Self {
+        DeltaPredictor {
+            timer,
+            reference,
+            thresholds: QualityThresholds::default(),
+            clock: ElapsedClock::default(),
+            previous: None,
+            distance: 0.0,
+            trace: Vec::new(),
+            bests: Vec::new(),
+            last: None,
+        }
+    }
+
+    pub fn timer(&self) -> &LapTimer {
+        &self.timer
+    }
+
+    pub fn set_reference(&mut self, reference: Reference) {
+        self.reference = reference;
+    }
+
+    // The lap the delta is currently measured against
+    pub fn reference_lap(&self) -> Option<&ReferenceLap> {
+        match &self.reference {
+            Reference::BestLap => self.best_lap(),
+            Reference::LastLap => self.last.as_ref(),
+            Reference::Fixed(lap) => Some(lap),
+        }
+    }
+
+    pub fn best_lap(&self) -> Option<&ReferenceLap> {
+        self.bests.last().map(|(_, lap)| lap)
+    }
+
+    pub fn last_lap(&self) -> Option<&ReferenceLap> {
+        self.last.as_ref()
+    }
+
+    pub fn update(&mut self, message: &RbMessage) -> DeltaUpdate {
+        let events = self.timer.update(message);
+        // A duplicate or out of order message is ignored, as the timer does
+        let Some(elapsed) = self.clock.advance(message) else {
+            return DeltaUpdate {
+                events,
+                delta: None,
+            };
+        };
+        // A pit stop seen late can turn the best lap into an in lap, the previous best takes over
+        while let Some((number, _)) = self.bests.last() {
+            if self.timer.laps()[*number as usize - 1].is_flying() {
+                break;
+            }
+            self.bests.pop();
+        }
+        if !FixQuality::assess(message, &self.thresholds).is_usable() {
+            return DeltaUpdate {
+                events,
+                delta: None,
+            };
+        }
+        let sample = Sample {
+            coordinates: message.gps_coordinates(),
+            elapsed,
+        };
+        let Some(previous) = self.previous.replace(sample) else {
+            return DeltaUpdate {
+                events,
+                delta: None,
+            };
+        };
+        let Some(lap_time) = self.timer.current_lap_time() else {
+            return DeltaUpdate {
+                events,
+                delta: None,
+            };
+        };
+
+        // A gap in usable samples is bridged in a straight line
+        let step = previous
+            .coordinates
+            .haversine_distance(sample.coordinates)
+            .meters();
+        let crossed = events
+            .iter()
+            .any(|event| matches!(event, LapEvent::Started | LapEvent::Completed(_)));
+        if crossed {
+            // The line was crossed during this step, split it at the crossing
+            let step_time = (sample.elapsed - previous.elapsed) / 1000.0;
+            let after = if step_time > 0.0 {
+                (lap_time.as_secs_f64() / step_time).min(1.0)
+            } else {
+                0.0
+            };
+            for event in &events {
+                if let LapEvent::Completed(lap) = event {
+                    self.distance += step * (1.0 - after);
+                    self.complete_lap(lap);
+                }
+            }
+            self.trace.clear();
+            self.trace.push(TracePoint {
+                distance: 0.0,
+                time: 0.0,
+            });
+            self.distance = step * after;
+        } else {
+            self.distance += step;
+        }
+        self.trace.push(TracePoint {
+            distance: self.distance,
+            time: lap_time.as_secs_f64(),
+        });
+
+        let delta = self.delta(lap_time);
+        DeltaUpdate { events, delta }
+    }
+
+    // Out and in laps can be the reference as the last lap but never as the best
+    fn complete_lap(&mut self, completed: &Lap) {
+        let time = completed.time;
+        let mut points = std::mem::take(&mut self.trace);
+        points.push(TracePoint {
+            distance: self.distance,
+            time: time.as_secs_f64(),
+        });
+        let lap = ReferenceLap::new(time, points);
+        if completed.is_flying() && self.best_lap().is_none_or(|best| time < best.time) {
+            self.bests.push((completed.number, lap.clone()));
+        }
+        self.last = Some(lap);
+    }
+
+    fn delta(&self, lap_time: Duration) -> Option<Delta> {
+        let reference = self.reference_lap()?;
+        let distance = Length::from_meters(self.distance);
+        let reference_time = reference.time_at(distance)?;
+        let delta = lap_time.as_nanos() as i64 - reference_time.as_nanos() as i64;
+        let predicted = reference.time.as_nanos() as i64 + delta;
+        Some(Delta {
+            lap: self.timer.current_lap_number()?,
+            distance,
+            lap_time,
+            delta: chrono::Duration::nanoseconds(delta),
+            predicted: Duration::from_nanos(predicted.max(0) as u64),
+        })
+    }
+}
+
+// The delta for every message in a stream that has one, for live displays
+pub fn delta_stream<S>(messages: S, mut predictor: DeltaPredictor) -> impl Stream<Item = Delta>
+where
+    S: Stream<Item = RbMessage>,
+{
+    messages.filter_map(move |message| future::ready(predictor.update(&message).delta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{delta_stream, DeltaPredictor, DeltaUpdate, Reference, ReferenceLap, TracePoint};
+    use crate::laps::{LapEvent, LapTimer, Line};
+    use crate::message::RbMessage;
+    use crate::pits::PitLane;
+    use crate::testing::{at, fast_in_lap, fix};
+    use crate::units::Length;
+    use futures::executor::block_on;
+    use futures::stream::{self, StreamExt};
+    use std::f64::consts::PI;
+    use std::time::Duration;
+
+    const RADIUS: f64 = 100.0;
+
+    fn message(itow: u32, x: f64, y: f64, speed: f64) -> RbMessage {
+        fix(itow).coordinates(at(x, y)).speed_mps(speed).build()
+    }
+
+    fn line() -> Line {
+        Line::new(at(90.0, 0.0), at(110.0, 0.0))
+    }
+
+    // Laps of a 100m radius circle with a speed for each lap, the line is due east
+    fn circuit(speeds: &[f64]) -> Vec<RbMessage> {
+        let speed = |lap: i64| match lap {
+            l if l < 0 => 20.0, // the out lap
+            l => speeds.get(l as usize).copied().unwrap_or(20.0),
+        };
+        let (mut angle, mut lap): (f64, i64) = (-0.5, -1);
+        let mut messages = Vec::new();
+        let mut itow = 0;
+        while lap < speeds.len() as i64 || angle < lap as f64 * 2.0 * PI + PI {
+            let (x, y) = (RADIUS * angle.cos(), RADIUS * angle.sin());
+            messages.push(message(itow, x, y, speed(lap)));
+            let mut dt = 0.04;
+            while dt > 0.0 {
+                let next = (lap + 1) as f64 * 2.0 * PI;
+                let to_next = (next - angle) * RADIUS / speed(lap);
+                if to_next <= dt {
+                    angle = next;
+                    lap += 1;
+                    dt -= to_next;
+                } else {
+                    angle += speed(lap) * dt / RADIUS;
+                    dt = 0.0;
+                }
+            }
+            itow += 40;
+        }
+        messages
+    }
+
+    fn seconds(delta: chrono::Duration) -> f64 {
+        delta.num_microseconds().unwrap() as f64 / 1e6
+    }
+
+    #[test]
+    fn test_reference_lap() {
+        let lap = ReferenceLap::new(
+            Duration::from_secs(30),
+            vec![
+                TracePoint {
+                    distance: 0.0,
+                    time: 0.0,
+                },
+                TracePoint {
+                    distance: 100.0,
+                    time: 10.0,
+                },
+                TracePoint {
+                    distance: 200.0,
+                    time: 30.0,
+                },
+            ],
+        );
+        assert_eq!(lap.distance(), Length::from_meters(200.0));
+        assert_eq!(lap.time_at(Length::from_meters(0.0)), Some(Duration::ZERO));
+        assert_eq!(
+            lap.time_at(Length::from_meters(50.0)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            lap.time_at(Length::from_meters(150.0)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(lap.time_at(Length::from_meters(200.1)), None);
+
+        let mut json = Vec::new();
+        lap.write_json(&mut json).unwrap();
+        assert_eq!(ReferenceLap::read_json(json.as_slice()).unwrap(), lap);
+        assert!(ReferenceLap::read_json("{}".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_best_lap_delta() {
+        // 20 m/s then 25 m/s, 31.4s then 25.1s laps
+        let mut predictor = DeltaPredictor::new(line(), Reference::BestLap);
+        let mut deltas = Vec::new();
+        for message in circuit(&[20.0, 25.0]) {
+            let update = predictor.update(&message);
+            for lap in update.events.iter().filter_map(|event| match event {
+                LapEvent::Completed(lap) => Some(lap),
+                _ => None,
+            }) {
+                let reference = predictor.reference_lap().unwrap();
+                assert_eq!(reference.time, lap.time);
+                assert!((reference.distance().meters() - 2.0 * PI * RADIUS).abs() < 0.1);
+            }
+            deltas.extend(update.delta);
+        }
+        // No reference until the first lap is done
+        assert!(deltas.iter().all(|d| d.lap == 2 || d.lap == 3));
+
+        let lap_2: Vec<_> = deltas.iter().filter(|d| d.lap == 2).collect();
+        let halfway = lap_2
+            .iter()
+            .min_by_key(|d| (d.distance.meters() - PI * RADIUS).abs() as i64 * 1000)
+            .unwrap();
+        // Half a lap at 25 m/s against 20 m/s
+        let expected = PI * RADIUS / 25.0 - PI * RADIUS / 20.0;
+        assert!(
+            (seconds(halfway.delta) - expected).abs() < 0.05,
+            "{:?}",
+            halfway
+        );
+        // The reference lap time less the time gained so far
+        let predicted = halfway.predicted.as_secs_f64();
+        assert!(
+            (predicted - (2.0 * PI * RADIUS / 20.0 + expected)).abs() < 0.05,
+            "{}",
+            predicted
+        );
+        // The gap only grows through a lap at a steady faster speed
+        assert!(lap_2.windows(2).all(|w| w[1].delta <= w[0].delta));
+
+        // Lap 2 was faster so it became the reference, lap 3 at 20 m/s is behind it
+        let lap_3 = deltas.iter().rfind(|d| d.lap == 3).unwrap();
+        assert!(seconds(lap_3.delta) > 0.0);
+        assert!(
+            (predictor.best_lap().unwrap().time.as_secs_f64() - 2.0 * PI * RADIUS / 25.0).abs()
+                < 0.01
+        );
+    }
+
+    #[test]
+    fn test_last_and_fixed_reference() {
+        let mut predictor = DeltaPredictor::new(line(), Reference::LastLap);
+        for message in circuit(&[25.0, 20.0]) {
+            predictor.update(&message);
+        }
+        // Last lap is the slower second one, best is the first
+        let last = predictor.last_lap().unwrap().clone();
+        assert!(last.time > predictor.best_lap().unwrap().time);
+        assert_eq!(predictor.reference_lap(), Some(&last));
+
+        // A fixed reference is there from the first lap
+        let mut predictor = DeltaPredictor::new(line(), Reference::Fixed(last));
+        let deltas: Vec<_> = circuit(&[20.0])
+            .iter()
+            .filter_map(|m| predictor.update(m).delta)
+            .collect();
+        assert!(deltas.iter().any(|d| d.lap == 1));
+        assert!(deltas.iter().all(|d| seconds(d.delta).abs() < 0.05));
+    }
+
+    #[test]
+    fn test_fast_in_lap() {
+        // The first lap was the best until the stop after it made it an in lap
+        let mut timer = LapTimer::new(line());
+        timer.set_pit_lane(PitLane::by_speed());
+        let mut predictor = DeltaPredictor::with_timer(timer, Reference::BestLap);
+        let mut bests = Vec::new();
+        for message in fast_in_lap() {
+            predictor.update(&message);
+            bests.push(predictor.best_lap().map(|lap| lap.time));
+        }
+        assert!(bests.contains(&predictor.timer().laps().first().map(|lap| lap.time)));
+        let best = predictor.best_lap().unwrap().time.as_secs_f64();
+        assert!((best - 10.0 * PI).abs() < 0.1, "{}", best);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        // Every 25th message is followed by a repeat of one from 10 messages back
+        let messages = circuit(&[20.0]);
+        let mut predictor = DeltaPredictor::new(line(), Reference::LastLap);
+        for (i, message) in messages.iter().enumerate() {
+            predictor.update(message);
+            if i % 25 == 0 && i >= 10 {
+                let update = predictor.update(&messages[i - 10]);
+                assert_eq!(update, DeltaUpdate::default());
+            }
+        }
+        let lap = predictor.last_lap().unwrap();
+        assert!((lap.distance().meters() - 2.0 * PI * RADIUS).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_delta_stream() {
+        let predictor = DeltaPredictor::new(line(), Reference::BestLap);
+        let messages = stream::iter(circuit(&[20.0, 25.0]));
+        let deltas: Vec<_> = block_on(delta_stream(messages, predictor).collect());
+        assert!(!deltas.is_empty());
+        assert!(deltas.iter().all(|d| d.lap >= 2));
+    }
+}
@@ -0,0 +1,749 @@
+use crate::geodesy::{project, unproject};
+use crate::gpstime::ElapsedClock;
+use crate::laps::millis;
+use crate::message::{Coordinates, RbMessage};
+use crate::quality::{FixQuality, QualityThresholds};
+use crate::session::Session;
+use crate::units::{Acceleration, Angle, AngularRate, Length, Speed};
+use serde::Deserialize;
+use serde::Serialize;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+/*
+GNSS/IMU sensor fusion
+
+An extended Kalman filter over a flat east/north plane centred on the first
+usable fix. The state is position, speed along the heading, heading, yaw rate
+and longitudinal acceleration, and the motion model carries the car along its
+heading at constant yaw rate and acceleration between samples.
+
+Each sample updates the state with the receiver's position, speed and heading,
+weighted by their reported accuracies, and with the X axis accelerometer and Z
+axis gyro weighted by the settings' sensor noise. Samples without a usable fix
+only get the IMU updates, and the heading is only used when it is valid and the
+car is moving fast enough for it to mean anything. The Z axis is taken as
+pointing up, so a left turn is a positive rotation rate and a falling heading.
+The accelerometer reading includes gravity along any slope and mounting tilt,
+the filter leans on the receiver's speed to take that out.
+
+Every measurement is a single state variable with independent noise, so they
+are applied one at a time and no matrix inverse is needed for the filter. The
+smoother is a Rauch-Tung-Striebel pass backwards over the filtered states of a
+whole recording, which does need the predicted covariance inverted.
+*/
+
+const N: usize = 6;
+
+type Vector = [f64; N];
+type Matrix = [[f64; N]; N];
+
+// State indices, in metres, m/s, radians clockwise from north, rad/s and m/s^2
+pub const EAST: usize = 0;
+pub const NORTH: usize = 1;
+pub const SPEED: usize = 2;
+pub const HEADING: usize = 3;
+pub const YAW_RATE: usize = 4;
+pub const ACCELERATION: usize = 5;
+
+// Smallest measurement variance used, a zero accuracy would lock the state
+const MIN_VARIANCE: f64 = 1e-6;
+
+/*
+Process noise is the spread each state variable picks up per second beyond the
+motion model, as a standard deviation over one second. Sensor noise is the one
+sigma error of a single IMU reading.
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FusionSettings {
+    pub position_noise: f64,         // m
+    pub speed_noise: f64,            // m/s
+    pub heading_noise: f64,          // rad
+    pub yaw_acceleration_noise: f64, // rad/s
+    pub jerk_noise: f64,             // m/s^2
+    pub accelerometer_noise: Acceleration,
+    pub gyro_noise: AngularRate,
+    pub thresholds: QualityThresholds,
+}
+
+impl Default for FusionSettings {
+    fn default() -> Self {
+        FusionSettings {
+            position_noise: 0.1,
+            speed_noise: 0.1,
+            heading_noise: 0.01,
+            yaw_acceleration_noise: 1.0,
+            jerk_noise: 5.0,
+            accelerometer_noise: Acceleration::from_g(0.05),
+            gyro_noise: AngularRate::from_degrees_per_second(0.5),
+            thresholds: QualityThresholds::default(),
+        }
+    }
+}
+
+// The filtered or smoothed state at a sample
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FusedState {
+    pub time: Duration, // since the filter's first message
+    pub coordinates: Coordinates,
+    pub east: f64,  // metres from the first usable fix
+    pub north: f64, // metres from the first usable fix
+    pub speed: Speed,
+    pub heading: Angle,
+    pub yaw_rate: AngularRate, // positive turning right
+    pub acceleration: Acceleration,
+    pub covariance: [[f64; N]; N], // in the order and units of the state indices
+}
+
+impl FusedState {
+    // One sigma, along the less certain of east and north
+    pub fn position_accuracy(&self) -> Length {
+        let variance = self.covariance[EAST][EAST].max(self.covariance[NORTH][NORTH]);
+        Length::from_meters(variance.sqrt())
+    }
+
+    pub fn speed_accuracy(&self) -> Speed {
+        Speed::from_meters_per_second(self.covariance[SPEED][SPEED].sqrt())
+    }
+
+    pub fn heading_accuracy(&self) -> Angle {
+        Angle::from_radians(self.covariance[HEADING][HEADING].sqrt())
+    }
+}
+
+// What the filter did at a sample, kept for the smoother
+#[derive(Clone, Debug)]
+struct Step {
+    elapsed: f64,
+    predicted: Vector,
+    predicted_covariance: Matrix,
+    state: Vector,
+    covariance: Matrix,
+    transition: Matrix, // from the previous step
+}
+
+#[derive(Debug)]
+pub struct KalmanFilter {
+    settings: FusionSettings,
+    clock: ElapsedClock,
+    origin: Option<Coordinates>,
+    state: Vector,
+    covariance: Matrix,
+    elapsed: f64, // milliseconds of the last update
+    step: Option<Step>,
+}
+
+impl KalmanFilter {
+    pub fn new(settings: FusionSettings) -> Self {
+        KalmanFilter {
+            settings,
+            clock: ElapsedClock::default(),
+            origin: None,
+            state: [0.0; N],
+            covariance: [[0.0; N]; N],
+            elapsed: 0.0,
+            step: None,
+        }
+    }
+
+    pub fn settings(&self) -> &FusionSettings {
+        &self.settings
+    }
+
+    // The first usable fix, the origin of east and north
+    pub fn origin(&self) -> Option<Coordinates> {
+        self.origin
+    }
+
+    /*
+    Takes the next sample and returns the filtered state, None until the first
+    usable fix and for duplicate or out of order samples.
+    */
+    pub fn update(&mut self, message: &RbMessage) -> Option<FusedState> {
+        let elapsed = self.clock.advance(message)?;
+        let usable = FixQuality::assess(message, &self.settings.thresholds).is_usable();
+        let Some(origin) = self.origin else {
+            if !usable {
+                return None;
+            }
+            self.start(message, elapsed);
+            return self.current();
+        };
+
+        let dt = (elapsed - self.elapsed) / 1000.0;
+        let (predicted, transition) = predict(&self.state, dt);
+        self.state = predicted;
+        self.covariance = add(
+            &multiply(
+                &multiply(&transition, &self.covariance),
+                &transpose(&transition),
+            ),
+            &self.process_noise(dt),
+        );
+        self.elapsed = elapsed;
+        let (predicted_covariance, predicted) = (self.covariance, self.state);
+
+        if usable {
+            let (east, north) = project(origin, message.gps_coordinates());
+            let variance = message.horiz_accuracy().meters().powi(2);
+            self.observe(EAST, east, variance);
+            self.observe(NORTH, north, variance);
+            let speed = message.speed();
+            self.observe(
+                SPEED,
+                speed.meters_per_second(),
+                message.speed_accuracy().meters_per_second().powi(2),
+            );
+            if message.is_valid_heading() && speed >= self.settings.thresholds.min_heading_speed {
+                self.observe(
+                    HEADING,
+                    message.heading().radians(),
+                    message.heading_accuracy().radians().powi(2),
+                );
+            }
+        }
+        let (x, _, _) = message.g_forces();
+        let (_, _, z) = message.rot_rates();
+        self.observe(
+            ACCELERATION,
+            x.meters_per_second_squared(),
+            self.settings
+                .accelerometer_noise
+                .meters_per_second_squared()
+                .powi(2),
+        );
+        self.observe(
+            YAW_RATE,
+            -z.radians_per_second(),
+            self.settings.gyro_noise.radians_per_second().powi(2),
+        );
+
+        self.step = Some(Step {
+            elapsed,
+            predicted,
+            predicted_covariance,
+            state: self.state,
+            covariance: self.covariance,
+            transition,
+        });
+        self.current()
+    }
+
+    // Starts the state from the first usable fix
+    fn start(&mut self, message: &RbMessage, elapsed: f64) {
+        let speed = message.speed();
+        let heading_known =
+            message.is_valid_heading() && speed >= self.settings.thresholds.min_heading_speed;
+        let heading_variance = match heading_known {
+            true => message.heading_accuracy().radians().powi(2),
+            false => PI * PI,
+        };
+        let (x, _, _) = message.g_forces();
+        let (_, _, z) = message.rot_rates();
+        self.origin = Some(message.gps_coordinates());
+        self.state = [
+            0.0,
+            0.0,
+            speed.meters_per_second(),
+            message.heading().radians(),
+            -z.radians_per_second(),
+            x.meters_per_second_squared(),
+        ];
+        let variances = [
+            message.horiz_accuracy().meters().powi(2),
+            message.horiz_accuracy().meters().powi(2),
+            message.speed_accuracy().meters_per_second().powi(2),
+            heading_variance,
+            self.settings.gyro_noise.radians_per_second().powi(2),
+            self.settings
+                .accelerometer_noise
+                .meters_per_second_squared()
+                .powi(2),
+        ];
+        self.covariance = [[0.0; N]; N];
+        for (i, variance) in variances.into_iter().enumerate() {
+            self.covariance[i][i] = variance.max(MIN_VARIANCE);
+        }
+        self.elapsed = elapsed;
+        self.step = Some(Step {
+            elapsed,
+            predicted: self.state,
+            predicted_covariance: self.covariance,
+            state: self.state,
+            covariance: self.covariance,
+            transition: identity(),
+        });
+    }
+
+    // A direct measurement of one state variable
+    fn observe(&mut self, index: usize, measurement: f64, variance: f64) {
+        let mut innovation = measurement - self.state[index];
+        if index == HEADING {
+            innovation = wrap(innovation);
+        }
+        let p = self.covariance;
+        let s = p[index][index] + variance.max(MIN_VARIANCE);
+        let gain: Vector = std::array::from_fn(|i| p[i][index] / s);
+        for (i, gain) in gain.into_iter().enumerate() {
+            self.state[i] += gain * innovation;
+            for (value, p) in self.covariance[i].iter_mut().zip(p[index]) {
+                *value -= gain * p;
+            }
+        }
+        self.state[HEADING] = self.state[HEADING].rem_euclid(2.0 * PI);
+        self.covariance = symmetric(&self.covariance);
+    }
+
+    fn process_noise(&self, dt: f64) -> Matrix {
+        let settings = &self.settings;
+        let deviations = [
+            settings.position_noise,
+            settings.position_noise,
+            settings.speed_noise,
+            settings.heading_noise,
+            settings.yaw_acceleration_noise,
+            settings.jerk_noise,
+        ];
+        let mut noise = [[0.0; N]; N];
+        for (i, deviation) in deviations.into_iter().enumerate() {
+            noise[i][i] = deviation * deviation * dt;
+        }
+        noise
+    }
+
+    // The state after the last update, None before the first usable fix
+    pub fn current(&self) -> Option<FusedState> {
+        Some(fused(
+            self.origin?,
+            self.elapsed,
+            &self.state,
+            &self.covariance,
+        ))
+    }
+
+    /*
+    The state carried forward to a later time by the motion model, for
+    positions between samples. Times before the last update give that update.
+    */
+    pub fn predict(&self, time: Duration) -> Option<FusedState> {
+        let elapsed = (time.as_secs_f64() * 1000.0).max(self.elapsed);
+        let dt = (elapsed - self.elapsed) / 1000.0;
+        let (state, transition) = predict(&self.state, dt);
+        let covariance = add(
+            &multiply(
+                &multiply(&transition, &self.covariance),
+                &transpose(&transition),
+            ),
+            &self.process_noise(dt),
+        );
+        Some(fused(self.origin?, elapsed, &state, &covariance))
+    }
+}
+
+// Collects a recording's filtered states for a smoothing pass when it is done
+#[derive(Debug)]
+pub struct Smoother {
+    filter: KalmanFilter,
+    origin: Option<Coordinates>,
+    steps: Vec<Step>,
+}
+
+impl Smoother {
+    pub fn new(settings: FusionSettings) -> Self {
+        Smoother {
+            filter: KalmanFilter::new(settings),
+            origin: None,
+            steps: Vec::new(),
+        }
+    }
+
+    // Takes the next sample and returns the filtered state, as the filter does
+    pub fn update(&mut self, message: &RbMessage) -> Option<FusedState> {
+        let state = self.filter.update(message)?;
+        self.origin = self.filter.origin();
+        self.steps.extend(self.filter.step.take());
+        Some(state)
+    }
+
+    // The smoothed states, one for each filtered state returned
+    pub fn finish(self) -> Vec<FusedState> {
+        let Some(origin) = self.origin else {
+            return Vec::new();
+        };
+        let steps = self.steps;
+        let Some(last) = steps.last() else {
+            return Vec::new();
+        };
+        let mut smoothed = vec![(last.state, last.covariance); steps.len()];
+        for k in (0..steps.len() - 1).rev() {
+            let (step, next) = (&steps[k], &steps[k + 1]);
+            let Some(inverse) = inverse(&next.predicted_covariance) else {
+                smoothed[k] = (step.state, step.covariance);
+                continue;
+            };
+            let gain = multiply(
+                &multiply(&step.covariance, &transpose(&next.transition)),
+                &inverse,
+            );
+            let (next_state, next_covariance) = smoothed[k + 1];
+            let mut difference: Vector = std::array::from_fn(|i| next_state[i] - next.predicted[i]);
+            difference[HEADING] = wrap(difference[HEADING]);
+            let mut state = step.state;
+            for (i, value) in state.iter_mut().enumerate() {
+                *value += (0..N).map(|j| gain[i][j] * difference[j]).sum::<f64>();
+            }
+            state[HEADING] = state[HEADING].rem_euclid(2.0 * PI);
+            let correction = multiply(
+                &multiply(
+                    &gain,
+                    &subtract(&next_covariance, &next.predicted_covariance),
+                ),
+                &transpose(&gain),
+            );
+            smoothed[k] = (state, symmetric(&add(&step.covariance, &correction)));
+        }
+        steps
+            .iter()
+            .zip(smoothed)
+            .map(|(step, (state, covariance))| fused(origin, step.elapsed, &state, &covariance))
+            .collect()
+    }
+}
+
+// The filtered states of a recording
+pub fn filter_session(session: &Session, settings: FusionSettings) -> Vec<FusedState> {
+    let mut filter = KalmanFilter::new(settings);
+    session
+        .messages()
+        .iter()
+        .filter_map(|message| filter.update(message))
+        .collect()
+}
+
+// The smoothed states of a recording
+pub fn smooth_session(session: &Session, settings: FusionSettings) -> Vec<FusedState> {
+    let mut smoother = Smoother::new(settings);
+    for message in session.messages() {
+        smoother.update(message);
+    }
+    smoother.finish()
+}
+
+fn fused(origin: Coordinates, elapsed: f64, state: &Vector, covariance: &Matrix) -> FusedState {
+    FusedState {
+        time: millis(elapsed),
+        coordinates: unproject(origin, (state[EAST], state[NORTH])),
+        east: state[EAST],
+        north: state[NORTH],
+        speed: Speed::from_meters_per_second(state[SPEED]),
+        heading: Angle::from_radians(state[HEADING]),
+        yaw_rate: AngularRate::from_degrees_per_second(state[YAW_RATE].to_degrees()),
+        acceleration: Acceleration::from_meters_per_second_squared(state[ACCELERATION]),
+        covariance: *covariance,
+    }
+}
+
+/*
+The motion model, moving along the heading halfway through the turn over the
+step. Returns the new state and the model's Jacobian.
+*/
+fn predict(state: &Vector, dt: f64) -> (Vector, Matrix) {
+    let [east, north, speed, heading, yaw_rate, acceleration] = *state;
+    let distance = speed * dt + acceleration * dt * dt / 2.0;
+    let (sin, cos) = (heading + yaw_rate * dt / 2.0).sin_cos();
+    let predicted = [
+        east + distance * sin,
+        north + distance * cos,
+        speed + acceleration * dt,
+        (heading + yaw_rate * dt).rem_euclid(2.0 * PI),
+        yaw_rate,
+        acceleration,
+    ];
+    let mut jacobian = identity();
+    jacobian[EAST][SPEED] = dt * sin;
+    jacobian[EAST][HEADING] = distance * cos;
+    jacobian[EAST][YAW_RATE] = distance * cos * dt / 2.0;
+    jacobian[EAST][ACCELERATION] = dt * dt / 2.0 * sin;
+    jacobian[NORTH][SPEED] = dt * cos;
+    jacobian[NORTH][HEADING] = -distance * sin;
+    jacobian[NORTH][YAW_RATE] = -distance * sin * dt / 2.0;
+    jacobian[NORTH][ACCELERATION] = dt * dt / 2.0 * cos;
+    jacobian[SPEED][ACCELERATION] = dt;
+    jacobian[HEADING][YAW_RATE] = dt;
+    (predicted, jacobian)
+}
+
+// An angle difference brought into -PI to PI
+fn wrap(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+fn identity() -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| f64::from(u8::from(i == j))))
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| (0..N).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn transpose(a: &Matrix) -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| a[j][i]))
+}
+
+fn add(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| a[i][j] + b[i][j]))
+}
+
+fn subtract(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| a[i][j] - b[i][j]))
+}
+
+// Rounding leaves a covariance slightly lopsided, averaging it with its transpose evens it out
+fn symmetric(a: &Matrix) -> Matrix {
+    std::array::from_fn(|i| std::array::from_fn(|j| (a[i][j] + a[j][i]) / 2.0))
+}
+
+// Gauss-Jordan elimination with partial pivoting, None for a singular matrix
+fn inverse(a: &Matrix) -> Option<Matrix> {
+    let mut a = *a;
+    let mut inverse = identity();
+    for column in 0..N {
+        let pivot =
+            (column..N).max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))?;
+        if a[pivot][column].abs() < 1e-300 {
+            return None;
+        }
+        a.swap(column, pivot);
+        inverse.swap(column, pivot);
+        let scale = a[column][column];
+        for j in 0..N {
+            a[column][j] /= scale;
+            inverse[column][j] /= scale;
+        }
+        for i in (0..N).filter(|&i| i != column) {
+            let factor = a[i][column];
+            for j in 0..N {
+                a[i][j] -= factor * a[column][j];
+                inverse[i][j] -= factor * inverse[column][j];
+            }
+        }
+    }
+    Some(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        filter_session, identity, inverse, multiply, smooth_session, FusionSettings, KalmanFilter,
+        EAST, NORTH,
+    };
+    use crate::geodesy::project;
+    use crate::message::RbMessage;
+    use crate::session::Session;
+    use crate::status::{FixFlags, FixStatus};
+    use crate::testing::at;
+    use crate::units::{Acceleration, Angle, AngularRate, Length, Speed};
+    use std::f64::consts::PI;
+    use std::time::Duration;
+
+    const SPEED: f64 = 15.0;
+    const YAW_RATE: f64 = 0.3; // rad/s, turning right
+    const POSITION_NOISE: f64 = 1.5;
+
+    // Repeatable noise, uniform with a standard deviation of one
+    struct Noise(u64);
+
+    impl Noise {
+        fn next(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            ((self.0 >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0) * 3f64.sqrt()
+        }
+    }
+
+    // Truth at time t on a clockwise circle through the origin, heading north at t = 0
+    fn truth(t: f64) -> (f64, f64, f64) {
+        let radius = SPEED / YAW_RATE;
+        let heading = YAW_RATE * t;
+        (
+            radius - radius * heading.cos(),
+            radius * heading.sin(),
+            heading,
+        )
+    }
+
+    // 20 seconds at 25 Hz, with the receiver and sensors noisy
+    fn session(fix: bool) -> Session {
+        let mut noise = Noise(0x2545_f491_4f6c_dd1d);
+        let mut session = Session::default();
+        for i in 0..500 {
+            let t = f64::from(i) * 0.04;
+            let (x, y, heading) = truth(t);
+            let heading = heading.to_degrees() + noise.next();
+            session.push(
+                RbMessage::builder()
+                    .itow(i * 40)
+                    .fix_status(if fix {
+                        FixStatus::Fix3D
+                    } else {
+                        FixStatus::NoFix
+                    })
+                    .fix_flags(FixFlags(0b10_0001))
+                    .satellites(12)
+                    .horiz_accuracy(Length::from_meters(POSITION_NOISE))
+                    .vert_accuracy(Length::from_meters(2.0))
+                    .speed_accuracy(Speed::from_meters_per_second(0.3))
+                    .heading_accuracy(Angle::from_degrees(1.0))
+                    .coordinates(at(
+                        x + noise.next() * POSITION_NOISE,
+                        y + noise.next() * POSITION_NOISE,
+                    ))
+                    .speed_mps(SPEED + noise.next() * 0.3)
+                    .heading(Angle::from_degrees(heading.rem_euclid(360.0)))
+                    .g_forces(
+                        Acceleration::from_g(noise.next() * 0.05),
+                        Acceleration::from_meters_per_second_squared(SPEED * YAW_RATE),
+                        Acceleration::from_g(1.0),
+                    )
+                    .rot_rates(
+                        AngularRate::from_degrees_per_second(0.0),
+                        AngularRate::from_degrees_per_second(0.0),
+                        AngularRate::from_degrees_per_second(
+                            -YAW_RATE.to_degrees() + noise.next() * 0.5,
+                        ),
+                    )
+                    .build(),
+            );
+        }
+        session
+    }
+
+    // RMS position error after the first two seconds
+    fn rms_error(positions: impl Iterator<Item = (f64, f64)>) -> f64 {
+        let errors: Vec<_> = positions
+            .enumerate()
+            .skip(50)
+            .map(|(i, (x, y))| {
+                let (tx, ty, _) = truth(i as f64 * 0.04);
+                (x - tx).powi(2) + (y - ty).powi(2)
+            })
+            .collect();
+        (errors.iter().sum::<f64>() / errors.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_filter() {
+        let session = session(true);
+        let origin = session.messages()[0].gps_coordinates();
+        let filtered = filter_session(&session, FusionSettings::default());
+        assert_eq!(filtered.len(), session.len());
+
+        let raw = rms_error(
+            session
+                .messages()
+                .iter()
+                .map(|m| project(at(0.0, 0.0), m.gps_coordinates())),
+        );
+        // East and north are from the first fix, which is off the truth by its noise
+        let (ox, oy) = project(at(0.0, 0.0), origin);
+        let error = rms_error(filtered.iter().map(|s| (s.east + ox, s.north + oy)));
+        assert!(raw > POSITION_NOISE, "{raw}");
+        assert!(error < raw / 2.0, "{error} {raw}");
+
+        let last = filtered.last().unwrap();
+        let (_, _, heading) = truth(19.96);
+        assert!((last.yaw_rate.radians_per_second() - YAW_RATE).abs() < 0.01);
+        assert!((last.speed.meters_per_second() - SPEED).abs() < 0.2);
+        let difference = (last.heading.radians() - heading).rem_euclid(2.0 * PI);
+        assert!(difference.min(2.0 * PI - difference) < 1f64.to_radians());
+        assert!(last.acceleration.g().abs() < 0.05);
+        assert!(last.position_accuracy() < Length::from_meters(POSITION_NOISE));
+        assert!(last.speed_accuracy() < Speed::from_meters_per_second(0.3));
+        assert!(last.heading_accuracy() < Angle::from_degrees(1.0));
+        let (x, y) = project(origin, last.coordinates);
+        assert!((x - last.east).abs() < 0.05 && (y - last.north).abs() < 0.05);
+        for i in 0..6 {
+            for j in 0..6 {
+                assert_eq!(last.covariance[i][j], last.covariance[j][i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_smoother() {
+        let session = session(true);
+        let (ox, oy) = project(at(0.0, 0.0), session.messages()[0].gps_coordinates());
+        let filtered = filter_session(&session, FusionSettings::default());
+        let smoothed = smooth_session(&session, FusionSettings::default());
+        assert_eq!(smoothed.len(), filtered.len());
+        assert_eq!(smoothed.last(), filtered.last());
+        assert_eq!(smoothed[10].time, filtered[10].time);
+
+        let filtered_error = rms_error(filtered.iter().map(|s| (s.east + ox, s.north + oy)));
+        let smoothed_error = rms_error(smoothed.iter().map(|s| (s.east + ox, s.north + oy)));
+        assert!(
+            smoothed_error < filtered_error,
+            "{smoothed_error} {filtered_error}"
+        );
+        for (smoothed, filtered) in smoothed.iter().zip(&filtered).skip(50) {
+            assert!(smoothed.covariance[EAST][EAST] <= filtered.covariance[EAST][EAST] + 1e-9);
+            assert!(smoothed.covariance[NORTH][NORTH] <= filtered.covariance[NORTH][NORTH] + 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_no_fix() {
+        let session = session(false);
+        assert!(filter_session(&session, FusionSettings::default()).is_empty());
+        assert!(smooth_session(&session, FusionSettings::default()).is_empty());
+        assert!(smooth_session(&Session::default(), FusionSettings::default()).is_empty());
+
+        let mut filter = KalmanFilter::new(FusionSettings::default());
+        assert_eq!(filter.update(&session.messages()[0]), None);
+        assert_eq!(filter.current(), None);
+        assert_eq!(filter.predict(Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn test_predict() {
+        let session = session(true);
+        let mut filter = KalmanFilter::new(FusionSettings::default());
+        let mut last = None;
+        for message in session.messages() {
+            last = filter.update(message).or(last);
+        }
+        let last = last.unwrap();
+        // A duplicate sample is ignored
+        assert_eq!(filter.update(session.messages().last().unwrap()), None);
+        assert_eq!(filter.predict(Duration::ZERO).unwrap(), last);
+
+        // Between samples the car carries on round the circle
+        let ahead = filter
+            .predict(last.time + Duration::from_millis(20))
+            .unwrap();
+        let moved = (ahead.east - last.east).hypot(ahead.north - last.north);
+        assert!((moved - SPEED * 0.02).abs() < 0.01);
+        let turned = ahead.heading.radians() - last.heading.radians();
+        assert!((turned - YAW_RATE * 0.02).abs() < 1e-3);
+        assert!(ahead.position_accuracy() > last.position_accuracy());
+    }
+
+    #[test]
+    fn test_inverse() {
+        let mut a = identity();
+        for (i, row) in a.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value += 1.0 / (1.0 + i as f64 + j as f64);
+            }
+        }
+        a.swap(0, 3);
+        let product = multiply(&a, &inverse(&a).unwrap());
+        for (i, row) in product.iter().enumerate() {
+            for (j, value) in row.iter().enumerate() {
+                assert!((value - f64::from(u8::from(i == j))).abs() < 1e-9);
+            }
+        }
+        assert_eq!(inverse(&[[1.0; 6]; 6]), None);
+    }
+}
@@ -0,0 +1,548 @@
+use crate::message::Coordinates;
+use crate::units::{Angle, Length};
+use serde::Deserialize;
+use serde::Serialize;
+use std::f64::consts::PI;
+use std::fmt;
+
+/*
+Geodesy
+
+Distances, bearings and metric coordinates for positions from the receiver,
+which are on the WGS84 ellipsoid.
+
+The haversine distance and the bearings treat the earth as a sphere of the mean
+radius, good to about half a percent, and Vincenty's formulae solve the same on
+the ellipsoid to well under a millimetre. project and unproject are a flat
+approximation around an origin, fast and plenty for the few hundred metres
+around a timing line or a corner.
+
+A local frame gives east, north and up metres from a reference point and
+altitude, exactly, through earth centred coordinates. UTM is the transverse
+Mercator projection of each 6 degree zone, with the Norway and Svalbard
+exceptions, using Krüger's series.
+*/
+
+// Mean earth radius in metres
+pub const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// WGS84 semi-major axis in metres and flattening
+pub const WGS84_A: f64 = 6_378_137.0;
+pub const WGS84_F: f64 = 1.0 / 298.257_223_563;
+
+// Eccentricity squared
+const WGS84_E2: f64 = WGS84_F * (2.0 - WGS84_F);
+
+const UTM_SCALE: f64 = 0.9996;
+const UTM_FALSE_EASTING: f64 = 500_000.0;
+const UTM_FALSE_NORTHING: f64 = 10_000_000.0; // southern hemisphere
+
+// East and north metres from the origin, an equirectangular projection
+pub(crate) fn project(origin: Coordinates, point: Coordinates) -> (f64, f64) {
+    let scale = EARTH_RADIUS * PI / 180.0;
+    let x = (point.longitude() - origin.longitude()) * scale * origin.latitude().to_radians().cos();
+    let y = (point.latitude() - origin.latitude()) * scale;
+    (x, y)
+}
+
+// The inverse of project
+pub(crate) fn unproject(origin: Coordinates, (x, y): (f64, f64)) -> Coordinates {
+    let scale = EARTH_RADIUS * PI / 180.0;
+    Coordinates::from_degrees(
+        origin.latitude() + y / scale,
+        origin.longitude() + x / (scale * origin.latitude().to_radians().cos()),
+    )
+}
+
+// The shortest path between two points on the ellipsoid
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Geodesic {
+    pub distance: Length,
+    pub initial_bearing: Angle, // leaving the first point
+    pub final_bearing: Angle,   // arriving at the second
+}
+
+impl Coordinates {
+    // Great circle distance on a sphere of the mean earth radius
+    pub fn haversine_distance(&self, other: Coordinates) -> Length {
+        let (lat1, lat2) = (self.latitude().to_radians(), other.latitude().to_radians());
+        let half_lat = (lat2 - lat1) / 2.0;
+        let half_lon = (other.longitude() - self.longitude()).to_radians() / 2.0;
+        let a = half_lat.sin().powi(2) + lat1.cos() * lat2.cos() * half_lon.sin().powi(2);
+        Length::from_meters(2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin())
+    }
+
+    // Great circle bearing leaving this point for the other, clockwise from north
+    pub fn initial_bearing(&self, other: Coordinates) -> Angle {
+        let (lat1, lat2) = (self.latitude().to_radians(), other.latitude().to_radians());
+        let dlon = (other.longitude() - self.longitude()).to_radians();
+        let y = dlon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+        Angle::from_degrees(y.atan2(x).to_degrees().rem_euclid(360.0))
+    }
+
+    // Great circle bearing arriving at the other point
+    pub fn final_bearing(&self, other: Coordinates) -> Angle {
+        let reverse = other.initial_bearing(*self).degrees();
+        Angle::from_degrees((reverse + 180.0).rem_euclid(360.0))
+    }
+
+    // Distance on the WGS84 ellipsoid, None for nearly antipodal points
+    pub fn vincenty_distance(&self, other: Coordinates) -> Option<Length> {
+        Some(self.vincenty(other)?.distance)
+    }
+
+    /*
+    Vincenty's inverse formula, iterated to convergence. The iteration can fail
+    for points close to opposite sides of the earth, which gives None.
+    */
+    pub fn vincenty(&self, other: Coordinates) -> Option<Geodesic> {
+        let (a, f) = (WGS84_A, WGS84_F);
+        let b = a * (1.0 - f);
+        let l = (other.longitude() - self.longitude()).to_radians();
+        let u1 = ((1.0 - f) * self.latitude().to_radians().tan()).atan();
+        let u2 = ((1.0 - f) * other.latitude().to_radians().tan()).atan();
+        let (sin_u1, cos_u1) = u1.sin_cos();
+        let (sin_u2, cos_u2) = u2.sin_cos();
+
+        let mut lambda = l;
+        for _ in 0..200 {
+            let (sin_lambda, cos_lambda) = lambda.sin_cos();
+            let sin_sigma =
+                (cos_u2 * sin_lambda).hypot(cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda);
+            if sin_sigma == 0.0 {
+                // The same point
+                return Some(Geodesic {
+                    distance: Length::from_meters(0.0),
+                    initial_bearing: Angle::from_degrees(0.0),
+                    final_bearing: Angle::from_degrees(0.0),
+                });
+            }
+            let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+            let sigma = sin_sigma.atan2(cos_sigma);
+            let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+            let cos2_alpha = 1.0 - sin_alpha * sin_alpha;
+            // Along the equator cos2_alpha is zero and the term drops out
+            let cos_2sigma_m = if cos2_alpha == 0.0 {
+                0.0
+            } else {
+                cos_sigma - 2.0 * sin_u1 * sin_u2 / cos2_alpha
+            };
+            let c = f / 16.0 * cos2_alpha * (4.0 + f * (4.0 - 3.0 * cos2_alpha));
+            let previous = lambda;
+            lambda = l
+                + (1.0 - c)
+                    * f
+                    * sin_alpha
+                    * (sigma
+                        + c * sin_sigma
+                            * (cos_2sigma_m
+                                + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m)));
+            if (lambda - previous).abs() > 1e-12 {
+                continue;
+            }
+
+            let u_sq = cos2_alpha * (a * a - b * b) / (b * b);
+            let big_a =
+                1.0 + u_sq / 16384.0 * (4096.0 + u_sq * (-768.0 + u_sq * (320.0 - 175.0 * u_sq)));
+            let big_b = u_sq / 1024.0 * (256.0 + u_sq * (-128.0 + u_sq * (74.0 - 47.0 * u_sq)));
+            let delta_sigma = big_b
+                * sin_sigma
+                * (cos_2sigma_m
+                    + big_b / 4.0
+                        * (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m)
+                            - big_b / 6.0
+                                * cos_2sigma_m
+                                * (-3.0 + 4.0 * sin_sigma * sin_sigma)
+                                * (-3.0 + 4.0 * cos_2sigma_m * cos_2sigma_m)));
+            let (sin_lambda, cos_lambda) = lambda.sin_cos();
+            let initial =
+                (cos_u2 * sin_lambda).atan2(cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda);
+            let last = (cos_u1 * sin_lambda).atan2(-sin_u1 * cos_u2 + cos_u1 * sin_u2 * cos_lambda);
+            return Some(Geodesic {
+                distance: Length::from_meters(b * big_a * (sigma - delta_sigma)),
+                initial_bearing: Angle::from_degrees(initial.to_degrees().rem_euclid(360.0)),
+                final_bearing: Angle::from_degrees(last.to_degrees().rem_euclid(360.0)),
+            });
+        }
+        None
+    }
+
+    // UTM coordinates in the point's own zone
+    pub fn to_utm(&self) -> Utm {
+        self.to_utm_zone(utm_zone(*self))
+    }
+
+    /*
+    UTM coordinates in a given zone, to keep a recording that crosses a zone
+    boundary in one grid. Accuracy falls off a few zones away.
+    */
+    pub fn to_utm_zone(&self, zone: u8) -> Utm {
+        let n = WGS84_F / (2.0 - WGS84_F);
+        let (latitude, longitude) = (self.latitude().to_radians(), self.longitude().to_radians());
+        let dlon = longitude - central_meridian(zone);
+        let e = 2.0 * n.sqrt() / (1.0 + n);
+        let t = (latitude.sin().atanh() - e * (e * latitude.sin()).atanh()).sinh();
+        let xi = t.atan2(dlon.cos());
+        let eta = (dlon.sin() / (1.0 + t * t).sqrt()).atanh();
+
+        let alpha = [
+            n / 2.0 - 2.0 / 3.0 * n * n + 5.0 / 16.0 * n.powi(3),
+            13.0 / 48.0 * n * n - 3.0 / 5.0 * n.powi(3),
+            61.0 / 240.0 * n.powi(3),
+        ];
+        let (mut x, mut y) = (eta, xi);
+        for (j, alpha) in alpha.iter().enumerate() {
+            let k = 2.0 * (j + 1) as f64;
+            x += alpha * (k * xi).cos() * (k * eta).sinh();
+            y += alpha * (k * xi).sin() * (k * eta).cosh();
+        }
+        let scale = UTM_SCALE * rectifying_radius(n);
+        let northern = self.latitude() >= 0.0;
+        Utm {
+            zone,
+            northern,
+            easting: UTM_FALSE_EASTING + scale * x,
+            northing: scale * y + if northern { 0.0 } else { UTM_FALSE_NORTHING },
+        }
+    }
+}
+
+// The UTM zone a point is in
+pub fn utm_zone(point: Coordinates) -> u8 {
+    let (latitude, longitude) = (point.latitude(), point.longitude());
+    let zone = (((longitude + 180.0) / 6.0).floor().rem_euclid(60.0) + 1.0) as u8;
+    match (latitude, longitude) {
+        // South west Norway is widened into zone 32
+        (56.0..64.0, 3.0..12.0) => 32,
+        // Svalbard uses the odd zones only
+        (72.0..=84.0, 0.0..9.0) => 31,
+        (72.0..=84.0, 9.0..21.0) => 33,
+        (72.0..=84.0, 21.0..33.0) => 35,
+        (72.0..=84.0, 33.0..42.0) => 37,
+        _ => zone,
+    }
+}
+
+fn central_meridian(zone: u8) -> f64 {
+    (f64::from(zone) * 6.0 - 183.0).to_radians()
+}
+
+// Krüger's A, the radius of a sphere with the ellipsoid's meridian length
+fn rectifying_radius(n: f64) -> f64 {
+    WGS84_A / (1.0 + n) * (1.0 + n * n / 4.0 + n.powi(4) / 64.0)
+}
+
+// A position in a UTM zone, in metres
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Utm {
+    pub zone: u8,
+    pub northern: bool, // hemisphere, southern northings start 10,000 km south
+    pub easting: f64,
+    pub northing: f64,
+}
+
+impl fmt::Display for Utm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hemisphere = if self.northern { 'N' } else { 'S' };
+        write!(
+            f,
+            "{}{} {:.0} {:.0}",
+            self.zone, hemisphere, self.easting, self.northing
+        )
+    }
+}
+
+impl Utm {
+    pub fn to_coordinates(&self) -> Coordinates {
+        let n = WGS84_F / (2.0 - WGS84_F);
+        let scale = UTM_SCALE * rectifying_radius(n);
+        let northing = self.northing
+            - if self.northern {
+                0.0
+            } else {
+                UTM_FALSE_NORTHING
+            };
+        let xi = northing / scale;
+        let eta = (self.easting - UTM_FALSE_EASTING) / scale;
+
+        let beta = [
+            n / 2.0 - 2.0 / 3.0 * n * n + 37.0 / 96.0 * n.powi(3),
+            n * n / 48.0 + n.powi(3) / 15.0,
+            17.0 / 480.0 * n.powi(3),
+        ];
+        let (mut xi_prime, mut eta_prime) = (xi, eta);
+        for (j, beta) in beta.iter().enumerate() {
+            let k = 2.0 * (j + 1) as f64;
+            xi_prime -= beta * (k * xi).sin() * (k * eta).cosh();
+            eta_prime -= beta * (k * xi).cos() * (k * eta).sinh();
+        }
+        let chi = (xi_prime.sin() / eta_prime.cosh()).asin();
+        let delta = [
+            2.0 * n - 2.0 / 3.0 * n * n - 2.0 * n.powi(3),
+            7.0 / 3.0 * n * n - 8.0 / 5.0 * n.powi(3),
+            56.0 / 15.0 * n.powi(3),
+        ];
+        let mut latitude = chi;
+        for (j, delta) in delta.iter().enumerate() {
+            latitude += delta * (2.0 * (j + 1) as f64 * chi).sin();
+        }
+        let longitude = central_meridian(self.zone) + eta_prime.sinh().atan2(xi_prime.cos());
+        Coordinates::from_degrees(latitude.to_degrees(), longitude.to_degrees())
+    }
+}
+
+// East, north and up metres in a local frame
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Enu {
+    pub east: f64,
+    pub north: f64,
+    pub up: f64,
+}
+
+// A local east, north, up frame at a reference point on the ellipsoid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalFrame {
+    origin: Coordinates,
+    altitude: Length,
+    ecef: [f64; 3],
+    axes: [[f64; 3]; 3], // east, north and up in earth centred coordinates
+}
+
+impl LocalFrame {
+    // Altitudes are heights above the WGS84 ellipsoid
+    pub fn new(origin: Coordinates, altitude: Length) -> Self {
+        let (sin_lat, cos_lat) = origin.latitude().to_radians().sin_cos();
+        let (sin_lon, cos_lon) = origin.longitude().to_radians().sin_cos();
+        LocalFrame {
+            origin,
+            altitude,
+            ecef: to_ecef(origin, altitude),
+            axes: [
+                [-sin_lon, cos_lon, 0.0],
+                [-sin_lat * cos_lon, -sin_lat * sin_lon, cos_lat],
+                [cos_lat * cos_lon, cos_lat * sin_lon, sin_lat],
+            ],
+        }
+    }
+
+    pub fn origin(&self) -> Coordinates {
+        self.origin
+    }
+
+    pub fn altitude(&self) -> Length {
+        self.altitude
+    }
+
+    pub fn to_enu(&self, point: Coordinates, altitude: Length) -> Enu {
+        let ecef = to_ecef(point, altitude);
+        let d: [f64; 3] = std::array::from_fn(|i| ecef[i] - self.ecef[i]);
+        let [east, north, up] = self.axes.map(|axis| (0..3).map(|i| axis[i] * d[i]).sum());
+        Enu { east, north, up }
+    }
+
+    // The position and altitude of a point in the frame
+    pub fn from_enu(&self, enu: Enu) -> (Coordinates, Length) {
+        let local = [enu.east, enu.north, enu.up];
+        let ecef: [f64; 3] = std::array::from_fn(|i| {
+            self.ecef[i] + (0..3).map(|j| self.axes[j][i] * local[j]).sum::<f64>()
+        });
+        from_ecef(ecef)
+    }
+}
+
+fn to_ecef(point: Coordinates, altitude: Length) -> [f64; 3] {
+    let (sin_lat, cos_lat) = point.latitude().to_radians().sin_cos();
+    let (sin_lon, cos_lon) = point.longitude().to_radians().sin_cos();
+    let radius = WGS84_A / (1.0 - WGS84_E2 * sin_lat * sin_lat).sqrt();
+    let h = altitude.meters();
+    [
+        (radius + h) * cos_lat * cos_lon,
+        (radius + h) * cos_lat * sin_lon,
+        (radius * (1.0 - WGS84_E2) + h) * sin_lat,
+    ]
+}
+
+// Converges in a few iterations anywhere near the surface
+fn from_ecef([x, y, z]: [f64; 3]) -> (Coordinates, Length) {
+    let p = x.hypot(y);
+    let longitude = y.atan2(x);
+    let mut latitude = z.atan2(p * (1.0 - WGS84_E2));
+    let mut height = 0.0;
+    for _ in 0..5 {
+        let sin_lat = latitude.sin();
+        let radius = WGS84_A / (1.0 - WGS84_E2 * sin_lat * sin_lat).sqrt();
+        height = p / latitude.cos() - radius;
+        latitude = z.atan2(p * (1.0 - WGS84_E2 * radius / (radius + height)));
+    }
+    (
+        Coordinates::from_degrees(latitude.to_degrees(), longitude.to_degrees()),
+        Length::from_meters(height),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{project, unproject, utm_zone, Enu, LocalFrame, Utm};
+    use crate::message::Coordinates;
+    use crate::units::Length;
+
+    // Degrees, minutes and seconds
+    fn dms(degrees: f64, minutes: f64, seconds: f64) -> f64 {
+        degrees.signum() * (degrees.abs() + minutes / 60.0 + seconds / 3600.0)
+    }
+
+    // The geodetic survey example of Vincenty's formula
+    fn flinders_peak() -> Coordinates {
+        Coordinates::from_degrees(dms(-37.0, 57.0, 3.72030), dms(144.0, 25.0, 29.52440))
+    }
+
+    fn buninyong() -> Coordinates {
+        Coordinates::from_degrees(dms(-37.0, 39.0, 10.15610), dms(143.0, 55.0, 35.38390))
+    }
+
+    #[test]
+    fn test_vincenty() {
+        let geodesic = flinders_peak().vincenty(buninyong()).unwrap();
+        assert!((geodesic.distance.meters() - 54_972.271).abs() < 0.02);
+        assert!((geodesic.initial_bearing.degrees() - dms(306.0, 52.0, 5.37)).abs() < 1e-4);
+        assert!((geodesic.final_bearing.degrees() - dms(307.0, 10.0, 25.07)).abs() < 1e-4);
+
+        let same = flinders_peak().vincenty_distance(flinders_peak());
+        assert_eq!(same, Some(Length::from_meters(0.0)));
+        // Along the equator
+        let a = Coordinates::from_degrees(0.0, 0.0);
+        let b = Coordinates::from_degrees(0.0, 1.0);
+        let distance = a.vincenty_distance(b).unwrap().meters();
+        assert!((distance - 111_319.491).abs() < 0.01);
+        // Antipodal points don't converge
+        let antipode = Coordinates::from_degrees(0.5, 179.7);
+        assert_eq!(a.vincenty(antipode), None);
+    }
+
+    #[test]
+    fn test_haversine() {
+        let (a, b) = (flinders_peak(), buninyong());
+        let ellipsoid = a.vincenty_distance(b).unwrap().meters();
+        let sphere = a.haversine_distance(b).meters();
+        assert!((sphere - ellipsoid).abs() / ellipsoid < 0.005);
+        assert_eq!(a.haversine_distance(a).meters(), 0.0);
+
+        let bearing = a.initial_bearing(b).degrees();
+        assert!((bearing - dms(306.0, 52.0, 5.37)).abs() < 0.2);
+        let bearing = a.final_bearing(b).degrees();
+        assert!((bearing - dms(307.0, 10.0, 25.07)).abs() < 0.2);
+
+        let origin = Coordinates::from_degrees(42.6719035, 23.2887238);
+        let north = unproject(origin, (0.0, 100.0));
+        let east = unproject(origin, (100.0, 0.0));
+        assert!((origin.initial_bearing(north).degrees() - 0.0).abs() < 0.01);
+        assert!((origin.initial_bearing(east).degrees() - 90.0).abs() < 0.01);
+        assert!((east.initial_bearing(origin).degrees() - 270.0).abs() < 0.01);
+        assert!((origin.haversine_distance(east).meters() - 100.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_project() {
+        let origin = Coordinates::from_degrees(42.6719035, 23.2887238);
+        let point = unproject(origin, (120.0, -80.0));
+        let (x, y) = project(origin, point);
+        assert!((x - 120.0).abs() < 0.02 && (y + 80.0).abs() < 0.02);
+        // Close to the true distance over a track's length
+        let far = unproject(origin, (2000.0, 1500.0));
+        let true_distance = origin.vincenty_distance(far).unwrap().meters();
+        assert!((true_distance - 2500.0).abs() / 2500.0 < 0.005);
+    }
+
+    #[test]
+    fn test_utm() {
+        // On the central meridian at the equator
+        let utm = Coordinates::from_degrees(0.0, 3.0).to_utm();
+        assert_eq!((utm.zone, utm.northern), (31, true));
+        assert!((utm.easting - 500_000.0).abs() < 1e-6 && utm.northing.abs() < 1e-6);
+        // The scaled meridian arc to 45 degrees
+        let utm = Coordinates::from_degrees(45.0, 3.0).to_utm();
+        assert!((utm.northing - 0.9996 * 4_984_944.378).abs() < 0.01);
+
+        let sofia = Coordinates::from_degrees(42.6719035, 23.2887238);
+        let utm = sofia.to_utm();
+        assert_eq!(utm.to_string()[..4], *"34N ");
+        for point in [
+            sofia,
+            flinders_peak(),
+            Coordinates::from_degrees(-33.9, 18.4),
+        ] {
+            let utm = point.to_utm();
+            assert_eq!(utm.northern, point.latitude() > 0.0);
+            let back = utm.to_coordinates();
+            assert!((back.latitude() - point.latitude()).abs() < 2e-7);
+            assert!((back.longitude() - point.longitude()).abs() < 2e-7);
+        }
+
+        // Grid distances near the central meridian are scaled by 0.9996
+        let a = Coordinates::from_degrees(42.0, 21.001);
+        let b = Coordinates::from_degrees(42.01, 21.0);
+        let (ua, ub) = (a.to_utm(), b.to_utm());
+        let grid = (ua.easting - ub.easting).hypot(ua.northing - ub.northing);
+        let ratio = grid / a.vincenty_distance(b).unwrap().meters();
+        assert!((ratio - 0.9996).abs() < 1e-5);
+
+        // A zone forced on a point next door
+        let utm = sofia.to_utm_zone(35);
+        assert_eq!(utm.zone, 35);
+        let back = utm.to_coordinates();
+        assert!((back.latitude() - sofia.latitude()).abs() < 2e-7);
+        assert!((back.longitude() - sofia.longitude()).abs() < 2e-7);
+
+        let southern = Utm {
+            zone: 55,
+            northern: false,
+            easting: 500_000.0,
+            northing: 10_000_000.0,
+        };
+        let equator = southern.to_coordinates();
+        assert_eq!((equator.latitude(), equator.longitude()), (0.0, 147.0));
+    }
+
+    #[test]
+    fn test_utm_zone() {
+        let zone = |latitude, longitude| utm_zone(Coordinates::from_degrees(latitude, longitude));
+        assert_eq!(zone(0.0, -180.0), 1);
+        assert_eq!(zone(0.0, 179.9), 60);
+        assert_eq!(zone(51.5, -0.1), 30);
+        assert_eq!(zone(51.5, 0.1), 31);
+        assert_eq!(zone(60.0, 5.0), 32);
+        assert_eq!(zone(78.0, 15.0), 33);
+        assert_eq!(zone(-37.9, 144.4), 55);
+    }
+
+    #[test]
+    fn test_local_frame() {
+        let origin = Coordinates::from_degrees(42.6719035, 23.2887238);
+        let frame = LocalFrame::new(origin, Length::from_meters(550.0));
+        assert_eq!(frame.origin(), origin);
+        assert_eq!(frame.altitude(), Length::from_meters(550.0));
+
+        let above = frame.to_enu(origin, Length::from_meters(560.0));
+        assert!(above.east.abs() < 1e-6 && above.north.abs() < 1e-6);
+        assert!((above.up - 10.0).abs() < 1e-6);
+
+        // A kilometre north, level with the origin, the ground curves away below
+        let (north, altitude) = frame.from_enu(Enu {
+            east: 0.0,
+            north: 1000.0,
+            up: 0.0,
+        });
+        assert!((altitude.meters() - 550.0 - 0.0785).abs() < 0.001);
+        assert!((north.longitude() - origin.longitude()).abs() < 1e-7);
+        let distance = origin.vincenty_distance(north).unwrap().meters();
+        assert!((distance - 1000.0).abs() < 0.1);
+
+        let point = unproject(origin, (350.0, -420.0));
+        let enu = frame.to_enu(point, Length::from_meters(530.0));
+        assert!((enu.east - 350.0).abs() < 2.0 && (enu.north + 420.0).abs() < 2.0);
+        let (back, altitude) = frame.from_enu(enu);
+        assert_eq!(back, point);
+        assert!((altitude.meters() - 530.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::message::{Coordinates, RbMessage};
+use crate::units::Length;
+use std::io::Read;
+
+/*
+Geoid model
+
+The receiver reports height above the WGS84 ellipsoid and its own coarse
+estimate of height above mean sea level. A geoid model gives the height of the
+geoid above the ellipsoid, the undulation, on a grid of latitude and longitude,
+and the height above mean sea level is the ellipsoid height less the undulation
+interpolated between the four grid points around the position.
+
+Grids are read in the text format NGA publishes the EGM96 grid in, WW15MGH.GRD
+for the 15 minute grid. It is too big to bundle, so load it from wherever it
+has been downloaded to. The first six numbers are the south, north, west and
+east edges and the latitude and longitude spacing in degrees, followed by the
+heights in metres, rows from north to south and west to east along each row.
+Grids of other models in the same layout, such as EGM2008, load the same way.
+
+The messages keep the receiver's own altitude. RbMessage::corrected_altitude()
+takes an optional geoid, and the CSV and MoTeC exports take one to write the
+corrected height in place of the receiver's. The JSON export keeps the
+receiver's msl_altitude_m and adds the corrected height as geoid_altitude_m.
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Geoid {
+    south: f64,
+    north: f64,
+    west: f64,
+    east: f64,
+    latitude_spacing: f64,
+    longitude_spacing: f64,
+    columns: usize,
+    heights: Vec<f32>, // metres, north row first
+}
+
+impl Geoid {
+    pub fn read_grd<R: Read>(mut reader: R) -> Result<Self, String> {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .map_err(|e| e.to_string())?;
+        let mut numbers = text.split_whitespace().map(|field| {
+            field
+                .parse::<f64>()
+                .map_err(|_| format!("invalid number in geoid grid: {field}"))
+        });
+        let mut header = [0.0; 6];
+        for value in &mut header {
+            *value = numbers
+                .next()
+                .ok_or_else(|| String::from("geoid grid header is incomplete"))??;
+        }
+        let [south, north, west, east, latitude_spacing, longitude_spacing] = header;
+        if !(south < north && west < east && latitude_spacing > 0.0 && longitude_spacing > 0.0) {
+            return Err(String::from("invalid geoid grid header"));
+        }
+        let rows = ((north - south) / latitude_spacing).round() as usize + 1;
+        let columns = ((east - west) / longitude_spacing).round() as usize + 1;
+        // Interpolation needs a grid point either side
+        if rows < 2 || columns < 2 {
+            return Err(String::from(
+                "geoid grid needs at least two rows and columns",
+            ));
+        }
+        let heights = numbers
+            .map(|height| height.map(|h| h as f32))
+            .collect::<Result<Vec<_>, _>>()?;
+        if heights.len() != rows * columns {
+            return Err(format!(
+                "geoid grid has {} heights, expected {}",
+                heights.len(),
+                rows * columns
+            ));
+        }
+        Ok(Geoid {
+            south,
+            north,
+            west,
+            east,
+            latitude_spacing,
+            longitude_spacing,
+            columns,
+            heights,
+        })
+    }
+
+    // Whether the grid goes all the way round, so longitudes wrap
+    fn is_global(&self) -> bool {
+        self.east - self.west >= 360.0 - self.longitude_spacing / 2.0
+    }
+
+    // Height of the geoid above the ellipsoid, None outside the grid
+    pub fn undulation(&self, point: Coordinates) -> Option<Length> {
+        let latitude = point.latitude();
+        if !(self.south..=self.north).contains(&latitude) {
+            return None;
+        }
+        let mut longitude = point.longitude() - self.west;
+        if self.is_global() {
+            longitude = longitude.rem_euclid(360.0);
+        }
+        if !(0.0..=self.east - self.west).contains(&longitude) {
+            return None;
+        }
+
+        let rows = self.heights.len() / self.columns;
+        let row = (self.north - latitude) / self.latitude_spacing;
+        let column = longitude / self.longitude_spacing;
+        // Past the last grid line on a global grid, interpolate back to the first
+        let (r0, c0) = (
+            (row.floor() as usize).min(rows - 2),
+            (column.floor() as usize).min(self.columns - 1),
+        );
+        let c1 = (c0 + 1) % self.columns;
+        let (dr, dc) = (row - r0 as f64, column - c0 as f64);
+        let height = |r: usize, c: usize| f64::from(self.heights[r * self.columns + c]);
+        let top = height(r0, c0) * (1.0 - dc) + height(r0, c1) * dc;
+        let bottom = height(r0 + 1, c0) * (1.0 - dc) + height(r0 + 1, c1) * dc;
+        Some(Length::from_meters(top * (1.0 - dr) + bottom * dr))
+    }
+
+    // Height above mean sea level from the message's ellipsoid height
+    pub fn altitude(&self, message: &RbMessage) -> Option<Length> {
+        let undulation = self.undulation(message.gps_coordinates())?;
+        Some(Length::from_meters(
+            message.wgs_altitude().meters() - undulation.meters(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Geoid;
+    use crate::csv::write_harrys;
+    use crate::json::write_ndjson;
+    use crate::message::{Coordinates, RbMessage};
+    use crate::motec::write_ld;
+    use crate::session::Session;
+    use crate::testing::fix;
+    use crate::units::Length;
+    use chrono::{TimeZone, Utc};
+
+    // A grid over Bulgaria, heights rising 0.5m per degree north and 0.25m per degree east
+    fn grid() -> String {
+        let mut text = String::from("40.0 44.0 20.0 26.0 1.0 1.0\n");
+        for latitude in (40..=44).rev() {
+            let row: Vec<_> = (20..=26)
+                .map(|longitude| {
+                    format!(
+                        "{:.3}",
+                        10.0 + 0.5 * latitude as f64 + 0.25 * longitude as f64
+                    )
+                })
+                .collect();
+            text.push_str(&row.join(" "));
+            text.push('\n');
+        }
+        text
+    }
+
+    fn sample(latitude: f64, longitude: f64, wgs: f64) -> RbMessage {
+        RbMessage::builder()
+            .coordinates(Coordinates::from_degrees(latitude, longitude))
+            .wgs_altitude(Length::from_meters(wgs))
+            .msl_altitude(Length::from_meters(wgs - 40.0))
+            .build()
+    }
+
+    #[test]
+    fn test_undulation() {
+        let geoid = Geoid::read_grd(grid().as_bytes()).unwrap();
+        let undulation = |latitude, longitude| {
+            geoid
+                .undulation(Coordinates::from_degrees(latitude, longitude))
+                .map(|u| u.meters())
+        };
+        // On a grid point and in between
+        assert!((undulation(42.0, 23.0).unwrap() - 36.75).abs() < 1e-4);
+        let expected = 10.0 + 0.5 * 42.6719035 + 0.25 * 23.2887238;
+        assert!((undulation(42.6719035, 23.2887238).unwrap() - expected).abs() < 1e-4);
+        // The edges are inside, beyond them isn't
+        assert!((undulation(44.0, 26.0).unwrap() - 38.5).abs() < 1e-4);
+        assert!((undulation(40.0, 20.0).unwrap() - 35.0).abs() < 1e-4);
+        assert_eq!(undulation(44.1, 23.0), None);
+        assert_eq!(undulation(42.0, 19.9), None);
+        assert_eq!(undulation(42.0, -23.0), None);
+    }
+
+    #[test]
+    fn test_global_grid() {
+        // 90 degree spacing, 0 to 360 with the last column repeating the first
+        let text = "-90 90 0 360 90 90\n\
+                    10 10 10 10 10\n\
+                    0 20 40 20 0\n\
+                    -10 -10 -10 -10 -10\n";
+        let geoid = Geoid::read_grd(text.as_bytes()).unwrap();
+        let undulation = |latitude, longitude| {
+            geoid
+                .undulation(Coordinates::from_degrees(latitude, longitude))
+                .unwrap()
+                .meters()
+        };
+        assert!((undulation(0.0, 45.0) - 10.0).abs() < 1e-6);
+        // West longitudes wrap round to the east of the grid
+        assert!((undulation(0.0, -45.0) - 10.0).abs() < 1e-6);
+        assert!((undulation(0.0, -90.0) - 20.0).abs() < 1e-6);
+        assert!((undulation(45.0, 180.0) - 25.0).abs() < 1e-6);
+        assert!((undulation(-90.0, 10.0) + 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_altitude() {
+        let geoid = Geoid::read_grd(grid().as_bytes()).unwrap();
+        let message = sample(42.0, 23.0, 600.0);
+        assert_eq!(message.altitude(), Length::from_meters(560.0));
+        assert_eq!(geoid.altitude(&message), Some(Length::from_meters(563.25)));
+        assert_eq!(
+            message.corrected_altitude(Some(&geoid)),
+            Length::from_meters(563.25)
+        );
+        assert_eq!(message.corrected_altitude(None), Length::from_meters(560.0));
+        // The message itself is left alone
+        assert_eq!(message.msl_altitude(), Length::from_meters(560.0));
+
+        // Outside the grid the receiver's altitude is used
+        let outside = sample(50.0, 23.0, 600.0);
+        assert_eq!(geoid.altitude(&outside), None);
+        assert_eq!(
+            outside.corrected_altitude(Some(&geoid)),
+            Length::from_meters(560.0)
+        );
+    }
+
+    #[test]
+    fn test_exports() {
+        let geoid = Geoid::read_grd(grid().as_bytes()).unwrap();
+        let mut session = Session::default();
+        session.push(
+            fix(0)
+                .time(Utc.with_ymd_and_hms(2022, 1, 10, 8, 51, 8).unwrap())
+                .wgs_altitude(Length::from_meters(600.0))
+                .msl_altitude(Length::from_meters(560.0))
+                .build(),
+        );
+        let expected = 600.0 - (10.0 + 0.5 * 42.6719035 + 0.25 * 23.2887238);
+
+        let mut csv = Vec::new();
+        write_harrys(&mut csv, &session, Some(&geoid)).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let row: Vec<_> = csv.lines().nth(1).unwrap().split(',').collect();
+        assert!((row[3].parse::<f64>().unwrap() - expected).abs() < 1e-3);
+
+        let mut json = Vec::new();
+        write_ndjson(&mut json, &session, Some(&geoid)).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert!((json["geoid_altitude_m"].as_f64().unwrap() - expected).abs() < 1e-3);
+        // The receiver's own estimate is kept
+        assert_eq!(json["msl_altitude_m"], 560.0);
+        assert_eq!(json["wgs_altitude_m"], 600.0);
+
+        // GPS Altitude is the fifth channel, its data pointer is 8 bytes into its descriptor
+        let mut ld = Vec::new();
+        write_ld(&mut ld, &session, Some(&geoid)).unwrap();
+        let u32_at = |at: usize| u32::from_le_bytes(ld[at..at + 4].try_into().unwrap()) as usize;
+        let data = u32_at(u32_at(8) + 4 * 124 + 8);
+        let altitude = i32::from_le_bytes(ld[data..data + 4].try_into().unwrap());
+        assert!((f64::from(altitude) / 1000.0 - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let error = |text: &str| Geoid::read_grd(text.as_bytes()).unwrap_err();
+        assert_eq!(error("40 44 20"), "geoid grid header is incomplete");
+        assert_eq!(error("44 40 20 26 1 1"), "invalid geoid grid header");
+        assert_eq!(error("40 44 20 26 1 0"), "invalid geoid grid header");
+        assert_eq!(
+            error("40 41 20 21 1 1 1 2 3"),
+            "geoid grid has 3 heights, expected 4"
+        );
+        assert_eq!(
+            error("40 41 20 21 1 1 1 2 x 4"),
+            "invalid number in geoid grid: x"
+        );
+        assert_eq!(
+            error("40 40.4 20 26 1 1 1 2 3 4 5 6 7"),
+            "geoid grid needs at least two rows and columns"
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use crate::message::RbMessage;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+/*
+GPS time helpers
+
+GPS time counts from 1980-01-06 00:00:00 UTC without leap seconds, so it runs
+ahead of UTC by the number of leap seconds inserted since then. The receiver
+reports the position in the week as itow, milliseconds since the start of the
+GPS week (Sunday 00:00 GPS time), which wraps back to zero every week.
+*/
+
+pub const MS_PER_WEEK: u32 = 604_800_000;
+
+// The RaceBox Mini streams at 25hz, one sample every 40ms
+pub const NOMINAL_INTERVAL_MS: u32 = 40;
+
+// GPS epoch as seconds since the unix epoch
+const GPS_EPOCH_UNIX_SECONDS: i64 = 315_964_800;
+
+/*
+Leap seconds as (unix time the offset took effect, GPS - UTC in seconds).
+This needs a new entry whenever the IERS announces a leap second, see
+https://hpiers.obspm.fr/iers/bul/bulc/Leap_Second.dat
+*/
+const LEAP_SECONDS: [(i64, i64); 18] = [
+    (362_793_600, 1),    // 1981-07-01
+    (394_329_600, 2),    // 1982-07-01
+    (425_865_600, 3),    // 1983-07-01
+    (489_024_000, 4),    // 1985-07-01
+    (567_993_600, 5),    // 1988-01-01
+    (631_152_000, 6),    // 1990-01-01
+    (662_688_000, 7),    // 1991-01-01
+    (709_948_800, 8),    // 1992-07-01
+    (741_484_800, 9),    // 1993-07-01
+    (773_020_800, 10),   // 1994-07-01
+    (820_454_400, 11),   // 1996-01-01
+    (867_715_200, 12),   // 1997-07-01
+    (915_148_800, 13),   // 1999-01-01
+    (1_136_073_600, 14), // 2006-01-01
+    (1_230_768_000, 15), // 2009-01-01
+    (1_341_100_800, 16), // 2012-07-01
+    (1_435_708_800, 17), // 2015-07-01
+    (1_483_228_800, 18), // 2017-01-01
+];
+
+// GPS - UTC offset in seconds at the given UTC time
+pub fn leap_seconds(utc: DateTime<Utc>) -> i64 {
+    let t = utc.timestamp();
+    LEAP_SECONDS
+        .iter()
+        .rev()
+        .find(|(since, _)| t >= *since)
+        .map(|(_, offset)| *offset)
+        .unwrap_or(0)
+}
+
+// A point in GPS time, the week number since the GPS epoch and time of week
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GpsTime {
+    pub week: u32,
+    pub itow: u32, // milliseconds
+}
+
+impl GpsTime {
+    pub fn from_utc(utc: DateTime<Utc>) -> Self {
+        let ms = (utc.timestamp() - GPS_EPOCH_UNIX_SECONDS + leap_seconds(utc)) * 1000
+            + i64::from(utc.timestamp_subsec_millis());
+        GpsTime {
+            week: ms.div_euclid(MS_PER_WEEK.into()) as u32,
+            itow: ms.rem_euclid(MS_PER_WEEK.into()) as u32,
+        }
+    }
+
+    pub fn to_utc(&self) -> DateTime<Utc> {
+        let gps = Utc.timestamp_opt(GPS_EPOCH_UNIX_SECONDS, 0).unwrap()
+            + Duration::milliseconds(self.milliseconds());
+        // The table is indexed by UTC, look up again in case the first guess crossed a leap second
+        let offset = leap_seconds(gps - Duration::seconds(leap_seconds(gps)));
+        gps - Duration::seconds(offset)
+    }
+
+    // Milliseconds since the GPS epoch
+    pub fn milliseconds(&self) -> i64 {
+        i64::from(self.week) * i64::from(MS_PER_WEEK) + i64::from(self.itow)
+    }
+}
+
+// GPS week number of a UTC date
+pub fn gps_week(utc: DateTime<Utc>) -> u32 {
+    GpsTime::from_utc(utc).week
+}
+
+/*
+Signed milliseconds from one itow to the next. A week rollover, itow wrapping
+from the end of the week back to zero, is treated as moving forward. Intervals
+are assumed to be shorter than half a week.
+*/
+pub fn itow_delta(from: u32, to: u32) -> i64 {
+    let week = i64::from(MS_PER_WEEK);
+    let delta = (i64::from(to) - i64::from(from)).rem_euclid(week);
+    if delta > week / 2 {
+        delta - week
+    } else {
+        delta
+    }
+}
+
+// How a sample relates to the one received before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleInterval {
+    Nominal,
+    Dropped(u32), // number of missing samples
+    Duplicate,
+    OutOfOrder,
+    Irregular(i64), // milliseconds, not a multiple of the sample rate
+}
+
+// Classifies the gap between two consecutive itow values at the RaceBox sample rate
+pub fn classify_interval(previous: u32, current: u32) -> SampleInterval {
+    let delta = itow_delta(previous, current);
+    let nominal = i64::from(NOMINAL_INTERVAL_MS);
+    // Allow a few milliseconds of jitter in the receiver's timestamps
+    let tolerance = nominal / 4;
+
+    if delta == 0 {
+        return SampleInterval::Duplicate;
+    }
+    if delta < 0 {
+        return SampleInterval::OutOfOrder;
+    }
+
+    let samples = (delta + nominal / 2) / nominal;
+    if samples == 0 || (delta - samples * nominal).abs() > tolerance {
+        SampleInterval::Irregular(delta)
+    } else if samples == 1 {
+        SampleInterval::Nominal
+    } else {
+        SampleInterval::Dropped((samples - 1) as u32)
+    }
+}
+
+// Running count of stream continuity problems
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IntervalStats {
+    pub samples: u64,
+    pub dropped: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub irregular: u64,
+    pub week_rollovers: u64,
+}
+
+// Tracks itow over a message stream to detect dropped and duplicated samples
+#[derive(Debug, Default)]
+pub struct IntervalTracker {
+    last_itow: Option<u32>,
+    stats: IntervalStats,
+}
+
+impl IntervalTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, message: &RbMessage) -> Option<SampleInterval> {
+        self.update_itow(message.itow())
+    }
+
+    pub fn update_itow(&mut self, itow: u32) -> Option<SampleInterval> {
+        self.stats.samples += 1;
+        let previous = self.last_itow.replace(itow)?;
+
+        let interval = classify_interval(previous, itow);
+        match interval {
+            SampleInterval::Nominal => {}
+            SampleInterval::Dropped(n) => self.stats.dropped += u64::from(n),
+            SampleInterval::Duplicate => self.stats.duplicates += 1,
+            SampleInterval::OutOfOrder => self.stats.out_of_order += 1,
+            SampleInterval::Irregular(_) => self.stats.irregular += 1,
+        }
+        if itow < previous && itow_delta(previous, itow) > 0 {
+            self.stats.week_rollovers += 1;
+        }
+        // Keep the newest time so a late packet doesn't count the next one as dropped
+        if itow_delta(previous, itow) < 0 {
+            self.last_itow = Some(previous);
+        }
+        Some(interval)
+    }
+
+    pub fn stats(&self) -> IntervalStats {
+        self.stats
+    }
+}
+
+/*
+Elapsed time over a message stream. Accumulating itow deltas carries on over a
+week rollover, and the nanoseconds field refines itow to below a millisecond.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ElapsedClock {
+    last_itow: Option<u32>,
+    itow_elapsed: i64, // whole milliseconds since the first message
+}
+
+impl ElapsedClock {
+    // Milliseconds since the first message, None for a duplicate or out of order message
+    pub(crate) fn advance(&mut self, message: &RbMessage) -> Option<f64> {
+        let itow = message.itow();
+        if let Some(last) = self.last_itow {
+            let delta = itow_delta(last, itow);
+            if delta <= 0 {
+                return None;
+            }
+            self.itow_elapsed += delta;
+        }
+        self.last_itow = Some(itow);
+        Some(self.itow_elapsed as f64 + sub_millisecond(message))
+    }
+}
+
+// Milliseconds from first_itow to the message, refined to below a millisecond
+pub(crate) fn elapsed_since(first_itow: u32, message: &RbMessage) -> f64 {
+    itow_delta(first_itow, message.itow()) as f64 + sub_millisecond(message)
+}
+
+// itow is rounded to the millisecond, the nanoseconds carry the rest
+fn sub_millisecond(message: &RbMessage) -> f64 {
+    let nanos_ms = f64::from(message.raw_nanoseconds()) / 1_000_000.0;
+    nanos_ms - nanos_ms.round()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        classify_interval, gps_week, itow_delta, leap_seconds, GpsTime, IntervalTracker,
+        SampleInterval, MS_PER_WEEK,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn test_leap_seconds() {
+        assert_eq!(
+            leap_seconds(Utc.with_ymd_and_hms(1980, 1, 6, 0, 0, 0).unwrap()),
+            0
+        );
+        assert_eq!(
+            leap_seconds(Utc.with_ymd_and_hms(1999, 1, 1, 0, 0, 0).unwrap()),
+            13
+        );
+        assert_eq!(
+            leap_seconds(Utc.with_ymd_and_hms(2016, 12, 31, 23, 59, 59).unwrap()),
+            17
+        );
+        assert_eq!(
+            leap_seconds(Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap()),
+            18
+        );
+        assert_eq!(
+            leap_seconds(Utc.with_ymd_and_hms(2022, 1, 10, 8, 51, 8).unwrap()),
+            18
+        );
+    }
+
+    #[test]
+    fn test_gps_time_from_utc() {
+        // The sample packet, Monday 2022-01-10 08:51:08.240 UTC
+        let utc =
+            Utc.with_ymd_and_hms(2022, 1, 10, 8, 51, 8).unwrap() + Duration::milliseconds(240);
+        let gps = GpsTime::from_utc(utc);
+        assert_eq!(gps.itow, 118286240);
+        assert_eq!(gps.week, 2192);
+        assert_eq!(gps_week(utc), 2192);
+        assert_eq!(gps.to_utc(), utc);
+
+        assert_eq!(
+            GpsTime::from_utc(Utc.with_ymd_and_hms(1980, 1, 6, 0, 0, 0).unwrap()),
+            GpsTime { week: 0, itow: 0 }
+        );
+        // Week 1024, the first rollover of the 10 bit week number
+        assert_eq!(
+            gps_week(Utc.with_ymd_and_hms(1999, 8, 22, 0, 0, 0).unwrap()),
+            1024
+        );
+    }
+
+    #[test]
+    fn test_gps_time_to_utc_across_leap_second() {
+        // The last second of 2016 and the first of 2017 either side of the leap second
+        let before = Utc.with_ymd_and_hms(2016, 12, 31, 23, 59, 59).unwrap();
+        let after = Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap();
+        let gps_before = GpsTime::from_utc(before);
+        let gps_after = GpsTime::from_utc(after);
+        // Two GPS seconds pass while UTC repeats 23:59:60
+        assert_eq!(gps_after.milliseconds() - gps_before.milliseconds(), 2000);
+        assert_eq!(gps_before.to_utc(), before);
+        assert_eq!(gps_after.to_utc(), after);
+    }
+
+    #[test]
+    fn test_itow_delta() {
+        assert_eq!(itow_delta(1000, 1040), 40);
+        assert_eq!(itow_delta(1040, 1000), -40);
+        assert_eq!(itow_delta(MS_PER_WEEK - 20, 20), 40);
+        assert_eq!(itow_delta(20, MS_PER_WEEK - 20), -40);
+    }
+
+    #[test]
+    fn test_classify_interval() {
+        assert_eq!(classify_interval(1000, 1040), SampleInterval::Nominal);
+        assert_eq!(classify_interval(1000, 1041), SampleInterval::Nominal);
+        assert_eq!(classify_interval(1000, 1080), SampleInterval::Dropped(1));
+        assert_eq!(classify_interval(1000, 1200), SampleInterval::Dropped(4));
+        assert_eq!(classify_interval(1000, 1000), SampleInterval::Duplicate);
+        assert_eq!(classify_interval(1040, 1000), SampleInterval::OutOfOrder);
+        assert_eq!(classify_interval(1000, 1020), SampleInterval::Irregular(20));
+        assert_eq!(classify_interval(1000, 1060), SampleInterval::Irregular(60));
+        assert_eq!(
+            classify_interval(MS_PER_WEEK - 40, 0),
+            SampleInterval::Nominal
+        );
+    }
+
+    #[test]
+    fn test_interval_tracker() {
+        let mut tracker = IntervalTracker::new();
+        let itows = [MS_PER_WEEK - 80, MS_PER_WEEK - 40, 0, 40, 40, 160, 120, 200];
+        let intervals: Vec<_> = itows.iter().map(|i| tracker.update_itow(*i)).collect();
+        assert_eq!(
+            intervals,
+            vec![
+                None,
+                Some(SampleInterval::Nominal),
+                Some(SampleInterval::Nominal),
+                Some(SampleInterval::Nominal),
+                Some(SampleInterval::Duplicate),
+                Some(SampleInterval::Dropped(2)),
+                Some(SampleInterval::OutOfOrder),
+                Some(SampleInterval::Nominal),
+            ]
+        );
+        let stats = tracker.stats();
+        assert_eq!(stats.samples, 8);
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.week_rollovers, 1);
+    }
+}
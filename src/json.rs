@@ -0,0 +1,323 @@
+use crate::battery::BatteryStatus;
+use crate::geoid::Geoid;
+use crate::message::{Coordinates, Datetime, RbMessage};
+use crate::session::Session;
+use crate::status::{DateTimeFlags, FixFlags, FixStatus, LatLonFlags, Validity};
+use crate::units::{Acceleration, Angle, AngularRate, Dop, Length, Speed};
+use chrono::{Duration, SecondsFormat};
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::{self, BufRead, Write};
+
+/*
+Stable JSON representation of a message
+
+One JSON object per message in physical units, with a version number so readers
+can tell which layout they have. The layout is published as a JSON Schema in
+schema/message-v1.schema.json and doesn't follow the internal struct, so fields
+can be renamed inside the crate without breaking other tooling. Bitmask fields
+are passed through unchanged, their bits are documented in the schema.
+
+NDJSON files hold one message object per line.
+*/
+
+pub const JSON_VERSION: u32 = 1;
+
+// The JSON Schema for version 1 messages
+pub const JSON_SCHEMA: &str = include_str!("../schema/message-v1.schema.json");
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct JsonDateTime {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    nanosecond: i32, // signed, may be negative
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct JsonMessage {
+    version: u32,
+    itow_ms: u32,
+    // Full UTC timestamp when the date and time are valid, ignored when reading
+    #[serde(default)]
+    utc: Option<String>,
+    date_time: JsonDateTime,
+    time_accuracy_ns: u32,
+    validity: u8,
+    fix_status: u8,
+    fix_flags: u8,
+    date_time_flags: u8,
+    lat_lon_flags: u8,
+    satellites: u8,
+    latitude_deg: f64,
+    longitude_deg: f64,
+    wgs_altitude_m: f64,
+    msl_altitude_m: f64,
+    // Height above mean sea level from a geoid model, only written when one is given
+    // and ignored when reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    geoid_altitude_m: Option<f64>,
+    horizontal_accuracy_m: f64,
+    vertical_accuracy_m: f64,
+    speed_mps: f64,
+    speed_accuracy_mps: f64,
+    heading_deg: f64,
+    heading_accuracy_deg: f64,
+    pdop: f64,
+    battery_percent: u8,
+    charging: bool,
+    g_force_g: [f64; 3],    // X, Y and Z axes
+    rot_rate_dps: [f64; 3], // roll, pitch and yaw
+}
+
+impl JsonMessage {
+    fn from_message(message: &RbMessage, geoid: Option<&Geoid>) -> Self {
+        let datetime = message.datetime();
+        let coordinates = message.gps_coordinates();
+        let battery = message.battery_status();
+        let (x, y, z) = message.g_forces();
+        let (roll, pitch, yaw) = message.rot_rates();
+        JsonMessage {
+            version: JSON_VERSION,
+            itow_ms: message.itow(),
+            utc: message
+                .timestamp()
+                .map(|t| t.time.to_rfc3339_opts(SecondsFormat::Nanos, true)),
+            date_time: JsonDateTime {
+                year: datetime.year,
+                month: datetime.month,
+                day: datetime.day,
+                hour: datetime.hour,
+                minute: datetime.minute,
+                second: datetime.second,
+                nanosecond: message.raw_nanoseconds(),
+            },
+            time_accuracy_ns: message.raw_time_accuracy(),
+            validity: message.validity().bits(),
+            fix_status: message.fix_status().into(),
+            fix_flags: message.fix_flags().bits(),
+            date_time_flags: message.date_time_flags().bits(),
+            lat_lon_flags: message.lat_lon_flags().bits(),
+            satellites: message.satelites(),
+            latitude_deg: coordinates.latitude(),
+            longitude_deg: coordinates.longitude(),
+            wgs_altitude_m: message.wgs_altitude().meters(),
+            msl_altitude_m: message.msl_altitude().meters(),
+            geoid_altitude_m: geoid
+                .and_then(|geoid| geoid.altitude(message))
+                .map(|altitude| altitude.meters()),
+            horizontal_accuracy_m: message.horiz_accuracy().meters(),
+            vertical_accuracy_m: message.vert_accuracy().meters(),
+            speed_mps: message.speed().meters_per_second(),
+            speed_accuracy_mps: message.speed_accuracy().meters_per_second(),
+            heading_deg: message.heading().degrees(),
+            heading_accuracy_deg: message.heading_accuracy().degrees(),
+            pdop: message.pdop().value(),
+            battery_percent: battery.percent,
+            charging: battery.charging,
+            g_force_g: [x.g(), y.g(), z.g()],
+            rot_rate_dps: [
+                roll.degrees_per_second(),
+                pitch.degrees_per_second(),
+                yaw.degrees_per_second(),
+            ],
+        }
+    }
+
+    fn to_message(&self) -> Result<RbMessage, String> {
+        if self.version != JSON_VERSION {
+            return Err(format!("unsupported message version {}", self.version));
+        }
+        let dt = self.date_time;
+        let g = self.g_force_g.map(Acceleration::from_g);
+        let rate = self.rot_rate_dps.map(AngularRate::from_degrees_per_second);
+        Ok(RbMessage::builder()
+            .itow(self.itow_ms)
+            .datetime(Datetime {
+                year: dt.year,
+                month: dt.month,
+                day: dt.day,
+                hour: dt.hour,
+                minute: dt.minute,
+                second: dt.second,
+            })
+            .nanoseconds(dt.nanosecond)
+            .time_accuracy(Duration::nanoseconds(self.time_accuracy_ns.into()))
+            .validity(Validity(self.validity))
+            .fix_status(FixStatus::from(self.fix_status))
+            .fix_flags(FixFlags(self.fix_flags))
+            .date_time_flags(DateTimeFlags(self.date_time_flags))
+            .lat_lon_flags(LatLonFlags(self.lat_lon_flags))
+            .satellites(self.satellites)
+            .coordinates(Coordinates::from_degrees(
+                self.latitude_deg,
+                self.longitude_deg,
+            ))
+            .wgs_altitude(Length::from_meters(self.wgs_altitude_m))
+            .msl_altitude(Length::from_meters(self.msl_altitude_m))
+            .horiz_accuracy(Length::from_meters(self.horizontal_accuracy_m))
+            .vert_accuracy(Length::from_meters(self.vertical_accuracy_m))
+            .speed(Speed::from_meters_per_second(self.speed_mps))
+            .speed_accuracy(Speed::from_meters_per_second(self.speed_accuracy_mps))
+            .heading(Angle::from_degrees(self.heading_deg))
+            .heading_accuracy(Angle::from_degrees(self.heading_accuracy_deg))
+            .pdop(Dop::from_value(self.pdop))
+            .battery(BatteryStatus {
+                percent: self.battery_percent,
+                charging: self.charging,
+            })
+            .g_forces(g[0], g[1], g[2])
+            .rot_rates(rate[0], rate[1], rate[2])
+            .build())
+    }
+}
+
+pub fn to_json(message: &RbMessage) -> String {
+    serde_json::to_string(&JsonMessage::from_message(message, None)).unwrap()
+}
+
+pub fn from_json(json: &str) -> Result<RbMessage, String> {
+    let message: JsonMessage = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    message.to_message()
+}
+
+// Reads one message per line, blank lines are skipped
+pub fn read_ndjson<R: BufRead>(reader: R) -> Result<Session, String> {
+    let mut session = Session::default();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = from_json(&line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        session.push(message);
+    }
+    Ok(session)
+}
+
+// Writes one message per line, adding the altitude from the geoid if given
+pub fn write_ndjson<W: Write>(
+    writer: &mut W,
+    session: &Session,
+    geoid: Option<&Geoid>,
+) -> io::Result<()> {
+    for message in session.messages() {
+        let json = serde_json::to_string(&JsonMessage::from_message(message, geoid))?;
+        writeln!(writer, "{}", json)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_json, read_ndjson, to_json, write_ndjson, JSON_SCHEMA};
+    use crate::message::{decode_rb_message, RbMessage};
+    use crate::session::Session;
+    use crate::testing::RAW;
+    use serde_json::Value;
+
+    #[test]
+    fn test_to_json() {
+        let json: Value =
+            serde_json::from_str(&to_json(&decode_rb_message(&RAW).unwrap())).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["itow_ms"], 118286240);
+        assert_eq!(json["utc"], "2022-01-10T08:51:08.239971626Z");
+        assert_eq!(json["date_time"]["year"], 2022);
+        assert_eq!(json["date_time"]["nanosecond"], 239971626);
+        assert_eq!(json["fix_status"], 3);
+        assert_eq!(json["latitude_deg"], 42.6719035);
+        assert_eq!(json["longitude_deg"], 23.2887238);
+        assert_eq!(json["msl_altitude_m"], 590.095);
+        assert_eq!(json["speed_mps"], 0.035);
+        assert_eq!(json["pdop"], 3.0);
+        assert_eq!(json["battery_percent"], 89);
+        assert_eq!(json["charging"], false);
+        assert_eq!(json["g_force_g"][2], 0.974);
+        assert_eq!(json["rot_rate_dps"][0], -2.09);
+        // No internal fields leak into the stable format
+        assert!(json.get("header").is_none());
+        assert!(json.get("checksum").is_none());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let message = decode_rb_message(&RAW).unwrap();
+        let decoded = from_json(&to_json(&message)).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.encode(), RAW);
+
+        let empty = RbMessage::builder().build();
+        assert_eq!(from_json(&to_json(&empty)).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_from_json_errors() {
+        let mut json: Value =
+            serde_json::from_str(&to_json(&decode_rb_message(&RAW).unwrap())).unwrap();
+        json["version"] = 2.into();
+        assert_eq!(
+            from_json(&json.to_string()).unwrap_err(),
+            "unsupported message version 2"
+        );
+        assert!(from_json("{\"version\": 1}").is_err());
+        assert!(from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let mut session = Session::default();
+        session.push(decode_rb_message(&RAW).unwrap());
+        session.push(RbMessage::builder().itow(118286280).speed_mps(12.5).build());
+
+        let mut out = Vec::new();
+        write_ndjson(&mut out, &session, None).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(!text.contains("geoid_altitude_m"));
+
+        let read = read_ndjson(format!("{}\n\n", text).as_bytes()).unwrap();
+        assert_eq!(read.messages(), session.messages());
+
+        let error = read_ndjson("\n{}\n".as_bytes()).unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+    }
+
+    #[test]
+    fn test_schema_matches_output() {
+        let schema: Value = serde_json::from_str(JSON_SCHEMA).unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        let json: Value =
+            serde_json::from_str(&to_json(&decode_rb_message(&RAW).unwrap())).unwrap();
+        let fields = json.as_object().unwrap();
+
+        for field in fields.keys() {
+            assert!(properties.contains_key(field), "{} not in schema", field);
+        }
+        // Only the geoid altitude is left out, without a geoid
+        for field in properties.keys() {
+            assert!(
+                fields.contains_key(field) || field == "geoid_altitude_m",
+                "{} not in output",
+                field
+            );
+        }
+        for required in schema["required"].as_array().unwrap() {
+            assert!(fields.contains_key(required.as_str().unwrap()));
+        }
+        assert!(!schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&"geoid_altitude_m".into()));
+        let date_time = schema["properties"]["date_time"]["properties"]
+            .as_object()
+            .unwrap();
+        assert_eq!(
+            date_time.len(),
+            json["date_time"].as_object().unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,1049 @@
+use crate::geodesy::project;
+use crate::gpstime::ElapsedClock;
+use crate::message::{Coordinates, RbMessage};
+use crate::pits::{PitDetector, PitEvent, PitLane, PitStop};
+use crate::quality::{FixQuality, QualityThresholds};
+use crate::session::Session;
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/*
+Lap timing
+
+A timing line is defined by two points either side of the track. A crossing is
+found when the path between two consecutive samples intersects the line, and
+the crossing time is interpolated along that path, so the result isn't limited
+to the 40ms sample interval. Sample times come from itow, refined to below a
+millisecond with the nanoseconds field, and are accumulated as elapsed time so a
+GPS week rollover mid session doesn't break a lap.
+
+Only crossings in the direction of the first one are counted, and a crossing
+sooner than the minimum lap time after the previous one is ignored, so driving
+back over the line in the pits or wobbling on it doesn't start a new lap.
+Samples that fail the fix quality check are skipped, a crossing is only looked
+for between two usable samples.
+
+Split lines divide a lap into sectors and are timed the same way. They have to
+be crossed in order, a lap that misses one keeps only the sectors before it.
+
+Laps are tagged using the pit lane detection in pits. Without a pit lane polygon
+set with set_pit_lane() it uses the speed heuristic, so a stop of 10 seconds or
+more is a pit stop. A lap that enters the pit lane is an in lap, one that
+starts in the pit lane or leaves it is an out lap, and the rest are flying laps.
+The best lap and best sectors only count flying laps, so a pit stop or a short
+cut through the pit lane never sets a best.
+*/
+
+// Crossings closer together than this are treated as the same crossing
+pub const DEFAULT_MIN_LAP_TIME: Duration = Duration::from_secs(10);
+
+// A timing line across the track
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub a: Coordinates,
+    pub b: Coordinates,
+}
+
+impl Line {
+    pub fn new(a: Coordinates, b: Coordinates) -> Self {
+        Line { a, b }
+    }
+
+    /*
+    Where the path from one point to the next crosses the line, as the fraction
+    of the way along the path and the side of the line it crossed from (the sign
+    of the cross product of the line and the path). None if they don't cross.
+    */
+    pub fn crossing(&self, from: Coordinates, to: Coordinates) -> Option<(f64, Side)> {
+        // Project into metres on a plane centred on the line
+        let origin = self.a;
+        let a = (0.0, 0.0);
+        let b = project(origin, self.b);
+        let p = project(origin, from);
+        let q = project(origin, to);
+
+        let line = (b.0 - a.0, b.1 - a.1);
+        let path = (q.0 - p.0, q.1 - p.1);
+        let denominator = cross(path, line);
+        if denominator == 0.0 {
+            return None; // parallel, or not moving
+        }
+        let offset = (a.0 - p.0, a.1 - p.1);
+        let t = cross(offset, line) / denominator; // along the path
+        let u = cross(offset, path) / denominator; // along the line
+        if !(0.0..=1.0).contains(&t) || !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let side = if denominator > 0.0 {
+            Side::Left
+        } else {
+            Side::Right
+        };
+        Some((t, side))
+    }
+}
+
+// Which side of a line, looking from a to b, a crossing came from
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+fn cross(a: (f64, f64), b: (f64, f64)) -> f64 {
+    a.0 * b.1 - a.1 * b.0
+}
+
+/*
+The lines timing a track, the start/finish line and the split lines dividing a
+lap into sectors. Splits are crossed in the order given, so n splits make n + 1
+sectors with the last one ending at the finish line.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TimingLines {
+    pub finish: Line,
+    pub splits: Vec<Line>,
+}
+
+impl TimingLines {
+    pub fn new(finish: Line) -> Self {
+        TimingLines {
+            finish,
+            splits: Vec::new(),
+        }
+    }
+
+    pub fn with_split(mut self, split: Line) -> Self {
+        self.splits.push(split);
+        self
+    }
+
+    // Inserts a split so it becomes split number index + 1
+    pub fn insert_split(&mut self, index: usize, split: Line) {
+        self.splits.insert(index, split);
+    }
+
+    pub fn remove_split(&mut self, index: usize) -> Line {
+        self.splits.remove(index)
+    }
+
+    pub fn sector_count(&self) -> usize {
+        self.splits.len() + 1
+    }
+}
+
+impl From<Line> for TimingLines {
+    fn from(finish: Line) -> Self {
+        TimingLines::new(finish)
+    }
+}
+
+/*
+A completed lap, start and end are measured from the first message the timer
+saw. A lap has a time for every sector unless a split was missed, in which case
+it only has the sectors before the missed split.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Lap {
+    pub number: u32, // from 1
+    pub start: Duration,
+    pub end: Duration,
+    pub time: Duration,
+    pub sectors: Vec<Duration>,
+    #[serde(default)]
+    pub kind: LapKind,
+    #[serde(default)]
+    pub pit_time: Duration,
+    #[serde(default)]
+    pub stationary_time: Duration,
+}
+
+impl Lap {
+    pub fn has_all_sectors(&self, lines: &TimingLines) -> bool {
+        self.sectors.len() == lines.sector_count()
+    }
+
+    pub fn is_flying(&self) -> bool {
+        self.kind == LapKind::Flying
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LapKind {
+    Out,
+    #[default]
+    Flying,
+    In,
+}
+
+// A sector completed by crossing a split line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sector {
+    pub lap: u32,
+    pub number: usize, // from 1
+    pub time: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LapEvent {
+    // The first crossing of the line, timing has started
+    Started,
+    Sector(Sector),
+    Completed(Lap),
+}
+
+// The last usable sample, its position and elapsed time in milliseconds
+#[derive(Clone, Copy, Debug)]
+struct Point {
+    coordinates: Coordinates,
+    elapsed: f64,
+}
+
+/*
+Times laps from a message stream, either live with update() or offline over a
+recorded session with session_timer()
+*/
+#[derive(Debug)]
+pub struct LapTimer {
+    lines: TimingLines,
+    min_lap_time: Duration,
+    thresholds: QualityThresholds,
+    clock: ElapsedClock,
+    elapsed: f64, // milliseconds since the first message, to below a millisecond
+    previous: Option<Point>,
+    direction: Option<Side>,
+    lap_start: Option<f64>,
+    sector_start: f64,
+    sectors: Vec<Duration>, // completed sectors of the lap in progress
+    best_sectors: Vec<Option<Duration>>,
+    laps: Vec<Lap>,
+    pits: PitDetector,
+    started_in_pit: bool, // the lap in progress started in the pit lane
+    pit_entry: bool,      // the lap in progress entered the pit lane
+    pit_exit: bool,       // the lap in progress left the pit lane
+    lap_stationary: f64,  // stationary milliseconds at the start of the lap in progress
+}
+
+impl LapTimer {
+    pub fn new<L: Into<TimingLines>>(lines: L) -> Self {
+        Self::with_min_lap_time(lines, DEFAULT_MIN_LAP_TIME)
+    }
+
+    pub fn with_min_lap_time<L: Into<TimingLines>>(lines: L, min_lap_time: Duration) -> Self {
+        let lines = lines.into();
+        LapTimer {
+            best_sectors: vec![None; lines.sector_count()],
+            lines,
+            min_lap_time,
+            thresholds: QualityThresholds::default(),
+            clock: ElapsedClock::default(),
+            elapsed: 0.0,
+            previous: None,
+            direction: None,
+            lap_start: None,
+            sector_start: 0.0,
+            sectors: Vec::new(),
+            laps: Vec::new(),
+            pits: PitDetector::new(PitLane::default()),
+            started_in_pit: false,
+            pit_entry: false,
+            pit_exit: false,
+            lap_stationary: 0.0,
+        }
+    }
+
+    pub fn lines(&self) -> &TimingLines {
+        &self.lines
+    }
+
+    pub fn line(&self) -> &Line {
+        &self.lines.finish
+    }
+
+    // Only count finish line crossings from this side, instead of the side of the first crossing
+    pub fn set_direction(&mut self, direction: Side) {
+        self.direction = Some(direction);
+    }
+
+    /*
+    Detect the pits with a pit lane polygon or different limits, before any
+    messages. The default is the speed heuristic.
+    */
+    pub fn set_pit_lane(&mut self, lane: PitLane) {
+        self.pits = PitDetector::new(lane);
+    }
+
+    // The events of a message in order, a split and the finish crossed in one step give both
+    pub fn update(&mut self, message: &RbMessage) -> Vec<LapEvent> {
+        let mut events = Vec::new();
+        if !self.advance(message) {
+            return events;
+        }
+
+        if !FixQuality::assess(message, &self.thresholds).is_usable() {
+            self.previous = None;
+            return events;
+        }
+        let point = Point {
+            coordinates: message.gps_coordinates(),
+            elapsed: self.elapsed,
+        };
+        match self.pits.update(message, self.elapsed) {
+            Some(PitEvent::Entry(at)) => self.enter_pit(at),
+            Some(PitEvent::Exit(_)) => self.pit_exit = self.lap_start.is_some(),
+            None => (),
+        }
+        let Some(previous) = self.previous.replace(point) else {
+            return events;
+        };
+        let time_at = |t: f64| previous.elapsed + t * (point.elapsed - previous.elapsed);
+
+        // The next split is checked first, a finish crossing in the same step comes after it
+        if let Some(split) = self.next_split() {
+            if let Some((t, _)) = split.crossing(previous.coordinates, point.coordinates) {
+                events.extend(self.split(time_at(t)));
+            }
+        }
+
+        let finish = self
+            .lines
+            .finish
+            .crossing(previous.coordinates, point.coordinates);
+        if let Some((t, side)) = finish {
+            if *self.direction.get_or_insert(side) == side {
+                if let Some(PitEvent::Exit(_)) = self.pits.cross(time_at(t)) {
+                    self.pit_exit = self.lap_start.is_some();
+                }
+                events.extend(self.cross(time_at(t)));
+            }
+        }
+        events
+    }
+
+    /*
+    Moves the clock on to the message's time, false for a duplicate or out of
+    order message which is then ignored
+    */
+    fn advance(&mut self, message: &RbMessage) -> bool {
+        match self.clock.advance(message) {
+            Some(elapsed) => {
+                self.elapsed = elapsed;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /*
+    The speed heuristic only sees a pit entry once the car has stopped, and puts
+    it back to when it slowed down, which may be before the line was last crossed
+    */
+    fn enter_pit(&mut self, at: f64) {
+        let Some(start) = self.lap_start else {
+            return;
+        };
+        if at >= start {
+            self.pit_entry = true;
+            return;
+        }
+        self.started_in_pit = true;
+        let entry = millis(at);
+        for lap in self.laps.iter_mut().rev().take_while(|lap| lap.end > entry) {
+            if lap.kind == LapKind::Flying {
+                lap.kind = LapKind::In;
+            }
+            lap.pit_time = self.pits.time_between(lap.start, lap.end);
+        }
+        // A lap that is no longer flying may have set a best sector
+        self.best_sectors = vec![None; self.lines.sector_count()];
+        for lap in self.laps.iter().filter(|lap| lap.is_flying()) {
+            for (best, &time) in self.best_sectors.iter_mut().zip(&lap.sectors) {
+                if best.is_none_or(|best| time < best) {
+                    *best = Some(time);
+                }
+            }
+        }
+    }
+
+    fn is_flying(&self) -> bool {
+        !(self.started_in_pit || self.pit_entry || self.pit_exit)
+    }
+
+    // The split expected next in the lap in progress
+    fn next_split(&self) -> Option<Line> {
+        self.lap_start?;
+        self.lines.splits.get(self.sectors.len()).copied()
+    }
+
+    fn split(&mut self, crossed: f64) -> Option<LapEvent> {
+        let time = self.end_sector(crossed);
+        Some(LapEvent::Sector(Sector {
+            lap: self.laps.len() as u32 + 1,
+            number: self.sectors.len(),
+            time,
+        }))
+    }
+
+    fn end_sector(&mut self, crossed: f64) -> Duration {
+        let time = millis(crossed - self.sector_start);
+        let flying = self.is_flying();
+        let best = &mut self.best_sectors[self.sectors.len()];
+        if flying && best.is_none_or(|best| time < best) {
+            *best = Some(time);
+        }
+        self.sectors.push(time);
+        self.sector_start = crossed;
+        time
+    }
+
+    fn cross(&mut self, crossed: f64) -> Option<LapEvent> {
+        let Some(start) = self.lap_start else {
+            self.start_lap(crossed);
+            return Some(LapEvent::Started);
+        };
+        let time = crossed - start;
+        if time < self.min_lap_time.as_secs_f64() * 1000.0 {
+            return None;
+        }
+        // The last sector only counts if every split was crossed
+        if self.sectors.len() == self.lines.splits.len() {
+            self.end_sector(crossed);
+        }
+        let kind = if self.pit_entry {
+            LapKind::In
+        } else if self.started_in_pit || self.pit_exit {
+            LapKind::Out
+        } else {
+            LapKind::Flying
+        };
+        let lap = Lap {
+            number: self.laps.len() as u32 + 1,
+            start: millis(start),
+            end: millis(crossed),
+            time: millis(time),
+            sectors: std::mem::take(&mut self.sectors),
+            kind,
+            pit_time: self.pits.time_between(millis(start), millis(crossed)),
+            stationary_time: millis(self.pits.stationary() - self.lap_stationary),
+        };
+        self.laps.push(lap.clone());
+        self.start_lap(crossed);
+        Some(LapEvent::Completed(lap))
+    }
+
+    fn start_lap(&mut self, crossed: f64) {
+        self.lap_start = Some(crossed);
+        self.sector_start = crossed;
+        self.sectors.clear();
+        self.started_in_pit = self.pits.in_pit();
+        self.pit_entry = false;
+        self.pit_exit = false;
+        self.lap_stationary = self.pits.stationary();
+    }
+
+    pub fn laps(&self) -> &[Lap] {
+        &self.laps
+    }
+
+    pub fn lap_count(&self) -> usize {
+        self.laps.len()
+    }
+
+    pub fn last_lap(&self) -> Option<&Lap> {
+        self.laps.last()
+    }
+
+    // The fastest flying lap
+    pub fn best_lap(&self) -> Option<&Lap> {
+        self.flying_laps().min_by_key(|lap| lap.time)
+    }
+
+    pub fn flying_laps(&self) -> impl Iterator<Item = &Lap> {
+        self.laps.iter().filter(|lap| lap.is_flying())
+    }
+
+    pub fn pit_stops(&self) -> &[PitStop] {
+        self.pits.stops()
+    }
+
+    pub fn in_pit_lane(&self) -> bool {
+        self.pits.in_pit()
+    }
+
+    // Total time in the pit lane, an open stop runs to the last message
+    pub fn pit_time(&self) -> Duration {
+        self.pits.time_between(Duration::ZERO, millis(self.elapsed))
+    }
+
+    // Total time stopped, in the pits or not
+    pub fn stationary_time(&self) -> Duration {
+        millis(self.pits.stationary())
+    }
+
+    // Number of the lap in progress, None before the line is first crossed
+    pub fn current_lap_number(&self) -> Option<u32> {
+        self.lap_start.map(|_| self.laps.len() as u32 + 1)
+    }
+
+    // Time into the lap in progress, as of the last message
+    pub fn current_lap_time(&self) -> Option<Duration> {
+        self.lap_start.map(|start| millis(self.elapsed - start))
+    }
+
+    // Number of the sector in progress, None before the line is first crossed
+    pub fn current_sector_number(&self) -> Option<usize> {
+        self.lap_start.map(|_| self.sectors.len() + 1)
+    }
+
+    // Time into the sector in progress, as of the last message
+    pub fn current_sector_time(&self) -> Option<Duration> {
+        self.lap_start
+            .map(|_| millis(self.elapsed - self.sector_start))
+    }
+
+    // Completed sectors of the lap in progress
+    pub fn current_sectors(&self) -> &[Duration] {
+        &self.sectors
+    }
+
+    // Fastest time through each sector on flying laps, including the lap in progress
+    pub fn best_sectors(&self) -> &[Option<Duration>] {
+        &self.best_sectors
+    }
+
+    // Sum of the best sectors, None until every sector has a time
+    pub fn theoretical_best(&self) -> Option<Duration> {
+        self.best_sectors.iter().copied().sum()
+    }
+
+    /*
+    Each of the lap's sector times less the best time for that sector, zero for
+    the sector that set the best and positive for slower ones
+    */
+    pub fn sector_deltas(&self, lap: &Lap) -> Vec<chrono::Duration> {
+        lap.sectors
+            .iter()
+            .zip(&self.best_sectors)
+            .filter_map(|(time, best)| {
+                let best = (*best)?;
+                Some(chrono::Duration::nanoseconds(
+                    time.as_nanos() as i64 - best.as_nanos() as i64,
+                ))
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn millis(ms: f64) -> Duration {
+    // The first sample can sit a fraction of a millisecond before zero
+    Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+}
+
+// Runs a timer over a recorded session, the laps and sector statistics are read from it
+pub fn session_timer<L: Into<TimingLines>>(session: &Session, lines: L) -> LapTimer {
+    let mut timer = LapTimer::new(lines);
+    for message in session.messages() {
+        timer.update(message);
+    }
+    timer
+}
+
+// Completed laps in a recorded session
+pub fn session_laps<L: Into<TimingLines>>(session: &Session, lines: L) -> Vec<Lap> {
+    session_timer(session, lines).laps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        session_laps, session_timer, LapEvent, LapKind, LapTimer, Line, Side, TimingLines,
+    };
+    use crate::message::RbMessage;
+    use crate::pits::PitLane;
+    use crate::session::Session;
+    use crate::testing::{at, fast_in_lap, fix};
+    use crate::units::Speed;
+    use std::f64::consts::PI;
+    use std::time::Duration;
+
+    fn message(itow: u32, x: f64, y: f64) -> RbMessage {
+        moving(itow, x, y, 20.0)
+    }
+
+    fn moving(itow: u32, x: f64, y: f64, speed: f64) -> RbMessage {
+        fix(itow).speed_mps(speed).coordinates(at(x, y)).build()
+    }
+
+    // Laps of a 100m radius circle at 20 m/s, the line crosses the circle due east
+    fn circuit(start_itow: u32, seconds: u32) -> Vec<RbMessage> {
+        let (radius, speed) = (100.0, 20.0);
+        (0..seconds * 25)
+            .map(|i| {
+                let t = f64::from(i) * 0.04;
+                // Start a quarter lap before the line, moving anticlockwise
+                let angle = speed * t / radius - PI / 2.0 + 0.1;
+                message(
+                    start_itow.wrapping_add(i * 40) % crate::gpstime::MS_PER_WEEK,
+                    radius * angle.cos(),
+                    radius * angle.sin(),
+                )
+            })
+            .collect()
+    }
+
+    fn line() -> Line {
+        Line::new(at(90.0, 0.0), at(110.0, 0.0))
+    }
+
+    /*
+    Four laps of the circle at 20 m/s, with a 20s stop at stop_at radians round.
+    The car slows to 10 m/s within a quarter lap of the stop, and with a pit lane
+    it is on a 130m radius within 0.4 radians of it.
+    */
+    fn pit_stop_circuit(stop_at: f64, pit_lane: bool) -> Vec<RbMessage> {
+        let mut messages = Vec::new();
+        let (mut angle, mut itow, mut stopped) = (-0.3, 0, 0.0);
+        while angle < 8.0 * PI + 0.2 {
+            let near = (angle - stop_at).abs();
+            let radius = if pit_lane && near < 0.4 { 130.0 } else { 100.0 };
+            let speed = if near < PI / 2.0 { 10.0 } else { 20.0 };
+            let stopping = angle >= stop_at && stopped < 20.0;
+            let (x, y) = (radius * angle.cos(), radius * angle.sin());
+            messages.push(moving(itow, x, y, if stopping { 0.0 } else { speed }));
+            itow += 40;
+            if stopping {
+                stopped += 0.04;
+            } else {
+                angle += speed * 0.04 / 100.0;
+            }
+        }
+        messages
+    }
+
+    fn assert_near(duration: Duration, seconds: f64) {
+        assert!(
+            (duration.as_secs_f64() - seconds).abs() < 0.1,
+            "{:?} {}",
+            duration,
+            seconds
+        );
+    }
+
+    #[test]
+    fn test_crossing() {
+        let line = Line::new(at(5.3, -10.0), at(5.3, 10.0));
+        // Moving east at 10m per sample, crossing 53% of the way along. Coordinates are
+        // stored to 10^-7 degrees, about a centimetre, which limits the precision
+        let (t, side) = line.crossing(at(0.0, 0.0), at(10.0, 0.0)).unwrap();
+        assert!((t - 0.53).abs() < 1e-3, "{}", t);
+        assert_eq!(side, Side::Left);
+        let (_, side) = line.crossing(at(10.0, 0.0), at(0.0, 0.0)).unwrap();
+        assert_eq!(side, Side::Right);
+
+        assert_eq!(line.crossing(at(0.0, 0.0), at(5.0, 0.0)), None);
+        assert_eq!(line.crossing(at(0.0, 11.0), at(10.0, 11.0)), None);
+        assert_eq!(line.crossing(at(0.0, 0.0), at(0.0, 0.0)), None);
+    }
+
+    #[test]
+    fn test_sub_sample_crossing() {
+        let line = Line::new(at(5.3, -10.0), at(5.3, 10.0));
+        let mut timer = LapTimer::with_min_lap_time(line, Duration::from_secs(1));
+        assert!(timer.update(&message(1000, 0.0, 0.0)).is_empty());
+        assert_eq!(timer.update(&message(1040, 10.0, 0.0)), [LapEvent::Started]);
+        assert_eq!(timer.current_lap_number(), Some(1));
+        // Crossed 21.2ms after the first sample, 18.8ms before the second
+        let into_lap = timer.current_lap_time().unwrap().as_secs_f64();
+        assert!((into_lap - 0.0188).abs() < 1e-4, "{}", into_lap);
+    }
+
+    #[test]
+    fn test_lap_timer() {
+        let expected = 2.0 * PI * 100.0 / 20.0; // 31.4s per lap
+        let mut timer = LapTimer::new(line());
+        let mut events = Vec::new();
+        for message in circuit(100_000, 110) {
+            events.extend(timer.update(&message));
+        }
+        assert_eq!(events.first(), Some(&LapEvent::Started));
+        assert_eq!(events.len(), 4);
+        assert_eq!(timer.lap_count(), 3);
+        for (i, lap) in timer.laps().iter().enumerate() {
+            assert_eq!(lap.number, i as u32 + 1);
+            assert!(
+                (lap.time.as_secs_f64() - expected).abs() < 0.005,
+                "{:?}",
+                lap
+            );
+            assert_eq!(lap.end - lap.start, lap.time);
+        }
+        // The first crossing comes a little after a quarter lap
+        let first = timer.laps()[0].start.as_secs_f64();
+        assert!((first - (PI / 2.0 - 0.1) * 5.0).abs() < 0.005, "{}", first);
+
+        assert_eq!(timer.current_lap_number(), Some(4));
+        assert!(timer.best_lap().is_some());
+        assert_eq!(timer.last_lap(), timer.laps().last());
+    }
+
+    #[test]
+    fn test_week_rollover() {
+        // The same circuit starting just before the end of the GPS week
+        let laps = {
+            let mut session = Session::default();
+            for message in circuit(crate::gpstime::MS_PER_WEEK - 20_000, 110) {
+                session.push(message);
+            }
+            session_laps(&session, line())
+        };
+        assert_eq!(laps.len(), 3);
+        assert!((laps[1].time.as_secs_f64() - 10.0 * PI).abs() < 0.005);
+    }
+
+    #[test]
+    fn test_ignored_crossings() {
+        let mut timer = LapTimer::new(Line::new(at(5.0, -10.0), at(5.0, 10.0)));
+        assert!(timer.update(&message(0, 0.0, 0.0)).is_empty());
+        assert_eq!(timer.update(&message(40, 10.0, 0.0)), [LapEvent::Started]);
+        // Backing over the line the other way isn't a crossing
+        assert!(timer.update(&message(80, 0.0, 0.0)).is_empty());
+        // Nor is crossing again before the minimum lap time
+        assert!(timer.update(&message(120, 10.0, 0.0)).is_empty());
+        assert!(timer.update(&message(160, 0.0, 0.0)).is_empty());
+
+        // A sample without a fix breaks the path
+        assert!(timer
+            .update(&RbMessage::builder().itow(20_000).build())
+            .is_empty());
+        assert!(timer.update(&message(20_040, 10.0, 0.0)).is_empty());
+        assert_eq!(timer.lap_count(), 0);
+
+        assert!(timer.update(&message(20_080, 0.0, 0.0)).is_empty());
+        assert!(matches!(
+            timer.update(&message(30_000, 10.0, 0.0))[..],
+            [LapEvent::Completed(_)]
+        ));
+        assert_eq!(timer.lap_count(), 1);
+
+        // Duplicate and out of order messages are ignored
+        assert!(timer.update(&message(30_000, 0.0, 0.0)).is_empty());
+        assert!(timer.update(&message(29_960, 0.0, 0.0)).is_empty());
+        assert_eq!(timer.current_lap_number(), Some(2));
+    }
+
+    /*
+    Laps of the same circle with the speed set per sector, splits due north and
+    west make sectors of a quarter, a quarter and a half lap
+    */
+    fn sector_circuit(speeds: &[[f64; 3]]) -> (TimingLines, Vec<RbMessage>) {
+        let lines = TimingLines::new(line())
+            .with_split(Line::new(at(0.0, 90.0), at(0.0, 110.0)))
+            .with_split(Line::new(at(-110.0, 0.0), at(-90.0, 0.0)));
+        let radius = 100.0;
+        let speed = |quarter: i64| {
+            let lap = quarter.div_euclid(4) as usize;
+            let sector = [0, 1, 2, 2][quarter.rem_euclid(4) as usize];
+            match quarter {
+                q if q < 0 => 20.0, // the out lap
+                _ => speeds.get(lap).map_or(20.0, |s| s[sector]),
+            }
+        };
+        // Start before the line on the out lap, finish past the first split
+        let (mut angle, mut quarter): (f64, i64) = (-0.5, -1);
+        let mut messages = Vec::new();
+        let mut itow = 0;
+        while quarter < 4 * speeds.len() as i64 + 2 {
+            messages.push(message(itow, radius * angle.cos(), radius * angle.sin()));
+            // Step exactly through any change of speed within the sample
+            let mut dt = 0.04;
+            while dt > 0.0 {
+                let next = (quarter + 1) as f64 * PI / 2.0;
+                let to_next = (next - angle) * radius / speed(quarter);
+                if to_next <= dt {
+                    angle = next;
+                    quarter += 1;
+                    dt -= to_next;
+                } else {
+                    angle += speed(quarter) * dt / radius;
+                    dt = 0.0;
+                }
+            }
+            itow += 40;
+        }
+        (lines, messages)
+    }
+
+    /*
+    The speed steps instantly at the split lines, which the interpolation assumes
+    is constant through a sample, so allow a few milliseconds either way
+    */
+    fn assert_secs(duration: Duration, seconds: f64) {
+        let actual = duration.as_secs_f64();
+        assert!((actual - seconds).abs() < 0.01, "{} != {}", actual, seconds);
+    }
+
+    #[test]
+    fn test_sectors() {
+        let quarter = PI * 100.0 / 2.0;
+        let (lines, messages) = sector_circuit(&[[20.0, 20.0, 10.0], [10.0, 10.0, 20.0]]);
+        assert_eq!(lines.sector_count(), 3);
+        let mut timer = LapTimer::new(lines.clone());
+
+        let mut sector_events = Vec::new();
+        for message in &messages {
+            for event in timer.update(message) {
+                match event {
+                    LapEvent::Sector(sector) => sector_events.push(sector),
+                    LapEvent::Completed(lap) => assert!(lap.has_all_sectors(&lines)),
+                    _ => {}
+                }
+            }
+        }
+        // Two splits a lap, two laps and the start of a third
+        assert_eq!(sector_events.len(), 5);
+        assert_eq!((sector_events[0].lap, sector_events[0].number), (1, 1));
+        assert_eq!((sector_events[3].lap, sector_events[3].number), (2, 2));
+        assert_secs(sector_events[3].time, quarter / 10.0);
+
+        let laps = timer.laps();
+        assert_eq!(laps.len(), 2);
+        for (lap, expected) in laps.iter().zip([[7.854, 7.854, 31.416], [15.708; 3]]) {
+            assert_secs(lap.time, expected.iter().sum());
+            for (time, expected) in lap.sectors.iter().zip(expected) {
+                assert_secs(*time, expected);
+            }
+        }
+
+        let best: Vec<_> = timer.best_sectors().iter().map(|b| b.unwrap()).collect();
+        assert_secs(best[0], 7.854);
+        assert_secs(best[1], 7.854);
+        assert_secs(best[2], 15.708);
+        assert_secs(timer.theoretical_best().unwrap(), 31.416);
+
+        let deltas: Vec<Vec<f64>> = laps
+            .iter()
+            .map(|lap| {
+                timer
+                    .sector_deltas(lap)
+                    .iter()
+                    .map(|d| d.num_microseconds().unwrap() as f64 / 1e6)
+                    .collect()
+            })
+            .collect();
+        assert!(deltas[0][0].abs() < 0.01);
+        assert!((deltas[0][2] - 15.708).abs() < 0.01);
+        assert!((deltas[1][0] - 7.854).abs() < 0.01);
+        assert!(deltas[1][2].abs() < 0.01);
+
+        // Third lap in progress, through its first sector
+        assert_eq!(timer.current_lap_number(), Some(3));
+        assert_eq!(timer.current_sector_number(), Some(2));
+        assert_eq!(timer.current_sectors().len(), 1);
+        assert!(timer.current_sector_time().is_some());
+    }
+
+    #[test]
+    fn test_split_and_finish_in_one_step() {
+        let finish = Line::new(at(5.3, -10.0), at(5.3, 10.0));
+        let split = Line::new(at(5.0, -10.0), at(5.0, 10.0));
+        let lines = TimingLines::new(finish).with_split(split);
+        let mut timer = LapTimer::with_min_lap_time(lines, Duration::from_secs(1));
+        assert!(timer.update(&message(0, 0.0, 0.0)).is_empty());
+        assert_eq!(timer.update(&message(40, 10.0, 0.0)), [LapEvent::Started]);
+        // Round the ends of the lines and back to the start
+        for (i, (x, y)) in [(10.0, 50.0), (0.0, 50.0), (0.0, 0.0)]
+            .into_iter()
+            .enumerate()
+        {
+            assert!(timer
+                .update(&message(1000 * (i as u32 + 1), x, y))
+                .is_empty());
+        }
+        let events = timer.update(&message(4000, 10.0, 0.0));
+        assert!(
+            matches!(
+                events[..],
+                [LapEvent::Sector(sector), LapEvent::Completed(_)] if sector.number == 1
+            ),
+            "{:?}",
+            events
+        );
+        assert_eq!(timer.laps()[0].sectors.len(), 2);
+    }
+
+    #[test]
+    fn test_missed_split() {
+        let (lines, messages) = sector_circuit(&[[20.0; 3], [20.0; 3], [20.0; 3]]);
+        let mut session = Session::default();
+        for message in messages {
+            // Lose the fix around the north split on the second lap
+            let north = message.gps_coordinates().latitude() > at(0.0, 95.0).latitude();
+            let second_lap = (35_000..45_000).contains(&message.itow());
+            if north && second_lap {
+                session.push(RbMessage::builder().itow(message.itow()).build());
+            } else {
+                session.push(message);
+            }
+        }
+        let timer = session_timer(&session, lines.clone());
+        let laps = timer.laps();
+        assert_eq!(laps.len(), 3);
+        assert!(laps[0].has_all_sectors(&lines));
+        assert!(laps[1].sectors.is_empty());
+        assert!(laps[2].has_all_sectors(&lines));
+        assert_secs(laps[1].time, 10.0 * PI);
+        assert_eq!(timer.sector_deltas(&laps[1]), vec![]);
+        assert_secs(timer.theoretical_best().unwrap(), 10.0 * PI);
+    }
+
+    #[test]
+    fn test_timing_lines() {
+        let mut lines = TimingLines::from(line());
+        assert_eq!(lines.sector_count(), 1);
+        let split = Line::new(at(0.0, 90.0), at(0.0, 110.0));
+        lines.insert_split(0, split);
+        assert_eq!(lines.sector_count(), 2);
+        assert_eq!(lines.remove_split(0), split);
+
+        // No splits, the single sector is the whole lap
+        let timer = session_timer(&Session::default(), lines);
+        assert_eq!(timer.best_sectors(), &[None]);
+        assert_eq!(timer.theoretical_best(), None);
+    }
+
+    #[test]
+    fn test_pit_stop_by_speed() {
+        // Stopped just after the line, so the entry is only seen after it was crossed.
+        // The speed heuristic is the default
+        let mut timer = LapTimer::new(line());
+        for message in pit_stop_circuit(2.0 * PI + 0.3, false) {
+            timer.update(&message);
+        }
+        let kinds: Vec<_> = timer.laps().iter().map(|lap| lap.kind).collect();
+        assert_eq!(
+            kinds,
+            [LapKind::In, LapKind::Out, LapKind::Flying, LapKind::Flying]
+        );
+
+        // Slow from a quarter lap before the stop to a quarter lap after it
+        let slow = PI / 2.0 * 10.0;
+        assert_eq!(timer.pit_stops().len(), 1);
+        let stop = timer.pit_stops()[0];
+        assert_near(stop.time().unwrap(), 2.0 * slow + 20.0);
+        assert_near(stop.stationary, 20.0);
+        assert!(!timer.in_pit_lane());
+        assert_near(timer.pit_time(), 2.0 * slow + 20.0);
+        assert_near(timer.stationary_time(), 20.0);
+
+        let laps = timer.laps();
+        assert_near(laps[0].pit_time, slow - 3.0);
+        assert_near(laps[1].pit_time, slow + 23.0);
+        assert_near(laps[0].stationary_time, 0.0);
+        assert_near(laps[1].stationary_time, 20.0);
+        assert_eq!(laps[2].pit_time, Duration::ZERO);
+
+        // The in and out laps don't count for the best
+        let best = timer.best_lap().unwrap();
+        assert!(best.is_flying() && best.number >= 3);
+        assert_near(best.time, 10.0 * PI);
+        assert_eq!(timer.flying_laps().count(), 2);
+        assert_near(timer.theoretical_best().unwrap(), 10.0 * PI);
+    }
+
+    #[test]
+    fn test_pit_stop_below_speed_limit() {
+        // Never over the limit, the stop is the entry and the next crossing the exit
+        let mut timer = LapTimer::new(line());
+        timer.set_pit_lane(PitLane {
+            speed_limit: Speed::from_kph(100.0),
+            ..Default::default()
+        });
+        for message in pit_stop_circuit(2.0 * PI + 0.3, false) {
+            timer.update(&message);
+        }
+        let kinds: Vec<_> = timer.laps().iter().map(|lap| lap.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                LapKind::Flying,
+                LapKind::In,
+                LapKind::Flying,
+                LapKind::Flying
+            ]
+        );
+        let (laps, stop) = (timer.laps(), timer.pit_stops()[0]);
+        assert_eq!(timer.pit_stops().len(), 1);
+        assert!(stop.entry > laps[1].start);
+        assert_eq!(stop.exit, Some(laps[1].end));
+        assert_near(stop.stationary, 20.0);
+        assert_eq!(laps[2].pit_time, Duration::ZERO);
+
+        // Turned off, every lap is a flying lap
+        let mut timer = LapTimer::new(line());
+        timer.set_pit_lane(PitLane {
+            speed_heuristic: false,
+            ..Default::default()
+        });
+        for message in pit_stop_circuit(2.0 * PI + 0.3, false) {
+            timer.update(&message);
+        }
+        assert_eq!(timer.flying_laps().count(), 4);
+        assert!(timer.pit_stops().is_empty());
+    }
+
+    #[test]
+    fn test_fast_in_lap() {
+        // The first lap set both best sectors before the stop made it an in lap
+        let split = Line::new(at(-110.0, 0.0), at(-90.0, 0.0));
+        let mut timer = LapTimer::new(TimingLines::new(line()).with_split(split));
+        timer.set_pit_lane(PitLane::by_speed());
+        for message in fast_in_lap() {
+            timer.update(&message);
+        }
+        let kinds: Vec<_> = timer.laps().iter().map(|lap| lap.kind).collect();
+        assert_eq!(
+            kinds,
+            [LapKind::In, LapKind::Out, LapKind::Flying, LapKind::Flying]
+        );
+        assert!(timer.laps()[0].time < timer.laps()[2].time);
+        for best in timer.best_sectors() {
+            assert_near(best.unwrap(), 5.0 * PI);
+        }
+        assert_near(timer.theoretical_best().unwrap(), 10.0 * PI);
+        assert_near(timer.best_lap().unwrap().time, 10.0 * PI);
+    }
+
+    #[test]
+    fn test_pit_stop_in_pit_lane() {
+        // The pit lane runs alongside the line, which reaches across it
+        let mut timer = LapTimer::new(Line::new(at(90.0, 0.0), at(140.0, 0.0)));
+        let polygon = vec![
+            at(110.0, -70.0),
+            at(145.0, -70.0),
+            at(145.0, 70.0),
+            at(110.0, 70.0),
+        ];
+        timer.set_pit_lane(PitLane::new(polygon));
+        let mut in_pit = Vec::new();
+        for message in pit_stop_circuit(2.0 * PI + 0.1, true) {
+            timer.update(&message);
+            in_pit.push(timer.in_pit_lane());
+        }
+        let kinds: Vec<_> = timer.laps().iter().map(|lap| lap.kind).collect();
+        assert_eq!(
+            kinds,
+            [LapKind::In, LapKind::Out, LapKind::Flying, LapKind::Flying]
+        );
+        // One visit to the pit lane
+        assert_eq!(in_pit.windows(2).filter(|w| w[0] != w[1]).count(), 2);
+        let stop = timer.pit_stops()[0];
+        assert_near(stop.stationary, 20.0);
+        assert_near(stop.time().unwrap(), 0.8 * 10.0 + 20.0);
+        assert!(timer.laps()[0].pit_time > Duration::ZERO);
+        assert!(timer.laps()[1].pit_time > Duration::from_secs(20));
+        assert!(timer.best_lap().unwrap().number >= 3);
+    }
+}
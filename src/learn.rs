@@ -0,0 +1,325 @@
+use crate::geodesy::{project, unproject};
+use crate::laps::{Lap, LapTimer, Line, Side, TimingLines};
+use crate::message::{Coordinates, RbMessage};
+use crate::quality::{FixQuality, QualityThresholds};
+use crate::session::Session;
+use crate::tracks::Track;
+use crate::units::Angle;
+
+/*
+Track learning
+
+For circuits that aren't in any database, such as private test tracks and
+autocross layouts, the start/finish line is worked out from the driven path. A
+point on the path is taken as the candidate line position, and once the path has
+gone well away from it and comes back within a few metres heading the same way
+the path has closed a lap. The line is put through the candidate point,
+perpendicular to the heading there, and wide enough to catch a pass offset to
+either side.
+
+Candidates are tried along the path in turn, so the pit exit road or a
+warm up loop that is never driven again doesn't stop a line being found. Only
+samples with a usable fix, a valid heading and enough speed for the heading to
+mean anything are used.
+*/
+
+// The path has to get this far from a point before returning to it closes a lap
+pub const MIN_LOOP_DISTANCE: f64 = 150.0;
+
+// How close a return has to come to the candidate point, in metres
+pub const RETURN_RADIUS: f64 = 15.0;
+
+// How far the heading on return can be from the heading at the candidate point
+pub const HEADING_TOLERANCE: f64 = 30.0;
+
+// Half the width of a learned line, wider than the return radius
+const LINE_HALF_WIDTH: f64 = 20.0;
+
+// Distance between candidate points along the path
+const CANDIDATE_SPACING: f64 = 25.0;
+
+// Margin added around the path for a learned track's geofence
+const GEOFENCE_MARGIN: f64 = 100.0;
+
+/*
+Finds a start/finish line from a path of positions and headings. Returns the
+line with the side it is approached from, or None if the path never closes a
+lap.
+*/
+pub fn learn_line(path: &[(Coordinates, Angle)]) -> Option<(Line, Side)> {
+    let mut candidate = 0;
+    loop {
+        let (origin, heading) = *path.get(candidate)?;
+        let mut left = false;
+        for (point, point_heading) in &path[candidate + 1..] {
+            let (x, y) = project(origin, *point);
+            let distance = x.hypot(y);
+            if distance > MIN_LOOP_DISTANCE {
+                left = true;
+            } else if left
+                && distance < RETURN_RADIUS
+                && heading_difference(heading, *point_heading) < HEADING_TOLERANCE
+            {
+                return Some(line_across(origin, heading));
+            }
+        }
+        // Move on to the first point far enough along from this one
+        candidate = path[candidate + 1..]
+            .iter()
+            .position(|(point, _)| {
+                let (x, y) = project(origin, *point);
+                x.hypot(y) >= CANDIDATE_SPACING
+            })
+            .map(|i| candidate + 1 + i)?;
+    }
+}
+
+// Difference between two headings in degrees, 0 to 180
+fn heading_difference(a: Angle, b: Angle) -> f64 {
+    let difference = (a.degrees() - b.degrees()).rem_euclid(360.0);
+    difference.min(360.0 - difference)
+}
+
+// A line through the point perpendicular to the heading, and the side it is approached from
+fn line_across(origin: Coordinates, heading: Angle) -> (Line, Side) {
+    let (east, north) = (heading.radians().sin(), heading.radians().cos());
+    // a is to the left of the direction of travel, b to the right
+    let a = unproject(origin, (-north * LINE_HALF_WIDTH, east * LINE_HALF_WIDTH));
+    let b = unproject(origin, (north * LINE_HALF_WIDTH, -east * LINE_HALF_WIDTH));
+    let line = Line::new(a, b);
+    let behind = unproject(origin, (-east, -north));
+    let ahead = unproject(origin, (east, north));
+    // A path straight through the middle of the line always crosses it
+    let (_, side) = line.crossing(behind, ahead).unwrap();
+    (line, side)
+}
+
+// A start/finish line learned from a session, and the session's laps timed on it
+#[derive(Clone, Debug, PartialEq)]
+pub struct LearnedTrack {
+    pub line: Line,
+    pub direction: Side,
+    pub geofence: Vec<Coordinates>,
+    pub laps: Vec<Lap>,
+}
+
+impl LearnedTrack {
+    // A track for the database, so the venue is recognised next time
+    pub fn track(&self, name: &str) -> Track {
+        Track {
+            name: name.to_string(),
+            configuration: String::new(),
+            geofence: self.geofence.clone(),
+            lines: TimingLines::new(self.line),
+            pit_lane: Vec::new(),
+            direction: Some(self.direction),
+            source: String::from("Learned from a session"),
+        }
+    }
+
+    pub fn lap_timer(&self) -> LapTimer {
+        let mut timer = LapTimer::new(self.line);
+        timer.set_direction(self.direction);
+        timer
+    }
+}
+
+/*
+Learns the start/finish line from a session's path and splits the whole session
+into laps with it, including the laps driven before the line was found.
+*/
+pub fn learn_session(session: &Session) -> Option<LearnedTrack> {
+    let thresholds = QualityThresholds::default();
+    let path: Vec<_> = session
+        .messages()
+        .iter()
+        .filter(|m| usable(m, &thresholds))
+        .map(|m| (m.gps_coordinates(), m.heading()))
+        .collect();
+    let (line, direction) = learn_line(&path)?;
+
+    let mut learned = LearnedTrack {
+        line,
+        direction,
+        geofence: bounding_box(&path),
+        laps: Vec::new(),
+    };
+    let mut timer = learned.lap_timer();
+    for message in session.messages() {
+        timer.update(message);
+    }
+    learned.laps = timer.laps().to_vec();
+    Some(learned)
+}
+
+fn usable(message: &RbMessage, thresholds: &QualityThresholds) -> bool {
+    FixQuality::assess(message, thresholds).is_usable()
+        && message.is_valid_heading()
+        && message.speed() >= thresholds.min_heading_speed
+}
+
+// The path's bounding box with a margin, as a geofence
+fn bounding_box(path: &[(Coordinates, Angle)]) -> Vec<Coordinates> {
+    let origin = path[0].0;
+    let (mut min, mut max) = ((0.0f64, 0.0f64), (0.0f64, 0.0f64));
+    for (point, _) in path {
+        let (x, y) = project(origin, *point);
+        min = (min.0.min(x), min.1.min(y));
+        max = (max.0.max(x), max.1.max(y));
+    }
+    let (min, max) = (
+        (min.0 - GEOFENCE_MARGIN, min.1 - GEOFENCE_MARGIN),
+        (max.0 + GEOFENCE_MARGIN, max.1 + GEOFENCE_MARGIN),
+    );
+    [
+        (min.0, min.1),
+        (max.0, min.1),
+        (max.0, max.1),
+        (min.0, max.1),
+    ]
+    .into_iter()
+    .map(|corner| unproject(origin, corner))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{learn_line, learn_session, LINE_HALF_WIDTH};
+    use crate::geodesy::project;
+    use crate::laps::session_laps;
+    use crate::message::RbMessage;
+    use crate::session::Session;
+    use crate::testing::{at, fix};
+    use crate::tracks::TrackDatabase;
+    use crate::units::Angle;
+    use std::f64::consts::PI;
+    use std::time::Duration;
+
+    const RADIUS: f64 = 100.0;
+    const SPEED: f64 = 20.0;
+
+    fn message(itow: u32, x: f64, y: f64, heading: f64) -> RbMessage {
+        fix(itow)
+            .coordinates(at(x, y))
+            .speed_mps(SPEED)
+            .heading(Angle::from_degrees(heading))
+            .build()
+    }
+
+    /*
+    A straight pit exit road heading north from 300m south of the circle, then
+    anticlockwise laps of a 100m radius circle centred on the origin.
+    */
+    fn session(laps: f64) -> Session {
+        let mut session = Session::default();
+        let mut itow = 0;
+        let step = SPEED * 0.04;
+        let mut y = -400.0;
+        while y < -RADIUS {
+            session.push(message(itow, 0.0, y, 0.0));
+            (itow, y) = (itow + 40, y + step);
+        }
+        // Join the circle at the bottom, heading east
+        let mut angle = -PI / 2.0;
+        while angle < -PI / 2.0 + laps * 2.0 * PI {
+            let heading = (-angle.to_degrees()).rem_euclid(360.0);
+            session.push(message(
+                itow,
+                RADIUS * angle.cos(),
+                RADIUS * angle.sin(),
+                heading,
+            ));
+            (itow, angle) = (itow + 40, angle + step / RADIUS);
+        }
+        session
+    }
+
+    #[test]
+    fn test_learn_line() {
+        // A loop with the return slightly offset, heading north
+        let path: Vec<_> = (0..400)
+            .map(|i| {
+                let angle = f64::from(i) / 200.0 * 2.0 * PI;
+                let radius = if i < 200 { 100.0 } else { 105.0 };
+                let heading = Angle::from_degrees((-angle.to_degrees()).rem_euclid(360.0));
+                (at(radius * angle.cos(), radius * angle.sin()), heading)
+            })
+            .collect();
+        let (line, side) = learn_line(&path).unwrap();
+        let (ax, ay) = project(at(0.0, 0.0), line.a);
+        let (bx, by) = project(at(0.0, 0.0), line.b);
+        // Across the path at the first point, a to the left of travel
+        assert!((ax - (100.0 - LINE_HALF_WIDTH)).abs() < 0.1 && ay.abs() < 0.1);
+        assert!((bx - (100.0 + LINE_HALF_WIDTH)).abs() < 0.1 && by.abs() < 0.1);
+        // The path crosses it going round again, from the side given
+        assert_eq!(line.crossing(path[399].0, at(105.0, 1.0)).unwrap().1, side);
+
+        // Not a loop
+        let straight: Vec<_> = (0..400)
+            .map(|i| (at(0.0, f64::from(i)), Angle::from_degrees(0.0)))
+            .collect();
+        assert_eq!(learn_line(&straight), None);
+        assert_eq!(learn_line(&[]), None);
+
+        // Coming back the other way isn't a lap
+        let back: Vec<_> = (0..400)
+            .map(|i| (at(f64::from(i), 0.0), Angle::from_degrees(90.0)))
+            .chain(
+                (0..400)
+                    .rev()
+                    .map(|i| (at(f64::from(i), 5.0), Angle::from_degrees(270.0))),
+            )
+            .collect();
+        assert_eq!(learn_line(&back), None);
+    }
+
+    #[test]
+    fn test_learn_session() {
+        let session = session(3.5);
+        let learned = learn_session(&session).unwrap();
+
+        // The pit exit road is never driven again, the line is on the circle
+        let (x, y) = project(at(0.0, 0.0), learned.line.a);
+        let (bx, by) = project(at(0.0, 0.0), learned.line.b);
+        let middle = ((x + bx) / 2.0, (y + by) / 2.0);
+        assert!((middle.0.hypot(middle.1) - RADIUS).abs() < 1.0);
+
+        // Three full laps, the first one from the line's first crossing
+        let lap_time = 2.0 * PI * RADIUS / SPEED;
+        assert_eq!(learned.laps.len(), 3);
+        for lap in &learned.laps {
+            assert!((lap.time.as_secs_f64() - lap_time).abs() < 0.05);
+        }
+
+        let timer = learned.lap_timer();
+        assert_eq!(timer.line(), &learned.line);
+
+        // As a track the session is recognised by its geofence
+        let mut database = TrackDatabase::default();
+        database.insert(learned.track("Test Track"));
+        let mut recorded = session;
+        let track = recorded.detect_track(&database).unwrap().clone();
+        assert_eq!(track.full_name(), "Test Track");
+        assert!(track.contains(at(0.0, -400.0)));
+        assert!(!track.contains(at(0.0, -600.0)));
+        let laps = session_laps(&recorded, track.lines);
+        assert_eq!(laps.len(), 3);
+        assert!(laps[0].time > Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_session_lap_timer() {
+        // No track was detected, the session times its laps on a learned line
+        let session = session(3.5);
+        assert!(session.track.is_none());
+        let timer = session.lap_timer().unwrap();
+        assert_eq!(timer.laps(), learn_session(&session).unwrap().laps);
+        assert!(Session::default().lap_timer().is_none());
+    }
+
+    #[test]
+    fn test_learn_session_too_short() {
+        assert!(learn_session(&session(0.5)).is_none());
+        assert!(learn_session(&Session::default()).is_none());
+    }
+}
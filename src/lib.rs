@@ -1,2 +1,26 @@
+pub mod acceleration;
+pub mod battery;
+pub mod braking;
 pub mod connection;
+pub mod csv;
+pub mod delta;
+pub mod fusion;
+pub mod geodesy;
+pub mod geoid;
+pub mod gpstime;
+pub mod json;
+pub mod laps;
+pub mod learn;
 pub mod message;
+pub mod motec;
+pub mod mounting;
+pub mod odometer;
+pub mod pits;
+pub mod quality;
+pub mod resample;
+pub mod session;
+pub mod status;
+#[cfg(test)]
+mod testing;
+pub mod tracks;
+pub mod units;
@@ -1,19 +1,28 @@
-use bincode::deserialize;
-use chrono::LocalResult;
+use crate::battery::BatteryStatus;
+use crate::geoid::Geoid;
+use crate::gpstime::{itow_delta, GpsTime, MS_PER_WEEK};
+use crate::json;
+use crate::status::{
+    CarrierPhaseSolution, CorrectionAge, DateTimeFlags, FixFlags, FixStatus, LatLonFlags,
+    PowerState, Validity,
+};
+use crate::units::{Acceleration, Angle, AngularRate, Dop, Length, Speed};
+use bincode::serialize;
+use builder::RbMessageBuilder;
+use chrono::DateTime;
+use chrono::Duration;
 use chrono::TimeZone;
 use chrono::Utc;
+use rbmini_core::checksum::checksum;
+use rbmini_core::units::coordinate_degrees;
+use rbmini_core::{Packet, MESSAGE_CLASS, MESSAGE_ID, PACKET_LEN, PAYLOAD_LEN, SYNC};
 use serde::Deserialize;
 use serde::Serialize;
 use std::fmt;
 
-#[allow(dead_code)]
-enum FixStatus {
-    NoFix = 0,
-    Fix2D = 2,
-    Fix3D = 3,
-}
+pub mod builder;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 struct RbHeader {
     start: u16,
     class: u16,
@@ -39,12 +48,19 @@ impl fmt::Display for Coordinates {
 }
 
 impl Coordinates {
+    pub fn from_degrees(latitude: f64, longitude: f64) -> Self {
+        Coordinates {
+            longitude: (longitude * 10000000.0).round() as i32,
+            latitude: (latitude * 10000000.0).round() as i32,
+        }
+    }
+
     pub fn longitude(&self) -> f64 {
-        self.longitude as f64 / 10000000.0
+        coordinate_degrees(self.longitude.into())
     }
 
     pub fn latitude(&self) -> f64 {
-        self.latitude as f64 / 10000000.0
+        coordinate_degrees(self.latitude.into())
     }
 }
 
@@ -58,32 +74,44 @@ pub struct Datetime {
     pub second: u8,
 }
 
+impl Datetime {
+    // The date and time as UTC, None if the fields don't form a valid date and time
+    pub fn to_utc(&self) -> Option<DateTime<Utc>> {
+        Utc.with_ymd_and_hms(
+            self.year.into(),
+            self.month.into(),
+            self.day.into(),
+            self.hour.into(),
+            self.minute.into(),
+            self.second.into(),
+        )
+        .single()
+    }
+}
+
 impl fmt::Display for Datetime {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match Utc.with_ymd_and_hms(
-            self.year.try_into().unwrap(),
-            self.month.try_into().unwrap(),
-            self.day.try_into().unwrap(),
-            self.hour.try_into().unwrap(),
-            self.minute.try_into().unwrap(),
-            self.second.try_into().unwrap(),
-        ) {
-            LocalResult::Single(dt) => {
-                write!(f, "{}", dt)
-            }
-            LocalResult::Ambiguous(_dt, _dt2) => {
-                write!(f, "Ambiguous datetime")
-            }
-            LocalResult::None => {
-                write!(f, "No valid datetime")
-            }
+        match self.to_utc() {
+            Some(dt) => write!(f, "{}", dt),
+            None => write!(
+                f,
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02} (invalid)",
+                self.year, self.month, self.day, self.hour, self.minute, self.second
+            ),
         }
     }
 }
 
+// A UTC timestamp along with the receiver's estimate of its accuracy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub time: DateTime<Utc>,
+    pub accuracy: Duration,
+}
+
 // RaceBox Mini data message sent at 25hz
 // Message class 0xFF, message ID 0x01
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct RbMessage {
     // Todo: factor out the first three fields
     header: RbHeader,
@@ -212,7 +240,7 @@ pub struct RbMessage {
 }
 
 // RaceBox Mini
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 struct RbChecksum {
     value: u16,
 }
@@ -278,113 +306,136 @@ impl RbMessage {
         Self::default()
     }
 
+    pub fn builder() -> RbMessageBuilder {
+        RbMessageBuilder::new()
+    }
+
+    /*
+    Encodes the message as a complete packet. The header and checksum are always
+    written fresh, so decoding the result gives back this message once its own
+    header and checksum are filled in, as they are for built and decoded messages.
+    */
+    pub fn encode(&self) -> [u8; PACKET_LEN] {
+        let mut raw = [0; PACKET_LEN];
+        // The layout is fixed size, serializing into it can't fail
+        raw.copy_from_slice(&serialize(self).unwrap());
+        raw[0..2].copy_from_slice(&SYNC);
+        raw[2] = MESSAGE_CLASS;
+        raw[3] = MESSAGE_ID;
+        raw[4..6].copy_from_slice(&(PAYLOAD_LEN as u16).to_le_bytes());
+        let (ck_a, ck_b) = checksum(&raw);
+        raw[PACKET_LEN - 2] = ck_a;
+        raw[PACKET_LEN - 1] = ck_b;
+        raw
+    }
+
+    // Fills in the header and checksum to match the encoded packet
+    fn seal(&mut self) {
+        let raw = self.encode();
+        self.header = RbHeader {
+            start: u16::from_le_bytes([raw[0], raw[1]]),
+            class: u16::from_le_bytes([raw[2], raw[3]]),
+            length: u16::from_le_bytes([raw[4], raw[5]]),
+        };
+        self.checksum.value = u16::from_le_bytes([raw[PACKET_LEN - 2], raw[PACKET_LEN - 1]]);
+    }
+
     // Validity Flags
+    pub fn validity(&self) -> Validity {
+        Validity(self.validity)
+    }
+
     pub fn is_valid_date(&self) -> bool {
-        if self.validity & 1 == 1 {
-            return true;
-        }
-        false
+        self.validity().valid_date()
     }
 
     pub fn is_valid_time(&self) -> bool {
-        if self.validity >> 1 & 1 == 1 {
-            return true;
-        }
-        false
+        self.validity().valid_time()
     }
 
     pub fn is_fully_resolved(&self) -> bool {
-        if self.validity >> 2 & 1 == 1 {
-            return true;
-        }
-        false
+        self.validity().fully_resolved()
     }
 
     pub fn is_valid_magnetic_declination(&self) -> bool {
-        if self.validity >> 3 & 1 == 1 {
-            return true;
-        }
-        false
+        self.validity().valid_magnetic_declination()
+    }
+
+    pub fn fix_status(&self) -> FixStatus {
+        FixStatus::from(self.fix_status)
     }
 
     // Fix Status Flags
+    pub fn fix_flags(&self) -> FixFlags {
+        FixFlags(self.fix_status_flags)
+    }
+
     pub fn is_valid_fix(&self) -> bool {
-        if self.fix_status_flags & 1 == 1 {
-            return true;
-        }
-        false
+        self.fix_flags().valid_fix()
     }
 
     pub fn is_differential_corrections_applied(&self) -> bool {
-        if self.fix_status_flags >> 1 & 1 == 1 {
-            return true;
-        }
-        false
+        self.fix_flags().differential_corrections_applied()
     }
 
-    pub fn power_state(&self) -> bool {
-        // TODO
-        false
+    pub fn power_state(&self) -> PowerState {
+        self.fix_flags().power_state()
     }
 
     pub fn is_valid_heading(&self) -> bool {
-        if self.fix_status_flags >> 5 & 1 == 1 {
-            return true;
-        }
-        false
+        self.fix_flags().valid_heading()
     }
 
-    pub fn carrier_phase_range_solution(&self) -> bool {
-        // TODO
-        false
+    pub fn carrier_phase_range_solution(&self) -> CarrierPhaseSolution {
+        self.fix_flags().carrier_phase_solution()
     }
 
     // Date/Time Flags
+    pub fn date_time_flags(&self) -> DateTimeFlags {
+        DateTimeFlags(self.date_time_flags)
+    }
+
     pub fn is_confirmation_datetime_validity(&self) -> bool {
-        if self.date_time_flags >> 4 & 1 == 1 {
-            return true;
-        }
-        false
+        self.date_time_flags().confirmation_available()
     }
 
     pub fn is_confirmed_utc_date_validty(&self) -> bool {
-        if self.date_time_flags >> 5 & 1 == 1 {
-            return true;
-        }
-        false
+        self.date_time_flags().confirmed_date()
     }
 
     pub fn is_confirmed_utc_time_validty(&self) -> bool {
-        if self.date_time_flags >> 6 & 1 == 1 {
-            return true;
-        }
-        false
+        self.date_time_flags().confirmed_time()
     }
 
     // Lat/Lon Flags
+    pub fn lat_lon_flags(&self) -> LatLonFlags {
+        LatLonFlags(self.lat_lon_flags)
+    }
+
     pub fn is_valid_position(&self) -> bool {
-        if self.date_time_flags & 1 == 1 {
-            return false;
-        }
-        true
+        !self.lat_lon_flags().invalid_position()
     }
 
-    pub fn differential_correction_age(&self) -> bool {
-        // TODO
-        false
+    pub fn differential_correction_age(&self) -> CorrectionAge {
+        self.lat_lon_flags().correction_age()
     }
 
     pub fn gps_coordinates(&self) -> Coordinates {
         self.coordinates
     }
 
-    // Returns speed in kph
-    pub fn speed(&self) -> f32 {
-        self.speed as f32 * 0.0036
+    // Ground speed
+    pub fn speed(&self) -> Speed {
+        Speed::from_mm_per_second(self.speed.into())
     }
 
+    // The stable JSON representation, see the json module
     pub fn to_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+        json::to_json(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        json::from_json(json)
     }
 
     // Not normally used, aids in testing
@@ -395,37 +446,169 @@ impl RbMessage {
 
     // Getters
 
+    // Milliseconds since the start of the GPS week
+    pub fn itow(&self) -> u32 {
+        self.itow
+    }
+
+    // GPS week and time of week, the week is derived from the UTC timestamp
+    pub fn gps_time(&self) -> Option<GpsTime> {
+        let estimate = GpsTime::from_utc(self.timestamp()?.time);
+        // Near the end of a week the rounded timestamp may fall in the next one
+        let week = match itow_delta(estimate.itow, self.itow) {
+            d if i64::from(estimate.itow) + d >= i64::from(MS_PER_WEEK) => estimate.week + 1,
+            d if i64::from(estimate.itow) + d < 0 => estimate.week.saturating_sub(1),
+            _ => estimate.week,
+        };
+        Some(GpsTime {
+            week,
+            itow: self.itow,
+        })
+    }
+
     pub fn datetime(&self) -> Datetime {
         self.datetime
     }
 
+    /*
+    Full precision UTC time of the message. The signed nanoseconds are applied to
+    the date and time, borrowing from the previous second when negative. None
+    unless the date and time are both valid and confirmed by the receiver.
+    */
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        if !self.is_valid_date()
+            || !self.is_valid_time()
+            || !self.is_confirmation_datetime_validity()
+            || !self.is_confirmed_utc_date_validty()
+            || !self.is_confirmed_utc_time_validty()
+        {
+            return None;
+        }
+        let time = self.datetime.to_utc()? + Duration::nanoseconds(self.nanoseconds.into());
+        Some(Timestamp {
+            time,
+            accuracy: Duration::nanoseconds(self.time_accuracy.into()),
+        })
+    }
+
+    pub fn battery_status(&self) -> BatteryStatus {
+        BatteryStatus::from(self.battery_status)
+    }
+
     pub fn satelites(&self) -> u8 {
         self.number_of_svs
     }
 
-    pub fn altitude(&self) -> i32 {
-        self.msl_altitude
+    /*
+    The packet's scaled integers as they were sent, for exports such as the MoTeC
+    log that store them unscaled. See the fields for their units.
+    */
+    pub(crate) fn raw_coordinates(&self) -> (i32, i32) {
+        (self.coordinates.latitude, self.coordinates.longitude)
+    }
+
+    pub(crate) fn raw_nanoseconds(&self) -> i32 {
+        self.nanoseconds
     }
 
-    pub fn horiz_accuracy(&self) -> u32 {
-        self.horizontal_accuracy
+    pub(crate) fn raw_time_accuracy(&self) -> u32 {
+        self.time_accuracy
     }
 
-    pub fn vert_accuracy(&self) -> u32 {
-        self.vertical_accuracy
+    pub(crate) fn raw_wgs_altitude(&self) -> i32 {
+        self.wgs_altitude
     }
 
-    pub fn heading(&self) -> i32 {
+    pub(crate) fn raw_speed(&self) -> i32 {
+        self.speed
+    }
+
+    pub(crate) fn raw_heading(&self) -> i32 {
         self.heading
     }
 
-    pub fn g_forces(&self) -> (i16, i16, i16) {
+    // Horizontal, vertical, speed and heading accuracy
+    pub(crate) fn raw_accuracies(&self) -> (u32, u32, u32, u32) {
+        (
+            self.horizontal_accuracy,
+            self.vertical_accuracy,
+            self.speed_accuracy,
+            self.heading_accuracy,
+        )
+    }
+
+    pub(crate) fn raw_g_forces(&self) -> (i16, i16, i16) {
         (self.g_force_x, self.g_force_y, self.g_force_z)
     }
 
-    pub fn rot_rates(&self) -> (i16, i16, i16) {
+    pub(crate) fn raw_rot_rates(&self) -> (i16, i16, i16) {
         (self.rot_rate_x, self.rot_rate_y, self.rot_rate_z)
     }
+
+    // Altitude above mean sea level, same as msl_altitude()
+    pub fn altitude(&self) -> Length {
+        self.msl_altitude()
+    }
+
+    // Altitude above mean sea level from the geoid, the receiver's own without one or outside it
+    pub fn corrected_altitude(&self, geoid: Option<&Geoid>) -> Length {
+        geoid
+            .and_then(|geoid| geoid.altitude(self))
+            .unwrap_or_else(|| self.msl_altitude())
+    }
+
+    // Height above the WGS-84 ellipsoid
+    pub fn wgs_altitude(&self) -> Length {
+        Length::from_mm(self.wgs_altitude.into())
+    }
+
+    // The receiver's approximation of height above mean sea level
+    pub fn msl_altitude(&self) -> Length {
+        Length::from_mm(self.msl_altitude.into())
+    }
+
+    pub fn horiz_accuracy(&self) -> Length {
+        Length::from_mm(self.horizontal_accuracy.into())
+    }
+
+    pub fn vert_accuracy(&self) -> Length {
+        Length::from_mm(self.vertical_accuracy.into())
+    }
+
+    pub fn speed_accuracy(&self) -> Speed {
+        Speed::from_mm_per_second(self.speed_accuracy.into())
+    }
+
+    // Direction of motion, zero is North
+    pub fn heading(&self) -> Angle {
+        Angle::from_degrees_e5(self.heading.into())
+    }
+
+    pub fn heading_accuracy(&self) -> Angle {
+        Angle::from_degrees_e5(self.heading_accuracy.into())
+    }
+
+    pub fn pdop(&self) -> Dop {
+        Dop::from_centi(self.pdop.into())
+    }
+
+    // Acceleration on the X (front/back), Y (right/left) and Z (up/down) axes
+    pub fn g_forces(&self) -> (Acceleration, Acceleration, Acceleration) {
+        (
+            Acceleration::from_milli_g(self.g_force_x.into()),
+            Acceleration::from_milli_g(self.g_force_y.into()),
+            Acceleration::from_milli_g(self.g_force_z.into()),
+        )
+    }
+
+    // Rotation rate on the X (roll), Y (pitch) and Z (yaw) axes
+    pub fn rot_rates(&self) -> (AngularRate, AngularRate, AngularRate) {
+        (
+            AngularRate::from_centi_degrees_per_second(self.rot_rate_x.into()),
+            AngularRate::from_centi_degrees_per_second(self.rot_rate_y.into()),
+            AngularRate::from_centi_degrees_per_second(self.rot_rate_z.into()),
+        )
+    }
 }
 
 impl fmt::Display for RbMessage {
@@ -443,7 +626,7 @@ Fix Flags        {fix_flags}
 WGS Altitude     {wgs_alt}
 MSL Altitude     {msl_alt}
 Accuracy        ({horiz_acc},\t{vert_acc})
-Speed            {speed:.1} kph
+Speed            {speed}
 Heading          {heading}
 Speed Accuracy   {speed_acc}
 Heading Accuracy {heading_acc}
@@ -462,61 +645,97 @@ Header/Checksum {header} {checksum}
             num_svs = self.number_of_svs,
             fix_status = self.fix_status,
             fix_flags = self.fix_status_flags,
-            wgs_alt = self.wgs_altitude,
-            msl_alt = self.msl_altitude,
-            horiz_acc = self.horizontal_accuracy,
-            vert_acc = self.vertical_accuracy,
+            wgs_alt = self.wgs_altitude(),
+            msl_alt = self.msl_altitude(),
+            horiz_acc = self.horiz_accuracy(),
+            vert_acc = self.vert_accuracy(),
             speed = self.speed(),
-            heading = self.heading,
-            speed_acc = self.speed_accuracy,
-            heading_acc = self.heading_accuracy,
-            pdop = self.pdop,
+            heading = self.heading(),
+            speed_acc = self.speed_accuracy(),
+            heading_acc = self.heading_accuracy(),
+            pdop = self.pdop(),
             coordinates = self.coordinates,
-            lat_g = self.g_force_x,
-            long_g = self.g_force_y,
-            alt_g = self.g_force_z,
-            rot_x = self.rot_rate_x,
-            rot_y = self.rot_rate_y,
-            rot_z = self.rot_rate_z,
+            lat_g = self.g_forces().0,
+            long_g = self.g_forces().1,
+            alt_g = self.g_forces().2,
+            rot_x = self.rot_rates().0,
+            rot_y = self.rot_rates().1,
+            rot_z = self.rot_rates().2,
             latlong_flags = self.lat_lon_flags,
-            battery_status = self.battery_status,
+            battery_status = self.battery_status(),
             header = self.header,
             checksum = self.checksum,
         )
     }
 }
 
-pub fn decode_rb_message(raw: &[u8]) -> RbMessage {
-    let message: RbMessage = deserialize(raw).unwrap();
-    message
+// The message read field by field through the no_std core's packet view
+impl From<Packet<'_>> for RbMessage {
+    fn from(packet: Packet<'_>) -> Self {
+        let (g_force_x, g_force_y, g_force_z) = packet.g_forces();
+        let (rot_rate_x, rot_rate_y, rot_rate_z) = packet.rot_rates();
+        RbMessage {
+            header: RbHeader {
+                start: u16::from_le_bytes([packet.raw()[0], packet.raw()[1]]),
+                class: u16::from_le_bytes([packet.class(), packet.id()]),
+                length: packet.payload_length(),
+            },
+            itow: packet.itow(),
+            datetime: Datetime {
+                year: packet.year(),
+                month: packet.month(),
+                day: packet.day(),
+                hour: packet.hour(),
+                minute: packet.minute(),
+                second: packet.second(),
+            },
+            validity: packet.validity(),
+            time_accuracy: packet.time_accuracy(),
+            nanoseconds: packet.nanoseconds(),
+            fix_status: packet.fix_status(),
+            fix_status_flags: packet.fix_status_flags(),
+            date_time_flags: packet.date_time_flags(),
+            number_of_svs: packet.number_of_svs(),
+            coordinates: Coordinates {
+                longitude: packet.longitude(),
+                latitude: packet.latitude(),
+            },
+            wgs_altitude: packet.wgs_altitude(),
+            msl_altitude: packet.msl_altitude(),
+            horizontal_accuracy: packet.horizontal_accuracy(),
+            vertical_accuracy: packet.vertical_accuracy(),
+            speed: packet.speed(),
+            heading: packet.heading(),
+            speed_accuracy: packet.speed_accuracy(),
+            heading_accuracy: packet.heading_accuracy(),
+            pdop: packet.pdop(),
+            lat_lon_flags: packet.lat_lon_flags(),
+            battery_status: packet.battery_status(),
+            g_force_x,
+            g_force_y,
+            g_force_z,
+            rot_rate_x,
+            rot_rate_y,
+            rot_rate_z,
+            checksum: RbChecksum {
+                value: packet.checksum(),
+            },
+        }
+    }
 }
 
 /*
-The 2-byte checksum is calculated over the packet’s contents - the message class
-and ID bytes, the payload length bytes, and the payload itself. The formula is:
-    // assuming Packet is a byte array containing the entire
-    // packet with header and 2 spare bytes at the end for
-    // the checksum:
-    byte CK_A = 0, CK_B = 0
-    for (int i = 2; i < len(Packet)-2; i++) {
-        CK_A = CK_A + Packet[i]
-        CK_B = CK_B + CK_A
-    }
-    Packet[len(Packet)-2] = CK_A
-    Packet[len(Packet)-1] = CK_B
+Decodes a data packet, None unless raw is a whole packet with the right header
+and checksum
 */
-pub fn rb_checksum(raw: &[u8]) -> bool {
-    let mut ck_a: u8 = 0;
-    let mut ck_b: u8 = 0;
-
-    for byte in raw.iter().take(raw.len() - 2).skip(2) {
-        (ck_a, _) = ck_a.overflowing_add(*byte);
-        (ck_b, _) = ck_b.overflowing_add(ck_a)
-    }
-
-    ck_a.eq(&raw[raw.len() - 2]) && ck_b.eq(&raw[raw.len() - 1])
+pub fn decode_rb_message(raw: &[u8]) -> Option<RbMessage> {
+    let raw = raw.try_into().ok()?;
+    Packet::parse(raw).map(RbMessage::from)
 }
 
+// The checksum is shared with the no_std core, see rbmini_core::checksum
+pub use rbmini_core::rb_checksum;
+
 /*
 Example packet
 
@@ -529,9 +748,18 @@ E1 0D 3B 37 6F 19 61 8C 09 00 0F 01 09 00 9C 03
 */
 #[cfg(test)]
 mod tests {
+    use crate::battery::BatteryStatus;
+    use crate::gpstime::{GpsTime, MS_PER_WEEK};
     use crate::message;
+    use crate::status::{CarrierPhaseSolution, CorrectionAge, FixStatus, PowerState};
+    use crate::testing::RAW;
+    use chrono::{Duration, TimeZone, Utc};
 
-    use super::RbMessage;
+    use super::{checksum, RbMessage};
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
 
     #[test]
     fn test_rb_new() {
@@ -563,6 +791,34 @@ mod tests {
         assert!(!message::rb_checksum(&raw_bad_checksum));
     }
 
+    #[test]
+    fn test_decode_matches_encode() {
+        // Every payload byte different, so a field encoded at the wrong offset shows up
+        let mut raw = RAW;
+        let mut state: u32 = 12345;
+        for _ in 0..100 {
+            for byte in &mut raw[6..86] {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                *byte = (state >> 16) as u8;
+            }
+            let (ck_a, ck_b) = checksum(&raw);
+            raw[86] = ck_a;
+            raw[87] = ck_b;
+            let message = message::decode_rb_message(&raw).unwrap();
+            assert_eq!(message.encode(), raw);
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        assert_eq!(message::decode_rb_message(&[]), None);
+        assert_eq!(message::decode_rb_message(&RAW[..87]), None);
+        assert_eq!(message::decode_rb_message(&[RAW, RAW].concat()), None);
+        let mut raw = RAW;
+        raw[87] ^= 0xFF;
+        assert_eq!(message::decode_rb_message(&raw), None);
+    }
+
     #[test]
     fn test_decode_rb_message() {
         let raw = [
@@ -574,7 +830,7 @@ mod tests {
             0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
             0xFC, 0xFF, 0x06, 0xDB,
         ];
-        let message = message::decode_rb_message(&raw);
+        let message = message::decode_rb_message(&raw).unwrap();
         assert_eq!(message.header.start, 0x62B5);
         assert_eq!(message.header.class, 0x01FF);
         assert_eq!(message.header.length, 80);
@@ -627,7 +883,7 @@ mod tests {
             0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
             0xFC, 0xFF, 0x06, 0xDB,
         ];
-        let message = message::decode_rb_message(&raw);
+        let message = message::decode_rb_message(&raw).unwrap();
         // TODO confirm the bits in the example packet
         assert!(message.is_valid_date());
         assert!(message.is_valid_time());
@@ -646,11 +902,17 @@ mod tests {
             0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
             0xFC, 0xFF, 0x06, 0xDB,
         ];
-        let message = message::decode_rb_message(&raw);
+        let message = message::decode_rb_message(&raw).unwrap();
         // TODO confirm the bits in the example packet
         assert!(message.is_valid_fix());
         assert!(!message.is_differential_corrections_applied());
         assert!(!message.is_valid_heading());
+        assert_eq!(message.fix_status(), FixStatus::Fix3D);
+        assert_eq!(message.power_state(), PowerState::NotActive);
+        assert_eq!(
+            message.carrier_phase_range_solution(),
+            CarrierPhaseSolution::None
+        );
     }
 
     #[test]
@@ -664,9 +926,9 @@ mod tests {
             0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
             0xFC, 0xFF, 0x06, 0xDB,
         ];
-        let message = message::decode_rb_message(&raw);
+        let message = message::decode_rb_message(&raw).unwrap();
         // TODO confirm the bits in the example packet
-        assert!(!message.is_confirmation_datetime_validity());
+        assert!(message.is_confirmation_datetime_validity());
         assert!(message.is_confirmed_utc_date_validty());
         assert!(message.is_confirmed_utc_time_validty());
     }
@@ -682,9 +944,23 @@ mod tests {
             0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
             0xFC, 0xFF, 0x06, 0xDB,
         ];
-        let message = message::decode_rb_message(&raw);
+        let message = message::decode_rb_message(&raw).unwrap();
         // TODO confirm the bits in the example packet
         assert!(message.is_valid_position());
+        assert_eq!(
+            message.differential_correction_age(),
+            CorrectionAge::NotAvailable
+        );
+
+        // Only the lat/lon flags decide the position is invalid
+        let mut message = message;
+        message.date_time_flags = 0xFF;
+        assert!(message.is_valid_position());
+        message.lat_lon_flags = 0x01;
+        assert!(!message.is_valid_position());
+        message.lat_lon_flags = 0x06;
+        assert!(message.is_valid_position());
+        assert_eq!(message.differential_correction_age(), CorrectionAge::UpTo5s);
     }
 
     #[test]
@@ -698,7 +974,7 @@ mod tests {
             0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
             0xFC, 0xFF, 0x06, 0xDB,
         ];
-        let message = message::decode_rb_message(&raw);
+        let message = message::decode_rb_message(&raw).unwrap();
         // TODO confirm the bits in the example packet
         assert_eq!(
             message.gps_coordinates(),
@@ -722,7 +998,139 @@ mod tests {
             0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03, 0x2F, 0xFF, 0x56, 0x00,
             0xFC, 0xFF, 0x06, 0xDB,
         ];
-        let message = message::decode_rb_message(&raw);
-        assert_eq!(message.speed(), 0.126);
+        let message = message::decode_rb_message(&raw).unwrap();
+        assert_close(message.speed().meters_per_second(), 0.035);
+        assert_close(message.speed().kph(), 0.126);
+        assert_close(message.speed_accuracy().meters_per_second(), 0.208);
+    }
+
+    #[test]
+    fn test_battery_status() {
+        let raw = RAW;
+        let message = message::decode_rb_message(&raw).unwrap();
+        assert_eq!(
+            message.battery_status(),
+            BatteryStatus {
+                percent: 89,
+                charging: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_units() {
+        let raw = RAW;
+        let message = message::decode_rb_message(&raw).unwrap();
+        assert_close(message.wgs_altitude().meters(), 625.761);
+        assert_close(message.msl_altitude().meters(), 590.095);
+        assert_close(message.altitude().meters(), 590.095);
+        assert_close(message.horiz_accuracy().meters(), 0.924);
+        assert_close(message.vert_accuracy().meters(), 1.836);
+        assert_close(message.heading().degrees(), 0.0);
+        assert_close(message.heading_accuracy().degrees(), 145.26856);
+        assert_close(message.pdop().value(), 3.0);
+
+        let (x, y, z) = message.g_forces();
+        assert_close(x.g(), -0.003);
+        assert_close(y.g(), 0.113);
+        assert_close(z.g(), 0.974);
+
+        let (roll, pitch, yaw) = message.rot_rates();
+        assert_close(roll.degrees_per_second(), -2.09);
+        assert_close(pitch.degrees_per_second(), 0.86);
+        assert_close(yaw.degrees_per_second(), -0.04);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let raw = RAW;
+        let mut message = message::decode_rb_message(&raw).unwrap();
+        let timestamp = message.timestamp().unwrap();
+        assert_eq!(
+            timestamp.time,
+            Utc.with_ymd_and_hms(2022, 1, 10, 8, 51, 8).unwrap() + Duration::nanoseconds(239971626)
+        );
+        assert_eq!(timestamp.accuracy, Duration::nanoseconds(25));
+
+        // Negative nanoseconds borrow from the previous second
+        message.nanoseconds = -1500;
+        assert_eq!(
+            message.timestamp().unwrap().time,
+            Utc.with_ymd_and_hms(2022, 1, 10, 8, 51, 7).unwrap() + Duration::nanoseconds(999998500)
+        );
+        message.datetime.second = 0;
+        message.datetime.minute = 0;
+        message.datetime.hour = 0;
+        message.datetime.day = 1;
+        assert_eq!(
+            message.timestamp().unwrap().time,
+            Utc.with_ymd_and_hms(2021, 12, 31, 23, 59, 59).unwrap()
+                + Duration::nanoseconds(999998500)
+        );
+
+        // Every validity and confirmation flag is required
+        for (validity, date_time_flags) in [
+            (0x36, 0xEA),
+            (0x35, 0xEA),
+            (0x37, 0xCA),
+            (0x37, 0xAA),
+            (0x37, 0x6A),
+        ] {
+            message.validity = validity;
+            message.date_time_flags = date_time_flags;
+            assert_eq!(message.timestamp(), None);
+        }
+
+        // Nonsense dates don't panic
+        message.validity = 0x37;
+        message.date_time_flags = 0xEA;
+        message.datetime.month = 13;
+        assert_eq!(message.timestamp(), None);
+    }
+
+    #[test]
+    fn test_datetime_display() {
+        let mut datetime = super::Datetime {
+            year: 2022,
+            month: 1,
+            day: 10,
+            hour: 8,
+            minute: 51,
+            second: 8,
+        };
+        assert_eq!(datetime.to_string(), "2022-01-10 08:51:08 UTC");
+        datetime.month = 0;
+        assert_eq!(datetime.to_utc(), None);
+        assert_eq!(datetime.to_string(), "2022-00-10 08:51:08 (invalid)");
+    }
+
+    #[test]
+    fn test_gps_time() {
+        let raw = RAW;
+        let mut message = message::decode_rb_message(&raw).unwrap();
+        assert_eq!(message.itow(), 118286240);
+        assert_eq!(
+            message.gps_time(),
+            Some(GpsTime {
+                week: 2192,
+                itow: 118286240
+            })
+        );
+
+        // Saturday 23:59:41.999 UTC is the last millisecond of GPS week 2192,
+        // a timestamp rounded up into the next week still gets the right week
+        message.datetime = super::Datetime {
+            year: 2022,
+            month: 1,
+            day: 15,
+            hour: 23,
+            minute: 59,
+            second: 42,
+        };
+        message.nanoseconds = 0;
+        message.itow = MS_PER_WEEK - 1;
+        assert_eq!(message.gps_time().unwrap().week, 2192);
+        message.itow = 0;
+        assert_eq!(message.gps_time().unwrap().week, 2193);
     }
 }
@@ -0,0 +1,320 @@
+use super::{Coordinates, Datetime, RbMessage};
+use crate::battery::BatteryStatus;
+use crate::gpstime::GpsTime;
+use crate::status::{DateTimeFlags, FixFlags, FixStatus, LatLonFlags, Validity};
+use crate::units::{Acceleration, Angle, AngularRate, Dop, Length, Speed};
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+
+/*
+Builds RbMessage values field by field, for simulators, test fixtures and
+re-emitting filtered data. Values are given in physical units and rounded to the
+packet's scaled integers, anything not set is zero. build() fills in the header
+and checksum, so the message encodes to a valid packet and decodes back equal.
+*/
+#[derive(Debug, Default)]
+pub struct RbMessageBuilder {
+    message: RbMessage,
+}
+
+// Starts from an existing message, to change some of its fields and re-emit it
+impl From<RbMessage> for RbMessageBuilder {
+    fn from(message: RbMessage) -> Self {
+        RbMessageBuilder { message }
+    }
+}
+
+impl RbMessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn itow(mut self, itow: u32) -> Self {
+        self.message.itow = itow;
+        self
+    }
+
+    pub fn datetime(mut self, datetime: Datetime) -> Self {
+        self.message.datetime = datetime;
+        self
+    }
+
+    pub fn nanoseconds(mut self, nanoseconds: i32) -> Self {
+        self.message.nanoseconds = nanoseconds;
+        self
+    }
+
+    /*
+    Sets the date, time, nanoseconds and itow from a UTC time, and marks the date
+    and time as valid and confirmed
+    */
+    pub fn time(mut self, time: DateTime<Utc>) -> Self {
+        self.message.datetime = Datetime {
+            year: time.year() as u16,
+            month: time.month() as u8,
+            day: time.day() as u8,
+            hour: time.hour() as u8,
+            minute: time.minute() as u8,
+            second: time.second() as u8,
+        };
+        self.message.nanoseconds = time.nanosecond() as i32;
+        self.message.itow = GpsTime::from_utc(time).itow;
+        self.message.validity |= 0b11; // valid date and time
+        self.message.date_time_flags |= 0b111 << 5; // confirmed date and time
+        self
+    }
+
+    // Clamped to the packet's u32 of nanoseconds, a negative accuracy is taken as zero
+    pub fn time_accuracy(mut self, accuracy: Duration) -> Self {
+        let nanos = accuracy
+            .max(Duration::zero())
+            .num_nanoseconds()
+            .unwrap_or(i64::MAX);
+        self.message.time_accuracy = u32::try_from(nanos).unwrap_or(u32::MAX);
+        self
+    }
+
+    pub fn validity(mut self, validity: Validity) -> Self {
+        self.message.validity = validity.bits();
+        self
+    }
+
+    pub fn fix_status(mut self, fix_status: FixStatus) -> Self {
+        self.message.fix_status = fix_status.into();
+        self
+    }
+
+    pub fn fix_flags(mut self, fix_flags: FixFlags) -> Self {
+        self.message.fix_status_flags = fix_flags.bits();
+        self
+    }
+
+    pub fn date_time_flags(mut self, date_time_flags: DateTimeFlags) -> Self {
+        self.message.date_time_flags = date_time_flags.bits();
+        self
+    }
+
+    pub fn satellites(mut self, satellites: u8) -> Self {
+        self.message.number_of_svs = satellites;
+        self
+    }
+
+    pub fn coordinates(mut self, coordinates: Coordinates) -> Self {
+        self.message.coordinates = coordinates;
+        self
+    }
+
+    // Sets both the WGS and MSL altitude
+    pub fn altitude(self, altitude: Length) -> Self {
+        self.wgs_altitude(altitude).msl_altitude(altitude)
+    }
+
+    pub fn wgs_altitude(mut self, altitude: Length) -> Self {
+        self.message.wgs_altitude = altitude.mm().round() as i32;
+        self
+    }
+
+    pub fn msl_altitude(mut self, altitude: Length) -> Self {
+        self.message.msl_altitude = altitude.mm().round() as i32;
+        self
+    }
+
+    pub fn horiz_accuracy(mut self, accuracy: Length) -> Self {
+        self.message.horizontal_accuracy = accuracy.mm().round() as u32;
+        self
+    }
+
+    pub fn vert_accuracy(mut self, accuracy: Length) -> Self {
+        self.message.vertical_accuracy = accuracy.mm().round() as u32;
+        self
+    }
+
+    pub fn speed(mut self, speed: Speed) -> Self {
+        self.message.speed = (speed.meters_per_second() * 1000.0).round() as i32;
+        self
+    }
+
+    pub fn speed_mps(self, meters_per_second: f64) -> Self {
+        self.speed(Speed::from_meters_per_second(meters_per_second))
+    }
+
+    pub fn speed_accuracy(mut self, accuracy: Speed) -> Self {
+        self.message.speed_accuracy = (accuracy.meters_per_second() * 1000.0).round() as u32;
+        self
+    }
+
+    // Normalised to 0..360 degrees
+    pub fn heading(mut self, heading: Angle) -> Self {
+        self.message.heading = (heading.degrees().rem_euclid(360.0) * 100000.0).round() as i32;
+        self
+    }
+
+    pub fn heading_accuracy(mut self, accuracy: Angle) -> Self {
+        self.message.heading_accuracy = (accuracy.degrees() * 100000.0).round() as u32;
+        self
+    }
+
+    pub fn pdop(mut self, pdop: Dop) -> Self {
+        self.message.pdop = (pdop.value() * 100.0).round() as u16;
+        self
+    }
+
+    pub fn lat_lon_flags(mut self, lat_lon_flags: LatLonFlags) -> Self {
+        self.message.lat_lon_flags = lat_lon_flags.bits();
+        self
+    }
+
+    pub fn battery(mut self, battery: BatteryStatus) -> Self {
+        self.message.battery_status = battery.into();
+        self
+    }
+
+    pub fn g_forces(mut self, x: Acceleration, y: Acceleration, z: Acceleration) -> Self {
+        let milli_g = |g: Acceleration| g.milli_g().round() as i16;
+        self.message.g_force_x = milli_g(x);
+        self.message.g_force_y = milli_g(y);
+        self.message.g_force_z = milli_g(z);
+        self
+    }
+
+    pub fn rot_rates(mut self, x: AngularRate, y: AngularRate, z: AngularRate) -> Self {
+        let centi_deg = |r: AngularRate| (r.degrees_per_second() * 100.0).round() as i16;
+        self.message.rot_rate_x = centi_deg(x);
+        self.message.rot_rate_y = centi_deg(y);
+        self.message.rot_rate_z = centi_deg(z);
+        self
+    }
+
+    pub fn build(self) -> RbMessage {
+        let mut message = self.message;
+        message.seal();
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::battery::BatteryStatus;
+    use crate::message::{decode_rb_message, rb_checksum, Coordinates, RbMessage};
+    use crate::status::{DateTimeFlags, FixFlags, FixStatus, LatLonFlags, Validity};
+    use crate::testing::RAW;
+    use crate::units::{Acceleration, Angle, AngularRate, Dop, Length, Speed};
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn test_encode_decoded_packet() {
+        let message = decode_rb_message(&RAW).unwrap();
+        assert_eq!(message.encode(), RAW);
+    }
+
+    #[test]
+    fn test_build_sample_packet() {
+        // The sample packet rebuilt from physical values
+        let time = Utc.with_ymd_and_hms(2022, 1, 10, 8, 51, 8).unwrap();
+        let message = RbMessage::builder()
+            .time(time)
+            .itow(118286240)
+            .nanoseconds(239971626)
+            .validity(Validity(0x37))
+            .time_accuracy(Duration::nanoseconds(25))
+            .fix_status(FixStatus::Fix3D)
+            .fix_flags(FixFlags(0x01))
+            .date_time_flags(DateTimeFlags(0xEA))
+            .satellites(11)
+            .coordinates(Coordinates::from_degrees(42.6719035, 23.2887238))
+            .wgs_altitude(Length::from_mm(625761))
+            .msl_altitude(Length::from_mm(590095))
+            .horiz_accuracy(Length::from_mm(924))
+            .vert_accuracy(Length::from_mm(1836))
+            .speed(Speed::from_mm_per_second(35))
+            .heading(Angle::from_degrees(0.0))
+            .speed_accuracy(Speed::from_mm_per_second(208))
+            .heading_accuracy(Angle::from_degrees_e5(14526856))
+            .pdop(Dop::from_centi(300))
+            .lat_lon_flags(LatLonFlags(0))
+            .battery(BatteryStatus::from(89))
+            .g_forces(
+                Acceleration::from_milli_g(-3),
+                Acceleration::from_milli_g(113),
+                Acceleration::from_milli_g(974),
+            )
+            .rot_rates(
+                AngularRate::from_centi_degrees_per_second(-209),
+                AngularRate::from_centi_degrees_per_second(86),
+                AngularRate::from_centi_degrees_per_second(-4),
+            )
+            .build();
+        assert_eq!(message.encode(), RAW);
+        assert_eq!(message, decode_rb_message(&RAW).unwrap());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let time =
+            Utc.with_ymd_and_hms(2023, 6, 3, 14, 2, 45).unwrap() + Duration::milliseconds(520);
+        let message = RbMessage::builder()
+            .time(time)
+            .fix_status(FixStatus::Fix3D)
+            .fix_flags(FixFlags(0b10_0001))
+            .satellites(14)
+            .coordinates(Coordinates::from_degrees(-33.8688197, 151.2092955))
+            .altitude(Length::from_meters(58.2))
+            .speed_mps(41.7)
+            .heading(Angle::from_degrees(-90.0))
+            .pdop(Dop::from_value(1.2))
+            .battery(BatteryStatus::from(0x80 | 64))
+            .g_forces(
+                Acceleration::from_g(-1.25),
+                Acceleration::from_g(0.4),
+                Acceleration::from_g(1.0),
+            )
+            .rot_rates(
+                AngularRate::from_degrees_per_second(1.5),
+                AngularRate::from_degrees_per_second(-0.25),
+                AngularRate::from_degrees_per_second(35.0),
+            )
+            .build();
+
+        let raw = message.encode();
+        assert!(rb_checksum(&raw));
+        let decoded = decode_rb_message(&raw).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.encode(), raw);
+
+        assert_eq!(decoded.timestamp().unwrap().time, time);
+        assert_eq!(decoded.speed(), Speed::from_mm_per_second(41700));
+        assert_eq!(decoded.heading(), Angle::from_degrees(270.0));
+        assert_eq!(decoded.wgs_altitude(), decoded.msl_altitude());
+        assert!(decoded.battery_status().charging);
+    }
+
+    #[test]
+    fn test_default_build() {
+        let message = RbMessage::builder().build();
+        let raw = message.encode();
+        assert_eq!(raw[0..6], [0xB5, 0x62, 0xFF, 0x01, 0x50, 0x00]);
+        assert_eq!(decode_rb_message(&raw).unwrap(), message);
+        // A plain new() message still encodes to a valid packet
+        assert!(rb_checksum(&RbMessage::new().encode()));
+    }
+
+    #[test]
+    fn test_time_accuracy_range() {
+        let time = Utc.with_ymd_and_hms(2022, 1, 10, 8, 51, 8).unwrap();
+        let accuracy = |duration| {
+            RbMessage::builder()
+                .time(time)
+                .time_accuracy(duration)
+                .build()
+                .timestamp()
+                .unwrap()
+                .accuracy
+        };
+        assert_eq!(accuracy(Duration::seconds(2)), Duration::seconds(2));
+        // Past what the packet holds, the largest it can
+        let max = Duration::nanoseconds(u32::MAX.into());
+        assert_eq!(accuracy(Duration::seconds(10)), max);
+        assert_eq!(accuracy(Duration::days(365 * 1000)), max);
+        assert_eq!(accuracy(Duration::milliseconds(-1)), Duration::zero());
+        assert_eq!(accuracy(Duration::days(-365 * 1000)), Duration::zero());
+    }
+}
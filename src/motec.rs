@@ -0,0 +1,504 @@
+use crate::geoid::Geoid;
+use crate::message::RbMessage;
+use crate::session::Session;
+use std::io::{self, Write};
+
+/*
+MoTeC i2 log file (.ld) writer
+
+The .ld file is a little endian binary file made up of a fixed size header,
+event, venue and vehicle blocks, a linked list of channel descriptors and
+finally the channel data. Offsets between blocks are absolute file positions.
+
+Each channel stores raw integers together with a conversion, i2 displays
+    (raw / scale * 10^-dec + shift) * mul
+which lets us store the RaceBox values untouched and have i2 scale them.
+*/
+
+// The RaceBox Mini streams at a fixed 25hz
+const RB_SAMPLE_RATE: u16 = 25;
+
+const LD_MARKER: u32 = 0x40;
+const LD_HEADER_SIZE: usize = 0x6E2;
+const LD_EVENT_SIZE: usize = 1154;
+const LD_VENUE_SIZE: usize = 1100;
+const LD_VEHICLE_SIZE: usize = 260;
+const LD_CHANNEL_SIZE: usize = 124;
+
+// Values i2 expects to find in the header, their meaning is not documented
+const LD_DEVICE_SERIAL: u32 = 0x1F44;
+const LD_DEVICE_TYPE: &str = "ADL";
+const LD_DEVICE_VERSION: u16 = 420;
+const LD_PRO_LOGGING: u32 = 0xC81A4;
+const LD_CHANNEL_ID: u16 = 0x2EE1;
+
+// Storage type of the channel samples
+#[derive(Clone, Copy)]
+enum LdType {
+    I16,
+    I32,
+}
+
+impl LdType {
+    // (type class, size in bytes) as stored in the channel descriptor
+    fn descriptor(&self) -> (u16, u16) {
+        match self {
+            LdType::I16 => (0x03, 2),
+            LdType::I32 => (0x05, 4),
+        }
+    }
+}
+
+struct LdChannel {
+    name: &'static str,
+    short_name: &'static str,
+    unit: &'static str,
+    data_type: LdType,
+    mul: i16,
+    dec: i16,
+    sample: fn(&RbMessage, Option<&Geoid>) -> i32,
+}
+
+fn clamp_u32(value: u32) -> i32 {
+    i32::try_from(value).unwrap_or(i32::MAX)
+}
+
+/*
+RaceBox fields mapped to the MoTeC channel names used by most MoTeC loggers, so
+that i2 maths and workbooks written against ECU logs pick them up unchanged.
+Axes follow the RaceBox orientation, X front/back, Y right/left, Z up/down.
+*/
+const LD_CHANNELS: [LdChannel; 17] = [
+    LdChannel {
+        name: "GPS Latitude",
+        short_name: "GPSLat",
+        unit: "deg",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 7,
+        sample: |m, _| m.raw_coordinates().0,
+    },
+    LdChannel {
+        name: "GPS Longitude",
+        short_name: "GPSLong",
+        unit: "deg",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 7,
+        sample: |m, _| m.raw_coordinates().1,
+    },
+    // mm/s to km/h is a factor of 0.0036
+    LdChannel {
+        name: "GPS Speed",
+        short_name: "GPSSpd",
+        unit: "km/h",
+        data_type: LdType::I32,
+        mul: 36,
+        dec: 4,
+        sample: |m, _| m.raw_speed(),
+    },
+    LdChannel {
+        name: "GPS Heading",
+        short_name: "GPSHead",
+        unit: "deg",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 5,
+        sample: |m, _| m.raw_heading(),
+    },
+    LdChannel {
+        name: "GPS Altitude",
+        short_name: "GPSAlt",
+        unit: "m",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 3,
+        sample: |m, geoid| m.corrected_altitude(geoid).mm().round() as i32,
+    },
+    LdChannel {
+        name: "GPS Altitude WGS",
+        short_name: "GPSAltW",
+        unit: "m",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 3,
+        sample: |m, _| m.raw_wgs_altitude(),
+    },
+    LdChannel {
+        name: "G Force Long",
+        short_name: "GLong",
+        unit: "G",
+        data_type: LdType::I16,
+        mul: 1,
+        dec: 3,
+        sample: |m, _| m.raw_g_forces().0.into(),
+    },
+    LdChannel {
+        name: "G Force Lat",
+        short_name: "GLat",
+        unit: "G",
+        data_type: LdType::I16,
+        mul: 1,
+        dec: 3,
+        sample: |m, _| m.raw_g_forces().1.into(),
+    },
+    LdChannel {
+        name: "G Force Vert",
+        short_name: "GVert",
+        unit: "G",
+        data_type: LdType::I16,
+        mul: 1,
+        dec: 3,
+        sample: |m, _| m.raw_g_forces().2.into(),
+    },
+    LdChannel {
+        name: "Roll Rate",
+        short_name: "RollRt",
+        unit: "deg/s",
+        data_type: LdType::I16,
+        mul: 1,
+        dec: 2,
+        sample: |m, _| m.raw_rot_rates().0.into(),
+    },
+    LdChannel {
+        name: "Pitch Rate",
+        short_name: "PitchRt",
+        unit: "deg/s",
+        data_type: LdType::I16,
+        mul: 1,
+        dec: 2,
+        sample: |m, _| m.raw_rot_rates().1.into(),
+    },
+    LdChannel {
+        name: "Yaw Rate",
+        short_name: "YawRt",
+        unit: "deg/s",
+        data_type: LdType::I16,
+        mul: 1,
+        dec: 2,
+        sample: |m, _| m.raw_rot_rates().2.into(),
+    },
+    LdChannel {
+        name: "GPS Sats Used",
+        short_name: "GPSSats",
+        unit: "",
+        data_type: LdType::I16,
+        mul: 1,
+        dec: 0,
+        sample: |m, _| m.satelites().into(),
+    },
+    LdChannel {
+        name: "GPS Pos Accuracy",
+        short_name: "GPSPAcc",
+        unit: "m",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 3,
+        sample: |m, _| clamp_u32(m.raw_accuracies().0),
+    },
+    LdChannel {
+        name: "GPS Alt Accuracy",
+        short_name: "GPSAAcc",
+        unit: "m",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 3,
+        sample: |m, _| clamp_u32(m.raw_accuracies().1),
+    },
+    LdChannel {
+        name: "GPS Speed Accuracy",
+        short_name: "GPSSAcc",
+        unit: "km/h",
+        data_type: LdType::I32,
+        mul: 36,
+        dec: 4,
+        sample: |m, _| clamp_u32(m.raw_accuracies().2),
+    },
+    LdChannel {
+        name: "GPS Heading Accuracy",
+        short_name: "GPSHAcc",
+        unit: "deg",
+        data_type: LdType::I32,
+        mul: 1,
+        dec: 5,
+        sample: |m, _| clamp_u32(m.raw_accuracies().3),
+    },
+];
+
+// Little endian writes into a buffer, strings are NUL padded to a fixed width
+struct LdBuffer {
+    bytes: Vec<u8>,
+}
+
+impl LdBuffer {
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i16(&mut self, value: i16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn pad(&mut self, len: usize) {
+        self.bytes.resize(self.bytes.len() + len, 0);
+    }
+
+    fn str(&mut self, value: &str, len: usize) {
+        let raw = value.as_bytes();
+        let n = raw.len().min(len);
+        self.bytes.extend_from_slice(&raw[..n]);
+        self.pad(len - n);
+    }
+}
+
+fn ptr(offset: usize) -> u32 {
+    offset as u32
+}
+
+// Writes the session as a MoTeC i2 .ld file, with altitudes from the geoid if given
+pub fn write_ld<W: Write>(
+    writer: &mut W,
+    session: &Session,
+    geoid: Option<&Geoid>,
+) -> io::Result<()> {
+    let metadata = &session.metadata;
+    let messages = session.messages();
+
+    let event_ptr = LD_HEADER_SIZE;
+    let venue_ptr = event_ptr + LD_EVENT_SIZE;
+    let vehicle_ptr = venue_ptr + LD_VENUE_SIZE;
+    let meta_ptr = vehicle_ptr + LD_VEHICLE_SIZE;
+    let data_ptr = meta_ptr + LD_CHANNELS.len() * LD_CHANNEL_SIZE;
+
+    // The session start is taken from the first sample
+    let (date, time) = match messages.first() {
+        Some(m) => {
+            let d = m.datetime();
+            (
+                format!("{:02}/{:02}/{:04}", d.day, d.month, d.year),
+                format!("{:02}:{:02}:{:02}", d.hour, d.minute, d.second),
+            )
+        }
+        None => (String::new(), String::new()),
+    };
+
+    let mut buf = LdBuffer { bytes: Vec::new() };
+
+    // Header
+    buf.u32(LD_MARKER);
+    buf.pad(4);
+    buf.u32(ptr(meta_ptr));
+    buf.u32(ptr(data_ptr));
+    buf.pad(20);
+    buf.u32(ptr(event_ptr));
+    buf.pad(24);
+    buf.u16(1);
+    buf.u16(0x4240);
+    buf.u16(0xF);
+    buf.u32(metadata.serial.parse().unwrap_or(LD_DEVICE_SERIAL));
+    buf.str(LD_DEVICE_TYPE, 8);
+    buf.u16(LD_DEVICE_VERSION);
+    buf.u16(0xADB0);
+    buf.u32(LD_CHANNELS.len() as u32);
+    buf.pad(4);
+    buf.str(&date, 16);
+    buf.pad(16);
+    buf.str(&time, 16);
+    buf.pad(16);
+    buf.str(&metadata.driver, 64);
+    buf.str(&metadata.vehicle, 64);
+    buf.pad(64);
+    buf.str(&metadata.venue, 64);
+    buf.pad(64);
+    buf.pad(1024);
+    buf.u32(LD_PRO_LOGGING);
+    buf.pad(66);
+    buf.str(&metadata.comment, 64);
+    buf.pad(126);
+
+    // Event
+    buf.str(&metadata.event, 64);
+    buf.str(&metadata.session, 64);
+    buf.str(&metadata.comment, 1024);
+    buf.u16(venue_ptr as u16);
+
+    // Venue
+    buf.str(&metadata.venue, 64);
+    buf.pad(1034);
+    buf.u16(vehicle_ptr as u16);
+
+    // Vehicle
+    buf.str(&metadata.vehicle, 64);
+    buf.pad(128);
+    buf.u32(0); // weight
+    buf.str("", 32); // type
+    buf.str("", 32); // comment
+
+    // Channel descriptors, a doubly linked list
+    let mut channel_data_ptr = data_ptr;
+    for (i, channel) in LD_CHANNELS.iter().enumerate() {
+        let this_ptr = meta_ptr + i * LD_CHANNEL_SIZE;
+        let prev_ptr = if i == 0 {
+            0
+        } else {
+            this_ptr - LD_CHANNEL_SIZE
+        };
+        let next_ptr = if i + 1 == LD_CHANNELS.len() {
+            0
+        } else {
+            this_ptr + LD_CHANNEL_SIZE
+        };
+        let (type_class, type_size) = channel.data_type.descriptor();
+
+        buf.u32(ptr(prev_ptr));
+        buf.u32(ptr(next_ptr));
+        buf.u32(ptr(channel_data_ptr));
+        buf.u32(messages.len() as u32);
+        buf.u16(LD_CHANNEL_ID + i as u16);
+        buf.u16(type_class);
+        buf.u16(type_size);
+        buf.u16(RB_SAMPLE_RATE);
+        buf.i16(0); // shift
+        buf.i16(channel.mul);
+        buf.i16(1); // scale
+        buf.i16(channel.dec);
+        buf.str(channel.name, 32);
+        buf.str(channel.short_name, 8);
+        buf.str(channel.unit, 12);
+        buf.pad(40);
+
+        channel_data_ptr += messages.len() * type_size as usize;
+    }
+
+    // Channel data
+    for channel in LD_CHANNELS.iter() {
+        for message in messages {
+            let value = (channel.sample)(message, geoid);
+            match channel.data_type {
+                LdType::I16 => buf.i16(value as i16),
+                LdType::I32 => buf.i32(value),
+            }
+        }
+    }
+
+    writer.write_all(&buf.bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_ld, LD_CHANNELS, LD_CHANNEL_SIZE, LD_HEADER_SIZE};
+    use crate::message::decode_rb_message;
+    use crate::session::{Session, SessionMetadata};
+    use crate::testing::RAW;
+
+    fn u16_at(b: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([b[at], b[at + 1]])
+    }
+
+    fn i16_at(b: &[u8], at: usize) -> i16 {
+        i16::from_le_bytes([b[at], b[at + 1]])
+    }
+
+    fn u32_at(b: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+    }
+
+    fn str_at(b: &[u8], at: usize, len: usize) -> String {
+        let s = &b[at..at + len];
+        let end = s.iter().position(|c| *c == 0).unwrap_or(len);
+        String::from_utf8(s[..end].to_vec()).unwrap()
+    }
+
+    fn ld_session() -> Vec<u8> {
+        let mut session = Session::new(SessionMetadata {
+            event: String::from("Club Day"),
+            session: String::from("Practice 1"),
+            venue: String::from("Sofia Ring"),
+            vehicle: String::from("Car 42"),
+            driver: String::from("Driver"),
+            comment: String::from("Test"),
+            serial: String::from("1221405314"),
+            ..Default::default()
+        });
+        session.push(decode_rb_message(&RAW).unwrap());
+        let mut second = decode_rb_message(&RAW).unwrap();
+        second.update_coordinates(232887300, 426719100);
+        session.push(second);
+
+        let mut out = Vec::new();
+        write_ld(&mut out, &session, None).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_ld_header() {
+        let ld = ld_session();
+        assert_eq!(u32_at(&ld, 0), 0x40);
+        assert_eq!(u32_at(&ld, 36) as usize, LD_HEADER_SIZE);
+        assert_eq!(u32_at(&ld, 70), 1221405314);
+        assert_eq!(u32_at(&ld, 86) as usize, LD_CHANNELS.len());
+        assert_eq!(str_at(&ld, 94, 16), "10/01/2022");
+        assert_eq!(str_at(&ld, 126, 16), "08:51:08");
+        assert_eq!(str_at(&ld, 158, 64), "Driver");
+        assert_eq!(str_at(&ld, 222, 64), "Car 42");
+        assert_eq!(str_at(&ld, 350, 64), "Sofia Ring");
+
+        // Event, venue, vehicle chain
+        let event = u32_at(&ld, 36) as usize;
+        assert_eq!(str_at(&ld, event, 64), "Club Day");
+        assert_eq!(str_at(&ld, event + 64, 64), "Practice 1");
+        let venue = u16_at(&ld, event + 1152) as usize;
+        assert_eq!(str_at(&ld, venue, 64), "Sofia Ring");
+        let vehicle = u16_at(&ld, venue + 1098) as usize;
+        assert_eq!(str_at(&ld, vehicle, 64), "Car 42");
+    }
+
+    #[test]
+    fn test_ld_channels() {
+        let ld = ld_session();
+        let meta = u32_at(&ld, 8) as usize;
+        let data = u32_at(&ld, 12) as usize;
+        assert_eq!(data, meta + LD_CHANNELS.len() * LD_CHANNEL_SIZE);
+
+        // Walk the linked list and check every channel is reachable
+        let mut ptr = meta;
+        let mut names = Vec::new();
+        while ptr != 0 {
+            assert_eq!(u32_at(&ld, ptr + 12), 2);
+            assert_eq!(u16_at(&ld, ptr + 22), 25);
+            names.push(str_at(&ld, ptr + 32, 32));
+            ptr = u32_at(&ld, ptr + 4) as usize;
+        }
+        assert_eq!(names.len(), LD_CHANNELS.len());
+        assert_eq!(names[0], "GPS Latitude");
+        assert_eq!(names[2], "GPS Speed");
+
+        // Latitude, 32 bit samples scaled by 10^-7
+        let lat_data = u32_at(&ld, meta + 8) as usize;
+        assert_eq!(u32_at(&ld, lat_data), 426719035);
+        assert_eq!(u32_at(&ld, lat_data + 4), 426719100);
+        assert_eq!(i16_at(&ld, meta + 30), 7);
+
+        // G Force Vert, 16 bit milli-g
+        let vert = meta + 8 * LD_CHANNEL_SIZE;
+        assert_eq!(str_at(&ld, vert + 32, 32), "G Force Vert");
+        assert_eq!(u16_at(&ld, vert + 20), 2);
+        let vert_data = u32_at(&ld, vert + 8) as usize;
+        assert_eq!(i16_at(&ld, vert_data), 974);
+        assert_eq!(i16_at(&ld, vert + 30), 3);
+        assert_eq!(str_at(&ld, vert + 72, 12), "G");
+
+        // Last channel ends the file
+        let last = meta + (LD_CHANNELS.len() - 1) * LD_CHANNEL_SIZE;
+        let last_data = u32_at(&ld, last + 8) as usize;
+        assert_eq!(last_data + 2 * 4, ld.len());
+    }
+}
@@ -0,0 +1,541 @@
+use crate::gpstime::ElapsedClock;
+use crate::message::builder::RbMessageBuilder;
+use crate::message::RbMessage;
+use crate::quality::{FixQuality, QualityThresholds};
+use crate::session::Session;
+use crate::units::{Acceleration, Angle, AngularRate, Speed, STANDARD_GRAVITY};
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+/*
+Sensor mounting calibration
+
+The IMU axes are only the car's axes, X forwards, Y to the side and Z up, if the
+unit is mounted square. A mounting is the rotation taking the unit's axes to the
+car's: roll about X, then pitch about Y to level the unit, then yaw about the
+vertical to line it up with the direction of travel.
+
+Roll and pitch come from gravity, averaged over samples where the car is
+stopped on level ground with nothing rotating. Yaw comes from straight line
+acceleration and braking, where the levelled horizontal acceleration has to
+point along the change in the receiver's speed. Without enough of that yaw is
+left at zero.
+
+Applying a mounting to a session rewrites its IMU data in the car's axes and
+records the mounting in the session metadata, so it can't be applied twice.
+g_forces() and rot_rates() give the same without changing the message.
+
+Mountings are kept per device serial in a JSON file, angles in degrees:
+
+    {
+      "version": 1,
+      "devices": {
+        "1221405314": { "roll": 180.0, "pitch": -2.5, "yaw": 90.0 }
+      }
+    }
+*/
+
+pub const MOUNTING_VERSION: u32 = 1;
+
+type Rotation = [[f64; 3]; 3];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Mounting {
+    pub roll: Angle,
+    pub pitch: Angle,
+    pub yaw: Angle,
+}
+
+impl Mounting {
+    // The rotation from the unit's axes to the car's
+    pub fn rotation(&self) -> [[f64; 3]; 3] {
+        let (roll, pitch, yaw) = (
+            self.roll.radians(),
+            self.pitch.radians(),
+            self.yaw.radians(),
+        );
+        let x = [
+            [1.0, 0.0, 0.0],
+            [0.0, roll.cos(), -roll.sin()],
+            [0.0, roll.sin(), roll.cos()],
+        ];
+        let y = [
+            [pitch.cos(), 0.0, pitch.sin()],
+            [0.0, 1.0, 0.0],
+            [-pitch.sin(), 0.0, pitch.cos()],
+        ];
+        let z = [
+            [yaw.cos(), -yaw.sin(), 0.0],
+            [yaw.sin(), yaw.cos(), 0.0],
+            [0.0, 0.0, 1.0],
+        ];
+        multiply(&z, &multiply(&y, &x))
+    }
+
+    // Longitudinal, lateral and vertical g
+    pub fn g_forces(&self, message: &RbMessage) -> (Acceleration, Acceleration, Acceleration) {
+        let (x, y, z) = message.g_forces();
+        let [x, y, z] = rotate(&self.rotation(), [x.g(), y.g(), z.g()]);
+        (
+            Acceleration::from_g(x),
+            Acceleration::from_g(y),
+            Acceleration::from_g(z),
+        )
+    }
+
+    // Roll, pitch and yaw rates of the car
+    pub fn rot_rates(&self, message: &RbMessage) -> (AngularRate, AngularRate, AngularRate) {
+        let (x, y, z) = message.rot_rates();
+        let [x, y, z] = rotate(
+            &self.rotation(),
+            [
+                x.degrees_per_second(),
+                y.degrees_per_second(),
+                z.degrees_per_second(),
+            ],
+        );
+        (
+            AngularRate::from_degrees_per_second(x),
+            AngularRate::from_degrees_per_second(y),
+            AngularRate::from_degrees_per_second(z),
+        )
+    }
+
+    // Rewrites the message's IMU data in the car's axes
+    fn apply(&self, message: &mut RbMessage) {
+        let (x, y, z) = self.g_forces(message);
+        let (roll, pitch, yaw) = self.rot_rates(message);
+        *message = RbMessageBuilder::from(std::mem::take(message))
+            .g_forces(x, y, z)
+            .rot_rates(roll, pitch, yaw)
+            .build();
+    }
+
+    // Rewrites every message's IMU data, false if the session already has a mounting applied
+    pub fn apply_session(&self, session: &mut Session) -> bool {
+        if session.metadata.mounting.is_some() {
+            return false;
+        }
+        for message in session.messages_mut() {
+            self.apply(message);
+        }
+        session.metadata.mounting = Some(*self);
+        true
+    }
+
+    // The roll and pitch that bring a gravity reading to straight down the Z axis
+    fn level(gravity: [f64; 3]) -> Self {
+        let [x, y, z] = gravity;
+        let roll = y.atan2(z);
+        let pitch = (-x).atan2(y.hypot(z));
+        Mounting {
+            roll: Angle::from_radians(roll),
+            pitch: Angle::from_radians(pitch),
+            yaw: Angle::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MountingSettings {
+    pub stationary_speed: Speed,
+    pub max_stationary_rotation: AngularRate, // on any axis
+    pub min_stationary_samples: usize,
+    pub min_speed: Speed, // for straight line samples
+    pub min_acceleration: Acceleration,
+    pub max_heading_rate: AngularRate,
+    pub min_straight_samples: usize,
+}
+
+impl Default for MountingSettings {
+    fn default() -> Self {
+        MountingSettings {
+            stationary_speed: Speed::from_kph(1.0),
+            max_stationary_rotation: AngularRate::from_degrees_per_second(2.0),
+            min_stationary_samples: 25,
+            min_speed: Speed::from_kph(10.0),
+            min_acceleration: Acceleration::from_g(0.1),
+            max_heading_rate: AngularRate::from_degrees_per_second(3.0),
+            min_straight_samples: 25,
+        }
+    }
+}
+
+// Usable sample before the current one, for the speed and heading change
+#[derive(Clone, Copy, Debug)]
+struct Previous {
+    elapsed: f64,
+    speed: Speed,
+    heading: Angle,
+    g_forces: [f64; 3],
+}
+
+#[derive(Debug)]
+pub struct MountingCalibrator {
+    settings: MountingSettings,
+    thresholds: QualityThresholds,
+    clock: ElapsedClock,
+    previous: Option<Previous>,
+    gravity: [f64; 3], // sum of the stationary readings
+    stationary: usize,
+    forward: [f64; 3], // sum of the readings weighted by the speed change
+    straight: usize,
+}
+
+impl MountingCalibrator {
+    pub fn new(settings: MountingSettings) -> Self {
+        MountingCalibrator {
+            settings,
+            thresholds: QualityThresholds::default(),
+            clock: ElapsedClock::default(),
+            previous: None,
+            gravity: [0.0; 3],
+            stationary: 0,
+            forward: [0.0; 3],
+            straight: 0,
+        }
+    }
+
+    pub fn settings(&self) -> &MountingSettings {
+        &self.settings
+    }
+
+    pub fn update(&mut self, message: &RbMessage) {
+        let Some(elapsed) = self.clock.advance(message) else {
+            return;
+        };
+        if !FixQuality::assess(message, &self.thresholds).is_usable() {
+            self.previous = None;
+            return;
+        }
+        let (x, y, z) = message.g_forces();
+        let g_forces = [x.g(), y.g(), z.g()];
+        let speed = message.speed();
+        let heading = message.heading();
+
+        let (x, y, z) = message.rot_rates();
+        let rotation = [x, y, z]
+            .iter()
+            .map(|rate| rate.degrees_per_second().abs())
+            .fold(0.0, f64::max);
+        if speed < self.settings.stationary_speed
+            && rotation < self.settings.max_stationary_rotation.degrees_per_second()
+        {
+            self.gravity = add(self.gravity, g_forces, 1.0);
+            self.stationary += 1;
+        }
+
+        if let Some(previous) = self.previous {
+            let dt = (elapsed - previous.elapsed) / 1000.0;
+            let acceleration = (speed.meters_per_second() - previous.speed.meters_per_second())
+                / dt
+                / STANDARD_GRAVITY;
+            let turn = (heading.degrees() - previous.heading.degrees()).rem_euclid(360.0);
+            let heading_rate = turn.min(360.0 - turn) / dt;
+            if previous.speed >= self.settings.min_speed
+                && speed >= self.settings.min_speed
+                && acceleration.abs() >= self.settings.min_acceleration.g()
+                && heading_rate < self.settings.max_heading_rate.degrees_per_second()
+            {
+                // The IMU readings either side of the speed change
+                let reading = add(previous.g_forces, g_forces, 1.0).map(|g| g / 2.0);
+                self.forward = add(self.forward, reading, acceleration);
+                self.straight += 1;
+            }
+        }
+        self.previous = Some(Previous {
+            elapsed,
+            speed,
+            heading,
+            g_forces,
+        });
+    }
+
+    // Whether there has been enough straight line driving to find the yaw
+    pub fn is_yaw_found(&self) -> bool {
+        self.straight >= self.settings.min_straight_samples
+    }
+
+    // The mounting so far, None until the car has been stationary long enough
+    pub fn mounting(&self) -> Option<Mounting> {
+        if self.stationary < self.settings.min_stationary_samples {
+            return None;
+        }
+        let mut mounting = Mounting::level(self.gravity);
+        if self.is_yaw_found() {
+            let [x, y, _] = rotate(&mounting.rotation(), self.forward);
+            mounting.yaw = Angle::from_radians((-y).atan2(x));
+        }
+        Some(mounting)
+    }
+}
+
+// Works out the mounting from a recording
+pub fn calibrate_session(session: &Session, settings: MountingSettings) -> Option<Mounting> {
+    let mut calibrator = MountingCalibrator::new(settings);
+    for message in session.messages() {
+        calibrator.update(message);
+    }
+    calibrator.mounting()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MountingFile {
+    version: u32,
+    devices: BTreeMap<String, Mounting>,
+}
+
+// Mountings by RaceBox Mini serial number
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MountingStore {
+    devices: BTreeMap<String, Mounting>,
+}
+
+impl MountingStore {
+    pub fn read_json<R: Read>(reader: R) -> Result<Self, String> {
+        let file: MountingFile = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+        if file.version != MOUNTING_VERSION {
+            return Err(format!(
+                "unsupported mounting file version {}",
+                file.version
+            ));
+        }
+        Ok(MountingStore {
+            devices: file.devices,
+        })
+    }
+
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let file = MountingFile {
+            version: MOUNTING_VERSION,
+            devices: self.devices.clone(),
+        };
+        serde_json::to_writer_pretty(writer, &file).map_err(io::Error::from)
+    }
+
+    pub fn get(&self, serial: &str) -> Option<&Mounting> {
+        self.devices.get(serial)
+    }
+
+    // Sets a device's mounting, replacing any it had
+    pub fn insert(&mut self, serial: &str, mounting: Mounting) {
+        self.devices.insert(serial.to_string(), mounting);
+    }
+
+    pub fn remove(&mut self, serial: &str) -> Option<Mounting> {
+        self.devices.remove(serial)
+    }
+
+    // Applies the mounting of the session's device, false if it has none or one is already applied
+    pub fn apply_session(&self, session: &mut Session) -> bool {
+        let Some(mounting) = self.get(&session.metadata.serial).copied() else {
+            return false;
+        };
+        mounting.apply_session(session)
+    }
+}
+
+fn multiply(a: &Rotation, b: &Rotation) -> Rotation {
+    std::array::from_fn(|i| std::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn rotate(rotation: &Rotation, vector: [f64; 3]) -> [f64; 3] {
+    rotation.map(|row| row.iter().zip(vector).map(|(r, v)| r * v).sum())
+}
+
+fn add(a: [f64; 3], b: [f64; 3], scale: f64) -> [f64; 3] {
+    std::array::from_fn(|i| a[i] + b[i] * scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        calibrate_session, rotate, Mounting, MountingCalibrator, MountingSettings, MountingStore,
+    };
+    use crate::message::{decode_rb_message, RbMessage};
+    use crate::session::{Session, SessionMetadata};
+    use crate::testing::fix;
+    use crate::units::{Acceleration, Angle, AngularRate};
+
+    fn mounting(roll: f64, pitch: f64, yaw: f64) -> Mounting {
+        Mounting {
+            roll: Angle::from_degrees(roll),
+            pitch: Angle::from_degrees(pitch),
+            yaw: Angle::from_degrees(yaw),
+        }
+    }
+
+    // A sample with the car's g forces and yaw rate as read by a unit mounted this way
+    fn message(
+        mounted: &Mounting,
+        itow: u32,
+        speed: f64,
+        car: [f64; 3],
+        yaw_rate: f64,
+    ) -> RbMessage {
+        // The transpose takes the car's axes back to the unit's
+        let rotation = mounted.rotation();
+        let back: [[f64; 3]; 3] = std::array::from_fn(|i| std::array::from_fn(|j| rotation[j][i]));
+        let [x, y, z] = rotate(&back, car);
+        let [rx, ry, rz] = rotate(&back, [0.0, 0.0, yaw_rate]);
+        fix(itow)
+            .speed_mps(speed)
+            .heading(Angle::from_degrees(90.0))
+            .g_forces(
+                Acceleration::from_g(x),
+                Acceleration::from_g(y),
+                Acceleration::from_g(z),
+            )
+            .rot_rates(
+                AngularRate::from_degrees_per_second(rx),
+                AngularRate::from_degrees_per_second(ry),
+                AngularRate::from_degrees_per_second(rz),
+            )
+            .build()
+    }
+
+    // Stopped for 3 seconds, then 0.3 g along the straight to 30 m/s and braking at 0.6 g
+    fn session(mounted: &Mounting) -> Session {
+        let mut session = Session::new(SessionMetadata {
+            serial: String::from("1221405314"),
+            ..Default::default()
+        });
+        let mut speed = 0.0;
+        for i in 0..500 {
+            let itow = i * 40;
+            let accel = match i {
+                0..=74 => 0.0,
+                _ if speed < 30.0 && i < 350 => 0.3,
+                _ if speed > 0.0 => -0.6,
+                _ => 0.0,
+            };
+            session.push(message(mounted, itow, speed, [accel, 0.0, 1.0], 0.0));
+            speed = (speed + accel * 9.80665 * 0.04).max(0.0);
+        }
+        session
+    }
+
+    fn assert_rotation(a: &Mounting, b: &Mounting) {
+        let (a, b) = (a.rotation(), b.rotation());
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((a[i][j] - b[i][j]).abs() < 0.01, "{a:?} {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        assert_rotation(&Mounting::default(), &mounting(0.0, 0.0, 0.0));
+        let rotation = Mounting::default().rotation();
+        assert_eq!(rotation[0], [1.0, 0.0, 0.0]);
+
+        // Yawed a quarter turn, the unit's X axis is the car's Y
+        let turned = mounting(0.0, 0.0, 90.0);
+        let [x, y, z] = rotate(&turned.rotation(), [1.0, 0.0, 0.0]);
+        assert!((x.abs() + (y - 1.0).abs() + z.abs()) < 1e-9);
+
+        // Upside down, the unit reads gravity as -Z
+        let level = Mounting::level([0.0, 0.0, -1.0]);
+        let [x, y, z] = rotate(&level.rotation(), [0.0, 0.0, -1.0]);
+        assert!((x.abs() + y.abs() + (z - 1.0).abs()) < 1e-9);
+    }
+
+    #[test]
+    fn test_calibrate() {
+        for mounted in [
+            mounting(10.0, -20.0, 100.0),
+            mounting(180.0, 5.0, -90.0),
+            mounting(-3.0, 2.0, 180.0),
+        ] {
+            let mut session = session(&mounted);
+            let mut calibrator = MountingCalibrator::new(MountingSettings::default());
+            for message in session.messages() {
+                calibrator.update(message);
+            }
+            assert!(calibrator.is_yaw_found());
+            let found = calibrator.mounting().unwrap();
+            assert_rotation(&found, &mounted);
+            assert_eq!(
+                calibrate_session(&session, MountingSettings::default()),
+                Some(found)
+            );
+
+            // Accelerating the unit reads true longitudinal and vertical g
+            let (x, y, z) = found.g_forces(&session.messages()[100]);
+            assert!((x.g() - 0.3).abs() < 0.01 && y.g().abs() < 0.01 && (z.g() - 1.0).abs() < 0.01);
+            let yawing = message(&mounted, 0, 10.0, [0.0, 0.3, 1.0], 20.0);
+            let (x, y, z) = found.rot_rates(&yawing);
+            assert!(x.degrees_per_second().abs() < 0.5 && y.degrees_per_second().abs() < 0.5);
+            assert!((z.degrees_per_second() - 20.0).abs() < 0.5);
+
+            assert!(found.apply_session(&mut session));
+            assert_eq!(session.metadata.mounting, Some(found));
+            let (x, y, z) = session.messages()[100].g_forces();
+            assert!((x.g() - 0.3).abs() < 0.01 && y.g().abs() < 0.01 && (z.g() - 1.0).abs() < 0.01);
+            let sealed = &session.messages()[100];
+            assert_eq!(&decode_rb_message(&sealed.encode()).unwrap(), sealed);
+
+            // Applying it again would rotate the data twice
+            assert!(!found.apply_session(&mut session));
+            let (x, _, _) = session.messages()[100].g_forces();
+            assert!((x.g() - 0.3).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_calibrate_without_driving() {
+        // Stationary only, level but yaw unknown
+        let mounted = mounting(5.0, 10.0, 45.0);
+        let mut session = Session::default();
+        for i in 0..100 {
+            session.push(message(&mounted, i * 40, 0.0, [0.0, 0.0, 1.0], 0.0));
+        }
+        let found = calibrate_session(&session, MountingSettings::default()).unwrap();
+        assert_eq!(found.yaw, Angle::default());
+        let (x, y, z) = found.g_forces(&session.messages()[0]);
+        assert!(x.g().abs() < 0.01 && y.g().abs() < 0.01 && (z.g() - 1.0).abs() < 0.01);
+
+        // Never stationary
+        let moving: Vec<_> = (0..100)
+            .map(|i| message(&mounted, i * 40, 20.0, [0.0, 0.0, 1.0], 0.0))
+            .collect();
+        let mut calibrator = MountingCalibrator::new(MountingSettings::default());
+        for message in &moving {
+            calibrator.update(message);
+        }
+        assert_eq!(calibrator.mounting(), None);
+    }
+
+    #[test]
+    fn test_store() {
+        let mounted = mounting(180.0, -2.5, 90.0);
+        let mut store = MountingStore::default();
+        store.insert("1221405314", mounted);
+        assert_eq!(store.get("1221405314"), Some(&mounted));
+        assert_eq!(store.get("1"), None);
+
+        let mut json = Vec::new();
+        store.write_json(&mut json).unwrap();
+        let text = String::from_utf8(json).unwrap();
+        assert!(text.contains("\"1221405314\""));
+        let read = MountingStore::read_json(text.as_bytes()).unwrap();
+        assert_eq!(read, store);
+        let version = text.replace("\"version\": 1", "\"version\": 2");
+        assert_eq!(
+            MountingStore::read_json(version.as_bytes()),
+            Err(String::from("unsupported mounting file version 2"))
+        );
+
+        // Applied by the session's serial number
+        let mut session = session(&mounted);
+        assert!(store.apply_session(&mut session));
+        let (x, _, z) = session.messages()[100].g_forces();
+        assert!((x.g() - 0.3).abs() < 0.01 && (z.g() - 1.0).abs() < 0.01);
+        assert!(!store.apply_session(&mut session));
+        assert_eq!(store.remove("1221405314"), Some(mounted));
+        assert!(!MountingStore::default().apply_session(&mut session));
+    }
+}
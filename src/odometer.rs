@@ -0,0 +1,339 @@
+use crate::gpstime::ElapsedClock;
+use crate::laps::{LapEvent, LapTimer, TimingLines};
+use crate::message::{Coordinates, RbMessage};
+use crate::quality::{FixQuality, QualityClass, QualityThresholds};
+use crate::session::Session;
+use crate::units::{Length, Speed};
+use serde::Deserialize;
+use serde::Serialize;
+
+/*
+Distance travelled
+
+The odometer adds up the distance between consecutive accepted samples, either
+along the straight line between their positions or as their mean speed over the
+time between them. Positions follow the path exactly but wander a little with
+the fix, speed is smoother but drifts with any speed error. While the car is
+stationary position jitter would add up, so a step between two samples slower
+than the stationary speed counts as nothing.
+
+Samples without a fix or below the minimum quality are rejected and have no
+distance. The next accepted sample is joined to the last one before them, so a
+short dropout costs at most the corner cut by the straight line across it.
+*/
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceSource {
+    #[default]
+    Position,
+    Speed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct OdometerSettings {
+    pub source: DistanceSource,
+    pub min_quality: QualityClass,
+    pub stationary_speed: Speed,
+    pub thresholds: QualityThresholds,
+}
+
+impl Default for OdometerSettings {
+    fn default() -> Self {
+        OdometerSettings {
+            source: DistanceSource::Position,
+            min_quality: QualityClass::Good,
+            stationary_speed: Speed::from_kph(1.0),
+            thresholds: QualityThresholds::default(),
+        }
+    }
+}
+
+// The last accepted sample
+#[derive(Clone, Copy, Debug)]
+struct Previous {
+    elapsed: f64, // milliseconds
+    coordinates: Coordinates,
+    speed: Speed,
+}
+
+#[derive(Debug)]
+pub struct Odometer {
+    settings: OdometerSettings,
+    clock: ElapsedClock,
+    previous: Option<Previous>,
+    distance: f64, // metres since the last reset
+    total: f64,    // metres since the first sample
+}
+
+impl Odometer {
+    pub fn new(settings: OdometerSettings) -> Self {
+        Odometer {
+            settings,
+            clock: ElapsedClock::default(),
+            previous: None,
+            distance: 0.0,
+            total: 0.0,
+        }
+    }
+
+    pub fn settings(&self) -> &OdometerSettings {
+        &self.settings
+    }
+
+    // Distance since the last reset at this sample, None if it was rejected
+    pub fn update(&mut self, message: &RbMessage) -> Option<Length> {
+        self.step(message)?;
+        Some(self.distance())
+    }
+
+    // The distance added by an accepted sample and the time it took, in seconds
+    fn step(&mut self, message: &RbMessage) -> Option<(f64, f64)> {
+        let elapsed = self.clock.advance(message)?;
+        if FixQuality::assess(message, &self.settings.thresholds).class < self.settings.min_quality
+        {
+            return None;
+        }
+        let sample = Previous {
+            elapsed,
+            coordinates: message.gps_coordinates(),
+            speed: message.speed(),
+        };
+        let Some(previous) = self.previous.replace(sample) else {
+            return Some((0.0, 0.0));
+        };
+
+        let time = (elapsed - previous.elapsed) / 1000.0;
+        let stationary = self.settings.stationary_speed;
+        let step = if previous.speed < stationary && sample.speed < stationary {
+            0.0
+        } else {
+            match self.settings.source {
+                DistanceSource::Position => previous
+                    .coordinates
+                    .haversine_distance(sample.coordinates)
+                    .meters(),
+                DistanceSource::Speed => {
+                    (previous.speed.meters_per_second() + sample.speed.meters_per_second()) / 2.0
+                        * time
+                }
+            }
+        };
+        self.distance += step;
+        self.total += step;
+        Some((step, time))
+    }
+
+    // Distance since the last reset
+    pub fn distance(&self) -> Length {
+        Length::from_meters(self.distance)
+    }
+
+    // Distance since the first sample, not affected by resets
+    pub fn total(&self) -> Length {
+        Length::from_meters(self.total)
+    }
+
+    // Starts the distance again from zero, for a new lap
+    pub fn reset(&mut self) {
+        self.distance = 0.0;
+    }
+}
+
+// Cumulative distance at each message of a session, None for rejected samples
+pub fn session_distances(session: &Session, settings: OdometerSettings) -> Vec<Option<Length>> {
+    let mut odometer = Odometer::new(settings);
+    session
+        .messages()
+        .iter()
+        .map(|message| odometer.update(message))
+        .collect()
+}
+
+/*
+Distance into the lap at each message of a session, None before the line is
+first crossed and for rejected samples. The step a crossing happens in is split
+at the crossing time, so each lap starts from the line rather than from the
+first sample after it. The timer accepts samples the odometer rejects, so a
+crossing on a rejected sample splits the step to the next accepted one.
+*/
+pub fn lap_distances<L: Into<TimingLines>>(
+    session: &Session,
+    lines: L,
+    settings: OdometerSettings,
+) -> Vec<Option<Length>> {
+    let mut timer = LapTimer::new(lines);
+    let mut odometer = Odometer::new(settings);
+    let mut crossed = false;
+    session
+        .messages()
+        .iter()
+        .map(|message| {
+            let events = timer.update(message);
+            if events
+                .iter()
+                .any(|event| matches!(event, LapEvent::Started | LapEvent::Completed(_)))
+            {
+                crossed = true;
+            }
+            let (step, time) = odometer.step(message)?;
+            let lap_time = timer.current_lap_time()?.as_secs_f64();
+            if std::mem::take(&mut crossed) {
+                let after = if time > 0.0 {
+                    (lap_time / time).min(1.0)
+                } else {
+                    0.0
+                };
+                odometer.distance = step * after;
+            }
+            Some(odometer.distance())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lap_distances, session_distances, DistanceSource, Odometer, OdometerSettings};
+    use crate::laps::Line;
+    use crate::message::RbMessage;
+    use crate::session::Session;
+    use crate::testing::{at, fix};
+    use crate::units::Length;
+    use std::f64::consts::PI;
+
+    fn message(itow: u32, x: f64, y: f64, speed: f64, accuracy: f64) -> RbMessage {
+        fix(itow)
+            .horiz_accuracy(Length::from_meters(accuracy))
+            .coordinates(at(x, y))
+            .speed_mps(speed)
+            .build()
+    }
+
+    // Stopped with the position jittering for 2 seconds, then 10 seconds north at 20 m/s
+    fn session() -> Session {
+        let mut session = Session::default();
+        for i in 0..50 {
+            let jitter = if i % 2 == 0 { 0.3 } else { -0.3 };
+            session.push(message(i * 40, jitter, -jitter, 0.0, 0.5));
+        }
+        for i in 0..=250 {
+            // A patch of poor accuracy in the middle
+            let accuracy = if (100..110).contains(&i) { 5.0 } else { 0.5 };
+            let y = f64::from(i) * 0.8;
+            session.push(message(2000 + i * 40, 0.0, y, 20.0, accuracy));
+        }
+        session
+    }
+
+    #[test]
+    fn test_odometer() {
+        for source in [DistanceSource::Position, DistanceSource::Speed] {
+            let settings = OdometerSettings {
+                source,
+                ..Default::default()
+            };
+            let session = session();
+            let distances = session_distances(&session, settings);
+            assert_eq!(distances.len(), session.len());
+            // Standing still adds nothing
+            assert_eq!(distances[49], Some(Length::from_meters(0.0)));
+            // Poor samples are rejected and bridged
+            assert_eq!(distances[150], None);
+            let last = distances.last().unwrap().unwrap().meters();
+            // Both count a fraction of a metre for the step pulling away
+            assert!((last - 200.0).abs() < 0.5, "{source:?} {last}");
+            let cumulative: Vec<_> = distances.iter().flatten().collect();
+            assert!(cumulative.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+
+        let session = session();
+        let mut odometer = Odometer::new(OdometerSettings::default());
+        for message in &session.messages()[..150] {
+            odometer.update(message);
+        }
+        let before = odometer.distance();
+        odometer.reset();
+        assert_eq!(odometer.distance(), Length::from_meters(0.0));
+        for message in &session.messages()[150..] {
+            odometer.update(message);
+        }
+        assert!((odometer.total().meters() - 200.0).abs() < 0.5);
+        let distance = before.meters() + odometer.distance().meters();
+        assert!((distance - odometer.total().meters()).abs() < 1e-9);
+        // A duplicate is ignored
+        assert_eq!(odometer.update(session.messages().last().unwrap()), None);
+    }
+
+    #[test]
+    fn test_lap_distances() {
+        // Anticlockwise laps of a 100m circle from the bottom, the line across it there
+        let radius = 100.0;
+        let mut session = Session::default();
+        let mut angle = -PI / 2.0 - 0.1;
+        for i in 0..2000 {
+            session.push(message(
+                i * 40,
+                radius * angle.cos(),
+                radius * angle.sin(),
+                20.0,
+                0.5,
+            ));
+            angle += 0.8 / radius;
+        }
+        let line = Line::new(at(0.0, -120.0), at(0.0, -80.0));
+        let distances = lap_distances(&session, line, OdometerSettings::default());
+        assert_eq!(distances.len(), session.len());
+        assert_eq!(distances[0], None);
+
+        let lap = 2.0 * PI * radius;
+        let resets: Vec<_> = distances
+            .windows(2)
+            .filter_map(|pair| match (pair[0], pair[1]) {
+                (Some(before), Some(after)) if after < before => Some((before, after)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(resets.len(), 2);
+        for (before, after) in resets {
+            // The crossing splits the step, the lap is the circle's circumference
+            assert!(after.meters() < 0.8);
+            assert!((before.meters() + 0.8 - after.meters() - lap).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_poor_crossing() {
+        // The same laps, with the fix too poor for the odometer for 4m either side of the line
+        let radius = 100.0;
+        let mut session = Session::default();
+        let mut angle = -PI / 2.0 - 0.1;
+        for i in 0..2000 {
+            let from_line = (angle + PI / 2.0 + PI).rem_euclid(2.0 * PI) - PI;
+            let accuracy = if from_line.abs() < 0.04 { 5.0 } else { 0.5 };
+            session.push(message(
+                i * 40,
+                radius * angle.cos(),
+                radius * angle.sin(),
+                20.0,
+                accuracy,
+            ));
+            angle += 0.8 / radius;
+        }
+        let line = Line::new(at(0.0, -120.0), at(0.0, -80.0));
+        let distances = lap_distances(&session, line, OdometerSettings::default());
+
+        // Each lap starts again from the line at the next accepted sample
+        let lap = 2.0 * PI * radius;
+        let accepted: Vec<_> = distances.iter().flatten().map(|d| d.meters()).collect();
+        let resets: Vec<_> = accepted
+            .windows(2)
+            .filter(|pair| pair[1] < pair[0])
+            .collect();
+        assert_eq!(resets.len(), 2);
+        for pair in resets {
+            assert!(pair[0] > lap - 5.0 && pair[0] < lap, "{}", pair[0]);
+            assert!(pair[1] < 5.0, "{}", pair[1]);
+        }
+        assert!(accepted.iter().all(|&distance| distance < lap));
+    }
+}
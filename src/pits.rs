@@ -0,0 +1,251 @@
+use crate::laps::millis;
+use crate::message::{Coordinates, RbMessage};
+use crate::tracks::in_polygon;
+use crate::units::Speed;
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/*
+Pit lane detection
+
+With a pit lane polygon, from the track database, the car is in the pits while
+its position is inside the polygon. Without one the speed heuristic is used
+unless it is turned off: a stop of at least min_stop counts as a pit stop, and
+the entry is put back to when the speed last dropped below the pit speed limit.
+A car that was below the limit for more than a lap, like a kart that never
+reaches it, enters the pits where it stopped instead. The car has left the pits
+once it is moving again and either goes over the pit speed limit or crosses the
+finish line, so a kart also leaves at the line. A spin that stops the car on
+track for min_stop looks the same as a pit stop, set speed_heuristic to false
+where that matters.
+
+Stationary time is counted whether in the pits or not, and is the time after
+each sample slower than the stationary speed until the next one.
+*/
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PitLane {
+    pub polygon: Vec<Coordinates>,
+    pub speed_limit: Speed,
+    pub stationary_speed: Speed,
+    pub min_stop: Duration,
+    pub speed_heuristic: bool, // detect stops by speed when there's no polygon
+}
+
+impl PitLane {
+    pub fn new(polygon: Vec<Coordinates>) -> Self {
+        PitLane {
+            polygon,
+            ..Default::default()
+        }
+    }
+
+    // No polygon, a long enough stop is a pit stop, the same as the default
+    pub fn by_speed() -> Self {
+        PitLane {
+            speed_heuristic: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for PitLane {
+    fn default() -> Self {
+        PitLane {
+            polygon: Vec::new(),
+            speed_limit: Speed::from_kph(60.0),
+            stationary_speed: Speed::from_kph(2.0),
+            min_stop: Duration::from_secs(10),
+            speed_heuristic: true,
+        }
+    }
+}
+
+// A visit to the pit lane, times are from the lap timer's first message
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PitStop {
+    pub entry: Duration,
+    pub exit: Option<Duration>, // None while still in the pit lane
+    pub stationary: Duration,
+}
+
+impl PitStop {
+    // Time from entry to exit, None while still in the pit lane
+    pub fn time(&self) -> Option<Duration> {
+        Some(self.exit? - self.entry)
+    }
+
+    // Time in the pit lane between two times, an open stop runs to the end
+    pub fn overlap(&self, start: Duration, end: Duration) -> Duration {
+        let exit = self.exit.unwrap_or(end).min(end);
+        exit.saturating_sub(self.entry.max(start))
+    }
+}
+
+// Elapsed milliseconds of a pit lane entry or exit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PitEvent {
+    Entry(f64),
+    Exit(f64),
+}
+
+// Follows the car in and out of the pits, fed usable samples by the lap timer
+#[derive(Debug, Default)]
+pub(crate) struct PitDetector {
+    lane: PitLane,
+    previous: Option<(f64, bool)>, // elapsed ms of the last sample and whether it was stationary
+    stationary: f64,
+    slow_since: Option<f64>,
+    stopped_since: Option<f64>,
+    crossings: (Option<f64>, Option<f64>), // elapsed ms of the last two finish line crossings
+    stops: Vec<PitStop>,
+}
+
+impl PitDetector {
+    pub(crate) fn new(lane: PitLane) -> Self {
+        PitDetector {
+            lane,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn update(&mut self, message: &RbMessage, elapsed: f64) -> Option<PitEvent> {
+        let speed = message.speed();
+        let stationary = speed < self.lane.stationary_speed;
+        if let Some((last, true)) = self.previous.replace((elapsed, stationary)) {
+            self.stationary += elapsed - last;
+            if let Some(stop) = self.open_stop() {
+                stop.stationary += millis(elapsed - last);
+            }
+        }
+
+        if !self.lane.polygon.is_empty() {
+            let inside = in_polygon(&self.lane.polygon, message.gps_coordinates());
+            return match (self.in_pit(), inside) {
+                (false, true) => self.enter(elapsed, 0.0),
+                (true, false) => self.exit(elapsed),
+                _ => None,
+            };
+        }
+        if !self.lane.speed_heuristic {
+            return None;
+        }
+
+        if speed < self.lane.speed_limit {
+            self.slow_since.get_or_insert(elapsed);
+        } else {
+            self.slow_since = None;
+        }
+        if stationary {
+            self.stopped_since.get_or_insert(elapsed);
+        } else {
+            self.stopped_since = None;
+        }
+        if self.in_pit() {
+            return match self.slow_since {
+                None => self.exit(elapsed),
+                Some(_) => None,
+            };
+        }
+        let (slow, stopped) = (self.slow_since?, self.stopped_since?);
+        if elapsed - stopped >= self.lane.min_stop.as_secs_f64() * 1000.0 {
+            // Slow since before the crossing ahead of the last one, it never got up to speed
+            let lap_ago = self.crossings.0.unwrap_or(f64::NEG_INFINITY);
+            let entry = if slow > lap_ago { slow } else { stopped };
+            return self.enter(entry, elapsed - stopped);
+        }
+        None
+    }
+
+    // The finish line was crossed, which takes the car out of the pits once it has left its stop
+    pub(crate) fn cross(&mut self, elapsed: f64) -> Option<PitEvent> {
+        self.crossings = (self.crossings.1, Some(elapsed));
+        let heuristic = self.lane.polygon.is_empty() && self.lane.speed_heuristic;
+        if heuristic && self.in_pit() && self.stopped_since.is_none() {
+            return self.exit(elapsed);
+        }
+        None
+    }
+
+    fn enter(&mut self, elapsed: f64, stationary: f64) -> Option<PitEvent> {
+        self.stops.push(PitStop {
+            entry: millis(elapsed),
+            exit: None,
+            stationary: millis(stationary),
+        });
+        Some(PitEvent::Entry(elapsed))
+    }
+
+    fn exit(&mut self, elapsed: f64) -> Option<PitEvent> {
+        self.open_stop()?.exit = Some(millis(elapsed));
+        Some(PitEvent::Exit(elapsed))
+    }
+
+    fn open_stop(&mut self) -> Option<&mut PitStop> {
+        self.stops.last_mut().filter(|stop| stop.exit.is_none())
+    }
+
+    pub(crate) fn in_pit(&self) -> bool {
+        self.stops.last().is_some_and(|stop| stop.exit.is_none())
+    }
+
+    pub(crate) fn stops(&self) -> &[PitStop] {
+        &self.stops
+    }
+
+    // Total stationary time in milliseconds
+    pub(crate) fn stationary(&self) -> f64 {
+        self.stationary
+    }
+
+    // Time in the pit lane between two times
+    pub(crate) fn time_between(&self, start: Duration, end: Duration) -> Duration {
+        self.stops.iter().map(|stop| stop.overlap(start, end)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PitLane, PitStop};
+    use crate::units::Speed;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pit_stop() {
+        let secs = Duration::from_secs;
+        let mut stop = PitStop {
+            entry: secs(100),
+            exit: Some(secs(130)),
+            stationary: secs(20),
+        };
+        assert_eq!(stop.time(), Some(secs(30)));
+        assert_eq!(stop.overlap(secs(0), secs(200)), secs(30));
+        assert_eq!(stop.overlap(secs(110), secs(120)), secs(10));
+        assert_eq!(stop.overlap(secs(120), secs(200)), secs(10));
+        assert_eq!(stop.overlap(secs(0), secs(90)), Duration::ZERO);
+        assert_eq!(stop.overlap(secs(140), secs(200)), Duration::ZERO);
+
+        // Still in the pit lane
+        stop.exit = None;
+        assert_eq!(stop.time(), None);
+        assert_eq!(stop.overlap(secs(0), secs(115)), secs(15));
+    }
+
+    #[test]
+    fn test_pit_lane() {
+        let lane = PitLane::new(Vec::new());
+        assert_eq!(lane, PitLane::default());
+        assert!(lane.speed_limit > Speed::from_kph(50.0));
+        assert!(lane.stationary_speed < lane.speed_limit);
+        assert!(lane.speed_heuristic);
+        assert_eq!(PitLane::by_speed(), lane);
+
+        // Fields left out of a saved lane take their defaults
+        let json = r#"{"polygon": [], "min_stop": {"secs": 30, "nanos": 0}}"#;
+        let read: PitLane = serde_json::from_str(json).unwrap();
+        assert_eq!(read.min_stop, Duration::from_secs(30));
+        assert!(read.speed_heuristic);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::gpstime::itow_delta;
+use crate::message::RbMessage;
+use crate::status::FixStatus;
+use crate::units::{Angle, Dop, Length, Speed};
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/*
+Fix quality assessment
+
+Every tool that consumes RaceBox samples needs to decide which ones to trust.
+This rolls the fix status, validity flags, satellite count, dilution of
+precision and the receiver's accuracy estimates into a single class, so the
+lap timer, exports and analysis all reject the same samples.
+*/
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QualityClass {
+    Unusable,
+    Poor,
+    Good,
+    Excellent,
+}
+
+// The limits a sample has to meet to reach a class
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct QualityLimits {
+    pub min_satellites: u8,
+    pub max_pdop: Dop,
+    pub max_horizontal_accuracy: Length,
+    pub max_vertical_accuracy: Length,
+    pub max_speed_accuracy: Speed,
+    // Only checked above min_heading_speed, heading is meaningless when stationary
+    pub max_heading_accuracy: Angle,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct QualityThresholds {
+    pub poor: QualityLimits,
+    pub good: QualityLimits,
+    pub excellent: QualityLimits,
+    pub min_heading_speed: Speed,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        QualityThresholds {
+            poor: QualityLimits {
+                min_satellites: 4,
+                max_pdop: Dop::from_value(10.0),
+                max_horizontal_accuracy: Length::from_meters(10.0),
+                max_vertical_accuracy: Length::from_meters(20.0),
+                max_speed_accuracy: Speed::from_meters_per_second(2.0),
+                max_heading_accuracy: Angle::from_degrees(30.0),
+            },
+            good: QualityLimits {
+                min_satellites: 6,
+                max_pdop: Dop::from_value(3.0),
+                max_horizontal_accuracy: Length::from_meters(2.5),
+                max_vertical_accuracy: Length::from_meters(5.0),
+                max_speed_accuracy: Speed::from_meters_per_second(0.5),
+                max_heading_accuracy: Angle::from_degrees(5.0),
+            },
+            excellent: QualityLimits {
+                min_satellites: 10,
+                max_pdop: Dop::from_value(1.5),
+                max_horizontal_accuracy: Length::from_meters(1.0),
+                max_vertical_accuracy: Length::from_meters(2.0),
+                max_speed_accuracy: Speed::from_meters_per_second(0.2),
+                max_heading_accuracy: Angle::from_degrees(1.0),
+            },
+            min_heading_speed: Speed::from_kph(5.0),
+        }
+    }
+}
+
+// Quality summary of a single sample
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FixQuality {
+    pub class: QualityClass,
+    pub fix_status: FixStatus,
+    pub valid_fix: bool,
+    pub valid_position: bool,
+    pub satellites: u8,
+    pub pdop: Dop,
+    pub horizontal_accuracy: Length,
+    pub vertical_accuracy: Length,
+    pub speed_accuracy: Speed,
+    pub heading_accuracy: Angle,
+}
+
+impl FixQuality {
+    pub fn assess(message: &RbMessage, thresholds: &QualityThresholds) -> Self {
+        let mut quality = FixQuality {
+            class: QualityClass::Unusable,
+            fix_status: message.fix_status(),
+            valid_fix: message.is_valid_fix(),
+            valid_position: message.is_valid_position(),
+            satellites: message.satelites(),
+            pdop: message.pdop(),
+            horizontal_accuracy: message.horiz_accuracy(),
+            vertical_accuracy: message.vert_accuracy(),
+            speed_accuracy: message.speed_accuracy(),
+            heading_accuracy: message.heading_accuracy(),
+        };
+
+        let has_fix = matches!(quality.fix_status, FixStatus::Fix2D | FixStatus::Fix3D);
+        if !has_fix || !quality.valid_fix || !quality.valid_position {
+            return quality;
+        }
+
+        let moving = message.speed() >= thresholds.min_heading_speed;
+        let meets = |limits: &QualityLimits| {
+            quality.satellites >= limits.min_satellites
+                && quality.pdop <= limits.max_pdop
+                && quality.horizontal_accuracy <= limits.max_horizontal_accuracy
+                && quality.vertical_accuracy <= limits.max_vertical_accuracy
+                && quality.speed_accuracy <= limits.max_speed_accuracy
+                && (!moving || quality.heading_accuracy <= limits.max_heading_accuracy)
+        };
+
+        // A 2D fix has no altitude, it can't be better than poor
+        quality.class = if quality.fix_status == FixStatus::Fix3D && meets(&thresholds.excellent) {
+            QualityClass::Excellent
+        } else if quality.fix_status == FixStatus::Fix3D && meets(&thresholds.good) {
+            QualityClass::Good
+        } else if meets(&thresholds.poor) {
+            QualityClass::Poor
+        } else {
+            QualityClass::Unusable
+        };
+        quality
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.class > QualityClass::Unusable
+    }
+}
+
+// Quality statistics over a session
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct QualityStats {
+    pub samples: u64,
+    pub unusable: u64,
+    pub poor: u64,
+    pub good: u64,
+    pub excellent: u64,
+    // From the first sample of the session to the first usable one
+    pub time_to_first_fix: Option<Duration>,
+    pub min_satellites: Option<u8>,
+    pub max_satellites: Option<u8>,
+    pub mean_horizontal_accuracy: Option<Length>,
+}
+
+impl QualityStats {
+    // Fraction of samples that were usable
+    pub fn usable_ratio(&self) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        (self.samples - self.unusable) as f64 / self.samples as f64
+    }
+}
+
+// Assesses each sample of a stream and keeps session statistics
+#[derive(Debug, Default)]
+pub struct QualityTracker {
+    thresholds: QualityThresholds,
+    stats: QualityStats,
+    first_itow: Option<u32>,
+    horizontal_accuracy_sum: f64,
+    usable: u64,
+}
+
+impl QualityTracker {
+    pub fn new(thresholds: QualityThresholds) -> Self {
+        QualityTracker {
+            thresholds,
+            ..Default::default()
+        }
+    }
+
+    pub fn update(&mut self, message: &RbMessage) -> FixQuality {
+        let quality = FixQuality::assess(message, &self.thresholds);
+        let stats = &mut self.stats;
+        stats.samples += 1;
+        match quality.class {
+            QualityClass::Unusable => stats.unusable += 1,
+            QualityClass::Poor => stats.poor += 1,
+            QualityClass::Good => stats.good += 1,
+            QualityClass::Excellent => stats.excellent += 1,
+        }
+
+        let first_itow = *self.first_itow.get_or_insert(message.itow());
+        if !quality.is_usable() {
+            return quality;
+        }
+
+        if stats.time_to_first_fix.is_none() {
+            let elapsed = itow_delta(first_itow, message.itow()).max(0);
+            stats.time_to_first_fix = Some(Duration::from_millis(elapsed as u64));
+        }
+        stats.min_satellites = Some(
+            stats
+                .min_satellites
+                .map_or(quality.satellites, |s| s.min(quality.satellites)),
+        );
+        stats.max_satellites = Some(
+            stats
+                .max_satellites
+                .map_or(quality.satellites, |s| s.max(quality.satellites)),
+        );
+        self.usable += 1;
+        self.horizontal_accuracy_sum += quality.horizontal_accuracy.meters();
+        stats.mean_horizontal_accuracy = Some(Length::from_meters(
+            self.horizontal_accuracy_sum / self.usable as f64,
+        ));
+        quality
+    }
+
+    pub fn stats(&self) -> QualityStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixQuality, QualityClass, QualityThresholds, QualityTracker};
+    use crate::message::builder::RbMessageBuilder;
+    use crate::message::{decode_rb_message, RbMessage};
+    use crate::status::{FixFlags, FixStatus, LatLonFlags};
+    use crate::testing::RAW;
+    use crate::units::{Angle, Dop, Length, Speed};
+    use std::time::Duration;
+
+    fn assess(message: &RbMessage) -> QualityClass {
+        FixQuality::assess(message, &QualityThresholds::default()).class
+    }
+
+    #[test]
+    fn test_sample_packet() {
+        // 11 satellites, PDOP 3, 0.924m horizontal, 1.836m vertical, 0.208m/s speed
+        let message = decode_rb_message(&RAW).unwrap();
+        let quality = FixQuality::assess(&message, &QualityThresholds::default());
+        assert_eq!(quality.class, QualityClass::Good);
+        assert_eq!(quality.satellites, 11);
+        assert!(quality.is_usable());
+    }
+
+    // Re-emits the message with some fields changed
+    fn with(message: RbMessage, f: impl FnOnce(RbMessageBuilder) -> RbMessageBuilder) -> RbMessage {
+        f(RbMessageBuilder::from(message)).build()
+    }
+
+    // The sample packet with a PDOP and speed accuracy good enough to be excellent
+    fn excellent() -> RbMessage {
+        with(decode_rb_message(&RAW).unwrap(), |b| {
+            b.pdop(Dop::from_centi(120))
+                .speed_accuracy(Speed::from_mm_per_second(150))
+        })
+    }
+
+    #[test]
+    fn test_classes() {
+        assert_eq!(assess(&excellent()), QualityClass::Excellent);
+
+        // Any one limit is enough to drop a class
+        let fewer = |satellites| assess(&with(excellent(), |b| b.satellites(satellites)));
+        assert_eq!(fewer(9), QualityClass::Good);
+        assert_eq!(fewer(5), QualityClass::Poor);
+        assert_eq!(fewer(3), QualityClass::Unusable);
+
+        let inaccurate = with(excellent(), |b| b.horiz_accuracy(Length::from_mm(5000)));
+        assert_eq!(assess(&inaccurate), QualityClass::Poor);
+
+        // A 2D fix is never better than poor
+        let fix_2d = with(excellent(), |b| b.fix_status(FixStatus::Fix2D));
+        assert_eq!(assess(&fix_2d), QualityClass::Poor);
+
+        // Heading accuracy only counts when moving, the sample packet is stationary
+        let heading = |b: RbMessageBuilder| b.heading_accuracy(Angle::from_degrees_e5(9000000));
+        assert_eq!(assess(&with(excellent(), heading)), QualityClass::Excellent);
+        let moving = with(with(excellent(), heading), |b| {
+            b.speed(Speed::from_mm_per_second(10000))
+        });
+        assert_eq!(assess(&moving), QualityClass::Unusable);
+    }
+
+    #[test]
+    fn test_invalid_fix() {
+        let no_fix = with(decode_rb_message(&RAW).unwrap(), |b| {
+            b.fix_status(FixStatus::NoFix)
+        });
+        assert_eq!(assess(&no_fix), QualityClass::Unusable);
+
+        let no_flags = with(decode_rb_message(&RAW).unwrap(), |b| {
+            b.fix_flags(FixFlags(0))
+        });
+        assert_eq!(assess(&no_flags), QualityClass::Unusable);
+
+        let invalid_position = with(decode_rb_message(&RAW).unwrap(), |b| {
+            b.lat_lon_flags(LatLonFlags(1))
+        });
+        assert_eq!(assess(&invalid_position), QualityClass::Unusable);
+    }
+
+    #[test]
+    fn test_custom_thresholds() {
+        let mut thresholds = QualityThresholds::default();
+        thresholds.good.min_satellites = 12;
+        let message = decode_rb_message(&RAW).unwrap();
+        assert_eq!(
+            FixQuality::assess(&message, &thresholds).class,
+            QualityClass::Poor
+        );
+    }
+
+    #[test]
+    fn test_quality_tracker() {
+        let mut tracker = QualityTracker::new(QualityThresholds::default());
+        let start = decode_rb_message(&RAW).unwrap().itow();
+
+        // Two seconds without a fix, then a fix
+        for i in 0..50 {
+            tracker.update(&with(decode_rb_message(&RAW).unwrap(), |b| {
+                b.itow(start + i * 40).fix_status(FixStatus::NoFix)
+            }));
+        }
+        for i in 50..100 {
+            tracker.update(&with(decode_rb_message(&RAW).unwrap(), |b| {
+                b.itow(start + i * 40).fix_status(FixStatus::Fix3D)
+            }));
+        }
+
+        let stats = tracker.stats();
+        assert_eq!(stats.samples, 100);
+        assert_eq!(stats.unusable, 50);
+        assert_eq!(stats.good, 50);
+        assert_eq!(stats.time_to_first_fix, Some(Duration::from_millis(2000)));
+        assert_eq!(stats.min_satellites, Some(11));
+        assert!((stats.mean_horizontal_accuracy.unwrap().meters() - 0.924).abs() < 1e-9);
+        assert!((stats.usable_ratio() - 0.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,655 @@
+use crate::gpstime::elapsed_since;
+use crate::laps::{session_timer, TimingLines};
+use crate::message::{Coordinates, RbMessage};
+use crate::odometer::{Odometer, OdometerSettings};
+use crate::quality::{FixQuality, QualityClass, QualityThresholds};
+use crate::session::Session;
+use crate::units::{Acceleration, Angle, AngularRate, Length, Speed};
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/*
+Resampling
+
+Samples arrive at around 25 Hz, with jitter and the odd one lost over BLE. The
+resampler puts a session onto a uniform time base, or each lap onto a uniform
+distance base so laps can be overlaid, interpolating each channel between the
+samples either side.
+
+Samples are put in order by itow, duplicates are dropped and only samples with
+a usable fix are used. Between two samples further apart than the maximum gap
+nothing is interpolated, the points there are None rather than made up.
+
+Channels can be interpolated linearly, with a cubic through the samples either
+side as well, or for position along the great circle between the two samples.
+Great circle is linear for the other channels. Headings are interpolated the
+short way round.
+*/
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    Cubic,
+    GreatCircle,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ResampleSettings {
+    pub interval: Duration,
+    pub max_gap: Duration,
+    pub position: Interpolation,
+    pub altitude: Interpolation,
+    pub speed: Interpolation,
+    pub heading: Interpolation,
+    pub imu: Interpolation, // g forces and rotation rates
+    pub thresholds: QualityThresholds,
+}
+
+impl ResampleSettings {
+    // Default settings at a rate in Hz
+    pub fn hz(rate: f64) -> Result<Self, String> {
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err(format!("invalid resample rate {rate} Hz"));
+        }
+        let settings = ResampleSettings {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            ..Default::default()
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.interval.is_zero() {
+            return Err(String::from("resample interval must be more than zero"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ResampleSettings {
+    fn default() -> Self {
+        ResampleSettings {
+            interval: Duration::from_millis(50),
+            max_gap: Duration::from_millis(250),
+            position: Interpolation::GreatCircle,
+            altitude: Interpolation::Linear,
+            speed: Interpolation::Cubic,
+            heading: Interpolation::Linear,
+            imu: Interpolation::Linear,
+            thresholds: QualityThresholds::default(),
+        }
+    }
+}
+
+// The channels at a point of the new base
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub time: Duration, // since the earliest message, or into the lap on a distance base
+    pub coordinates: Coordinates,
+    pub altitude: Length,
+    pub speed: Speed,
+    pub heading: Angle,
+    pub g_forces: (Acceleration, Acceleration, Acceleration),
+    pub rot_rates: (AngularRate, AngularRate, AngularRate),
+}
+
+// Points every interval from start, None across gaps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TimeSeries {
+    pub start: Duration, // since the earliest message
+    pub interval: Duration,
+    pub points: Vec<Option<Point>>,
+}
+
+impl TimeSeries {
+    pub fn time(&self, index: usize) -> Duration {
+        self.start + self.interval * index as u32
+    }
+}
+
+// A lap's points every spacing from the line, None across gaps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DistanceSeries {
+    pub lap: u32,
+    pub spacing: Length,
+    pub points: Vec<Option<Point>>,
+}
+
+impl DistanceSeries {
+    pub fn distance(&self, index: usize) -> Length {
+        Length::from_meters(self.spacing.meters() * index as f64)
+    }
+}
+
+// A sample's channels as numbers, times in seconds and distances in metres
+#[derive(Clone, Copy, Debug)]
+struct Record {
+    time: f64,
+    distance: f64,
+    latitude: f64,
+    longitude: f64,
+    altitude: f64,
+    speed: f64,
+    heading: f64,
+    g_forces: [f64; 3],
+    rot_rates: [f64; 3],
+}
+
+impl Record {
+    fn new(time: f64, distance: f64, message: &RbMessage) -> Self {
+        let coordinates = message.gps_coordinates();
+        let (gx, gy, gz) = message.g_forces();
+        let (rx, ry, rz) = message.rot_rates();
+        Record {
+            time,
+            distance,
+            latitude: coordinates.latitude(),
+            longitude: coordinates.longitude(),
+            altitude: message.altitude().meters(),
+            speed: message.speed().meters_per_second(),
+            heading: message.heading().degrees(),
+            g_forces: [gx.g(), gy.g(), gz.g()],
+            rot_rates: [
+                rx.degrees_per_second(),
+                ry.degrees_per_second(),
+                rz.degrees_per_second(),
+            ],
+        }
+    }
+}
+
+// The usable samples in itow order, with the distance travelled along them
+fn records(session: &Session, settings: &ResampleSettings) -> Vec<Record> {
+    let Some(first) = session.messages().first() else {
+        return Vec::new();
+    };
+    // Timed as the lap timer times them, from the first message
+    let mut timed: Vec<_> = session
+        .messages()
+        .iter()
+        .filter(|m| FixQuality::assess(m, &settings.thresholds).is_usable())
+        .map(|m| (elapsed_since(first.itow(), m) / 1000.0, m))
+        .collect();
+    timed.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut odometer = Odometer::new(OdometerSettings {
+        min_quality: QualityClass::Poor,
+        thresholds: settings.thresholds,
+        ..Default::default()
+    });
+    timed
+        .into_iter()
+        .filter_map(|(time, message)| {
+            let distance = odometer.update(message)?;
+            Some(Record::new(time, distance.meters(), message))
+        })
+        .collect()
+}
+
+// Puts a session onto a uniform time base, starting at a multiple of the interval
+pub fn resample_time(session: &Session, settings: &ResampleSettings) -> Result<TimeSeries, String> {
+    settings.validate()?;
+    let records = records(session, settings);
+    let interval = settings.interval.as_secs_f64();
+    let mut series = TimeSeries {
+        start: Duration::ZERO,
+        interval: settings.interval,
+        points: Vec::new(),
+    };
+    let (Some(first), Some(last)) = (records.first(), records.last()) else {
+        return Ok(series);
+    };
+    // From the earliest sample, when the first one received wasn't it
+    let earliest = first.time.min(0.0);
+    let end = ((last.time - earliest) / interval).floor();
+    if end >= f64::from(u32::MAX) {
+        return Err(String::from(
+            "resample interval is too short for the session",
+        ));
+    }
+    let start = ((first.time - earliest) / interval).ceil() as u32;
+    let end = end as u32;
+    series.start = settings.interval * start;
+    let times: Vec<_> = records.iter().map(|r| r.time).collect();
+    series.points = (start..=end)
+        .map(|i| {
+            let time = earliest + interval * f64::from(i);
+            point(&records, &times, time, settings).map(|point| Point {
+                time: settings.interval * i,
+                ..point
+            })
+        })
+        .collect();
+    Ok(series)
+}
+
+/*
+Puts each completed lap onto a uniform distance base from the start/finish
+line, with point times from the start of the lap.
+*/
+pub fn resample_laps<L: Into<TimingLines>>(
+    session: &Session,
+    lines: L,
+    settings: &ResampleSettings,
+    spacing: Length,
+) -> Result<Vec<DistanceSeries>, String> {
+    if spacing.meters().is_nan() || spacing.meters() <= 0.0 {
+        return Err(String::from("resample spacing must be more than zero"));
+    }
+    let records = records(session, settings);
+    let times: Vec<_> = records.iter().map(|r| r.time).collect();
+    let distances: Vec<_> = records.iter().map(|r| r.distance).collect();
+    let distance_at = |time: f64| {
+        let (i, u) = bracket(&times, time)?;
+        Some(lerp(distances[i], distances[i + 1], u))
+    };
+
+    let timer = session_timer(session, lines);
+    let laps = timer
+        .laps()
+        .iter()
+        .filter_map(|lap| {
+            let start = lap.start.as_secs_f64();
+            let from = distance_at(start)?;
+            let length = distance_at(lap.end.as_secs_f64())? - from;
+            let count = (length / spacing.meters()).floor() as usize + 1;
+            let points = (0..count)
+                .map(|i| {
+                    let distance = from + spacing.meters() * i as f64;
+                    let point = point(&records, &distances, distance, settings)?;
+                    let time = point.time.as_secs_f64() - start;
+                    Some(Point {
+                        time: Duration::from_secs_f64(time.max(0.0)),
+                        ..point
+                    })
+                })
+                .collect();
+            Some(DistanceSeries {
+                lap: lap.number,
+                spacing,
+                points,
+            })
+        })
+        .collect();
+    Ok(laps)
+}
+
+/*
+The records either side of a position on the base and how far between them it
+is, None outside the records.
+*/
+fn bracket(base: &[f64], x: f64) -> Option<(usize, f64)> {
+    let (first, last) = (*base.first()?, *base.last()?);
+    if base.len() < 2 || x < first || x > last {
+        return None;
+    }
+    let i = base.partition_point(|&b| b <= x).clamp(1, base.len() - 1) - 1;
+    let span = base[i + 1] - base[i];
+    let u = if span > 0.0 {
+        (x - base[i]) / span
+    } else {
+        0.0
+    };
+    Some((i, u))
+}
+
+fn point(records: &[Record], base: &[f64], x: f64, settings: &ResampleSettings) -> Option<Point> {
+    let (i, u) = bracket(base, x)?;
+    let gap = settings.max_gap.as_secs_f64();
+    // A point right on a sample either side of a gap is still that sample
+    if u > 0.0 && u < 1.0 && records[i + 1].time - records[i].time > gap {
+        return None;
+    }
+    let interpolator = Interpolator {
+        records,
+        base,
+        gap,
+        i,
+        u,
+    };
+
+    let coordinates = match settings.position {
+        Interpolation::GreatCircle => {
+            let (a, b) = (&records[i], &records[i + 1]);
+            great_circle((a.latitude, a.longitude), (b.latitude, b.longitude), u)
+        }
+        method => Coordinates::from_degrees(
+            interpolator.channel(method, |r| r.latitude, false),
+            interpolator.channel(method, |r| r.longitude, true),
+        ),
+    };
+    let imu = settings.imu;
+    let g =
+        |axis: usize| Acceleration::from_g(interpolator.channel(imu, |r| r.g_forces[axis], false));
+    let rate = |axis: usize| {
+        AngularRate::from_degrees_per_second(interpolator.channel(
+            imu,
+            |r| r.rot_rates[axis],
+            false,
+        ))
+    };
+    Some(Point {
+        time: Duration::from_secs_f64(
+            interpolator
+                .channel(Interpolation::Linear, |r| r.time, false)
+                .max(0.0),
+        ),
+        coordinates,
+        altitude: Length::from_meters(interpolator.channel(
+            settings.altitude,
+            |r| r.altitude,
+            false,
+        )),
+        speed: Speed::from_meters_per_second(interpolator.channel(
+            settings.speed,
+            |r| r.speed,
+            false,
+        )),
+        heading: Angle::from_degrees(
+            interpolator
+                .channel(settings.heading, |r| r.heading, true)
+                .rem_euclid(360.0),
+        ),
+        g_forces: (g(0), g(1), g(2)),
+        rot_rates: (rate(0), rate(1), rate(2)),
+    })
+}
+
+// Interpolates channels at u of the way from record i to i + 1
+struct Interpolator<'a> {
+    records: &'a [Record],
+    base: &'a [f64],
+    gap: f64,
+    i: usize,
+    u: f64,
+}
+
+impl Interpolator<'_> {
+    // wrap is for channels in degrees that go round, taken the short way
+    fn channel(&self, method: Interpolation, value: impl Fn(&Record) -> f64, wrap: bool) -> f64 {
+        let (records, base, i) = (self.records, self.base, self.i);
+        let p0 = value(&records[i]);
+        // Values near p0, unwrapped so they follow on from it
+        let near = |k: usize| {
+            let v = value(&records[k]);
+            if wrap {
+                p0 + (v - p0 + 180.0).rem_euclid(360.0) - 180.0
+            } else {
+                v
+            }
+        };
+        let p1 = near(i + 1);
+        if method != Interpolation::Cubic {
+            return lerp(p0, p1, self.u);
+        }
+
+        // Cubic Hermite, slopes from the neighbours on either side where there are any
+        let h = base[i + 1] - base[i];
+        if h <= 0.0 {
+            return p0;
+        }
+        let joined = |k: usize| records[k + 1].time - records[k].time <= self.gap;
+        let slope = |k: usize| {
+            let before = if k > 0 && joined(k - 1) { k - 1 } else { k };
+            let after = if k + 1 < records.len() && joined(k) {
+                k + 1
+            } else {
+                k
+            };
+            let span = base[after] - base[before];
+            if span > 0.0 {
+                (near(after) - near(before)) / span
+            } else {
+                (p1 - p0) / h
+            }
+        };
+        let (m0, m1) = (slope(i), slope(i + 1));
+        let (u, u2, u3) = (self.u, self.u * self.u, self.u.powi(3));
+        (2.0 * u3 - 3.0 * u2 + 1.0) * p0
+            + (u3 - 2.0 * u2 + u) * h * m0
+            + (-2.0 * u3 + 3.0 * u2) * p1
+            + (u3 - u2) * h * m1
+    }
+}
+
+fn lerp(a: f64, b: f64, u: f64) -> f64 {
+    a + (b - a) * u
+}
+
+// The point u of the way along the great circle between two points in degrees
+fn great_circle(a: (f64, f64), b: (f64, f64), u: f64) -> Coordinates {
+    let vector = |(latitude, longitude): (f64, f64)| {
+        let (latitude, longitude) = (latitude.to_radians(), longitude.to_radians());
+        [
+            latitude.cos() * longitude.cos(),
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+        ]
+    };
+    let (va, vb) = (vector(a), vector(b));
+    let dot: f64 = (0..3).map(|i| va[i] * vb[i]).sum();
+    let angle = dot.clamp(-1.0, 1.0).acos();
+    if angle < 1e-12 {
+        return Coordinates::from_degrees(a.0, a.1);
+    }
+    let (wa, wb) = (
+        ((1.0 - u) * angle).sin() / angle.sin(),
+        (u * angle).sin() / angle.sin(),
+    );
+    let [x, y, z]: [f64; 3] = std::array::from_fn(|i| wa * va[i] + wb * vb[i]);
+    Coordinates::from_degrees(z.atan2(x.hypot(y)).to_degrees(), y.atan2(x).to_degrees())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resample_laps, resample_time, Interpolation, ResampleSettings};
+    use crate::geodesy::project;
+    use crate::laps::Line;
+    use crate::message::{Coordinates, RbMessage};
+    use crate::session::Session;
+    use crate::testing::{at, fix};
+    use crate::units::{Acceleration, Angle, Length};
+    use std::f64::consts::PI;
+    use std::time::Duration;
+
+    fn message(itow: u32, coordinates: Coordinates, heading: f64, g: f64) -> RbMessage {
+        fix(itow)
+            .coordinates(coordinates)
+            .speed_mps(20.0)
+            .heading(Angle::from_degrees(heading))
+            .g_forces(
+                Acceleration::from_g(g),
+                Acceleration::from_g(0.0),
+                Acceleration::from_g(1.0),
+            )
+            .build()
+    }
+
+    /*
+    North at 20 m/s for 10 seconds from itow 1000, with a 1 Hz swing in the X
+    axis g. One sample is lost at 2 seconds and a whole second from 5 seconds.
+    */
+    fn session() -> Session {
+        let mut session = Session::default();
+        for i in 0..250 {
+            if i == 50 || (125..150).contains(&i) {
+                continue;
+            }
+            let t = f64::from(i) * 0.04;
+            let g = (2.0 * PI * t).sin();
+            session.push(message(1000 + i * 40, at(0.0, 20.0 * t), 0.0, g));
+        }
+        session
+    }
+
+    #[test]
+    fn test_resample_time() {
+        let session = session();
+        let settings = ResampleSettings::hz(100.0).unwrap();
+        let series = resample_time(&session, &settings).unwrap();
+        assert_eq!(series.interval, Duration::from_millis(10));
+        assert_eq!(series.start, Duration::ZERO);
+        assert_eq!(series.points.len(), 997);
+        assert_eq!(series.time(150), Duration::from_millis(1500));
+
+        for (i, point) in series.points.iter().enumerate() {
+            let t = i as f64 * 0.01;
+            // Inside the second lost, the samples either side are too far apart
+            if (497..600).contains(&i) {
+                assert_eq!(point, &None, "{t}");
+                continue;
+            }
+            let point = point.unwrap();
+            assert_eq!(point.time, series.time(i));
+            let (x, y) = project(at(0.0, 0.0), point.coordinates);
+            assert!(x.abs() < 0.02 && (y - 20.0 * t).abs() < 0.02, "{t} {y}");
+            assert!((point.speed.meters_per_second() - 20.0).abs() < 1e-6);
+        }
+        // The single lost sample is bridged
+        assert!(series.points[200].is_some());
+
+        // Order comes from itow, not the order received
+        let mut shuffled = Session::default();
+        let messages = session.messages();
+        for i in [1, 0, 2, 3] {
+            shuffled.push(message(
+                messages[i].itow(),
+                messages[i].gps_coordinates(),
+                0.0,
+                messages[i].g_forces().0.g(),
+            ));
+        }
+        let series = resample_time(&shuffled, &settings).unwrap();
+        assert_eq!(series.points.len(), 13);
+        let (_, y) = project(at(0.0, 0.0), series.points[2].unwrap().coordinates);
+        assert!((y - 0.4).abs() < 0.02);
+
+        assert!(resample_time(&Session::default(), &settings)
+            .unwrap()
+            .points
+            .is_empty());
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(ResampleSettings::hz(0.0).is_err());
+        assert!(ResampleSettings::hz(-25.0).is_err());
+        assert!(ResampleSettings::hz(f64::NAN).is_err());
+        assert!(ResampleSettings::hz(1e12).is_err());
+
+        let session = session();
+        let zero = ResampleSettings {
+            interval: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(resample_time(&session, &zero).is_err());
+        let short = ResampleSettings {
+            interval: Duration::from_nanos(1),
+            ..Default::default()
+        };
+        assert!(resample_time(&session, &short).is_err());
+        let line = Line::new(at(-10.0, 0.0), at(10.0, 0.0));
+        let settings = ResampleSettings::default();
+        assert!(resample_laps(&session, line, &settings, Length::default()).is_err());
+    }
+
+    #[test]
+    fn test_cubic() {
+        let session = session();
+        let error = |imu| {
+            let settings = ResampleSettings {
+                imu,
+                ..ResampleSettings::hz(100.0).unwrap()
+            };
+            let series = resample_time(&session, &settings).unwrap();
+            series
+                .points
+                .iter()
+                .enumerate()
+                .filter_map(|(i, point)| Some((i as f64 * 0.01, (*point)?)))
+                .map(|(t, point)| (point.g_forces.0.g() - (2.0 * PI * t).sin()).abs())
+                .fold(0.0, f64::max)
+        };
+        let (linear, cubic) = (error(Interpolation::Linear), error(Interpolation::Cubic));
+        assert!(linear > 0.005, "{linear}");
+        assert!(cubic < linear / 3.0, "{cubic} {linear}");
+    }
+
+    #[test]
+    fn test_heading_and_great_circle() {
+        // A second apart, a long way apart at 60 degrees north
+        let mut session = Session::default();
+        session.push(message(0, Coordinates::from_degrees(60.0, 0.0), 350.0, 0.0));
+        session.push(message(
+            1000,
+            Coordinates::from_degrees(60.0, 10.0),
+            10.0,
+            0.0,
+        ));
+        let settings = ResampleSettings {
+            interval: Duration::from_millis(500),
+            max_gap: Duration::from_secs(2),
+            ..Default::default()
+        };
+        let series = resample_time(&session, &settings).unwrap();
+        assert_eq!(series.points.len(), 3);
+        let middle = series.points[1].unwrap();
+        // Headings go the short way round through north
+        let heading = middle.heading.degrees();
+        assert!(!(0.001..=359.999).contains(&heading), "{heading}");
+        // The great circle bulges towards the pole
+        assert!((middle.coordinates.longitude() - 5.0).abs() < 1e-6);
+        assert!(middle.coordinates.latitude() > 60.05);
+
+        let settings = ResampleSettings {
+            position: Interpolation::Linear,
+            ..settings
+        };
+        let middle = resample_time(&session, &settings).unwrap().points[1].unwrap();
+        assert_eq!(middle.coordinates, Coordinates::from_degrees(60.0, 5.0));
+    }
+
+    #[test]
+    fn test_resample_laps() {
+        // Anticlockwise laps of a 100m circle, the line across it at the bottom
+        let radius = 100.0;
+        let mut session = Session::default();
+        let mut angle = -PI / 2.0 - 0.1;
+        for i in 0..2000 {
+            let heading = (90.0 - angle.to_degrees() - 90.0).rem_euclid(360.0);
+            let position = at(radius * angle.cos(), radius * angle.sin());
+            session.push(message(i * 40, position, heading, 0.0));
+            angle += 0.8 / radius;
+        }
+        let line = Line::new(at(0.0, -120.0), at(0.0, -80.0));
+        let laps = resample_laps(
+            &session,
+            line,
+            &ResampleSettings::default(),
+            Length::from_meters(5.0),
+        )
+        .unwrap();
+        assert_eq!(laps.len(), 2);
+
+        let lap = 2.0 * PI * radius;
+        for series in &laps {
+            assert_eq!(series.points.len(), (lap / 5.0) as usize + 1);
+            for (i, point) in series.points.iter().enumerate() {
+                let point = point.unwrap();
+                let distance = series.distance(i).meters();
+                // At constant speed time follows distance
+                assert!((point.time.as_secs_f64() - distance / 20.0).abs() < 0.01);
+                // Round the circle from the line
+                let angle = -PI / 2.0 + distance / radius;
+                let (x, y) = project(at(0.0, 0.0), point.coordinates);
+                assert!((x - radius * angle.cos()).abs() < 0.1);
+                assert!((y - radius * angle.sin()).abs() < 0.1);
+            }
+        }
+        assert_eq!((laps[0].lap, laps[1].lap), (1, 2));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::laps::LapTimer;
+use crate::learn::learn_session;
+use crate::message::RbMessage;
+use crate::mounting::Mounting;
+use crate::tracks::{Track, TrackDatabase, TrackDetector};
+use serde::Deserialize;
+use serde::Serialize;
+
+/*
+Descriptive information about a recording. None of this comes from the RaceBox
+itself, it is supplied by the user and is carried into the headers of exported
+log files.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SessionMetadata {
+    pub event: String,
+    pub session: String,
+    pub venue: String,
+    pub vehicle: String,
+    pub driver: String,
+    pub comment: String,
+    pub serial: String, // RaceBox Mini serial number, see RbConnection
+    #[serde(default)]
+    pub mounting: Option<Mounting>, // already applied to the IMU data, see Mounting::apply_session
+}
+
+/*
+A recorded sequence of RaceBox Mini messages
+
+A session started with with_tracks() picks its track from the database while
+it is recorded, from the first usable position inside a geofence. The track is
+provisional until the configurations of a circuit have been told apart. A
+session read from a file has its track picked with detect_track().
+*/
+#[derive(Debug, Default)]
+pub struct Session {
+    pub metadata: SessionMetadata,
+    pub track: Option<Track>,
+    messages: Vec<RbMessage>,
+    detector: Option<TrackDetector>,
+}
+
+impl Session {
+    pub fn new(metadata: SessionMetadata) -> Self {
+        Session {
+            metadata,
+            track: None,
+            messages: Vec::new(),
+            detector: None,
+        }
+    }
+
+    pub fn with_tracks(metadata: SessionMetadata, database: &TrackDatabase) -> Self {
+        Session {
+            detector: Some(TrackDetector::new(database)),
+            ..Session::new(metadata)
+        }
+    }
+
+    pub fn push(&mut self, message: RbMessage) {
+        if let Some(detector) = &mut self.detector {
+            let decided = detector.update(&message).is_some();
+            if decided || self.track.is_none() {
+                let track = detector.track().cloned();
+                if decided {
+                    self.detector = None;
+                }
+                if let Some(track) = track {
+                    self.set_track(track);
+                }
+            }
+        }
+        self.messages.push(message);
+    }
+
+    pub fn messages(&self) -> &[RbMessage] {
+        &self.messages
+    }
+
+    pub(crate) fn messages_mut(&mut self) -> &mut [RbMessage] {
+        &mut self.messages
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /*
+    Picks the track the session was recorded at from the database, and fills in
+    the venue if the user hasn't set one. Keeps the most likely configuration if
+    the recording never tells them apart.
+    */
+    pub fn detect_track(&mut self, database: &TrackDatabase) -> Option<&Track> {
+        let mut detector = TrackDetector::new(database);
+        for message in &self.messages {
+            if detector.update(message).is_some() {
+                break;
+            }
+        }
+        self.set_track(detector.track()?.clone());
+        self.track.as_ref()
+    }
+
+    // The venue follows the detected track unless the user has named it
+    fn set_track(&mut self, track: Track) {
+        let detected = self.track.as_ref().map(Track::full_name);
+        if self.metadata.venue.is_empty() || detected.as_ref() == Some(&self.metadata.venue) {
+            self.metadata.venue = track.full_name();
+        }
+        self.track = Some(track);
+    }
+
+    /*
+    A lap timer run over the session, on the detected track or, at a circuit
+    the database doesn't know, on a start/finish line learned from the driving.
+    None if there is no track and no line could be learned.
+    */
+    pub fn lap_timer(&self) -> Option<LapTimer> {
+        let mut timer = match &self.track {
+            Some(track) => track.lap_timer(),
+            None => learn_session(self)?.lap_timer(),
+        };
+        for message in &self.messages {
+            timer.update(message);
+        }
+        Some(timer)
+    }
+}
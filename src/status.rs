@@ -0,0 +1,429 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/*
+Typed views of the RaceBox Mini status fields
+
+The enums decode multi-bit fields, the flag structs wrap a raw bitmask byte and
+name each bit. Values the protocol doesn't define are kept rather than dropped so
+nothing is silently misreported.
+*/
+
+/*
+Fix Status
+0 - no fix
+2 - 2d fix
+3 - 3d fix
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FixStatus {
+    NoFix,
+    Fix2D,
+    Fix3D,
+    Unknown(u8),
+}
+
+impl From<u8> for FixStatus {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => FixStatus::NoFix,
+            2 => FixStatus::Fix2D,
+            3 => FixStatus::Fix3D,
+            _ => FixStatus::Unknown(raw),
+        }
+    }
+}
+
+impl From<FixStatus> for u8 {
+    fn from(status: FixStatus) -> Self {
+        match status {
+            FixStatus::NoFix => 0,
+            FixStatus::Fix2D => 2,
+            FixStatus::Fix3D => 3,
+            FixStatus::Unknown(raw) => raw,
+        }
+    }
+}
+
+// Power save mode state, bits 4..2 of the fix status flags
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    NotActive,
+    Enabled,
+    Acquisition,
+    Tracking,
+    PowerOptimizedTracking,
+    Inactive,
+    Unknown(u8),
+}
+
+impl From<u8> for PowerState {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => PowerState::NotActive,
+            1 => PowerState::Enabled,
+            2 => PowerState::Acquisition,
+            3 => PowerState::Tracking,
+            4 => PowerState::PowerOptimizedTracking,
+            5 => PowerState::Inactive,
+            _ => PowerState::Unknown(raw),
+        }
+    }
+}
+
+// Carrier phase range solution, bits 7..6 of the fix status flags
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CarrierPhaseSolution {
+    None,
+    Float,
+    Fixed,
+    Unknown(u8),
+}
+
+impl From<u8> for CarrierPhaseSolution {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => CarrierPhaseSolution::None,
+            1 => CarrierPhaseSolution::Float,
+            2 => CarrierPhaseSolution::Fixed,
+            _ => CarrierPhaseSolution::Unknown(raw),
+        }
+    }
+}
+
+// Age of the most recently received differential correction, bits 4..1 of the lat/lon flags
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorrectionAge {
+    NotAvailable,
+    UpTo1s,
+    UpTo2s,
+    UpTo5s,
+    UpTo10s,
+    UpTo15s,
+    UpTo20s,
+    UpTo30s,
+    UpTo45s,
+    UpTo60s,
+    UpTo90s,
+    UpTo120s,
+    Over120s,
+    Unknown(u8),
+}
+
+impl From<u8> for CorrectionAge {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => CorrectionAge::NotAvailable,
+            1 => CorrectionAge::UpTo1s,
+            2 => CorrectionAge::UpTo2s,
+            3 => CorrectionAge::UpTo5s,
+            4 => CorrectionAge::UpTo10s,
+            5 => CorrectionAge::UpTo15s,
+            6 => CorrectionAge::UpTo20s,
+            7 => CorrectionAge::UpTo30s,
+            8 => CorrectionAge::UpTo45s,
+            9 => CorrectionAge::UpTo60s,
+            10 => CorrectionAge::UpTo90s,
+            11 => CorrectionAge::UpTo120s,
+            12 => CorrectionAge::Over120s,
+            _ => CorrectionAge::Unknown(raw),
+        }
+    }
+}
+
+impl CorrectionAge {
+    // Range of the correction age in seconds as (at least, less than), None when not available
+    pub fn seconds(&self) -> Option<(u32, Option<u32>)> {
+        let range = match self {
+            CorrectionAge::UpTo1s => (0, Some(1)),
+            CorrectionAge::UpTo2s => (1, Some(2)),
+            CorrectionAge::UpTo5s => (2, Some(5)),
+            CorrectionAge::UpTo10s => (5, Some(10)),
+            CorrectionAge::UpTo15s => (10, Some(15)),
+            CorrectionAge::UpTo20s => (15, Some(20)),
+            CorrectionAge::UpTo30s => (20, Some(30)),
+            CorrectionAge::UpTo45s => (30, Some(45)),
+            CorrectionAge::UpTo60s => (45, Some(60)),
+            CorrectionAge::UpTo90s => (60, Some(90)),
+            CorrectionAge::UpTo120s => (90, Some(120)),
+            CorrectionAge::Over120s => (120, None),
+            CorrectionAge::NotAvailable | CorrectionAge::Unknown(_) => return None,
+        };
+        Some(range)
+    }
+}
+
+fn bit(raw: u8, n: u8) -> bool {
+    raw >> n & 1 == 1
+}
+
+/*
+Validity Flags
+Bit 0 - valid date
+Bit 1 - valid time
+Bit 2 - fully resolved
+Bit 3 - valid magnetic declination
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Validity(pub u8);
+
+impl Validity {
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn valid_date(&self) -> bool {
+        bit(self.0, 0)
+    }
+
+    pub fn valid_time(&self) -> bool {
+        bit(self.0, 1)
+    }
+
+    pub fn fully_resolved(&self) -> bool {
+        bit(self.0, 2)
+    }
+
+    pub fn valid_magnetic_declination(&self) -> bool {
+        bit(self.0, 3)
+    }
+}
+
+/*
+Fix Status Flags
+Bit 0 - valid fix
+Bit 1 - differential corrections applied
+Bit 4..2 - power state
+Bit 5 - valid heading
+Bit 7..6 - carrier phase range solution
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FixFlags(pub u8);
+
+impl FixFlags {
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn valid_fix(&self) -> bool {
+        bit(self.0, 0)
+    }
+
+    pub fn differential_corrections_applied(&self) -> bool {
+        bit(self.0, 1)
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        PowerState::from(self.0 >> 2 & 0b111)
+    }
+
+    pub fn valid_heading(&self) -> bool {
+        bit(self.0, 5)
+    }
+
+    pub fn carrier_phase_solution(&self) -> CarrierPhaseSolution {
+        CarrierPhaseSolution::from(self.0 >> 6 & 0b11)
+    }
+}
+
+/*
+Date Time Flags
+Bit 5 - available confirmation of date/time validity
+Bit 6 - confirmed UTC date validity
+Bit 7 - confirmed UTC time validity
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DateTimeFlags(pub u8);
+
+impl DateTimeFlags {
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn confirmation_available(&self) -> bool {
+        bit(self.0, 5)
+    }
+
+    pub fn confirmed_date(&self) -> bool {
+        bit(self.0, 6)
+    }
+
+    pub fn confirmed_time(&self) -> bool {
+        bit(self.0, 7)
+    }
+}
+
+/*
+Lat/Lon Flags
+Bit 0 - 1 = Invalid lat, long, wgs altitude, and msl altitude
+Bit 4..1 - Differential Correction Age
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatLonFlags(pub u8);
+
+impl LatLonFlags {
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn invalid_position(&self) -> bool {
+        bit(self.0, 0)
+    }
+
+    pub fn correction_age(&self) -> CorrectionAge {
+        CorrectionAge::from(self.0 >> 1 & 0b1111)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CarrierPhaseSolution, CorrectionAge, DateTimeFlags, FixFlags, FixStatus, LatLonFlags,
+        PowerState, Validity,
+    };
+
+    #[test]
+    fn test_fix_status() {
+        assert_eq!(FixStatus::from(0), FixStatus::NoFix);
+        assert_eq!(FixStatus::from(1), FixStatus::Unknown(1));
+        assert_eq!(FixStatus::from(2), FixStatus::Fix2D);
+        assert_eq!(FixStatus::from(3), FixStatus::Fix3D);
+        assert_eq!(FixStatus::from(4), FixStatus::Unknown(4));
+        for raw in 0..=255u8 {
+            assert_eq!(u8::from(FixStatus::from(raw)), raw);
+        }
+    }
+
+    #[test]
+    fn test_validity() {
+        let bits = |v: Validity| {
+            [
+                v.valid_date(),
+                v.valid_time(),
+                v.fully_resolved(),
+                v.valid_magnetic_declination(),
+            ]
+        };
+        assert_eq!(bits(Validity(0)), [false; 4]);
+        for n in 0..4 {
+            let mut expected = [false; 4];
+            expected[n] = true;
+            assert_eq!(bits(Validity(1 << n)), expected);
+        }
+        // Upper bits are unused
+        assert_eq!(bits(Validity(0xF0)), [false; 4]);
+        assert_eq!(Validity(0x37).bits(), 0x37);
+    }
+
+    #[test]
+    fn test_fix_flags() {
+        let bits = |f: FixFlags| {
+            [
+                f.valid_fix(),
+                f.differential_corrections_applied(),
+                f.valid_heading(),
+            ]
+        };
+        assert_eq!(bits(FixFlags(0)), [false; 3]);
+        assert_eq!(bits(FixFlags(1 << 0)), [true, false, false]);
+        assert_eq!(bits(FixFlags(1 << 1)), [false, true, false]);
+        assert_eq!(bits(FixFlags(1 << 5)), [false, false, true]);
+
+        // Power state and carrier solution don't leak into the single bit flags
+        assert_eq!(bits(FixFlags(0b1101_1100)), [false; 3]);
+
+        assert_eq!(FixFlags(0).power_state(), PowerState::NotActive);
+        assert_eq!(FixFlags(1 << 2).power_state(), PowerState::Enabled);
+        assert_eq!(FixFlags(2 << 2).power_state(), PowerState::Acquisition);
+        assert_eq!(FixFlags(3 << 2).power_state(), PowerState::Tracking);
+        assert_eq!(
+            FixFlags(4 << 2).power_state(),
+            PowerState::PowerOptimizedTracking
+        );
+        assert_eq!(FixFlags(5 << 2).power_state(), PowerState::Inactive);
+        assert_eq!(FixFlags(6 << 2).power_state(), PowerState::Unknown(6));
+        assert_eq!(FixFlags(7 << 2).power_state(), PowerState::Unknown(7));
+        assert_eq!(FixFlags(0b1110_0011).power_state(), PowerState::NotActive);
+
+        assert_eq!(
+            FixFlags(0).carrier_phase_solution(),
+            CarrierPhaseSolution::None
+        );
+        assert_eq!(
+            FixFlags(1 << 6).carrier_phase_solution(),
+            CarrierPhaseSolution::Float
+        );
+        assert_eq!(
+            FixFlags(2 << 6).carrier_phase_solution(),
+            CarrierPhaseSolution::Fixed
+        );
+        assert_eq!(
+            FixFlags(3 << 6).carrier_phase_solution(),
+            CarrierPhaseSolution::Unknown(3)
+        );
+        assert_eq!(
+            FixFlags(0b0011_1111).carrier_phase_solution(),
+            CarrierPhaseSolution::None
+        );
+    }
+
+    #[test]
+    fn test_date_time_flags() {
+        let bits = |f: DateTimeFlags| {
+            [
+                f.confirmation_available(),
+                f.confirmed_date(),
+                f.confirmed_time(),
+            ]
+        };
+        assert_eq!(bits(DateTimeFlags(0)), [false; 3]);
+        assert_eq!(bits(DateTimeFlags(1 << 5)), [true, false, false]);
+        assert_eq!(bits(DateTimeFlags(1 << 6)), [false, true, false]);
+        assert_eq!(bits(DateTimeFlags(1 << 7)), [false, false, true]);
+        // Bits 4..0 are unused
+        assert_eq!(bits(DateTimeFlags(0b0001_1111)), [false; 3]);
+        assert_eq!(bits(DateTimeFlags(0xEA)), [true; 3]);
+    }
+
+    #[test]
+    fn test_lat_lon_flags() {
+        assert!(!LatLonFlags(0).invalid_position());
+        assert!(LatLonFlags(1).invalid_position());
+        assert!(!LatLonFlags(0b1111_1110).invalid_position());
+
+        let expected = [
+            CorrectionAge::NotAvailable,
+            CorrectionAge::UpTo1s,
+            CorrectionAge::UpTo2s,
+            CorrectionAge::UpTo5s,
+            CorrectionAge::UpTo10s,
+            CorrectionAge::UpTo15s,
+            CorrectionAge::UpTo20s,
+            CorrectionAge::UpTo30s,
+            CorrectionAge::UpTo45s,
+            CorrectionAge::UpTo60s,
+            CorrectionAge::UpTo90s,
+            CorrectionAge::UpTo120s,
+            CorrectionAge::Over120s,
+            CorrectionAge::Unknown(13),
+            CorrectionAge::Unknown(14),
+            CorrectionAge::Unknown(15),
+        ];
+        for (raw, age) in expected.iter().enumerate() {
+            let flags = LatLonFlags((raw as u8) << 1);
+            assert_eq!(flags.correction_age(), *age);
+            assert!(!flags.invalid_position());
+            // The invalid bit and unused upper bits don't change the age
+            assert_eq!(LatLonFlags(flags.bits() | 0xE1).correction_age(), *age);
+        }
+    }
+
+    #[test]
+    fn test_correction_age_seconds() {
+        assert_eq!(CorrectionAge::NotAvailable.seconds(), None);
+        assert_eq!(CorrectionAge::UpTo1s.seconds(), Some((0, Some(1))));
+        assert_eq!(CorrectionAge::UpTo45s.seconds(), Some((30, Some(45))));
+        assert_eq!(CorrectionAge::Over120s.seconds(), Some((120, None)));
+        assert_eq!(CorrectionAge::Unknown(14).seconds(), None);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::geodesy::unproject;
+use crate::message::builder::RbMessageBuilder;
+use crate::message::{Coordinates, RbMessage};
+use crate::status::{FixFlags, FixStatus};
+use crate::units::{Length, Speed};
+use std::f64::consts::PI;
+
+/*
+Fixtures shared by the test modules. Positions are in metres east and north of
+the origin, and fix() starts a message with a good 3D fix for the test to set
+the fields it cares about on.
+*/
+
+// The sample data packet the tests decode, stationary with an 11 satellite 3D fix
+pub(crate) const RAW: [u8; 88] = [
+    0xB5, 0x62, 0xFF, 0x01, 0x50, 0x00, 0xA0, 0xE7, 0x0C, 0x07, 0xE6, 0x07, 0x01, 0x0A, 0x08, 0x33,
+    0x08, 0x37, 0x19, 0x00, 0x00, 0x00, 0x2A, 0xAD, 0x4D, 0x0E, 0x03, 0x01, 0xEA, 0x0B, 0xC6, 0x93,
+    0xE1, 0x0D, 0x3B, 0x37, 0x6F, 0x19, 0x61, 0x8C, 0x09, 0x00, 0x0F, 0x01, 0x09, 0x00, 0x9C, 0x03,
+    0x00, 0x00, 0x2C, 0x07, 0x00, 0x00, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xD0, 0x00,
+    0x00, 0x00, 0x88, 0xA9, 0xDD, 0x00, 0x2C, 0x01, 0x00, 0x59, 0xFD, 0xFF, 0x71, 0x00, 0xCE, 0x03,
+    0x2F, 0xFF, 0x56, 0x00, 0xFC, 0xFF, 0x06, 0xDB,
+];
+
+pub(crate) fn origin() -> Coordinates {
+    Coordinates::from_degrees(42.6719035, 23.2887238)
+}
+
+// Coordinates x metres east and y metres north of the origin
+pub(crate) fn at(x: f64, y: f64) -> Coordinates {
+    unproject(origin(), (x, y))
+}
+
+// A message at itow with a good 3D fix at the origin
+pub(crate) fn fix(itow: u32) -> RbMessageBuilder {
+    RbMessage::builder()
+        .itow(itow)
+        .fix_status(FixStatus::Fix3D)
+        .fix_flags(FixFlags(0b10_0001))
+        .satellites(12)
+        .horiz_accuracy(Length::from_meters(0.5))
+        .vert_accuracy(Length::from_meters(1.0))
+        .speed_accuracy(Speed::from_meters_per_second(0.1))
+        .coordinates(origin())
+}
+
+/*
+Four laps of a 100m radius circle anticlockwise, the line is due east. The first
+lap is at 22 m/s until the car slows to 10 m/s just before the line, then it
+stops for 20s 0.3 radians into the second lap, which is an out lap once the pit
+stop is seen. The car is back to 20 m/s 0.6 radians round, and the last two laps
+are at 20 m/s.
+*/
+pub(crate) fn fast_in_lap() -> Vec<RbMessage> {
+    let mut messages = Vec::new();
+    let (mut angle, mut itow, mut stopped) = (-0.3, 0, 0.0);
+    while angle < 8.0 * PI + 0.2 {
+        let speed = match angle {
+            a if a < 2.0 * PI - 0.05 => 22.0,
+            a if a < 2.0 * PI + 0.6 => 10.0,
+            _ => 20.0,
+        };
+        let stopping = angle >= 2.0 * PI + 0.3 && stopped < 20.0;
+        let speed = if stopping { 0.0 } else { speed };
+        let position = at(100.0 * angle.cos(), 100.0 * angle.sin());
+        messages.push(fix(itow).speed_mps(speed).coordinates(position).build());
+        itow += 40;
+        if stopping {
+            stopped += 0.04;
+        } else {
+            angle += speed * 0.04 / 100.0;
+        }
+    }
+    messages
+}
@@ -0,0 +1,645 @@
+use crate::geodesy::project;
+use crate::laps::{LapTimer, Line, Side, TimingLines};
+use crate::message::{Coordinates, RbMessage};
+use crate::pits::PitLane;
+use crate::quality::{FixQuality, QualityThresholds};
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+/*
+Track database
+
+Each entry is one configuration of a circuit: its timing lines, the geofence
+polygon used to recognise it, the pit lane polygon and optionally the direction
+the finish line is crossed in and where the coordinates came from. The database
+bundled in tracks/tracks.json is empty for now, users load their own files on
+top of it and sessions at unknown circuits are timed on a learned line. Entries
+added to it need surveyed coordinates with their source recorded, and timing
+lines no longer than the track is wide so they can't clip the pit lane.
+
+The file format is JSON with points as [latitude, longitude] in degrees:
+
+    {
+      "version": 1,
+      "tracks": [
+        {
+          "name": "Example Raceway",
+          "configuration": "Full",
+          "geofence": [[lat, lon], [lat, lon], [lat, lon], ...],
+          "finish": [[lat, lon], [lat, lon]],
+          "splits": [[[lat, lon], [lat, lon]], ...],
+          "pit_lane": [[lat, lon], [lat, lon], [lat, lon], ...],
+          "direction": "left",
+          "source": "Survey by the circuit, 2024"
+        }
+      ]
+    }
+
+configuration, splits, pit_lane, direction and source are optional, though the
+tests require a source for every bundled entry. direction is the side of the
+finish line, looking from its first point to its second, that cars approach
+from. Without it the lap timer takes the direction of the first crossing.
+*/
+
+pub const TRACKS_VERSION: u32 = 1;
+
+const BUNDLED_TRACKS: &str = include_str!("../tracks/tracks.json");
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    pub name: String,
+    pub configuration: String,
+    pub geofence: Vec<Coordinates>,
+    pub lines: TimingLines,
+    pub pit_lane: Vec<Coordinates>,
+    pub direction: Option<Side>,
+    // Where the coordinates came from, empty if unknown
+    pub source: String,
+}
+
+impl Track {
+    // True if the point is inside the track's geofence
+    pub fn contains(&self, point: Coordinates) -> bool {
+        in_polygon(&self.geofence, point)
+    }
+
+    pub fn in_pit_lane(&self, point: Coordinates) -> bool {
+        in_polygon(&self.pit_lane, point)
+    }
+
+    // A lap timer set up with the track's lines, direction and pit lane
+    pub fn lap_timer(&self) -> LapTimer {
+        let mut timer = LapTimer::new(self.lines.clone());
+        if let Some(direction) = self.direction {
+            timer.set_direction(direction);
+        }
+        timer.set_pit_lane(PitLane::new(self.pit_lane.clone()));
+        timer
+    }
+
+    // Name and configuration, as used for a session's venue
+    pub fn full_name(&self) -> String {
+        if self.configuration.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, self.configuration)
+        }
+    }
+
+    // Area inside the geofence in square metres
+    fn geofence_area(&self) -> f64 {
+        let Some(origin) = self.geofence.first() else {
+            return 0.0;
+        };
+        let points: Vec<_> = self.geofence.iter().map(|p| project(*origin, *p)).collect();
+        let doubled: f64 = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+            .sum();
+        doubled.abs() / 2.0
+    }
+}
+
+// Even-odd test of a point against a polygon, projected around its first vertex
+pub(crate) fn in_polygon(polygon: &[Coordinates], point: Coordinates) -> bool {
+    let Some(origin) = polygon.first() else {
+        return false;
+    };
+    let (x, y) = project(*origin, point);
+    let vertices: Vec<_> = polygon.iter().map(|p| project(*origin, *p)).collect();
+    let mut inside = false;
+    for (a, b) in vertices.iter().zip(vertices.iter().cycle().skip(1)) {
+        if (a.1 > y) != (b.1 > y) && x < a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+type Point = [f64; 2]; // latitude, longitude
+
+fn from_point(point: Point) -> Coordinates {
+    Coordinates::from_degrees(point[0], point[1])
+}
+
+fn to_point(coordinates: Coordinates) -> Point {
+    [coordinates.latitude(), coordinates.longitude()]
+}
+
+fn from_line(line: [Point; 2]) -> Line {
+    Line::new(from_point(line[0]), from_point(line[1]))
+}
+
+fn to_line(line: Line) -> [Point; 2] {
+    [to_point(line.a), to_point(line.b)]
+}
+
+// A track as stored in the file
+#[derive(Serialize, Deserialize, Debug)]
+struct TrackEntry {
+    name: String,
+    #[serde(default)]
+    configuration: String,
+    geofence: Vec<Point>,
+    finish: [Point; 2],
+    #[serde(default)]
+    splits: Vec<[Point; 2]>,
+    #[serde(default)]
+    pit_lane: Vec<Point>,
+    #[serde(default)]
+    direction: Option<Side>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    source: String,
+}
+
+impl TrackEntry {
+    fn from_track(track: &Track) -> Self {
+        TrackEntry {
+            name: track.name.clone(),
+            configuration: track.configuration.clone(),
+            geofence: track.geofence.iter().copied().map(to_point).collect(),
+            finish: to_line(track.lines.finish),
+            splits: track.lines.splits.iter().copied().map(to_line).collect(),
+            pit_lane: track.pit_lane.iter().copied().map(to_point).collect(),
+            direction: track.direction,
+            source: track.source.clone(),
+        }
+    }
+
+    fn to_track(&self) -> Result<Track, String> {
+        if self.geofence.len() < 3 {
+            return Err(format!("{}: geofence needs at least 3 points", self.name));
+        }
+        if !self.pit_lane.is_empty() && self.pit_lane.len() < 3 {
+            return Err(format!("{}: pit lane needs at least 3 points", self.name));
+        }
+        Ok(Track {
+            name: self.name.clone(),
+            configuration: self.configuration.clone(),
+            geofence: self.geofence.iter().copied().map(from_point).collect(),
+            lines: TimingLines {
+                finish: from_line(self.finish),
+                splits: self.splits.iter().copied().map(from_line).collect(),
+            },
+            pit_lane: self.pit_lane.iter().copied().map(from_point).collect(),
+            direction: self.direction,
+            source: self.source.clone(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TrackFile {
+    version: u32,
+    tracks: Vec<TrackEntry>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackDatabase {
+    tracks: Vec<Track>,
+}
+
+impl TrackDatabase {
+    // The tracks shipped with rbmini
+    pub fn bundled() -> Self {
+        // The bundled file is checked by the tests, it always parses
+        Self::read_json(BUNDLED_TRACKS.as_bytes()).unwrap()
+    }
+
+    pub fn read_json<R: Read>(reader: R) -> Result<Self, String> {
+        let file: TrackFile = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+        if file.version != TRACKS_VERSION {
+            return Err(format!("unsupported track file version {}", file.version));
+        }
+        let tracks = file
+            .tracks
+            .iter()
+            .map(TrackEntry::to_track)
+            .collect::<Result<_, _>>()?;
+        Ok(TrackDatabase { tracks })
+    }
+
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let file = TrackFile {
+            version: TRACKS_VERSION,
+            tracks: self.tracks.iter().map(TrackEntry::from_track).collect(),
+        };
+        serde_json::to_writer_pretty(writer, &file).map_err(io::Error::from)
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    // Adds a track, replacing one with the same name and configuration
+    pub fn insert(&mut self, track: Track) {
+        match self
+            .tracks
+            .iter_mut()
+            .find(|t| t.name == track.name && t.configuration == track.configuration)
+        {
+            Some(existing) => *existing = track,
+            None => self.tracks.push(track),
+        }
+    }
+
+    // Adds all the tracks of another database, such as a user's file over the bundled one
+    pub fn extend(&mut self, other: TrackDatabase) {
+        for track in other.tracks {
+            self.insert(track);
+        }
+    }
+
+    pub fn find(&self, name: &str, configuration: &str) -> Option<&Track> {
+        self.tracks
+            .iter()
+            .find(|t| t.name == name && t.configuration == configuration)
+    }
+
+    /*
+    Tracks whose geofence contains the point, the smallest geofence first so a
+    club circuit inside a bigger venue is preferred. Configurations sharing a
+    geofence keep their order in the database.
+    */
+    pub fn candidates(&self, point: Coordinates) -> Vec<&Track> {
+        let mut candidates: Vec<_> = self.tracks.iter().filter(|t| t.contains(point)).collect();
+        candidates.sort_by(|a, b| a.geofence_area().total_cmp(&b.geofence_area()));
+        candidates
+    }
+}
+
+/*
+Works out which track a message stream is at. The first usable position inside
+any geofence picks the tracks whose geofence it is in. When that leaves more
+than one, the configurations of a circuit, the first one whose finish line is
+crossed (in its direction, if it has one) is chosen. Until then the most likely
+candidate is reported as provisional.
+*/
+#[derive(Clone, Debug)]
+pub struct TrackDetector {
+    database: TrackDatabase,
+    thresholds: QualityThresholds,
+    candidates: Option<Vec<Track>>,
+    previous: Option<Coordinates>,
+    selected: Option<usize>,
+}
+
+impl TrackDetector {
+    pub fn new(database: &TrackDatabase) -> Self {
+        TrackDetector {
+            database: database.clone(),
+            thresholds: QualityThresholds::default(),
+            candidates: None,
+            previous: None,
+            selected: None,
+        }
+    }
+
+    // Returns the track when it has been decided
+    pub fn update(&mut self, message: &RbMessage) -> Option<&Track> {
+        if self.selected.is_some() || !FixQuality::assess(message, &self.thresholds).is_usable() {
+            return None;
+        }
+        let point = message.gps_coordinates();
+        let previous = self.previous.replace(point);
+
+        // Keep looking until a position falls inside a geofence, arriving from the road
+        if self.candidates.as_ref().is_none_or(|c| c.is_empty()) {
+            let candidates = self.database.candidates(point);
+            self.candidates = Some(candidates.into_iter().cloned().collect());
+        }
+        let candidates = self.candidates.as_ref()?;
+        self.selected = if candidates.len() == 1 {
+            Some(0)
+        } else {
+            let previous = previous?;
+            candidates
+                .iter()
+                .position(|track| match track.lines.finish.crossing(previous, point) {
+                    Some((_, side)) => track.direction.is_none_or(|d| d == side),
+                    None => false,
+                })
+        };
+        candidates.get(self.selected?)
+    }
+
+    // The chosen track, or the best guess while configurations are still being told apart
+    pub fn track(&self) -> Option<&Track> {
+        let candidates = self.candidates.as_ref()?;
+        candidates.get(self.selected.unwrap_or(0))
+    }
+
+    pub fn is_decided(&self) -> bool {
+        self.selected.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{in_polygon, Track, TrackDatabase, TrackDetector, TRACKS_VERSION};
+    use crate::laps::{Line, Side, TimingLines};
+    use crate::message::{Coordinates, RbMessage};
+    use crate::session::{Session, SessionMetadata};
+    use crate::testing::{at, fix};
+    use std::f64::consts::PI;
+
+    fn square(half: f64) -> Vec<Coordinates> {
+        vec![
+            at(-half, -half),
+            at(half, -half),
+            at(half, half),
+            at(-half, half),
+        ]
+    }
+
+    fn track(name: &str, configuration: &str, half: f64, finish: Line) -> Track {
+        Track {
+            name: name.to_string(),
+            configuration: configuration.to_string(),
+            geofence: square(half),
+            lines: TimingLines::new(finish),
+            pit_lane: Vec::new(),
+            direction: None,
+            source: String::new(),
+        }
+    }
+
+    // Two configurations sharing a geofence, finishing east and west of a 100m circle
+    fn database() -> TrackDatabase {
+        let mut database = TrackDatabase::default();
+        database.insert(track(
+            "Test",
+            "East",
+            200.0,
+            Line::new(at(90.0, 0.0), at(110.0, 0.0)),
+        ));
+        database.insert(track(
+            "Test",
+            "West",
+            200.0,
+            Line::new(at(-110.0, 0.0), at(-90.0, 0.0)),
+        ));
+        database
+    }
+
+    fn message(itow: u32, point: Coordinates) -> RbMessage {
+        fix(itow).coordinates(point).build()
+    }
+
+    // Anticlockwise round the 100m circle from just east of due south, at 20 m/s
+    fn circuit(seconds: u32) -> Vec<RbMessage> {
+        (0..seconds * 25)
+            .map(|i| {
+                let angle = 20.0 * f64::from(i) * 0.04 / 100.0 - PI / 2.0 + 0.1;
+                message(i * 40, at(100.0 * angle.cos(), 100.0 * angle.sin()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bundled() {
+        // Every bundled track says where it was surveyed, and its finish line sits
+        // in its geofence and spans no more than the track
+        let database = TrackDatabase::bundled();
+        for track in database.tracks() {
+            assert!(!track.source.is_empty(), "{}", track.full_name());
+            let finish = &track.lines.finish;
+            assert!(
+                finish.a.haversine_distance(finish.b).meters() <= 25.0,
+                "{}",
+                track.full_name()
+            );
+            assert!(
+                track.contains(track.lines.finish.a),
+                "{}",
+                track.full_name()
+            );
+            assert!(
+                track.contains(track.lines.finish.b),
+                "{}",
+                track.full_name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_in_polygon() {
+        // An L shape, the notch at the top right is outside
+        let polygon = [
+            at(0.0, 0.0),
+            at(100.0, 0.0),
+            at(100.0, 50.0),
+            at(50.0, 50.0),
+            at(50.0, 100.0),
+            at(0.0, 100.0),
+        ];
+        assert!(in_polygon(&polygon, at(25.0, 25.0)));
+        assert!(in_polygon(&polygon, at(75.0, 25.0)));
+        assert!(in_polygon(&polygon, at(25.0, 75.0)));
+        assert!(!in_polygon(&polygon, at(75.0, 75.0)));
+        assert!(!in_polygon(&polygon, at(-10.0, 50.0)));
+        assert!(!in_polygon(&[], at(0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_json() {
+        let mut database = database();
+        let mut east = database.find("Test", "East").unwrap().clone();
+        east.lines = east
+            .lines
+            .with_split(Line::new(at(0.0, 90.0), at(0.0, 110.0)));
+        east.pit_lane = square(20.0);
+        east.direction = Some(Side::Left);
+        east.source = String::from("Surveyed for the tests");
+        database.insert(east);
+
+        let mut json = Vec::new();
+        database.write_json(&mut json).unwrap();
+        let text = String::from_utf8(json).unwrap();
+        assert!(text.contains("\"direction\": \"left\""));
+        let read = TrackDatabase::read_json(text.as_bytes()).unwrap();
+        assert_eq!(read.tracks().len(), 2);
+        for (read, written) in read.tracks().iter().zip(database.tracks()) {
+            // Coordinates are stored to the device's 1e-7 degrees, so survive exactly
+            assert_eq!(read, written);
+        }
+
+        let minimal = r#"{"version": 1, "tracks": [{"name": "Minimal",
+            "geofence": [[0, 0], [0, 1], [1, 1]], "finish": [[0.5, 0.5], [0.5, 0.6]]}]}"#;
+        let minimal = TrackDatabase::read_json(minimal.as_bytes()).unwrap();
+        let track = &minimal.tracks()[0];
+        assert_eq!(track.full_name(), "Minimal");
+        assert!(track.lines.splits.is_empty() && track.pit_lane.is_empty());
+        assert_eq!(track.direction, None);
+        assert!(track.source.is_empty());
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let version = format!(r#"{{"version": {}, "tracks": []}}"#, TRACKS_VERSION + 1);
+        assert_eq!(
+            TrackDatabase::read_json(version.as_bytes()),
+            Err(format!(
+                "unsupported track file version {}",
+                TRACKS_VERSION + 1
+            ))
+        );
+        let short = r#"{"version": 1, "tracks": [{"name": "Short",
+            "geofence": [[0, 0], [0, 1]], "finish": [[0.5, 0.5], [0.5, 0.6]]}]}"#;
+        assert_eq!(
+            TrackDatabase::read_json(short.as_bytes()),
+            Err("Short: geofence needs at least 3 points".to_string())
+        );
+        assert!(TrackDatabase::read_json("{".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_insert_extend() {
+        let mut database = database();
+        let finish = Line::new(at(0.0, -110.0), at(0.0, -90.0));
+        let mut user = TrackDatabase::default();
+        user.insert(track("Test", "East", 150.0, finish));
+        user.insert(track("Other", "", 100.0, finish));
+        database.extend(user);
+
+        assert_eq!(database.tracks().len(), 3);
+        let east = database.find("Test", "East").unwrap();
+        assert_eq!(east.lines.finish, finish);
+        assert_eq!(database.tracks()[0].configuration, "East"); // replaced in place
+        assert!(database.find("Other", "").is_some());
+        assert!(database.find("Other", "East").is_none());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let finish = Line::new(at(90.0, 0.0), at(110.0, 0.0));
+        let mut database = TrackDatabase::default();
+        database.insert(track("Venue", "", 1000.0, finish));
+        database.insert(track("Club", "", 150.0, finish));
+        database.insert(track("Elsewhere", "", 100.0, finish));
+        database.tracks.last_mut().unwrap().geofence = square(100.0)
+            .into_iter()
+            .map(|p| Coordinates::from_degrees(p.latitude() + 1.0, p.longitude()))
+            .collect();
+
+        let names = |point| -> Vec<_> {
+            database
+                .candidates(point)
+                .iter()
+                .map(|t| t.name.clone())
+                .collect()
+        };
+        assert_eq!(names(at(0.0, 0.0)), ["Club", "Venue"]);
+        assert_eq!(names(at(500.0, 0.0)), ["Venue"]);
+        assert!(names(at(2000.0, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn test_detector_single() {
+        let mut database = database();
+        database.tracks.pop();
+        let mut detector = TrackDetector::new(&database);
+        assert_eq!(detector.track(), None);
+
+        // Positions outside the geofence, then on the circuit
+        let outside = message(0, at(500.0, 0.0));
+        assert_eq!(detector.update(&outside), None);
+        assert!(!detector.is_decided());
+        let messages = circuit(2);
+        let track = detector.update(&messages[0]).unwrap().clone();
+        assert_eq!(track.configuration, "East");
+        assert!(detector.is_decided());
+        assert_eq!(detector.update(&messages[1]), None);
+        assert_eq!(detector.track(), Some(&track));
+    }
+
+    #[test]
+    fn test_detector_configurations() {
+        let database = database();
+        let mut detector = TrackDetector::new(&database);
+        // Invalid fixes are ignored
+        assert_eq!(detector.update(&RbMessage::default()), None);
+        let mut decided = None;
+        for (i, message) in circuit(40).iter().enumerate() {
+            if detector.update(message).is_some() {
+                decided = Some(i);
+                break;
+            }
+            assert_eq!(detector.track().unwrap().configuration, "East");
+        }
+        // A quarter lap to the east line, at 25 Hz
+        let i = decided.unwrap();
+        assert_eq!(detector.track().unwrap().configuration, "East");
+        assert!((i as f64 - (PI / 2.0 - 0.1) * 5.0 * 25.0).abs() < 2.0);
+
+        // East only counts crossings the other way, so the west line is crossed first
+        let messages = circuit(40);
+        let side = database.tracks()[0]
+            .lines
+            .finish
+            .crossing(at(100.0, -10.0), at(100.0, 10.0))
+            .unwrap()
+            .1;
+        let mut database = database.clone();
+        database.tracks[0].direction = Some(match side {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        });
+        let mut detector = TrackDetector::new(&database);
+        let track = messages
+            .iter()
+            .find_map(|m| detector.update(m).cloned())
+            .unwrap();
+        assert_eq!(track.configuration, "West");
+    }
+
+    #[test]
+    fn test_session_detect_track() {
+        let database = database();
+        let mut session = Session::default();
+        assert_eq!(session.detect_track(&database), None);
+
+        for message in circuit(40) {
+            session.push(message);
+        }
+        let track = session.detect_track(&database).unwrap();
+        assert_eq!(track.full_name(), "Test (East)");
+        assert_eq!(session.metadata.venue, "Test (East)");
+        assert!(session.track.is_some());
+
+        // A venue entered by the user is kept
+        session.metadata.venue = "Home".to_string();
+        session.detect_track(&database);
+        assert_eq!(session.metadata.venue, "Home");
+    }
+
+    #[test]
+    fn test_session_with_tracks() {
+        // East only counts crossings the other way, so the west line decides
+        let mut database = database();
+        let side = database.tracks()[0]
+            .lines
+            .finish
+            .crossing(at(100.0, -10.0), at(100.0, 10.0))
+            .unwrap()
+            .1;
+        database.tracks[0].direction = Some(match side {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        });
+
+        let mut session = Session::with_tracks(SessionMetadata::default(), &database);
+        let mut messages = circuit(40).into_iter();
+        session.push(messages.next().unwrap());
+        assert_eq!(session.metadata.venue, "Test (East)");
+        for message in messages {
+            session.push(message);
+        }
+        let track = session.track.clone().unwrap();
+        assert_eq!(track.full_name(), "Test (West)");
+        assert_eq!(session.metadata.venue, "Test (West)");
+        assert_eq!(session.lap_timer().unwrap().line(), &track.lines.finish);
+    }
+}
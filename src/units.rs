@@ -0,0 +1,300 @@
+use rbmini_core::units;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+
+/*
+Physical units for the values carried in a RaceBox Mini message
+
+The packet stores everything as scaled integers (millimetres, milli-g,
+centi-degrees and so on). These newtypes hold the value in a single base unit
+and every conversion out of them is named, so a caller always states which unit
+they are reading.
+*/
+
+// Speed, stored in metres per second
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Speed(f64);
+
+impl Speed {
+    pub fn from_mm_per_second(mm_per_second: i64) -> Self {
+        Speed(units::meters_per_second(mm_per_second))
+    }
+
+    pub fn from_meters_per_second(meters_per_second: f64) -> Self {
+        Speed(meters_per_second)
+    }
+
+    pub fn from_kph(kph: f64) -> Self {
+        Speed(kph / 3.6)
+    }
+
+    pub fn from_mph(mph: f64) -> Self {
+        Speed(mph * 0.44704)
+    }
+
+    pub fn meters_per_second(&self) -> f64 {
+        self.0
+    }
+
+    pub fn kph(&self) -> f64 {
+        self.0 * 3.6
+    }
+
+    pub fn mph(&self) -> f64 {
+        self.0 / 0.44704
+    }
+
+    pub fn knots(&self) -> f64 {
+        self.0 * 3600.0 / 1852.0
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1} kph", self.kph())
+    }
+}
+
+// Length, stored in metres
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Length(f64);
+
+impl Length {
+    pub fn from_mm(mm: i64) -> Self {
+        Length(units::meters(mm))
+    }
+
+    pub fn from_meters(meters: f64) -> Self {
+        Length(meters)
+    }
+
+    pub fn from_feet(feet: f64) -> Self {
+        Length(feet * 0.3048)
+    }
+
+    pub fn meters(&self) -> f64 {
+        self.0
+    }
+
+    pub fn mm(&self) -> f64 {
+        self.0 * 1000.0
+    }
+
+    pub fn feet(&self) -> f64 {
+        self.0 / 0.3048
+    }
+
+    pub fn kilometers(&self) -> f64 {
+        self.0 / 1000.0
+    }
+
+    pub fn miles(&self) -> f64 {
+        self.0 / 1609.344
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3} m", self.0)
+    }
+}
+
+// Angle, stored in degrees
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Angle(f64);
+
+impl Angle {
+    // Heading and heading accuracy are sent in degrees with a factor of 10^5
+    pub fn from_degrees_e5(degrees_e5: i64) -> Self {
+        Angle(units::heading_degrees(degrees_e5))
+    }
+
+    pub fn from_degrees(degrees: f64) -> Self {
+        Angle(degrees)
+    }
+
+    pub fn from_radians(radians: f64) -> Self {
+        Angle(radians.to_degrees())
+    }
+
+    pub fn degrees(&self) -> f64 {
+        self.0
+    }
+
+    pub fn radians(&self) -> f64 {
+        self.0.to_radians()
+    }
+}
+
+impl fmt::Display for Angle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.5}°", self.0)
+    }
+}
+
+// Rotation rate, stored in degrees per second
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct AngularRate(f64);
+
+impl AngularRate {
+    pub fn from_centi_degrees_per_second(centi_degrees: i64) -> Self {
+        AngularRate(units::degrees_per_second(centi_degrees))
+    }
+
+    pub fn from_degrees_per_second(degrees_per_second: f64) -> Self {
+        AngularRate(degrees_per_second)
+    }
+
+    pub fn degrees_per_second(&self) -> f64 {
+        self.0
+    }
+
+    pub fn radians_per_second(&self) -> f64 {
+        self.0.to_radians()
+    }
+}
+
+impl fmt::Display for AngularRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}°/s", self.0)
+    }
+}
+
+// Standard gravity, m/s^2 per g
+pub const STANDARD_GRAVITY: f64 = 9.80665;
+
+// Acceleration, stored in g
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Acceleration(f64);
+
+impl Acceleration {
+    pub fn from_milli_g(milli_g: i64) -> Self {
+        Acceleration(units::g(milli_g))
+    }
+
+    pub fn from_g(g: f64) -> Self {
+        Acceleration(g)
+    }
+
+    pub fn from_meters_per_second_squared(mps2: f64) -> Self {
+        Acceleration(mps2 / STANDARD_GRAVITY)
+    }
+
+    pub fn g(&self) -> f64 {
+        self.0
+    }
+
+    pub fn milli_g(&self) -> f64 {
+        self.0 * 1000.0
+    }
+
+    pub fn meters_per_second_squared(&self) -> f64 {
+        self.0 * STANDARD_GRAVITY
+    }
+}
+
+impl fmt::Display for Acceleration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3} g", self.0)
+    }
+}
+
+// Dilution of precision, a unitless ratio
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Dop(f64);
+
+impl Dop {
+    // DOP is sent with a factor of 100
+    pub fn from_centi(centi: i64) -> Self {
+        Dop(units::dop(centi))
+    }
+
+    pub fn from_value(value: f64) -> Self {
+        Dop(value)
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Dop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acceleration, Angle, AngularRate, Dop, Length, Speed};
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_speed() {
+        let speed = Speed::from_mm_per_second(27778);
+        assert_close(speed.meters_per_second(), 27.778);
+        assert_close(speed.kph(), 100.0008);
+        assert_close(Speed::from_kph(100.0).meters_per_second(), 27.777777777777);
+        assert_close(Speed::from_mph(60.0).kph(), 96.56064);
+        assert_close(Speed::from_mph(60.0).mph(), 60.0);
+        assert_close(Speed::from_meters_per_second(1852.0 / 3600.0).knots(), 1.0);
+        assert_eq!(format!("{}", Speed::from_kph(123.46)), "123.5 kph");
+    }
+
+    #[test]
+    fn test_length() {
+        let length = Length::from_mm(625761);
+        assert_close(length.meters(), 625.761);
+        assert_close(length.mm(), 625761.0);
+        assert_close(Length::from_feet(1.0).meters(), 0.3048);
+        assert_close(Length::from_meters(402.336).miles(), 0.25);
+        assert_close(Length::from_meters(1500.0).kilometers(), 1.5);
+        assert_close(Length::from_meters(18.288).feet(), 60.0);
+    }
+
+    #[test]
+    fn test_angle() {
+        let angle = Angle::from_degrees_e5(14526856);
+        assert_close(angle.degrees(), 145.26856);
+        assert_close(Angle::from_degrees(180.0).radians(), std::f64::consts::PI);
+        assert_close(
+            Angle::from_radians(std::f64::consts::FRAC_PI_2).degrees(),
+            90.0,
+        );
+    }
+
+    #[test]
+    fn test_angular_rate() {
+        let rate = AngularRate::from_centi_degrees_per_second(-209);
+        assert_close(rate.degrees_per_second(), -2.09);
+        assert_close(
+            AngularRate::from_degrees_per_second(180.0).radians_per_second(),
+            std::f64::consts::PI,
+        );
+    }
+
+    #[test]
+    fn test_acceleration() {
+        let acceleration = Acceleration::from_milli_g(974);
+        assert_close(acceleration.g(), 0.974);
+        assert_close(acceleration.milli_g(), 974.0);
+        assert_close(
+            Acceleration::from_g(1.0).meters_per_second_squared(),
+            9.80665,
+        );
+        assert_close(
+            Acceleration::from_meters_per_second_squared(9.80665).g(),
+            1.0,
+        );
+    }
+
+    #[test]
+    fn test_dop() {
+        assert_close(Dop::from_centi(300).value(), 3.0);
+        assert_close(Dop::from_value(1.5).value(), 1.5);
+    }
+}